@@ -0,0 +1,47 @@
+/* C bindings of the kvs key-value store, built with the `ffi` feature of the crate. */
+
+#ifndef KVS_H
+#define KVS_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The call succeeded. */
+#define KVS_OK 0
+/* The key is not in the store. */
+#define KVS_NOT_FOUND 1
+/* An argument is a null pointer or not valid UTF-8. */
+#define KVS_INVALID_ARGUMENT -1
+/* The store failed, see kvs_last_error(). */
+#define KVS_ERROR -2
+
+typedef struct KvStore KvStore;
+
+/* Opens the store in the directory `path`, which must exist. Returns NULL on failure. */
+KvStore *kvs_open(const char *path);
+
+/* Closes `store`, which must not be used afterwards. */
+void kvs_close(KvStore *store);
+
+/* Sets the value of `key` to `value`. */
+int kvs_set(const KvStore *store, const char *key, const char *value);
+
+/* Stores in `*value` the value of `key`, to be freed with kvs_free(), or returns
+ * KVS_NOT_FOUND and stores NULL if the key is not in the store. */
+int kvs_get(const KvStore *store, const char *key, char **value);
+
+/* Removes `key`, or returns KVS_NOT_FOUND if it is not in the store. */
+int kvs_remove(const KvStore *store, const char *key);
+
+/* Frees a value returned by kvs_get(). */
+void kvs_free(char *value);
+
+/* Returns the message of the error of the last failed call on this thread, or NULL. */
+const char *kvs_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KVS_H */
@@ -0,0 +1,117 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The port of a server address given without one.
+pub const DEFAULT_PORT: u16 = 4000;
+
+/// The address of a kvs-server: an IP address or a hostname, and a port, 4000 if omitted.
+/// Hostnames are only resolved when connecting or binding, to every address they designate.
+///
+/// # Examples
+///
+/// ```
+/// use kvs::ServerAddr;
+///
+/// let addr: ServerAddr = "kvs.internal:4001".parse().unwrap();
+/// assert_eq!((addr.host(), addr.port()), ("kvs.internal", 4001));
+/// assert_eq!("::".parse::<ServerAddr>().unwrap().to_string(), "[::]:4000");
+/// assert!("kvs.internal:http".parse::<ServerAddr>().is_err());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerAddr {
+    host: String,
+    port: u16,
+}
+
+impl ServerAddr {
+    /// Creates the address of the server listening on `port` of `host`.
+    pub fn new<H: Into<String>>(host: H, port: u16) -> ServerAddr {
+        ServerAddr {
+            host: host.into(),
+            port,
+        }
+    }
+
+    /// The IP address or hostname of the server, without the brackets of an IPv6 address.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The port of the server.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Resolves the host into the socket addresses it designates, in the order of the resolver.
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = (self.host.as_str(), self.port).to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} resolves to no address", self),
+            ));
+        }
+        Ok(addrs)
+    }
+
+    /// Connects to the first of the resolved addresses to accept the connection, waiting at
+    /// most `timeout` for each. Returns the error of the last one tried if none does.
+    pub fn connect(&self, timeout: Duration) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.resolve()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.expect("resolved to no address"))
+    }
+}
+
+impl From<SocketAddr> for ServerAddr {
+    fn from(addr: SocketAddr) -> ServerAddr {
+        ServerAddr::new(addr.ip().to_string(), addr.port())
+    }
+}
+
+impl FromStr for ServerAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(addr.into());
+        }
+        // A bare IP address, IPv6 ones being possibly bracketed.
+        let ip = s.strip_prefix('[').and_then(|s| s.strip_suffix(']'));
+        if let Ok(ip) = ip.unwrap_or(s).parse::<IpAddr>() {
+            return Ok(ServerAddr::new(ip.to_string(), DEFAULT_PORT));
+        }
+
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("Invalid port in address: {}", s))?;
+                (host, port)
+            }
+            None => (s, DEFAULT_PORT),
+        };
+        if host.is_empty() || host.contains(|c: char| c == ':' || c.is_whitespace()) {
+            return Err(format!("Invalid host in address: {}", s));
+        }
+        Ok(ServerAddr::new(host, port))
+    }
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
@@ -0,0 +1,1283 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::process::exit;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use structopt::StructOpt;
+
+use kvs::{KvsError, ServerAddr};
+
+use self::redis::Redis;
+
+mod redis;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-client",
+    about = "A simple Key-Value database client",
+    raw(setting = "structopt::clap::AppSettings::VersionlessSubcommands")
+)]
+struct Kvs {
+    #[structopt(subcommand)]
+    option: Opt,
+
+    /// The address of the server with the format HOST:PORT, where HOST is an IP address or a
+    /// hostname, whose addresses are tried in turn. The port defaults to 4000.
+    #[structopt(
+        long = "addr",
+        default_value = "127.0.0.1:4000",
+        raw(set = "structopt::clap::ArgSettings::Global")
+    )]
+    ip: ServerAddr,
+}
+
+#[derive(StructOpt, Debug)]
+enum Opt {
+    ///Insert the <key> with <value> into dataset.
+    ///If the <key> already exists, update the associated value to <value>.
+    #[structopt(
+        name = "set",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Set {
+        key: String,
+        value: String,
+        /// Make the <key> expire after this many seconds.
+        #[structopt(long = "ttl")]
+        ttl: Option<u64>,
+        /// Only set the <key> if it is still at this version, as printed by get --with-version,
+        /// or still does not exist if "none". Print the new version of the key.
+        #[structopt(long = "if-version", raw(conflicts_with = "\"ttl\""))]
+        if_version: Option<ExpectedVersion>,
+    },
+
+    ///Make the <key> expire after <seconds>.
+    #[structopt(
+        name = "expire",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Expire { key: String, seconds: u64 },
+
+    ///Print the number of seconds the <key> has left to live, or -1 if it does not expire.
+    #[structopt(
+        name = "ttl",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Ttl { key: String },
+
+    ///Get the associated value of each <key>, one line per key. If a <key> does't exist,
+    ///print "Key not found" in its place.
+    #[structopt(
+        name = "get",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Get {
+        #[structopt(raw(required = "true"))]
+        keys: Vec<String>,
+        /// Print how many seconds each key has left to live on the line after its value, or
+        /// "none" if it does not expire.
+        #[structopt(long = "verbose", short = "v")]
+        verbose: bool,
+        /// Print the version of each key on the line after its value, for set --if-version.
+        /// Only servers started with --versioned know it.
+        #[structopt(long = "with-version", raw(conflicts_with = "\"verbose\""))]
+        with_version: bool,
+    },
+
+    ///Print the length in bytes of the value of the <key>, without fetching the value.
+    #[structopt(
+        name = "strlen",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Strlen { key: String },
+
+    ///Print the size of the value of the <key>, its version and when it was created and last
+    ///modified, in milliseconds since the Unix epoch or "none" if written by an older server.
+    #[structopt(
+        name = "meta",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Meta { key: String },
+
+    ///Remove each <key> and its associated value. When several keys are given, print one
+    ///line per key telling whether it was removed.
+    #[structopt(
+        name = "rm",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Remove {
+        #[structopt(raw(required = "true"))]
+        keys: Vec<String>,
+    },
+
+    ///Scan all keys in the dataset.
+    #[structopt(
+        name = "scan",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Scan {
+        /// Only print the keys this regular expression matches, anywhere in the key unless
+        /// anchored, one per line. Servers without regular expressions send all their keys to
+        /// be filtered here.
+        #[structopt(long = "regex")]
+        regex: Option<String>,
+    },
+
+    ///Print the keys matching a glob-style <pattern>, one per line, e.g. "user:*".
+    #[structopt(
+        name = "keys",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Keys { pattern: String },
+
+    ///Make the server write a checkpoint of its index, so that a restart does not have to
+    ///replay the whole log.
+    #[structopt(
+        name = "save",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Save,
+
+    ///Measure the round trip time to the server, connection included, and exit with a
+    ///non-zero code if it never answered.
+    #[structopt(
+        name = "ping",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Ping {
+        /// The number of pings to send.
+        #[structopt(long = "count", short = "c", default_value = "1")]
+        count: u32,
+        /// The milliseconds to wait between two pings.
+        #[structopt(long = "interval", short = "i", default_value = "1000")]
+        interval: u64,
+    },
+
+    ///Push each <value> to the head of the list <key>, so that the last one ends up first, and
+    ///print the length of the list.
+    #[structopt(
+        name = "lpush",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Lpush {
+        key: String,
+        #[structopt(raw(required = "true"))]
+        values: Vec<String>,
+    },
+
+    ///Print the elements of the list <key> from <start> to <stop> included, one per line. Both
+    ///count from the head of the list, or from its tail if negative, -1 being the last element.
+    #[structopt(
+        name = "lrange",
+        raw(
+            setting = "structopt::clap::AppSettings::DisableHelpFlags",
+            setting = "structopt::clap::AppSettings::AllowNegativeNumbers"
+        )
+    )]
+    Lrange { key: String, start: i64, stop: i64 },
+
+    ///Add each <member> to the set <key>, and print how many were not in it yet.
+    #[structopt(
+        name = "sadd",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Sadd {
+        key: String,
+        #[structopt(raw(required = "true"))]
+        members: Vec<String>,
+    },
+
+    ///Print the members of the set <key>, one per line in ascending order.
+    #[structopt(
+        name = "smembers",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Smembers { key: String },
+
+    ///Set the <field> of the hash <key> to <value>.
+    #[structopt(
+        name = "hset",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Hset {
+        key: String,
+        field: String,
+        value: String,
+    },
+
+    ///Print the value of the <field> of the hash <key>. If the hash has no such field, print
+    ///"Key not found".
+    #[structopt(
+        name = "hget",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Hget { key: String, field: String },
+
+    ///Acquire the lock <name> for <ttl> seconds, and print the fencing token of the hold, greater
+    ///than the tokens of every hold before it.
+    #[structopt(
+        name = "lock",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Lock {
+        name: String,
+        /// The seconds after which the hold expires unless released.
+        #[structopt(long = "ttl")]
+        ttl: u64,
+    },
+
+    ///Release the hold of the lock <name> acquired with the fencing <token>.
+    #[structopt(
+        name = "unlock",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Unlock { name: String, token: u64 },
+
+    ///Publish the <message> to the <channel>, and print how many subscribers received it.
+    #[structopt(
+        name = "publish",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Publish { channel: String, message: String },
+
+    ///Subscribe to each <channel>, and print the messages published to them as they come, one
+    ///line per message after the name of its channel.
+    #[structopt(
+        name = "subscribe",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Subscribe {
+        #[structopt(raw(required = "true"))]
+        channels: Vec<String>,
+        /// Exit once this many messages were received.
+        #[structopt(long = "count", short = "c")]
+        count: Option<u64>,
+    },
+
+    ///Print the statistics of the server.
+    #[structopt(
+        name = "info",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Info,
+
+    ///Inspect the connections of the server.
+    #[structopt(
+        name = "client",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Client {
+        #[structopt(subcommand)]
+        command: ClientOpt,
+    },
+
+    ///Copy the string keys of a Redis instance matching <pattern> to the dataset, and print
+    ///how many were imported.
+    #[structopt(
+        name = "import-redis",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    ImportRedis {
+        /// The URL of the Redis instance, e.g. "redis://:password@localhost:6379/0".
+        #[structopt(long = "from")]
+        from: String,
+        /// Only import the keys matching this glob-style pattern.
+        #[structopt(long = "pattern", default_value = "*")]
+        pattern: String,
+    },
+
+    ///Copy the keys of a server to another, and print how many were copied. The values are
+    ///read and written in pipelined batches while both servers keep serving requests, but the
+    ///times to live are not carried over.
+    #[structopt(
+        name = "copy",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    Copy {
+        /// The address of the server to copy the keys from.
+        #[structopt(long = "from")]
+        from: ServerAddr,
+        /// The address of the server to copy the keys to.
+        #[structopt(long = "to")]
+        to: ServerAddr,
+        /// Only copy the keys starting with this prefix.
+        #[structopt(long = "prefix", default_value = "")]
+        prefix: String,
+    },
+}
+
+/// The version a key is expected to be at, `None` if it is expected not to exist.
+#[derive(Debug)]
+struct ExpectedVersion(Option<u64>);
+
+impl FromStr for ExpectedVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ExpectedVersion(None)),
+            version => version
+                .parse()
+                .map(|version| ExpectedVersion(Some(version)))
+                .map_err(|_| format!("Invalid version: {}", s)),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+enum ClientOpt {
+    ///Print the connections of the server, one per line, with the address of their peer, their
+    ///age and idle time in seconds, their last command and how many commands they sent.
+    #[structopt(
+        name = "list",
+        raw(setting = "structopt::clap::AppSettings::DisableHelpFlags")
+    )]
+    List,
+}
+
+enum Command {
+    Set {
+        key: String,
+        value: String,
+    },
+    SetEx {
+        key: String,
+        value: String,
+        seconds: u64,
+    },
+    SetIfVersion {
+        key: String,
+        value: String,
+        expected: Option<u64>,
+    },
+    Expire {
+        key: String,
+        seconds: u64,
+    },
+    Ttl {
+        key: String,
+    },
+    Get {
+        key: String,
+    },
+    GetVersioned {
+        key: String,
+    },
+    MultiGet {
+        keys: Vec<String>,
+    },
+    Strlen {
+        key: String,
+    },
+    Meta {
+        key: String,
+    },
+    Rm {
+        key: String,
+    },
+    MultiRm {
+        keys: Vec<String>,
+    },
+    Scan,
+    RegexScan {
+        pattern: String,
+    },
+    Keys {
+        pattern: String,
+    },
+    Save,
+    Ping,
+    Info,
+    ClientList,
+    Lpush {
+        key: String,
+        values: Vec<String>,
+    },
+    Lrange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    Sadd {
+        key: String,
+        members: Vec<String>,
+    },
+    Smembers {
+        key: String,
+    },
+    Hset {
+        key: String,
+        field: String,
+        value: String,
+    },
+    Hget {
+        key: String,
+        field: String,
+    },
+    Lock {
+        name: String,
+        seconds: u64,
+    },
+    Unlock {
+        name: String,
+        token: u64,
+    },
+    Publish {
+        channel: String,
+        message: String,
+    },
+    Subscribe {
+        channels: Vec<String>,
+    },
+}
+
+/// The number of keys read from the source of a copy at once.
+const COPY_BATCH: usize = 100;
+
+/// The number of SETs a copy keeps waiting for an answer from its destination at once, each on a
+/// connection of its own.
+const COPY_CONNECTIONS: usize = 8;
+
+/// How many times a copy sends a SET its destination is too busy to take, and how long it waits
+/// before sending it again the first time, doubled every time after.
+const COPY_ATTEMPTS: u32 = 5;
+const COPY_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Exit code when the requested key does not exist.
+const EXIT_KEY_NOT_FOUND: i32 = 2;
+/// Exit code when the server answered with an error.
+const EXIT_SERVER_ERROR: i32 = 3;
+/// Exit code when the server could not be reached or the connection broke.
+const EXIT_CONNECTION_FAILURE: i32 = 4;
+/// Exit code when a conditional write was rejected because the key changed since it was read.
+const EXIT_VERSION_MISMATCH: i32 = 5;
+/// Exit code when a lock is held by someone else, or not under the token it is released with.
+const EXIT_LOCK_HELD: i32 = 6;
+
+/// The ways a request can fail, each mapped to a distinct exit code so shell scripts can
+/// branch on the outcome without parsing the output.
+enum ClientError {
+    /// At least one key was not found. Carries the output to print on stdout, if any.
+    KeyNotFound(Option<String>),
+    Server(String),
+    /// The server is too busy to take the request, which may be sent again.
+    Busy(String),
+    /// The server does not know the command, being older than the client.
+    Unsupported(String),
+    /// The key is not at the version the write expected.
+    VersionMismatch(String),
+    /// The lock is held by someone else, or not under the token it is released with.
+    LockHeld(&'static str),
+    Connection(io::Error),
+}
+
+impl ClientError {
+    fn exit(self) -> ! {
+        match self {
+            ClientError::KeyNotFound(Some(output)) => {
+                println!("{}", output);
+                exit(EXIT_KEY_NOT_FOUND)
+            }
+            ClientError::KeyNotFound(None) => {
+                eprintln!("{}", KvsError::KeyNotFound);
+                exit(EXIT_KEY_NOT_FOUND)
+            }
+            ClientError::Server(msg) | ClientError::Busy(msg) | ClientError::Unsupported(msg) => {
+                eprintln!("{}", msg);
+                exit(EXIT_SERVER_ERROR)
+            }
+            ClientError::Connection(err) => {
+                eprintln!("Failed to talk to the server: {}", err);
+                exit(EXIT_CONNECTION_FAILURE)
+            }
+            ClientError::VersionMismatch(msg) => {
+                eprintln!("{}", msg);
+                exit(EXIT_VERSION_MISMATCH)
+            }
+            ClientError::LockHeld(msg) => {
+                eprintln!("{}", msg);
+                exit(EXIT_LOCK_HELD)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(error: io::Error) -> Self {
+        ClientError::Connection(error)
+    }
+}
+
+fn main() {
+    let opt = Kvs::from_args();
+
+    let (cmd, response_type) = match opt.option {
+        Opt::Set {
+            key,
+            value,
+            ttl: None,
+            if_version: None,
+        } => (Command::Set { key, value }, "SET"),
+        Opt::Set {
+            key,
+            value,
+            if_version: Some(ExpectedVersion(expected)),
+            ..
+        } => (
+            Command::SetIfVersion {
+                key,
+                value,
+                expected,
+            },
+            "SETV",
+        ),
+        Opt::Set {
+            key,
+            value,
+            ttl: Some(seconds),
+            if_version: None,
+        } => (
+            Command::SetEx {
+                key,
+                value,
+                seconds,
+            },
+            "SETEX",
+        ),
+        Opt::Expire { key, seconds } => (Command::Expire { key, seconds }, "EXPIRE"),
+        Opt::Ttl { key } => (Command::Ttl { key }, "TTL"),
+        Opt::Get {
+            keys,
+            with_version: true,
+            ..
+        } => {
+            match get_with_version(&opt.ip, keys) {
+                Ok(output) => println!("{}", output),
+                Err(err) => err.exit(),
+            }
+            return;
+        }
+        Opt::Get {
+            keys,
+            verbose: true,
+            ..
+        } => {
+            match get_verbose(&opt.ip, keys) {
+                Ok(output) => println!("{}", output),
+                Err(err) => err.exit(),
+            }
+            return;
+        }
+        Opt::Get {
+            mut keys,
+            verbose: false,
+            with_version: false,
+        } => {
+            if keys.len() == 1 {
+                (
+                    Command::Get {
+                        key: keys.remove(0),
+                    },
+                    "GET",
+                )
+            } else {
+                (Command::MultiGet { keys }, "MGET")
+            }
+        }
+        Opt::Strlen { key } => (Command::Strlen { key }, "STRLEN"),
+        Opt::Meta { key } => (Command::Meta { key }, "META"),
+        Opt::Remove { mut keys } => {
+            if keys.len() == 1 {
+                (
+                    Command::Rm {
+                        key: keys.remove(0),
+                    },
+                    "RM",
+                )
+            } else {
+                (Command::MultiRm { keys }, "MRM")
+            }
+        }
+        Opt::Scan { regex: None } => (Command::Scan, "SCAN"),
+        Opt::Scan {
+            regex: Some(pattern),
+        } => {
+            match scan_regex(&opt.ip, pattern) {
+                Ok(keys) => keys.iter().for_each(|key| println!("{}", key)),
+                Err(err) => err.exit(),
+            }
+            return;
+        }
+        Opt::Keys { pattern } => (Command::Keys { pattern }, "KEYS"),
+        Opt::Save => (Command::Save, "SAVE"),
+        Opt::Ping { count, interval } => {
+            if !ping(&opt.ip, count, Duration::from_millis(interval)) {
+                exit(EXIT_CONNECTION_FAILURE);
+            }
+            return;
+        }
+        Opt::Lpush { key, values } => (Command::Lpush { key, values }, "LPUSH"),
+        Opt::Lrange { key, start, stop } => (Command::Lrange { key, start, stop }, "LRANGE"),
+        Opt::Sadd { key, members } => (Command::Sadd { key, members }, "SADD"),
+        Opt::Smembers { key } => (Command::Smembers { key }, "SMEMBERS"),
+        Opt::Hset { key, field, value } => (Command::Hset { key, field, value }, "HSET"),
+        // Answered as GET is.
+        Opt::Hget { key, field } => (Command::Hget { key, field }, "GET"),
+        Opt::Lock { name, ttl } => (Command::Lock { name, seconds: ttl }, "LOCK"),
+        Opt::Unlock { name, token } => (Command::Unlock { name, token }, "UNLOCK"),
+        Opt::Publish { channel, message } => (Command::Publish { channel, message }, "PUBLISH"),
+        Opt::Subscribe { channels, count } => {
+            if let Err(err) = subscribe(&opt.ip, channels, count) {
+                err.exit();
+            }
+            return;
+        }
+        Opt::Info => (Command::Info, "INFO"),
+        Opt::Client {
+            command: ClientOpt::List,
+        } => (Command::ClientList, "CLIENT"),
+        Opt::ImportRedis { from, pattern } => {
+            match import_redis(&opt.ip, &from, &pattern) {
+                Ok((imported, skipped)) => {
+                    println!("Imported {} keys", imported);
+                    if skipped > 0 {
+                        println!("Skipped {} keys not holding UTF-8 strings", skipped);
+                    }
+                }
+                Err(err) => err.exit(),
+            }
+            return;
+        }
+        Opt::Copy { from, to, prefix } => {
+            match copy(&from, &to, &prefix) {
+                Ok((copied, vanished)) => {
+                    println!("Copied {} keys", copied);
+                    if vanished > 0 {
+                        println!("Skipped {} keys removed during the copy", vanished);
+                    }
+                }
+                Err(err) => err.exit(),
+            }
+            return;
+        }
+    };
+
+    let response =
+        request_to_server(&opt.ip, cmd).and_then(|reader| parse_response(reader, response_type));
+    match response {
+        Ok(Some(response)) => println!("{}", response),
+        Ok(None) => (),
+        Err(err) => err.exit(),
+    }
+}
+
+fn request_to_server(addr: &ServerAddr, cmd: Command) -> Result<BufReader<TcpStream>, ClientError> {
+    let mut stream = addr.connect(Duration::from_secs(1))?;
+    let request = match cmd {
+        Command::Set { key, value } => {
+            format!("SET\r\n{}\r\n{}\r\n{}\r\n", key, value.len(), value)
+        }
+        Command::SetEx {
+            key,
+            value,
+            seconds,
+        } => format!(
+            "SETEX\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
+            key,
+            seconds,
+            value.len(),
+            value
+        ),
+        Command::Expire { key, seconds } => format!("EXPIRE\r\n{}\r\n{}\r\n", key, seconds),
+        Command::Ttl { key } => format!("TTL\r\n{}\r\n", key),
+        Command::SetIfVersion {
+            key,
+            value,
+            expected,
+        } => format!(
+            "SETV\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
+            key,
+            expected.map_or("-1".to_owned(), |version| version.to_string()),
+            value.len(),
+            value
+        ),
+        Command::Get { key } => format!("GET\r\n{}\r\n", key),
+        Command::GetVersioned { key } => format!("GETV\r\n{}\r\n", key),
+        Command::MultiGet { keys } => format!("MGET\r\n{}", format_keys(&keys)),
+        Command::Strlen { key } => format!("STRLEN\r\n{}\r\n", key),
+        Command::Meta { key } => format!("META\r\n{}\r\n", key),
+        Command::Rm { key } => format!("RM\r\n{}\r\n", key),
+        Command::MultiRm { keys } => format!("MRM\r\n{}", format_keys(&keys)),
+        Command::Scan => "SCAN\r\n".to_string(),
+        Command::RegexScan { pattern } => format!("RSCAN\r\n{}\r\n", pattern),
+        Command::Keys { pattern } => format!("KEYS\r\n{}\r\n", pattern),
+        Command::Save => "SAVE\r\n".to_string(),
+        Command::Ping => "PING\r\n".to_string(),
+        Command::Info => "INFO\r\n".to_string(),
+        Command::ClientList => "CLIENT\r\nLIST\r\n".to_string(),
+        Command::Lpush { key, values } => format!("LPUSH\r\n{}\r\n{}", key, format_values(&values)),
+        Command::Lrange { key, start, stop } => {
+            format!("LRANGE\r\n{}\r\n{}\r\n{}\r\n", key, start, stop)
+        }
+        Command::Sadd { key, members } => format!("SADD\r\n{}\r\n{}", key, format_values(&members)),
+        Command::Smembers { key } => format!("SMEMBERS\r\n{}\r\n", key),
+        Command::Hset { key, field, value } => format!(
+            "HSET\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
+            key,
+            field,
+            value.len(),
+            value
+        ),
+        Command::Hget { key, field } => format!("HGET\r\n{}\r\n{}\r\n", key, field),
+        Command::Lock { name, seconds } => format!("LOCK\r\n{}\r\n{}\r\n", name, seconds),
+        Command::Unlock { name, token } => format!("UNLOCK\r\n{}\r\n{}\r\n", name, token),
+        Command::Publish { channel, message } => format!(
+            "PUBLISH\r\n{}\r\n{}\r\n{}\r\n",
+            channel,
+            message.len(),
+            message
+        ),
+        Command::Subscribe { channels } => format!("SUBSCRIBE\r\n{}", format_keys(&channels)),
+    };
+
+    stream.write_all(request.as_bytes())?;
+    Ok(BufReader::new(stream))
+}
+
+/// Copies the string keys of the Redis instance at `url` matching `pattern` to the server at
+/// `addr`, one SET at a time. Returns the number of keys imported, and of the keys skipped
+/// because they hold something else than a string or are not valid UTF-8 lines.
+fn import_redis(addr: &ServerAddr, url: &str, pattern: &str) -> Result<(u64, u64), ClientError> {
+    let redis_error = |err: io::Error| ClientError::Server(format!("Redis: {}", err));
+    let mut redis = Redis::connect(url).map_err(redis_error)?;
+    let (mut imported, mut skipped) = (0, 0);
+    let mut cursor = "0".to_owned();
+    loop {
+        let (next, keys) = redis.scan(&cursor, pattern).map_err(redis_error)?;
+        if !keys.is_empty() {
+            let values = redis.mget(&keys).map_err(redis_error)?;
+            for (key, value) in keys.into_iter().zip(values) {
+                let key = String::from_utf8(key)
+                    .ok()
+                    .filter(|key| !key.contains('\n'));
+                match (key, value.and_then(|value| String::from_utf8(value).ok())) {
+                    (Some(key), Some(value)) => {
+                        let reader = request_to_server(addr, Command::Set { key, value })?;
+                        parse_response(reader, "SET")?;
+                        imported += 1;
+                    }
+                    _ => skipped += 1,
+                }
+            }
+        }
+        if next == "0" {
+            return Ok((imported, skipped));
+        }
+        cursor = next;
+    }
+}
+
+/// Gets the value of every key followed by the time it has left to live, one request after the
+/// other. A missing key does not stop the others, but turns the whole result into
+/// `ClientError::KeyNotFound`.
+fn get_verbose(addr: &ServerAddr, keys: Vec<String>) -> Result<String, ClientError> {
+    let mut lines = Vec::with_capacity(keys.len() * 2);
+    let mut all_found = true;
+    for key in keys {
+        let get = request_to_server(addr, Command::Get { key: key.clone() })?;
+        let found = parse_response(get, "GET").and_then(|value| {
+            let ttl = request_to_server(addr, Command::Ttl { key })?;
+            Ok((value, parse_response(ttl, "TTL")?))
+        });
+        match found {
+            Ok((Some(value), Some(ttl))) => {
+                lines.push(value);
+                lines.push(match ttl.as_ref() {
+                    "-1" => "ttl: none".to_string(),
+                    seconds => format!("ttl: {}s", seconds),
+                });
+            }
+            // Expired between both requests, if not by the GET.
+            Ok(_) | Err(ClientError::KeyNotFound(_)) => {
+                all_found = false;
+                lines.push(KvsError::KeyNotFound.to_string());
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    if all_found {
+        Ok(lines.join("\n"))
+    } else {
+        Err(ClientError::KeyNotFound(Some(lines.join("\n"))))
+    }
+}
+
+/// Gets the value of every key followed by its version, like
+/// [`get_verbose`](fn.get_verbose.html) but with a single request per key.
+fn get_with_version(addr: &ServerAddr, keys: Vec<String>) -> Result<String, ClientError> {
+    let mut lines = Vec::with_capacity(keys.len() * 2);
+    let mut all_found = true;
+    for key in keys {
+        let mut reader = request_to_server(addr, Command::GetVersioned { key })?;
+        read_status(&mut reader)?;
+        let version = read_line_from_stream(&mut reader)?;
+        if version == "-1" {
+            all_found = false;
+            lines.push(KvsError::KeyNotFound.to_string());
+            continue;
+        }
+        let value_len = read_line_from_stream(&mut reader)?;
+        lines.push(read_value_from_stream(&mut reader, &value_len)?);
+        lines.push(format!("version: {}", version));
+    }
+
+    if all_found {
+        Ok(lines.join("\n"))
+    } else {
+        Err(ClientError::KeyNotFound(Some(lines.join("\n"))))
+    }
+}
+
+/// Subscribes to `channels` on the server at `addr`, and prints every message pushed until
+/// `count` were received, if given, or the connection is closed.
+fn subscribe(
+    addr: &ServerAddr,
+    channels: Vec<String>,
+    count: Option<u64>,
+) -> Result<(), ClientError> {
+    let mut reader = request_to_server(addr, Command::Subscribe { channels })?;
+    read_status(&mut reader)?;
+    let mut received = 0;
+    while count.is_none_or(|count| received < count) {
+        if read_line_from_stream(&mut reader)? != "MESSAGE" {
+            return Err(ClientError::Server(
+                "Some unknown errors have occurred.".to_string(),
+            ));
+        }
+        let channel = read_line_from_stream(&mut reader)?;
+        let len = read_line_from_stream(&mut reader)?;
+        let message = read_value_from_stream(&mut reader, &len)?;
+        println!("{}: {}", channel, message);
+        received += 1;
+    }
+    Ok(())
+}
+
+/// Pings the server at `addr` `count` times, `interval` apart, printing the round trip time of
+/// every ping and a summary. Returns whether the server answered at least once.
+fn ping(addr: &ServerAddr, count: u32, interval: Duration) -> bool {
+    let mut times = Vec::new();
+    for i in 0..count {
+        if i > 0 {
+            thread::sleep(interval);
+        }
+        let started = Instant::now();
+        match request_to_server(addr, Command::Ping)
+            .and_then(|reader| parse_response(reader, "PING"))
+        {
+            Ok(_) => {
+                let time = started.elapsed();
+                println!("Reply from {}: time={:.3} ms", addr, millis(time));
+                times.push(time);
+            }
+            Err(ClientError::Connection(err)) => eprintln!("No reply from {}: {}", addr, err),
+            Err(ClientError::Server(msg))
+            | Err(ClientError::Busy(msg))
+            | Err(ClientError::Unsupported(msg)) => {
+                eprintln!("Error from {}: {}", addr, msg)
+            }
+            Err(ClientError::KeyNotFound(_))
+            | Err(ClientError::VersionMismatch(_))
+            | Err(ClientError::LockHeld(_)) => {
+                unreachable!("PING does not look a key up")
+            }
+        }
+    }
+
+    let received = times.len();
+    print!("{} sent, {} received", count, received);
+    if let (Some(min), Some(max)) = (times.iter().min(), times.iter().max()) {
+        let avg = times.iter().sum::<Duration>() / received as u32;
+        print!(
+            ", min/avg/max = {:.3}/{:.3}/{:.3} ms",
+            millis(*min),
+            millis(avg),
+            millis(*max)
+        );
+    }
+    println!();
+    received > 0
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Returns the keys of the server at `addr` matched by the regular expression `pattern`. A
+/// server which does not know RSCAN sends all its keys, which are filtered here instead.
+fn scan_regex(addr: &ServerAddr, pattern: String) -> Result<Vec<String>, ClientError> {
+    let invalid = |e: regex::Error| KvsError::InvalidPattern(e.to_string()).to_string();
+    let regex = Regex::new(&pattern).map_err(|e| ClientError::Server(invalid(e)))?;
+    let reader = request_to_server(addr, Command::RegexScan { pattern })?;
+    match parse_response(reader, "RSCAN") {
+        Ok(keys) => Ok(keys.map_or_else(Vec::new, |keys| {
+            keys.split('\n').map(str::to_owned).collect()
+        })),
+        Err(ClientError::Unsupported(_)) => {
+            let mut reader = request_to_server(addr, Command::Scan)?;
+            read_status(&mut reader)?;
+            let mut keys = String::new();
+            reader.read_to_string(&mut keys)?;
+            Ok(keys
+                .split("\r\n")
+                .filter(|key| !key.is_empty() && regex.is_match(key))
+                .map(str::to_owned)
+                .collect())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Copies the keys of the server at `from` starting with `prefix` to the server at `to`. The
+/// values of the next batch are fetched while the keys of a batch are set, with one SET per
+/// connection as the server answers a single request on each, and at most `COPY_CONNECTIONS` of
+/// them waiting for an answer at once. Returns the number of keys copied, and of the keys
+/// removed from `from` before their value was read.
+fn copy(from: &ServerAddr, to: &ServerAddr, prefix: &str) -> Result<(u64, u64), ClientError> {
+    let pattern = format!("{}*", escape_glob(prefix));
+    let listed = request_to_server(from, Command::Keys { pattern })?;
+    let keys = match parse_response(listed, "KEYS")? {
+        Some(keys) => keys.split('\n').map(str::to_owned).collect(),
+        None => Vec::new(),
+    };
+
+    let fetch = |batch: &[String]| {
+        request_to_server(
+            from,
+            Command::MultiGet {
+                keys: batch.to_vec(),
+            },
+        )
+    };
+    let batches: Vec<&[String]> = keys.chunks(COPY_BATCH).collect();
+    let mut fetching = match batches.first() {
+        Some(batch) => Some(fetch(batch)?),
+        None => None,
+    };
+    let (mut copied, mut vanished) = (0, 0);
+    for (i, batch) in batches.iter().enumerate() {
+        let values = match fetching.take() {
+            Some(reader) => parse_values_response(reader)?,
+            None => break,
+        };
+        if let Some(next) = batches.get(i + 1) {
+            fetching = Some(fetch(next)?);
+        }
+
+        let mut sets: VecDeque<(_, String, _)> = VecDeque::with_capacity(COPY_CONNECTIONS);
+        for (key, value) in batch.iter().zip(values) {
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    vanished += 1;
+                    continue;
+                }
+            };
+            if sets.len() == COPY_CONNECTIONS {
+                let (key, value, reader) = sets.pop_front().unwrap();
+                answer_copied_set(to, key, &value, reader)?;
+                copied += 1;
+            }
+            let reader = send_copied_set(to, key, &value)?;
+            sets.push_back((key, value, reader));
+        }
+        for (key, value, reader) in sets {
+            answer_copied_set(to, key, &value, reader)?;
+            copied += 1;
+        }
+    }
+    Ok((copied, vanished))
+}
+
+/// Sends the SET of `key` to `value` to the server at `addr` for a copy.
+fn send_copied_set(
+    addr: &ServerAddr,
+    key: &str,
+    value: &str,
+) -> Result<BufReader<TcpStream>, ClientError> {
+    let key = key.to_owned();
+    let value = value.to_owned();
+    request_to_server(addr, Command::Set { key, value })
+}
+
+/// Reads the answer to the SET of `key` to `value` sent to the server at `addr` by a copy,
+/// sending it again while the server is too busy to take it, up to `COPY_ATTEMPTS` times.
+fn answer_copied_set(
+    addr: &ServerAddr,
+    key: &str,
+    value: &str,
+    mut reader: BufReader<TcpStream>,
+) -> Result<(), ClientError> {
+    let mut delay = COPY_RETRY_DELAY;
+    for _ in 1..COPY_ATTEMPTS {
+        match parse_response(reader, "SET") {
+            Err(ClientError::Busy(_)) => {
+                thread::sleep(delay);
+                delay *= 2;
+                reader = send_copied_set(addr, key, value)?;
+            }
+            result => return result.map(drop),
+        }
+    }
+    parse_response(reader, "SET").map(drop)
+}
+
+/// Escapes the characters of `text` a glob-style pattern gives a meaning to.
+fn escape_glob(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Encodes a batch of keys as a count line followed by one line per key.
+fn format_keys(keys: &[String]) -> String {
+    let mut request = format!("{}\r\n", keys.len());
+    for key in keys {
+        request.push_str(key);
+        request.push_str("\r\n");
+    }
+    request
+}
+
+/// Formats a value count line followed by every value framed by its length.
+fn format_values(values: &[String]) -> String {
+    let mut request = format!("{}\r\n", values.len());
+    for value in values {
+        request.push_str(&format!("{}\r\n{}\r\n", value.len(), value));
+    }
+    request
+}
+
+/// Parses the response of the server. Returns the text to print, if the command has any.
+fn parse_response(
+    mut reader: BufReader<TcpStream>,
+    response_type: &str,
+) -> Result<Option<String>, ClientError> {
+    read_status(&mut reader)?;
+
+    if response_type == "GET" {
+        let value_len = read_line_from_stream(&mut reader)?;
+        if value_len == "-1" {
+            Err(ClientError::KeyNotFound(Some(
+                KvsError::KeyNotFound.to_string(),
+            )))
+        } else {
+            Ok(Some(read_value_from_stream(&mut reader, &value_len)?))
+        }
+    } else if response_type == "STRLEN" {
+        let len = read_line_from_stream(&mut reader)?;
+        if len == "-1" {
+            Err(ClientError::KeyNotFound(Some(
+                KvsError::KeyNotFound.to_string(),
+            )))
+        } else {
+            Ok(Some(len))
+        }
+    } else if response_type == "META" {
+        let size = read_line_from_stream(&mut reader)?;
+        if size == "-1" {
+            return Err(ClientError::KeyNotFound(Some(
+                KvsError::KeyNotFound.to_string(),
+            )));
+        }
+        let version = read_line_from_stream(&mut reader)?;
+        let time = |time: String| {
+            if time == "-1" {
+                "none".to_owned()
+            } else {
+                time
+            }
+        };
+        let created = time(read_line_from_stream(&mut reader)?);
+        let modified = time(read_line_from_stream(&mut reader)?);
+        Ok(Some(format!(
+            "size: {}\nversion: {}\ncreated: {}\nmodified: {}",
+            size, version, created, modified
+        )))
+    } else if response_type == "LOCK" {
+        match read_line_from_stream(&mut reader)?.as_ref() {
+            "-1" => Err(ClientError::LockHeld("Lock held")),
+            token => Ok(Some(token.to_owned())),
+        }
+    } else if response_type == "UNLOCK" {
+        match read_line_from_stream(&mut reader)?.as_ref() {
+            "0" => Err(ClientError::LockHeld("Lock not held")),
+            _ => Ok(None),
+        }
+    } else if response_type == "MGET" || response_type == "MRM" {
+        parse_batch_response(&mut reader, response_type)
+    } else if response_type == "INFO"
+        || response_type == "KEYS"
+        || response_type == "RSCAN"
+        || response_type == "CLIENT"
+    {
+        let count = read_line_from_stream(&mut reader)?
+            .parse::<usize>()
+            .map_err(|_| ClientError::Server("Malformed response.".to_string()))?;
+        let lines = (0..count)
+            .map(|_| read_line_from_stream(&mut reader))
+            .collect::<io::Result<Vec<_>>>()?;
+        // No key matching prints nothing rather than an empty line.
+        if lines.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(lines.join("\n")))
+        }
+    } else if response_type == "LRANGE" || response_type == "SMEMBERS" {
+        let count = read_line_from_stream(&mut reader)?
+            .parse::<usize>()
+            .map_err(|_| ClientError::Server("Malformed response.".to_string()))?;
+        let values = (0..count)
+            .map(|_| {
+                let len = read_line_from_stream(&mut reader)?;
+                read_value_from_stream(&mut reader, &len)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        // An empty collection prints nothing rather than an empty line.
+        if values.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(values.join("\n")))
+        }
+    } else if response_type == "SCAN"
+        || response_type == "TTL"
+        || response_type == "PING"
+        || response_type == "SETV"
+        || response_type == "PUBLISH"
+        || response_type == "LPUSH"
+        || response_type == "SADD"
+    {
+        Ok(Some(read_line_from_stream(&mut reader)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reads the status line of a response, and the error which follows it if the request failed.
+fn read_status(reader: &mut BufReader<TcpStream>) -> Result<(), ClientError> {
+    let is_success = read_line_from_stream(reader)?;
+
+    match is_success.as_ref() {
+        "Success" => Ok(()),
+        "Error" => {
+            let error = read_line_from_stream(reader)?;
+            let mut parts = error.splitn(2, ' ');
+            let code = parts.next().unwrap_or_default();
+            let msg = parts.next().unwrap_or_default().to_string();
+            if code == KvsError::KeyNotFound.code() {
+                Err(ClientError::KeyNotFound(None))
+            } else if code == KvsError::CmdNotSupport.code() {
+                Err(ClientError::Unsupported(msg))
+            } else if code == KvsError::QueueFull.code() {
+                Err(ClientError::Busy(msg))
+            } else if code == "VERSION_MISMATCH" {
+                Err(ClientError::VersionMismatch(msg))
+            } else {
+                Err(ClientError::Server(msg))
+            }
+        }
+        _ => Err(ClientError::Server(
+            "Some unknown errors have occurred.".to_string(),
+        )),
+    }
+}
+
+/// Parses the response of an MGET into the value of every key, `None` for the missing ones.
+fn parse_values_response(
+    mut reader: BufReader<TcpStream>,
+) -> Result<Vec<Option<String>>, ClientError> {
+    read_status(&mut reader)?;
+    let count = read_line_from_stream(&mut reader)?
+        .parse::<usize>()
+        .map_err(|_| ClientError::Server("Malformed batch response.".to_string()))?;
+
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        let status = read_line_from_stream(&mut reader)?;
+        values.push(match status.as_ref() {
+            "-1" => None,
+            len => Some(read_value_from_stream(&mut reader, len)?),
+        });
+    }
+    Ok(values)
+}
+
+/// Parses the per-key results of a batched command into one line per key. A missing key
+/// does not stop the batch, but turns the whole result into `ClientError::KeyNotFound`.
+fn parse_batch_response(
+    reader: &mut BufReader<TcpStream>,
+    response_type: &str,
+) -> Result<Option<String>, ClientError> {
+    let count = read_line_from_stream(reader)?
+        .parse::<usize>()
+        .map_err(|_| ClientError::Server("Malformed batch response.".to_string()))?;
+
+    let mut lines = Vec::with_capacity(count);
+    let mut all_found = true;
+    for _ in 0..count {
+        let status = read_line_from_stream(reader)?;
+        let line = match (response_type, status.as_ref()) {
+            (_, "-1") | ("MRM", "0") => {
+                all_found = false;
+                KvsError::KeyNotFound.to_string()
+            }
+            ("MRM", _) => "Removed".to_string(),
+            _ => read_value_from_stream(reader, &status)?,
+        };
+        lines.push(line);
+    }
+
+    if all_found {
+        Ok(Some(lines.join("\n")))
+    } else {
+        Err(ClientError::KeyNotFound(Some(lines.join("\n"))))
+    }
+}
+
+fn read_line_from_stream(reader: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.ends_with("\r\n") {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed by the server",
+        ));
+    }
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+/// Reads a value of `len` bytes, as given by the line before it, and the line break after it.
+fn read_value_from_stream(reader: &mut BufReader<TcpStream>, len: &str) -> io::Result<String> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed value");
+    let len = len.parse::<usize>().map_err(|_| malformed())?;
+    let mut value = vec![0u8; len + 2];
+    reader.read_exact(&mut value)?;
+    if !value.ends_with(b"\r\n") {
+        return Err(malformed());
+    }
+    value.truncate(len);
+    String::from_utf8(value).map_err(|_| malformed())
+}
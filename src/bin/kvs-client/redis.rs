@@ -0,0 +1,181 @@
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// The port of a Redis URL without one.
+const DEFAULT_PORT: u16 = 6379;
+
+/// How many keys a SCAN asks for at once.
+const SCAN_COUNT: &str = "100";
+
+/// A connection to a Redis instance, only speaking the part of the protocol needed to read its
+/// string keys.
+pub struct Redis {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+/// A reply of Redis, except for errors, which are returned as `io::Error`s.
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl Redis {
+    /// Connects to the instance at `url`, of the form `redis://[[user]:password@]host[:port][/db]`,
+    /// authenticating and selecting the database it gives.
+    pub fn connect(url: &str) -> io::Result<Redis> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid Redis URL");
+        let rest = url.strip_prefix("redis://").ok_or_else(invalid)?;
+        let (credentials, rest) = match rest.rfind('@') {
+            Some(at) => (Some(&rest[..at]), &rest[at + 1..]),
+            None => (None, rest),
+        };
+        let (host, db) = match rest.find('/') {
+            Some(slash) => (
+                &rest[..slash],
+                Some(&rest[slash + 1..]).filter(|db| !db.is_empty()),
+            ),
+            None => (rest, None),
+        };
+        let addr = if host.contains(':') {
+            host.to_socket_addrs()?.next()
+        } else {
+            (host, DEFAULT_PORT).to_socket_addrs()?.next()
+        }
+        .ok_or_else(invalid)?;
+
+        let writer = TcpStream::connect_timeout(&addr, Duration::from_secs(1))?;
+        let mut redis = Redis {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        };
+        match credentials.map(|credentials| credentials.splitn(2, ':').collect::<Vec<_>>()) {
+            Some(ref parts) if parts.len() == 2 && !parts[0].is_empty() => {
+                redis.command(&[b"AUTH", parts[0].as_bytes(), parts[1].as_bytes()])?;
+            }
+            Some(parts) => {
+                let password = parts[parts.len() - 1];
+                redis.command(&[b"AUTH", password.as_bytes()])?;
+            }
+            None => {}
+        }
+        if let Some(db) = db {
+            redis.command(&[b"SELECT", db.as_bytes()])?;
+        }
+        Ok(redis)
+    }
+
+    /// Returns the next cursor of the iteration of the keys matching `pattern` from `cursor`,
+    /// "0" once it is over, with a batch of the keys.
+    pub fn scan(&mut self, cursor: &str, pattern: &str) -> io::Result<(String, Vec<Vec<u8>>)> {
+        let args: &[&[u8]] = &[
+            b"SCAN",
+            cursor.as_bytes(),
+            b"MATCH",
+            pattern.as_bytes(),
+            b"COUNT",
+            SCAN_COUNT.as_bytes(),
+        ];
+        match self.command(args)? {
+            Reply::Array(Some(mut parts)) if parts.len() == 2 => {
+                let keys = match parts.pop() {
+                    Some(Reply::Array(Some(keys))) => keys.into_iter().map(bulk).collect(),
+                    _ => Err(malformed()),
+                }?;
+                let cursor = match parts.pop() {
+                    Some(Reply::Bulk(Some(cursor))) => String::from_utf8(cursor).ok(),
+                    _ => None,
+                }
+                .ok_or_else(malformed)?;
+                Ok((cursor, keys))
+            }
+            _ => Err(malformed()),
+        }
+    }
+
+    /// Returns the values of `keys`, `None` for the keys which are gone or do not hold a
+    /// string.
+    pub fn mget(&mut self, keys: &[Vec<u8>]) -> io::Result<Vec<Option<Vec<u8>>>> {
+        let mut args: Vec<&[u8]> = vec![b"MGET"];
+        args.extend(keys.iter().map(Vec::as_slice));
+        match self.command(&args)? {
+            Reply::Array(Some(values)) if values.len() == keys.len() => values
+                .into_iter()
+                .map(|value| match value {
+                    Reply::Bulk(value) => Ok(value),
+                    _ => Err(malformed()),
+                })
+                .collect(),
+            _ => Err(malformed()),
+        }
+    }
+
+    /// Sends the command made of `args` and reads its reply.
+    fn command(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+        self.writer.write_all(&request)?;
+        self.read_reply()
+    }
+
+    fn read_reply(&mut self) -> io::Result<Reply> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        if !line.ends_with("\r\n") {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by Redis",
+            ));
+        }
+        line.truncate(line.len() - 2);
+        if line.is_empty() {
+            return Err(malformed());
+        }
+        let (kind, rest) = line.split_at(1);
+        let len = || rest.parse::<i64>().map_err(|_| malformed());
+        match kind {
+            "+" => Ok(Reply::Status(rest.to_owned())),
+            "-" => Err(io::Error::other(rest.to_owned())),
+            ":" => Ok(Reply::Integer(len()?)),
+            "$" if len()? < 0 => Ok(Reply::Bulk(None)),
+            "$" => {
+                let mut value = vec![0u8; len()? as usize + 2];
+                self.reader.read_exact(&mut value)?;
+                if !value.ends_with(b"\r\n") {
+                    return Err(malformed());
+                }
+                value.truncate(value.len() - 2);
+                Ok(Reply::Bulk(Some(value)))
+            }
+            "*" if len()? < 0 => Ok(Reply::Array(None)),
+            "*" => {
+                let items = (0..len()?)
+                    .map(|_| self.read_reply())
+                    .collect::<io::Result<_>>()?;
+                Ok(Reply::Array(Some(items)))
+            }
+            _ => Err(malformed()),
+        }
+    }
+}
+
+fn bulk(reply: Reply) -> io::Result<Vec<u8>> {
+    match reply {
+        Reply::Bulk(Some(bytes)) => Ok(bytes),
+        Reply::Status(status) => Ok(status.into_bytes()),
+        Reply::Integer(i) => Ok(i.to_string().into_bytes()),
+        _ => Err(malformed()),
+    }
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed reply from Redis")
+}
@@ -0,0 +1,47 @@
+//! The audit log of the server, recording which client changed which key and when.
+
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use tracing::error;
+
+use crate::logfile::{LogFile, Rotation};
+
+/// An append-only file with a JSON line for every write the server made, rotated like the log
+/// file of the server.
+pub struct AuditLog {
+    file: LogFile,
+}
+
+impl AuditLog {
+    /// Opens the audit log at `path` for appending, created if needed.
+    pub fn open(path: &Path, rotation: Rotation, keep: usize) -> io::Result<AuditLog> {
+        Ok(AuditLog {
+            file: LogFile::open(path, rotation, keep)?,
+        })
+    }
+
+    /// Records that the client at `peer` wrote `key` with `command`, and the size in bytes of
+    /// the value it set, if any. The time is in milliseconds since the Unix epoch. A record
+    /// which cannot be written is logged, as the write it records was already made.
+    pub fn record(&self, peer: SocketAddr, command: &str, key: &str, value_size: Option<usize>) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let mut line = json!({
+            "time": time,
+            "peer": peer.to_string(),
+            "command": command,
+            "key": key,
+            "value_size": value_size,
+        })
+        .to_string();
+        line.push('\n');
+        if let Err(e) = (&self.file).write_all(line.as_bytes()) {
+            error!(error = %e, key, "Failed to write to the audit log.");
+        }
+    }
+}
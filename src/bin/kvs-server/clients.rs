@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+/// The connections of a running server, from their acceptance until their response is sent,
+/// as listed by the CLIENT LIST command.
+pub struct Clients {
+    next_id: AtomicU64,
+    connected: Mutex<BTreeMap<u64, Client>>,
+}
+
+/// What is known of a connection.
+struct Client {
+    addr: SocketAddr,
+    connected_at: Instant,
+    last_active: Instant,
+    last_command: Option<String>,
+    commands: u64,
+}
+
+impl Clients {
+    pub fn new() -> Clients {
+        Clients {
+            next_id: AtomicU64::new(1),
+            connected: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Tracks the connection accepted from `addr` until the returned handle is dropped.
+    pub fn connect(self: &Arc<Self>, addr: SocketAddr) -> ClientHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        self.connected().insert(
+            id,
+            Client {
+                addr,
+                connected_at: now,
+                last_active: now,
+                last_command: None,
+                commands: 0,
+            },
+        );
+        ClientHandle {
+            clients: Arc::clone(self),
+            id,
+            addr,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.connected().len()
+    }
+
+    /// Describes every connection as a line of `name=value` fields, oldest first: its id, the
+    /// address of its peer, the seconds since it was accepted and since its last command, the
+    /// last command, `NULL` if it sent none yet, and the number of commands it sent.
+    pub fn list_lines(&self) -> Vec<String> {
+        self.connected()
+            .iter()
+            .map(|(id, client)| {
+                format!(
+                    "id={} addr={} age={} idle={} cmd={} cmds={}",
+                    id,
+                    client.addr,
+                    client.connected_at.elapsed().as_secs(),
+                    client.last_active.elapsed().as_secs(),
+                    client.last_command.as_deref().unwrap_or("NULL"),
+                    client.commands
+                )
+            })
+            .collect()
+    }
+
+    // Nothing panics while holding the lock, but a poisoned map would still be consistent.
+    fn connected(&self) -> MutexGuard<'_, BTreeMap<u64, Client>> {
+        self.connected
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A tracked connection, forgotten once dropped.
+pub struct ClientHandle {
+    clients: Arc<Clients>,
+    id: u64,
+    addr: SocketAddr,
+}
+
+impl ClientHandle {
+    /// The address of the peer of the connection.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Replaces the address of the peer with `addr`, the one of the client a load balancer
+    /// relays the connection of.
+    pub fn set_addr(&mut self, addr: SocketAddr) {
+        self.addr = addr;
+        if let Some(client) = self.clients.connected().get_mut(&self.id) {
+            client.addr = addr;
+        }
+    }
+
+    /// Records that the connection sent `command`.
+    pub fn command(&self, command: &str) {
+        if let Some(client) = self.clients.connected().get_mut(&self.id) {
+            client.last_active = Instant::now();
+            client.last_command = Some(command.to_lowercase());
+            client.commands += 1;
+        }
+    }
+}
+
+impl Drop for ClientHandle {
+    fn drop(&mut self) {
+        self.clients.connected().remove(&self.id);
+    }
+}
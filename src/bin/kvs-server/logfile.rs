@@ -0,0 +1,149 @@
+//! The log file of the server, rotated by size or time so that it does not grow forever.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// When the log file is rotated.
+#[derive(Clone, Copy, Debug)]
+pub enum Rotation {
+    Never,
+    /// Once writing a line would make it exceed this many bytes.
+    Size(u64),
+    /// At the first line of every hour, in UTC.
+    Hourly,
+    /// At the first line of every day, in UTC.
+    Daily,
+}
+
+impl Rotation {
+    /// The period the line written at `time` falls in, if rotated by time.
+    fn period_of(self, time: SystemTime) -> Option<u64> {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        match self {
+            Rotation::Hourly => Some(secs / 3600),
+            Rotation::Daily => Some(secs / 86400),
+            Rotation::Never | Rotation::Size(_) => None,
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    /// Parses "never", "hourly", "daily" or "size:BYTES".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        let mut parts = s.splitn(2, ':');
+        let rotation = match (parts.next(), parts.next().map(str::parse)) {
+            (Some("never"), None) => Rotation::Never,
+            (Some("hourly"), None) => Rotation::Hourly,
+            (Some("daily"), None) => Rotation::Daily,
+            (Some("size"), Some(Ok(bytes))) if bytes > 0 => Rotation::Size(bytes),
+            _ => return Err(format!("Unknown log rotation: {}", s)),
+        };
+        Ok(rotation)
+    }
+}
+
+/// A log file which the logs of every thread are appended to. When rotated, it is renamed
+/// with the suffix `.1`, the previous ones being shifted to `.2` and so on, and only the
+/// `keep` most recent ones are kept.
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    keep: usize,
+    current: Mutex<Current>,
+}
+
+/// The file the logs are being appended to.
+struct Current {
+    file: File,
+    size: u64,
+    /// The period of its last line, if rotated by time.
+    period: Option<u64>,
+}
+
+impl LogFile {
+    /// Opens the log file at `path` for appending, created if needed.
+    pub fn open(path: &Path, rotation: Rotation, keep: usize) -> io::Result<LogFile> {
+        let (file, size, modified) = open_append(path)?;
+        Ok(LogFile {
+            path: path.to_owned(),
+            rotation,
+            keep,
+            current: Mutex::new(Current {
+                file,
+                size,
+                // A file last written in an earlier period is rotated at the first line.
+                period: rotation.period_of(modified),
+            }),
+        })
+    }
+
+    // Nothing panics while holding the lock, but a poisoned file could still be written.
+    fn current(&self) -> MutexGuard<'_, Current> {
+        self.current.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Shifts the rotated files, renames the log file to the first of them and reopens it.
+    fn rotate(&self, current: &mut Current) -> io::Result<()> {
+        current.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+            for n in (1..self.keep).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        let (file, size, _) = open_append(&self.path)?;
+        current.file = file;
+        current.size = size;
+        Ok(())
+    }
+}
+
+impl Write for &LogFile {
+    /// Appends the line `buf`, which the logging layer writes at once, after rotating the file
+    /// if needed.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut current = self.current();
+        let due = match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max) => current.size > 0 && current.size + buf.len() as u64 > max,
+            Rotation::Hourly | Rotation::Daily => {
+                let period = self.rotation.period_of(SystemTime::now());
+                let due = current.period.is_some_and(|current| Some(current) < period);
+                current.period = period;
+                due
+            }
+        };
+        if due {
+            self.rotate(&mut current)?;
+        }
+        current.file.write_all(buf)?;
+        current.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.current().file.flush()
+    }
+}
+
+/// Opens the file at `path` for appending, and returns it with its size and the time it was
+/// last modified.
+fn open_append(path: &Path) -> io::Result<(File, u64, SystemTime)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    Ok((file, metadata.len(), metadata.modified()?))
+}
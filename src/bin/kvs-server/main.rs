@@ -0,0 +1,1154 @@
+use std::env::current_dir;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::ErrorKind::WouldBlock;
+use std::io::{self, BufReader, IsTerminal};
+use std::net::SocketAddr;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, never, select, tick, Receiver};
+use ctrlc;
+use num_cpus;
+use regex::Regex;
+use structopt::StructOpt;
+use tracing::{error, field, info, info_span, warn, Span};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, Layer, Registry};
+
+use kvs::thread_pool::{CoreAffinity, ThreadPoolBuilder};
+#[cfg(feature = "sled")]
+use kvs::SledKvsEngine;
+use kvs::{
+    KeyCharset, KeyPolicy, KvStore, KvStoreBuilder, KvsEngine, KvsError, ResultExt, ServerAddr,
+    SledFlushPolicy,
+};
+use kvs::{SharedQueueThreadPool, ThreadPool};
+
+use audit::AuditLog;
+use clients::ClientHandle;
+use logfile::{LogFile, Rotation};
+use metrics::ServerMetrics;
+use pubsub::PubSub;
+use tracking::Tracking;
+
+mod audit;
+mod clients;
+mod logfile;
+mod metrics;
+mod proxy;
+mod pubsub;
+#[cfg(feature = "otlp")]
+mod telemetry;
+mod tracking;
+mod watch;
+
+/// A layer exporting the spans of the server to a tracing backend.
+type ExportLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// The number of accepted connections allowed to wait for a worker before the server
+/// starts answering "busy".
+const JOB_QUEUE_CAPACITY: usize = 1024;
+
+/// How long the server waits on Ctrl-C for the requests already accepted to finish, so that an
+/// idle or half-open client waited on by a worker does not keep it from exiting.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// What a request is read through.
+type RequestReader<'a> = BufReader<RequestStream<'a>>;
+
+enum BackEngines {
+    Kvs,
+    Sled,
+    Auto,
+}
+
+impl FromStr for BackEngines {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let engine_name = s.to_lowercase();
+        match engine_name.as_ref() {
+            "kvs" => Ok(BackEngines::Kvs),
+            "sled" => Ok(BackEngines::Sled),
+            "auto" => Ok(BackEngines::Auto),
+            _ => Err(KvsError::ParseEngineError),
+        }
+    }
+}
+
+impl std::fmt::Debug for BackEngines {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackEngines::Kvs => write!(f, "kvs"),
+            BackEngines::Sled => write!(f, "sled"),
+            BackEngines::Auto => write!(f, "automatically select from kvs or sled"),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-server", about = "A simple Key-Value Server")]
+struct Kvs {
+    /// The address kvs server will bind in, with format HOST:PORT, where HOST is an IP address
+    /// or a hostname, bound to the first of its addresses available. The port defaults to 4000,
+    /// and "0.0.0.0" or "::" listen on every interface, "::" also accepting IPv4 where the system
+    /// is dual-stack. Can be given several times to listen on each address.
+    #[structopt(
+        long = "addr",
+        default_value = "127.0.0.1:4000",
+        raw(number_of_values = "1")
+    )]
+    addrs: Vec<ServerAddr>,
+
+    /// The built-in engine used as backend, either "kvs" or "sled". By default, the engine
+    /// the data directory was written by, found from its files if db.type is missing, or "kvs".
+    #[structopt(long = "engine", default_value = "auto")]
+    engine: BackEngines,
+
+    /// The format of the logs: "json", or "text" and "pretty" to be read by humans, with one
+    /// line per event or several. Colored when written to a terminal.
+    #[structopt(long = "log-format", default_value = "json")]
+    log_format: LogFormat,
+
+    /// The least severe events logged: "error", "warn", "info", "debug", "trace", or "off".
+    #[structopt(long = "log-level", default_value = "info")]
+    log_level: LevelFilter,
+
+    /// Write the logs to this file instead of stderr.
+    #[structopt(long = "log-file", parse(from_os_str))]
+    log_file: Option<PathBuf>,
+
+    /// When the log file is rotated: "never", "hourly" or "daily" in UTC, or "size:BYTES" once
+    /// it would exceed that size.
+    #[structopt(long = "log-rotate", default_value = "never")]
+    log_rotate: Rotation,
+
+    /// The number of rotated log files kept, the oldest ones being removed.
+    #[structopt(long = "log-keep", default_value = "5")]
+    log_keep: usize,
+
+    /// Append a JSON line to this file for every key written or removed, with the time, the
+    /// address of the client, the command, the key and the size of the value.
+    #[structopt(long = "audit-log", parse(from_os_str))]
+    audit_log: Option<PathBuf>,
+
+    /// When the audit log is rotated, as with --log-rotate.
+    #[structopt(long = "audit-rotate", default_value = "never")]
+    audit_rotate: Rotation,
+
+    /// The number of rotated audit logs kept, the oldest ones being removed.
+    #[structopt(long = "audit-keep", default_value = "5")]
+    audit_keep: usize,
+
+    /// The number of seconds between two checkpoints of the engine, which bound the part of
+    /// the log replayed after a crash. 0 disables the periodic checkpoints.
+    #[structopt(long = "checkpoint-interval", default_value = "60")]
+    checkpoint_interval: u64,
+
+    /// Also checkpoint the kvs engine every given number of writes.
+    #[structopt(long = "checkpoint-records")]
+    checkpoint_records: Option<u64>,
+
+    /// Number the writes of the kvs engine, which GETV and SETV need to tell the version of the
+    /// keys, and keep the versions of the keys until the next compaction.
+    #[structopt(long = "versioned")]
+    versioned: bool,
+
+    /// Cache the values read most recently by the kvs engine, up to the given number of bytes.
+    #[structopt(long = "value-cache")]
+    value_cache: Option<usize>,
+
+    /// Read the values of the given number of keys written most recently into the value cache
+    /// of the kvs engine when it is opened.
+    #[structopt(long = "warm-up")]
+    warm_up: Option<usize>,
+
+    /// Delay the writes to the kvs engine once the log holds this many bytes compaction has yet
+    /// to reclaim, the more so the closer to the stop limit.
+    #[structopt(long = "write-slowdown")]
+    write_slowdown: Option<u64>,
+
+    /// Reject the writes to the kvs engine with a BUSY error once the log holds this many bytes
+    /// compaction has yet to reclaim, until it catches up.
+    #[structopt(long = "write-stop")]
+    write_stop: Option<u64>,
+
+    /// Also compact the log of the kvs engine once its redundant bytes make up this fraction of
+    /// it, e.g. 0.5, rather than only from 1MB of redundant bytes on.
+    #[structopt(long = "compaction-ratio")]
+    compaction_ratio: Option<f64>,
+
+    /// When the sled engine flushes its writes to disk: "background" to let sled do it every
+    /// 500ms, "never", "writes:N" every N writes, or "interval:MS" every MS milliseconds.
+    #[structopt(long = "sled-flush", default_value = "background")]
+    #[cfg_attr(not(feature = "sled"), allow(dead_code))]
+    sled_flush: SledFlushPolicy,
+
+    /// The characters allowed in keys, either "any", "printable" or "url-safe".
+    #[structopt(long = "key-charset", default_value = "any")]
+    key_charset: KeyCharset,
+
+    /// The maximum size of keys in bytes, at most 256.
+    #[structopt(long = "max-key-len")]
+    max_key_len: Option<usize>,
+
+    /// Pin the worker threads to cores: "spread" over the cores available, or a comma-separated
+    /// list of core IDs, used in turn. Only supported on Linux.
+    #[structopt(long = "pin-workers")]
+    pin_workers: Option<CoreAffinity>,
+
+    /// A prefix of the keys clients cannot use. Can be given several times.
+    #[structopt(long = "reserved-prefix", raw(number_of_values = "1"))]
+    reserved_prefixes: Vec<String>,
+
+    /// Expect every connection to start with a PROXY protocol header, of version 1 or 2, giving
+    /// the address of the client a TCP load balancer relays. Connections without one are
+    /// rejected, so only pass it when every client connects through the load balancer.
+    #[structopt(long = "proxy-protocol")]
+    proxy_protocol: bool,
+
+    /// The largest request accepted, in bytes. A connection sending more is answered with a
+    /// TOO_LARGE error and closed, so that a client cannot exhaust the memory of the server.
+    #[structopt(long = "max-request-size", default_value = "67108864")]
+    max_request_size: u64,
+
+    /// An address with format IP:PORT to serve metrics on, in the Prometheus text format.
+    #[structopt(long = "metrics-addr")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// An address with format IP:PORT of a statsd server to push the metrics to, over UDP.
+    #[structopt(long = "statsd-addr")]
+    statsd_addr: Option<SocketAddr>,
+
+    /// The prefix of the names of the metrics pushed to statsd, e.g. "kvs.host1".
+    #[structopt(long = "statsd-prefix", default_value = "kvs")]
+    statsd_prefix: String,
+
+    /// The number of seconds between two pushes of the metrics to statsd.
+    #[structopt(long = "statsd-interval", default_value = "10")]
+    statsd_interval: u64,
+
+    /// An OTLP/HTTP endpoint to export the spans of the requests to, e.g.
+    /// "http://localhost:4318/v1/traces".
+    #[cfg(feature = "otlp")]
+    #[structopt(long = "otlp-endpoint")]
+    otlp_endpoint: Option<String>,
+}
+
+#[derive(Debug)]
+enum LogFormat {
+    Json,
+    Text,
+    Pretty,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "json" => Ok(LogFormat::Json),
+            "text" => Ok(LogFormat::Text),
+            "pretty" => Ok(LogFormat::Pretty),
+            _ => Err(format!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+fn main() -> kvs::Result<()> {
+    let opt = Kvs::from_args();
+    #[cfg(feature = "otlp")]
+    let tracer_provider = match &opt.otlp_endpoint {
+        Some(endpoint) => Some(telemetry::tracer_provider(endpoint)?),
+        None => None,
+    };
+    #[cfg(feature = "otlp")]
+    let exporter = tracer_provider.as_ref().map(telemetry::layer);
+    #[cfg(not(feature = "otlp"))]
+    let exporter = None;
+    let (writer, ansi) = match &opt.log_file {
+        Some(path) => {
+            let log_file = LogFile::open(path, opt.log_rotate, opt.log_keep)
+                .with_context(|| format!("opening log file {}", path.display()))?;
+            (BoxMakeWriter::new(Arc::new(log_file)), false)
+        }
+        None => (
+            BoxMakeWriter::new(std::io::stderr),
+            std::io::stderr().is_terminal(),
+        ),
+    };
+    init_logging(&opt.log_format, opt.log_level, writer, ansi, exporter);
+    info!(version = env!("CARGO_PKG_VERSION"), "kvs-server start up");
+
+    let engine_type = get_engine(current_dir()?, opt.engine);
+    info!(
+        socket_address = %addresses(&opt.addrs),
+        engine_used = ?engine_type,
+        "kvs-server configuration"
+    );
+    let ctrl_c_events = ctrl_channel().unwrap();
+
+    let mut pool_builder = ThreadPoolBuilder::new(num_cpus::get())
+        .thread_name("kvs-worker")
+        .queue_capacity(JOB_QUEUE_CAPACITY);
+    if let Some(affinity) = opt.pin_workers {
+        pool_builder = pool_builder.core_affinity(affinity);
+    }
+    let thread_pool: SharedQueueThreadPool = pool_builder
+        .build()
+        .with_context(|| "starting the worker threads")?;
+    let metrics = Arc::new(ServerMetrics::new(thread_pool.metrics()));
+    if let Some(metrics_addr) = opt.metrics_addr {
+        metrics::serve(&metrics_addr, Arc::clone(&metrics))?;
+    }
+    if let Some(statsd_addr) = opt.statsd_addr {
+        metrics::push_statsd(
+            statsd_addr,
+            opt.statsd_prefix.clone(),
+            Duration::from_secs(opt.statsd_interval),
+            Arc::clone(&metrics),
+        )?;
+    }
+    let audit_log = match &opt.audit_log {
+        Some(path) => Some(
+            AuditLog::open(path, opt.audit_rotate, opt.audit_keep)
+                .with_context(|| format!("opening audit log {}", path.display()))?,
+        ),
+        None => None,
+    };
+    let checkpoints = match opt.checkpoint_interval {
+        0 => never(),
+        secs => tick(Duration::from_secs(secs)),
+    };
+    let mut key_policy = KeyPolicy::new().charset(opt.key_charset);
+    if let Some(max_len) = opt.max_key_len {
+        key_policy = key_policy.max_len(max_len);
+    }
+    for prefix in opt.reserved_prefixes {
+        key_policy = key_policy.reserve_prefix(prefix);
+    }
+    let result = match engine_type {
+        BackEngines::Kvs => {
+            let mut builder = KvStoreBuilder::new()
+                .key_policy(key_policy.clone())
+                .versioned(opt.versioned);
+            if let Some(records) = opt.checkpoint_records {
+                builder = builder.checkpoint_every(records);
+            }
+            if let Some(bytes) = opt.value_cache {
+                builder = builder.value_cache(bytes);
+            }
+            if let Some(records) = opt.warm_up {
+                builder = builder.warm_up_recent(records);
+            }
+            if let Some(ratio) = opt.compaction_ratio {
+                builder = builder.compaction_ratio(ratio);
+            }
+            match (opt.write_slowdown, opt.write_stop) {
+                (None, None) => {}
+                (slowdown, stop) => {
+                    let stop = stop.unwrap_or(u64::MAX);
+                    builder = builder.throttle_writes(slowdown.unwrap_or(stop), stop);
+                }
+            }
+            let engine = open_kvs(current_dir()?, builder).exit_if_err(1);
+            let context = ServerContext::new(
+                engine,
+                key_policy,
+                metrics,
+                audit_log,
+                opt.proxy_protocol,
+                opt.max_request_size,
+            );
+            run_server(
+                &opt.addrs,
+                ctrl_c_events,
+                checkpoints,
+                thread_pool,
+                Arc::new(context),
+            )
+        }
+        #[cfg(feature = "sled")]
+        BackEngines::Sled => {
+            let engine = SledKvsEngine::open_with_flush_policy(current_dir()?, opt.sled_flush)
+                .exit_if_err(1);
+            let context = ServerContext::new(
+                engine,
+                key_policy,
+                metrics,
+                audit_log,
+                opt.proxy_protocol,
+                opt.max_request_size,
+            );
+            run_server(
+                &opt.addrs,
+                ctrl_c_events,
+                checkpoints,
+                thread_pool,
+                Arc::new(context),
+            )
+        }
+        #[cfg(not(feature = "sled"))]
+        BackEngines::Sled => unreachable!("rejected by get_engine"),
+        BackEngines::Auto => exit(1),
+    };
+
+    #[cfg(feature = "otlp")]
+    telemetry::shutdown(tracer_provider);
+    result
+}
+
+/// The addresses the server listens on, for the logs.
+fn addresses(addrs: &[ServerAddr]) -> String {
+    let addrs: Vec<String> = addrs.iter().map(ServerAddr::to_string).collect();
+    addrs.join(", ")
+}
+
+/// Opens the kvs engine in `dir` with `builder`, logging the progress of the replay of its log
+/// every tenth of it, so that a long recovery does not look like a hang.
+fn open_kvs(dir: PathBuf, builder: KvStoreBuilder) -> kvs::Result<KvStore> {
+    let logged = AtomicU64::new(0);
+    builder.open_with_progress(dir, move |replayed, total| {
+        let tenths = replayed * 10 / total.max(1);
+        if tenths > logged.fetch_max(tenths, Ordering::SeqCst) {
+            info!(replayed, total, "Replaying the log");
+        }
+    })
+}
+
+/// Installs the global subscriber writing the logs of the server, and of the engine below it,
+/// to stderr. The spans are also handed to `exporter` if any, down to the engine operations.
+/// Logs the events of `level` and above in `format` through `writer`, colored if `ansi`, and
+/// hands the spans to the `exporter`, if any.
+fn init_logging(
+    format: &LogFormat,
+    level: LevelFilter,
+    writer: BoxMakeWriter,
+    ansi: bool,
+    exporter: Option<ExportLayer>,
+) {
+    let logs = match format {
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Text => fmt::layer().with_ansi(ansi).with_writer(writer).boxed(),
+        LogFormat::Pretty => fmt::layer()
+            .pretty()
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(exporter.map(|exporter| exporter.with_filter(LevelFilter::DEBUG)))
+        .with(logs.with_filter(level))
+        .init();
+}
+
+/// What the connections share: the engine they serve, which they all borrow rather than clone,
+/// hence `Sync`, and the state of the server.
+struct ServerContext<E: KvsEngine + Sync> {
+    engine: E,
+    key_policy: KeyPolicy,
+    metrics: Arc<ServerMetrics>,
+    audit_log: Option<AuditLog>,
+    tracking: Tracking,
+    pubsub: PubSub,
+    /// Whether the connections start with a PROXY header giving the address of the client.
+    proxy_protocol: bool,
+    /// The size in bytes past which a request is rejected.
+    max_request_size: u64,
+}
+
+impl<E: KvsEngine + Sync> ServerContext<E> {
+    fn new(
+        engine: E,
+        key_policy: KeyPolicy,
+        metrics: Arc<ServerMetrics>,
+        audit_log: Option<AuditLog>,
+        proxy_protocol: bool,
+        max_request_size: u64,
+    ) -> ServerContext<E> {
+        ServerContext {
+            engine,
+            key_policy,
+            metrics,
+            audit_log,
+            tracking: Tracking::new(),
+            pubsub: PubSub::new(),
+            proxy_protocol,
+            max_request_size,
+        }
+    }
+}
+
+fn run_server<E: KvsEngine + Sync>(
+    addrs: &[ServerAddr],
+    ctrl_c_events: Receiver<()>,
+    checkpoints: Receiver<Instant>,
+    thread_pool: SharedQueueThreadPool,
+    context: Arc<ServerContext<E>>,
+) -> kvs::Result<()> {
+    let listeners = addrs
+        .iter()
+        .map(|addr| {
+            let listener = TcpListener::bind(&addr.resolve()?[..])?;
+            info!(local_address = %listener.local_addr()?, "Listening.");
+            listener
+                .set_nonblocking(true)
+                .expect("Cannot set non-blocking");
+            Ok(listener)
+        })
+        .collect::<kvs::Result<Vec<_>>>()?;
+
+    loop {
+        select! {
+            recv(ctrl_c_events) -> _ => {
+                // Let the requests already accepted finish before persisting the index.
+                if !thread_pool.join(SHUTDOWN_GRACE) {
+                    warn!(
+                        grace_ms = SHUTDOWN_GRACE.as_millis() as u64,
+                        "Shutting down while some connections are still being served."
+                    );
+                }
+                return context.engine.save_index_log();
+            }
+            recv(checkpoints) -> _ => {
+                if let Err(e) = context.engine.save_index_log() {
+                    warn!(code = e.code(), error = %e, "Failed to write a checkpoint.");
+                }
+            }
+            default => {
+                // Every listener is polled in turn, so that none starves the others.
+                for listener in &listeners {
+                    match listener.accept() {
+                        Ok((stream, peer)) => {
+                            // Kept to answer "busy", which a lack of descriptors only costs
+                            // this connection.
+                            let mut busy_stream = match stream.try_clone() {
+                                Ok(busy_stream) => busy_stream,
+                                Err(e) => {
+                                    warn!(peer = %peer, error = %e, "Dropped a connection.");
+                                    continue;
+                                }
+                            };
+                            let context = Arc::clone(&context);
+                            let span = info_span!("connection", peer = %peer);
+                            let mut client = context.metrics.clients().connect(peer);
+                            let accepted = Instant::now();
+                            let spawned = thread_pool.try_spawn(move || {
+                                let _entered = span.enter();
+                                handle_connection(stream, &context, &mut client, accepted)
+                            });
+                            if let Err(e) = spawned {
+                                warn!(peer = %peer, error = %e, "Rejected a connection.");
+                                let _ = busy_stream.write_all(error_response(&e).as_bytes());
+                            }
+                        }
+                        Err(ref e) if e.kind() == WouldBlock => continue,
+                        Err(e) => {
+                            return Err(e.into())
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Answers the request received on `stream`, which was accepted at `accepted`, after the PROXY
+/// header giving the address of the client if `context.proxy_protocol`. The request is read up
+/// to `context.max_request_size` bytes. Every failure is logged and turned into an error
+/// response, so that a bad request or a broken connection never takes a worker down.
+fn handle_connection<E: KvsEngine + Sync>(
+    mut stream: TcpStream,
+    context: &ServerContext<E>,
+    client: &mut ClientHandle,
+    accepted: Instant,
+) {
+    let queued = accepted.elapsed();
+    let max_request_size = context.max_request_size;
+    let mut buf_reader = BufReader::new(RequestStream::new(&stream, max_request_size));
+    if context.proxy_protocol {
+        match proxy::read_header(&mut buf_reader) {
+            Ok(Some(addr)) => {
+                Span::current().record("peer", field::display(addr));
+                client.set_addr(addr);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!(code = e.code(), error = %e, "Received no valid PROXY header.");
+                let _ = stream.write_all(error_response(&e).as_bytes());
+                return;
+            }
+        }
+    }
+    let client = &*client;
+    let span = info_span!("command", command = field::Empty, key = field::Empty);
+    let _entered = span.enter();
+
+    let started = Instant::now();
+    let result = read_line_from_stream(&mut buf_reader).and_then(|cmd| {
+        span.record("command", cmd.as_str());
+        client.command(&cmd);
+        let written = |key: &str, value_size: Option<usize>| {
+            if let Some(audit_log) = &context.audit_log {
+                audit_log.record(client.addr(), &cmd, key, value_size);
+            }
+            context.tracking.invalidate(key);
+        };
+        let response = get_response(&cmd, &mut buf_reader, context, &span, &written);
+        context.metrics.record(&cmd, queued, started.elapsed());
+        response
+    });
+    let result = match result {
+        Err(_) if buf_reader.get_ref().exceeded => Err(KvsError::RequestTooLarge(max_request_size)),
+        result => result,
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            warn!(code = e.code(), error = %e, "Failed to serve a request.");
+            error_response(&e)
+        }
+    };
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        warn!(error = %e, "Failed to send a response.");
+    }
+}
+
+/// Formats an error response, which carries the code of the error before its message.
+fn error_response(error: &KvsError) -> String {
+    format!("Error\r\n{} {}\r\n", error.code(), error)
+}
+
+fn get_response<E: KvsEngine + Sync>(
+    cmd: &str,
+    buf_reader: &mut RequestReader<'_>,
+    context: &ServerContext<E>,
+    span: &Span,
+    written: &dyn Fn(&str, Option<usize>),
+) -> kvs::Result<String> {
+    let ServerContext {
+        engine,
+        key_policy,
+        metrics,
+        tracking,
+        pubsub,
+        ..
+    } = context;
+    let read_key = |buf_reader: &mut RequestReader<'_>| -> kvs::Result<String> {
+        let key = read_line_from_stream(buf_reader)?;
+        span.record("key", key.as_str());
+        key_policy.validate(&key)?;
+        Ok(key)
+    };
+    let read_keys = |buf_reader: &mut RequestReader<'_>| -> kvs::Result<Vec<String>> {
+        let keys = read_keys_from_stream(buf_reader)?;
+        for key in &keys {
+            key_policy.validate(key)?;
+        }
+        Ok(keys)
+    };
+
+    match cmd {
+        "SET" => {
+            let key = read_key(buf_reader)?;
+            let value = read_value_from_stream(buf_reader)?;
+            let value_size = value.len();
+            engine.set(key.clone(), value)?;
+            written(&key, Some(value_size));
+            Ok("Success\r\n".to_string())
+        }
+        "SETEX" => {
+            let key = read_key(buf_reader)?;
+            let ttl = read_seconds_from_stream(buf_reader)?;
+            let value = read_value_from_stream(buf_reader)?;
+            let value_size = value.len();
+            engine.set_with_ttl(key.clone(), value, ttl)?;
+            written(&key, Some(value_size));
+            Ok("Success\r\n".to_string())
+        }
+        "EXPIRE" => {
+            let key = read_key(buf_reader)?;
+            let ttl = read_seconds_from_stream(buf_reader)?;
+            engine.expire(key.clone(), ttl)?;
+            written(&key, None);
+            Ok("Success\r\n".to_string())
+        }
+        "TTL" => {
+            let key = read_key(buf_reader)?;
+            // Rounded up, so that a key about to expire is not reported as expired yet.
+            let seconds = match engine.ttl(key)? {
+                Some(ttl) => ttl.as_millis().div_ceil(1000) as i64,
+                None => -1,
+            };
+            Ok(format!("Success\r\n{}\r\n", seconds))
+        }
+        "GET" => {
+            let key = read_key(buf_reader)?;
+            let value = engine.get(key)?;
+            match value {
+                Some(v) => Ok(format!("Success\r\n{}\r\n{}\r\n", v.len(), v)),
+                None => Ok("Success\r\n-1\r\n".to_string()),
+            }
+        }
+        "TRACKING" => {
+            let stream = buf_reader.get_ref().stream.try_clone()?;
+            tracking.open(stream)?;
+            // The response was sent along with the id, ahead of any invalidation.
+            Ok(String::new())
+        }
+        "TGET" => {
+            let id = read_line_from_stream(buf_reader)?
+                .parse::<u64>()
+                .map_err(|_| KvsError::MalformedRequest)?;
+            let key = read_key(buf_reader)?;
+            // Tracked before it is read, so that no write after the read goes unnoticed.
+            if !tracking.track(id, &key) {
+                return Err(KvsError::TrackingClosed(id));
+            }
+            // The keys bound to expire are not to be cached, as their expiry is not pushed.
+            let cacheable = match engine.ttl(key.clone()) {
+                Ok(ttl) => ttl.is_none(),
+                Err(KvsError::KeyNotFound) => true,
+                Err(e) => return Err(e),
+            };
+            match engine.get(key)? {
+                Some(v) => Ok(format!(
+                    "Success\r\n{}\r\n{}\r\n{}\r\n",
+                    cacheable as u8,
+                    v.len(),
+                    v
+                )),
+                None => Ok(format!("Success\r\n{}\r\n-1\r\n", cacheable as u8)),
+            }
+        }
+        "SUBSCRIBE" => {
+            let channels = read_keys_from_stream(buf_reader)?;
+            if channels.is_empty() {
+                return Err(KvsError::MalformedRequest);
+            }
+            let stream = buf_reader.get_ref().stream.try_clone()?;
+            pubsub.subscribe(stream, channels)?;
+            // The response was sent ahead of any message.
+            Ok(String::new())
+        }
+        "PUBLISH" => {
+            let channel = read_line_from_stream(buf_reader)?;
+            let message = read_value_from_stream(buf_reader)?;
+            Ok(format!(
+                "Success\r\n{}\r\n",
+                pubsub.publish(&channel, &message)
+            ))
+        }
+        "GETV" => {
+            let key = read_key(buf_reader)?;
+            match engine.get_versioned(key)? {
+                Some((v, version)) => Ok(format!(
+                    "Success\r\n{}\r\n{}\r\n{}\r\n",
+                    version,
+                    v.len(),
+                    v
+                )),
+                None => Ok("Success\r\n-1\r\n".to_string()),
+            }
+        }
+        "SETV" => {
+            let key = read_key(buf_reader)?;
+            // -1 for a key expected not to exist.
+            let expected = match read_line_from_stream(buf_reader)?.as_str() {
+                "-1" => None,
+                version => Some(version.parse().map_err(|_| KvsError::MalformedRequest)?),
+            };
+            let value = read_value_from_stream(buf_reader)?;
+            let value_size = value.len();
+            let version = engine.set_if_version(key.clone(), value, expected)?;
+            written(&key, Some(value_size));
+            Ok(format!("Success\r\n{}\r\n", version))
+        }
+        "LPUSH" => {
+            let key = read_key(buf_reader)?;
+            let values = read_values_from_stream(buf_reader)?;
+            let value_size = values.iter().map(String::len).sum();
+            let len = engine.list_push(key.clone(), values)?;
+            written(&key, Some(value_size));
+            Ok(format!("Success\r\n{}\r\n", len))
+        }
+        "LRANGE" => {
+            let key = read_key(buf_reader)?;
+            let start = read_index_from_stream(buf_reader)?;
+            let stop = read_index_from_stream(buf_reader)?;
+            Ok(values_response(engine.list_range(key, start, stop)?))
+        }
+        "SADD" => {
+            let key = read_key(buf_reader)?;
+            let members = read_values_from_stream(buf_reader)?;
+            let value_size = members.iter().map(String::len).sum();
+            let added = engine.set_add(key.clone(), members)?;
+            if added > 0 {
+                written(&key, Some(value_size));
+            }
+            Ok(format!("Success\r\n{}\r\n", added))
+        }
+        "SMEMBERS" => {
+            let key = read_key(buf_reader)?;
+            Ok(values_response(engine.set_members(key)?))
+        }
+        "HSET" => {
+            let key = read_key(buf_reader)?;
+            let field = read_line_from_stream(buf_reader)?;
+            let value = read_value_from_stream(buf_reader)?;
+            let value_size = value.len();
+            let created = engine.hash_set(key.clone(), field, value)?;
+            written(&key, Some(value_size));
+            Ok(format!("Success\r\n{}\r\n", created as u8))
+        }
+        "HGET" => {
+            let key = read_key(buf_reader)?;
+            let field = read_line_from_stream(buf_reader)?;
+            match engine.hash_get(key, field)? {
+                Some(v) => Ok(format!("Success\r\n{}\r\n{}\r\n", v.len(), v)),
+                None => Ok("Success\r\n-1\r\n".to_string()),
+            }
+        }
+        "LOCK" => {
+            let name = read_key(buf_reader)?;
+            let ttl = read_seconds_from_stream(buf_reader)?;
+            // -1 for a lock held by someone else.
+            match engine.acquire_lock(name.clone(), ttl)? {
+                Some(token) => {
+                    written(&name, None);
+                    Ok(format!("Success\r\n{}\r\n", token))
+                }
+                None => Ok("Success\r\n-1\r\n".to_string()),
+            }
+        }
+        "UNLOCK" => {
+            let name = read_key(buf_reader)?;
+            let token = read_line_from_stream(buf_reader)?
+                .parse::<u64>()
+                .map_err(|_| KvsError::MalformedRequest)?;
+            let released = engine.release_lock(name.clone(), token)?;
+            if released {
+                written(&name, None);
+            }
+            Ok(format!("Success\r\n{}\r\n", released as u8))
+        }
+        "META" => {
+            let key = read_key(buf_reader)?;
+            // The size first, -1 for a missing key as with STRLEN, then -1 for unknown times.
+            let time = |time: Option<u64>| time.map_or("-1".to_owned(), |time| time.to_string());
+            match engine.get_metadata(key)? {
+                Some(meta) => Ok(format!(
+                    "Success\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
+                    meta.size,
+                    meta.version,
+                    time(meta.created_at),
+                    time(meta.modified_at)
+                )),
+                None => Ok("Success\r\n-1\r\n".to_string()),
+            }
+        }
+        "STRLEN" => {
+            let key = read_key(buf_reader)?;
+            match engine.strlen(key)? {
+                Some(len) => Ok(format!("Success\r\n{}\r\n", len)),
+                None => Ok("Success\r\n-1\r\n".to_string()),
+            }
+        }
+        "RM" => {
+            let key = read_key(buf_reader)?;
+            engine.remove(key.clone())?;
+            written(&key, None);
+            Ok("Success\r\n".to_string())
+        }
+        "MGET" => {
+            let keys = read_keys(buf_reader)?;
+            let mut response = format!("Success\r\n{}\r\n", keys.len());
+            for value in engine.get_many(keys)? {
+                match value {
+                    Some(v) => response.push_str(&format!("{}\r\n{}\r\n", v.len(), v)),
+                    None => response.push_str("-1\r\n"),
+                }
+            }
+            Ok(response)
+        }
+        "MRM" => {
+            let keys = read_keys(buf_reader)?;
+            let mut response = format!("Success\r\n{}\r\n", keys.len());
+            for key in keys {
+                match engine.remove(key.clone()) {
+                    Ok(()) => {
+                        written(&key, None);
+                        response.push_str("1\r\n")
+                    }
+                    Err(KvsError::KeyNotFound) => response.push_str("0\r\n"),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(response)
+        }
+        "SCAN" => {
+            let keys = engine.scan().join("\r\n");
+            Ok(format!("Success\r\n{}\r\n", keys))
+        }
+        "KEYS" => {
+            let pattern = read_line_from_stream(buf_reader)?;
+            let keys = engine.keys(&pattern);
+            let mut response = format!("Success\r\n{}\r\n", keys.len());
+            for key in keys {
+                response.push_str(&key);
+                response.push_str("\r\n");
+            }
+            Ok(response)
+        }
+        "RSCAN" => {
+            let pattern = read_line_from_stream(buf_reader)?;
+            let regex =
+                Regex::new(&pattern).map_err(|e| KvsError::InvalidPattern(e.to_string()))?;
+            let keys: Vec<String> = engine
+                .scan()
+                .into_iter()
+                .filter(|key| regex.is_match(key))
+                .collect();
+            let mut response = format!("Success\r\n{}\r\n", keys.len());
+            for key in keys {
+                response.push_str(&key);
+                response.push_str("\r\n");
+            }
+            Ok(response)
+        }
+        "PING" => Ok("Success\r\nPONG\r\n".to_string()),
+        "SAVE" => {
+            engine.save_index_log()?;
+            Ok("Success\r\n".to_string())
+        }
+        "CLIENT" => match read_line_from_stream(buf_reader)?.to_uppercase().as_str() {
+            "LIST" => {
+                let lines = metrics.clients().list_lines();
+                Ok(format!(
+                    "Success\r\n{}\r\n{}\r\n",
+                    lines.len(),
+                    lines.join("\r\n")
+                ))
+            }
+            _ => Err(KvsError::CmdNotSupport),
+        },
+        "INFO" => {
+            let mut lines = metrics.info_lines();
+            lines.extend(engine.info_lines());
+            Ok(format!(
+                "Success\r\n{}\r\n{}\r\n",
+                lines.len(),
+                lines.join("\r\n")
+            ))
+        }
+        _ => Err(KvsError::CmdNotSupport),
+    }
+}
+
+fn read_line_from_stream(reader: &mut RequestReader<'_>) -> kvs::Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.ends_with("\r\n") {
+        return Err(KvsError::MalformedRequest);
+    }
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+/// Reads a value framed by its length in bytes on a line of its own, so that it may contain
+/// line breaks.
+fn read_value_from_stream(reader: &mut RequestReader<'_>) -> kvs::Result<String> {
+    let len = read_line_from_stream(reader)?
+        .parse::<u64>()
+        .map_err(|_| KvsError::MalformedRequest)?;
+    let limit = reader.get_ref().limit;
+    if len > limit {
+        return Err(KvsError::RequestTooLarge(limit));
+    }
+    let mut value = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut value)?;
+    let mut end = [0u8; 2];
+    if value.len() as u64 != len || reader.read_exact(&mut end).is_err() || &end != b"\r\n" {
+        return Err(KvsError::MalformedRequest);
+    }
+    String::from_utf8(value).map_err(|_| KvsError::MalformedRequest)
+}
+
+/// Reads a value count line followed by that many values, each framed by its length.
+fn read_values_from_stream(reader: &mut RequestReader<'_>) -> kvs::Result<Vec<String>> {
+    let count = read_line_from_stream(reader)?
+        .parse::<usize>()
+        .map_err(|_| KvsError::MalformedRequest)?;
+    (0..count).map(|_| read_value_from_stream(reader)).collect()
+}
+
+/// Reads an index into a list on a line of its own, negative ones counting from its tail.
+fn read_index_from_stream(reader: &mut RequestReader<'_>) -> kvs::Result<i64> {
+    read_line_from_stream(reader)?
+        .parse::<i64>()
+        .map_err(|_| KvsError::MalformedRequest)
+}
+
+/// Formats the success response carrying `values`, as their count followed by each value
+/// framed by its length.
+fn values_response(values: Vec<String>) -> String {
+    let mut response = format!("Success\r\n{}\r\n", values.len());
+    for value in values {
+        response.push_str(&format!("{}\r\n{}\r\n", value.len(), value));
+    }
+    response
+}
+
+/// Reads a time to live given in seconds on a line of its own.
+fn read_seconds_from_stream(reader: &mut RequestReader<'_>) -> kvs::Result<Duration> {
+    read_line_from_stream(reader)?
+        .parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|_| KvsError::MalformedRequest)
+}
+
+/// Reads a key count line followed by that many key lines.
+fn read_keys_from_stream(reader: &mut RequestReader<'_>) -> kvs::Result<Vec<String>> {
+    let count = read_line_from_stream(reader)?
+        .parse::<usize>()
+        .map_err(|_| KvsError::MalformedRequest)?;
+    (0..count).map(|_| read_line_from_stream(reader)).collect()
+}
+
+/// The stream of a connection, from which at most `limit` bytes of request are read.
+struct RequestStream<'a> {
+    stream: &'a TcpStream,
+    limit: u64,
+    remaining: u64,
+    /// Whether the client sent more than the limit.
+    exceeded: bool,
+}
+
+impl<'a> RequestStream<'a> {
+    fn new(stream: &'a TcpStream, limit: u64) -> RequestStream<'a> {
+        RequestStream {
+            stream,
+            limit,
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+}
+
+impl Read for RequestStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            // A request may end right at the limit, which only the next byte tells.
+            if self.stream.read(&mut [0u8])? == 0 {
+                return Ok(0);
+            }
+            self.exceeded = true;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        let read = self.stream.read(&mut buf[..len])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+trait LogAndExit {
+    type RESULT;
+    fn exit_if_err(self, exit_code: i32) -> Self::RESULT;
+}
+
+impl<T, E: std::error::Error> LogAndExit for Result<T, E> {
+    type RESULT = T;
+    fn exit_if_err(self, exit_code: i32) -> Self::RESULT {
+        match self {
+            Result::Err(e) => {
+                error!(error = %e, "An error occurred.");
+                exit(exit_code)
+            }
+            Result::Ok(t) => t,
+        }
+    }
+}
+
+fn get_engine(dir: PathBuf, engine: BackEngines) -> BackEngines {
+    let persisted_engine = dir.join("db.type");
+    if persisted_engine.exists() {
+        let engine_type = std::fs::read_to_string(&persisted_engine).unwrap();
+        if format!("{:?}", engine).contains(&engine_type) {
+            ensure_compiled(BackEngines::from_str(&engine_type).unwrap())
+        } else {
+            error!(engine_previously_used = %engine_type, "Engines are not compatible.");
+            exit(1);
+        }
+    } else {
+        let engine = match (engine, sniff_engine(&dir)) {
+            (BackEngines::Auto, Some(found)) => {
+                info!(engine_found = ?found, "Found the files of an engine without db.type.");
+                ensure_compiled(found)
+            }
+            (BackEngines::Auto, None) => BackEngines::Kvs,
+            (engine, Some(found)) if format!("{:?}", engine) != format!("{:?}", found) => {
+                error!(engine_found = ?found, "Engines are not compatible.");
+                exit(1);
+            }
+            (engine, _) => ensure_compiled(engine),
+        };
+        let mut engine_file = File::create(persisted_engine).unwrap();
+        engine_file
+            .write_all(format!("{:?}", engine).as_bytes())
+            .unwrap();
+        engine
+    }
+}
+
+/// Guesses the engine of the data in `dir` from its files, for directories written before
+/// db.type was, or which lost it. Exits if there are files of both engines.
+fn sniff_engine(dir: &Path) -> Option<BackEngines> {
+    let kvs = dir.join("log").is_file();
+    let sled = dir.join("conf").is_file() && dir.join("db").is_file();
+    match (kvs, sled) {
+        (true, true) => {
+            error!("Found the files of both the kvs and sled engines, pass --engine.");
+            exit(1);
+        }
+        (true, false) => Some(BackEngines::Kvs),
+        (false, true) => Some(BackEngines::Sled),
+        (false, false) => None,
+    }
+}
+
+/// Exits if `engine` was left out of this build of the server, before anything is written to
+/// the data directory.
+fn ensure_compiled(engine: BackEngines) -> BackEngines {
+    if cfg!(not(feature = "sled")) && matches!(engine, BackEngines::Sled) {
+        error!("The sled engine is not compiled in, build kvs-server with the sled feature.");
+        exit(1);
+    }
+    engine
+}
+
+fn ctrl_channel() -> Result<Receiver<()>, ctrlc::Error> {
+    let (sender, receiver) = bounded(10);
+    ctrlc::set_handler(move || {
+        let _ = sender.send(());
+    })?;
+
+    Ok(receiver)
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use kvs::thread_pool::ThreadPoolMetrics;
+
+use crate::clients::Clients;
+
+/// The commands whose latency is tracked.
+const COMMANDS: &[&str] = &[
+    "SET",
+    "SETEX",
+    "EXPIRE",
+    "TTL",
+    "GET",
+    "STRLEN",
+    "RM",
+    "MGET",
+    "MRM",
+    "SCAN",
+    "RSCAN",
+    "KEYS",
+    "SAVE",
+    "INFO",
+    "CLIENT",
+    "PING",
+    "GETV",
+    "SETV",
+    "META",
+    "TRACKING",
+    "TGET",
+    "SUBSCRIBE",
+    "PUBLISH",
+    "LOCK",
+    "UNLOCK",
+    "LPUSH",
+    "LRANGE",
+    "SADD",
+    "SMEMBERS",
+    "HSET",
+    "HGET",
+];
+
+/// The percentiles reported for every histogram.
+const PERCENTILES: &[(&str, f64)] = &[("p50", 0.5), ("p95", 0.95), ("p99", 0.99)];
+
+/// The largest statsd datagram sent, which fits in the payload of an Ethernet frame.
+const STATSD_DATAGRAM: usize = 1432;
+
+/// How long a scrape may take to send its request or read the response, so that a stalled
+/// client does not hold the metrics endpoint, which answers one scrape at a time.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The bytes of the request of a scrape read at most, past which it is answered anyway.
+const SCRAPE_REQUEST_LIMIT: u64 = 8192;
+
+/// Every power of two is split into this many buckets, which bounds the error of a reported
+/// percentile to a quarter of its value.
+const SUB_BUCKETS: u64 = 4;
+const BUCKETS: usize = (SUB_BUCKETS + 62 * SUB_BUCKETS) as usize;
+
+/// A histogram of durations with a resolution of one microsecond, which can be updated
+/// concurrently without locking.
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        self.buckets[bucket_of(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum.load(Ordering::Relaxed))
+    }
+
+    /// Returns the smallest recorded duration that `quantile` of the records do not exceed,
+    /// rounded up to the upper bound of its bucket.
+    fn percentile(&self, quantile: f64) -> Duration {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return Duration::from_micros(0);
+        }
+
+        let rank = ((quantile * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(upper_bound_of(index));
+            }
+        }
+        Duration::from_micros(upper_bound_of(BUCKETS - 1))
+    }
+}
+
+fn bucket_of(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exp = 63 - u64::from(micros.leading_zeros());
+    let sub = (micros >> (exp - 2)) & (SUB_BUCKETS - 1);
+    (SUB_BUCKETS + (exp - 2) * SUB_BUCKETS + sub) as usize
+}
+
+fn upper_bound_of(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let exp = (bucket - SUB_BUCKETS) / SUB_BUCKETS + 2;
+    let sub = (bucket - SUB_BUCKETS) % SUB_BUCKETS;
+    ((SUB_BUCKETS + sub + 1) << (exp - 2)) - 1
+}
+
+/// The latencies of one command: the time its connection waited for a worker, and the time
+/// spent reading the request and running it against the engine.
+struct CommandMetrics {
+    name: &'static str,
+    queued: Histogram,
+    execution: Histogram,
+}
+
+impl CommandMetrics {
+    fn phases(&self) -> [(&'static str, &Histogram); 2] {
+        [("queued", &self.queued), ("exec", &self.execution)]
+    }
+}
+
+/// The metrics of a running server and its connections, reported by the INFO command and the
+/// metrics endpoint.
+pub struct ServerMetrics {
+    pool: Arc<ThreadPoolMetrics>,
+    clients: Arc<Clients>,
+    commands: Vec<CommandMetrics>,
+}
+
+impl ServerMetrics {
+    pub fn new(pool: Arc<ThreadPoolMetrics>) -> ServerMetrics {
+        ServerMetrics {
+            pool,
+            clients: Arc::new(Clients::new()),
+            commands: COMMANDS
+                .iter()
+                .map(|&name| CommandMetrics {
+                    name,
+                    queued: Histogram::new(),
+                    execution: Histogram::new(),
+                })
+                .collect(),
+        }
+    }
+
+    /// The connections of the server.
+    pub fn clients(&self) -> &Arc<Clients> {
+        &self.clients
+    }
+
+    /// Records the latency of a served request. Unknown commands are not tracked.
+    pub fn record(&self, command: &str, queued: Duration, execution: Duration) {
+        if let Some(metrics) = self.commands.iter().find(|m| m.name == command) {
+            metrics.queued.record(queued);
+            metrics.execution.record(execution);
+        }
+    }
+
+    /// Describes the state of the server as `name:value` lines. Latencies are in microseconds
+    /// and only reported for the commands served at least once.
+    pub fn info_lines(&self) -> Vec<String> {
+        let pool = self.pool.snapshot();
+        let mut lines = vec![
+            format!("pool_queued_jobs:{}", pool.queued),
+            format!("pool_busy_workers:{}", pool.busy),
+            format!("pool_idle_workers:{}", pool.idle),
+            format!("pool_executed_jobs:{}", pool.executed),
+            format!("pool_panicked_jobs:{}", pool.panics),
+            format!("connected_clients:{}", self.clients.len()),
+        ];
+
+        for command in self.commands.iter().filter(|m| m.execution.count() > 0) {
+            let name = command.name.to_lowercase();
+            lines.push(format!(
+                "latency_{}_count:{}",
+                name,
+                command.execution.count()
+            ));
+            for (phase, histogram) in command.phases().iter() {
+                for (label, quantile) in PERCENTILES {
+                    lines.push(format!(
+                        "latency_{}_{}_{}_us:{}",
+                        name,
+                        phase,
+                        label,
+                        histogram.percentile(*quantile).as_micros()
+                    ));
+                }
+            }
+        }
+        lines
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let pool = self.pool.snapshot();
+        let mut out = String::new();
+        for (name, kind, value) in &[
+            ("kvs_pool_queued_jobs", "gauge", pool.queued as u64),
+            ("kvs_pool_busy_workers", "gauge", pool.busy as u64),
+            ("kvs_pool_idle_workers", "gauge", pool.idle as u64),
+            ("kvs_pool_executed_jobs_total", "counter", pool.executed),
+            ("kvs_pool_panicked_jobs_total", "counter", pool.panics),
+            ("kvs_connected_clients", "gauge", self.clients.len() as u64),
+        ] {
+            out.push_str(&format!("# TYPE {} {}\n{} {}\n", name, kind, name, value));
+        }
+
+        out.push_str("# TYPE kvs_request_duration_seconds summary\n");
+        for command in &self.commands {
+            for (phase, histogram) in command.phases().iter() {
+                let labels = format!("command=\"{}\",phase=\"{}\"", command.name, phase);
+                for (_, quantile) in PERCENTILES {
+                    out.push_str(&format!(
+                        "kvs_request_duration_seconds{{{},quantile=\"{}\"}} {}\n",
+                        labels,
+                        quantile,
+                        histogram.percentile(*quantile).as_secs_f64()
+                    ));
+                }
+                out.push_str(&format!(
+                    "kvs_request_duration_seconds_sum{{{}}} {}\n",
+                    labels,
+                    histogram.sum().as_secs_f64()
+                ));
+                out.push_str(&format!(
+                    "kvs_request_duration_seconds_count{{{}}} {}\n",
+                    labels,
+                    histogram.count()
+                ));
+            }
+        }
+        out
+    }
+
+    /// Renders the metrics as statsd lines named under `prefix`: the gauges, the counters as
+    /// their increase since the values in `last`, which are updated, and the percentiles of the
+    /// latencies as gauges in milliseconds, for the commands served at least once.
+    pub fn statsd_lines(&self, prefix: &str, last: &mut HashMap<String, u64>) -> Vec<String> {
+        let name = |name: String| match prefix {
+            "" => name,
+            prefix => format!("{}.{}", prefix, name),
+        };
+        let pool = self.pool.snapshot();
+        let mut lines = Vec::new();
+        for (gauge, value) in &[
+            ("pool.queued_jobs", pool.queued as u64),
+            ("pool.busy_workers", pool.busy as u64),
+            ("pool.idle_workers", pool.idle as u64),
+            ("connected_clients", self.clients.len() as u64),
+        ] {
+            lines.push(format!("{}:{}|g", name(gauge.to_string()), value));
+        }
+
+        let mut counters = vec![
+            ("pool.executed_jobs".to_string(), pool.executed),
+            ("pool.panicked_jobs".to_string(), pool.panics),
+        ];
+        let served: Vec<&CommandMetrics> = self
+            .commands
+            .iter()
+            .filter(|m| m.execution.count() > 0)
+            .collect();
+        for command in &served {
+            let command_name = command.name.to_lowercase();
+            counters.push((
+                format!("requests.{}", command_name),
+                command.execution.count(),
+            ));
+            for (phase, histogram) in command.phases().iter() {
+                for (label, quantile) in PERCENTILES {
+                    lines.push(format!(
+                        "{}:{}|g",
+                        name(format!("latency.{}.{}.{}", command_name, phase, label)),
+                        histogram.percentile(*quantile).as_secs_f64() * 1000.0
+                    ));
+                }
+            }
+        }
+        for (counter, value) in counters {
+            let previous = last.insert(counter.clone(), value).unwrap_or(0);
+            lines.push(format!(
+                "{}:{}|c",
+                name(counter),
+                value.saturating_sub(previous)
+            ));
+        }
+        lines
+    }
+}
+
+/// Pushes the metrics to the statsd server at `addr` every `interval` from a background thread,
+/// named under `prefix`, as many lines per datagram as fit.
+pub fn push_statsd(
+    addr: SocketAddr,
+    prefix: String,
+    interval: Duration,
+    metrics: Arc<ServerMetrics>,
+) -> io::Result<()> {
+    let local: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(addr)?;
+    info!(statsd_address = %addr, "Pushing metrics to statsd.");
+    thread::Builder::new()
+        .name("kvs-statsd".to_string())
+        .spawn(move || {
+            let mut last = HashMap::new();
+            loop {
+                thread::sleep(interval);
+                for datagram in datagrams(&metrics.statsd_lines(&prefix, &mut last)) {
+                    if let Err(e) = socket.send(datagram.as_bytes()) {
+                        warn!(error = %e, "Failed to push metrics to statsd.");
+                        break;
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Packs `lines` into datagrams of at most `STATSD_DATAGRAM` bytes, one line per line of the
+/// datagram. A longer line is sent on its own.
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > STATSD_DATAGRAM {
+            datagrams.push(std::mem::take(&mut datagram));
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        datagrams.push(datagram);
+    }
+    datagrams
+}
+
+/// Serves the metrics over HTTP on `addr` from a background thread, answering every request
+/// with the Prometheus rendering of `metrics`.
+pub fn serve(addr: &SocketAddr, metrics: Arc<ServerMetrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!(metrics_address = %addr, "Serving metrics.");
+    thread::Builder::new()
+        .name("kvs-metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| answer_scrape(stream, &metrics));
+                if let Err(e) = result {
+                    warn!(error = %e, "Failed to serve metrics.");
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn answer_scrape(mut stream: TcpStream, metrics: &ServerMetrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    // The request itself does not matter, but it has to be read before answering.
+    let mut reader = BufReader::new(&stream).take(SCRAPE_REQUEST_LIMIT);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+
+    let body = metrics.prometheus();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,91 @@
+//! The PROXY protocol of HAProxy, with which a TCP load balancer tells the server the address of
+//! the client it relays, ahead of the first request.
+
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use kvs::KvsError;
+
+use crate::RequestReader;
+
+/// How a header of version 2 starts.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest header of version 1, its line break included.
+const V1_MAX_LEN: u64 = 107;
+
+/// Reads the PROXY header which starts every connection relayed by the load balancer, either of
+/// version 1 or 2, and returns the address of the client, or `None` for a connection the load
+/// balancer made itself, e.g. for a health check, or from a client of an unknown protocol.
+pub fn read_header(reader: &mut RequestReader<'_>) -> kvs::Result<Option<SocketAddr>> {
+    match reader.fill_buf()?.first() {
+        Some(b'P') => read_v1(reader),
+        Some(b'\r') => read_v2(reader),
+        _ => Err(KvsError::MalformedRequest),
+    }
+}
+
+/// Reads a header of version 1, a line such as "PROXY TCP4 192.0.2.1 192.0.2.2 56324 4000".
+fn read_v1(reader: &mut RequestReader<'_>) -> kvs::Result<Option<SocketAddr>> {
+    let mut line = String::new();
+    reader.by_ref().take(V1_MAX_LEN).read_line(&mut line)?;
+    let fields = line
+        .strip_suffix("\r\n")
+        .ok_or(KvsError::MalformedRequest)?
+        .split(' ')
+        .collect::<Vec<_>>();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", src, _, port, _] | ["PROXY", "TCP6", src, _, port, _] => {
+            let ip: IpAddr = src.parse().map_err(|_| KvsError::MalformedRequest)?;
+            let port: u16 = port.parse().map_err(|_| KvsError::MalformedRequest)?;
+            // Each family only comes with its own kind of address.
+            if ip.is_ipv4() != (fields[1] == "TCP4") {
+                return Err(KvsError::MalformedRequest);
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(KvsError::MalformedRequest),
+    }
+}
+
+/// Reads a header of version 2: the signature, the version and command, the address family and
+/// transport protocol, the length of the addresses, then the addresses, followed by extensions
+/// which are skipped.
+fn read_v2(reader: &mut RequestReader<'_>) -> kvs::Result<Option<SocketAddr>> {
+    let mut head = [0u8; 16];
+    reader
+        .read_exact(&mut head)
+        .map_err(|_| KvsError::MalformedRequest)?;
+    if &head[..12] != V2_SIGNATURE || head[12] >> 4 != 2 {
+        return Err(KvsError::MalformedRequest);
+    }
+    let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+    let mut addrs = vec![0u8; len];
+    reader
+        .read_exact(&mut addrs)
+        .map_err(|_| KvsError::MalformedRequest)?;
+
+    let port = |at: usize| u16::from_be_bytes([addrs[at], addrs[at + 1]]);
+    match (head[12] & 0x0f, head[13]) {
+        // LOCAL, made by the load balancer itself.
+        (0, _) => Ok(None),
+        // PROXY over TCP, with IPv4 addresses.
+        (1, 0x11) if len >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        // PROXY over TCP, with IPv6 addresses.
+        (1, 0x21) if len >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            Ok(Some(SocketAddr::new(
+                Ipv6Addr::from(octets).into(),
+                port(32),
+            )))
+        }
+        // PROXY over another protocol, whose addresses are of no use.
+        (1, _) => Ok(None),
+        _ => Err(KvsError::MalformedRequest),
+    }
+}
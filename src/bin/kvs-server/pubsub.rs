@@ -0,0 +1,158 @@
+//! The channels the clients subscribe to, apart from the keys, and which the messages published
+//! to them are pushed over to every subscriber.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use crate::watch;
+
+/// How long a push may wait for a subscriber reading its messages too slowly, after which the
+/// subscriber is dropped.
+const PUSH_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The connections opened by SUBSCRIBE, and the channels each of them listens to.
+pub struct PubSub {
+    next_id: AtomicU64,
+    /// Shared with the threads watching the connections, which close them once their clients
+    /// do.
+    state: Arc<Mutex<PubSubState>>,
+}
+
+#[derive(Default)]
+struct PubSubState {
+    /// The connections the messages are pushed to, by id.
+    subscribers: HashMap<u64, Subscriber>,
+    /// The connections subscribed to every channel.
+    channels: HashMap<String, HashSet<u64>>,
+}
+
+struct Subscriber {
+    /// Locked by every push, so that the pushes of concurrent publishers do not interleave.
+    stream: Arc<Mutex<TcpStream>>,
+    channels: Vec<String>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub {
+            next_id: AtomicU64::new(1),
+            state: Arc::default(),
+        }
+    }
+
+    /// Keeps `stream` open to push the messages published to `channels` to, once it was sent
+    /// the success response, so that no message comes before it. The connection is closed once
+    /// its client closes it, even if nothing is ever published to its channels.
+    pub fn subscribe(&self, mut stream: TcpStream, channels: Vec<String>) -> io::Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        stream.set_write_timeout(Some(PUSH_TIMEOUT))?;
+        let watched = stream.try_clone()?;
+        let mut state = self.state();
+        stream.write_all(b"Success\r\n")?;
+        for channel in &channels {
+            state
+                .channels
+                .entry(channel.clone())
+                .or_default()
+                .insert(id);
+        }
+        let stream = Arc::new(Mutex::new(stream));
+        state
+            .subscribers
+            .insert(id, Subscriber { stream, channels });
+        drop(state);
+
+        let pubsub = Arc::clone(&self.state);
+        watch::on_close(watched, "kvs-pubsub", move || {
+            debug!(subscriber_id = id, "A subscriber was closed.");
+            let closed = lock(&pubsub).unsubscribe(id);
+            closed.iter().for_each(|stream| shut_down(stream));
+        })?;
+        Ok(())
+    }
+
+    /// Pushes `message` to the subscribers of `channel`, and returns how many received it. A
+    /// subscriber which cannot be pushed to is dropped, and its connection closed.
+    ///
+    /// The pushes happen once the state is unlocked, so that a slow subscriber holds up neither
+    /// the other channels nor new subscriptions.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let targets = {
+            let state = self.state();
+            match state.channels.get(channel) {
+                Some(ids) => ids
+                    .iter()
+                    .map(|id| (*id, Arc::clone(&state.subscribers[id].stream)))
+                    .collect::<Vec<_>>(),
+                None => return 0,
+            }
+        };
+        let push = format!(
+            "MESSAGE\r\n{}\r\n{}\r\n{}\r\n",
+            channel,
+            message.len(),
+            message
+        );
+        let mut received = 0;
+        let mut failed = Vec::new();
+        for (id, stream) in targets {
+            let mut stream = stream.lock().unwrap_or_else(PoisonError::into_inner);
+            match stream.write_all(push.as_bytes()) {
+                Ok(()) => received += 1,
+                Err(e) => {
+                    warn!(subscriber_id = id, error = %e, "Dropped a subscriber.");
+                    failed.push(id);
+                }
+            }
+        }
+        if !failed.is_empty() {
+            let closed: Vec<_> = {
+                let mut state = self.state();
+                failed
+                    .into_iter()
+                    .filter_map(|id| state.unsubscribe(id))
+                    .collect()
+            };
+            closed.iter().for_each(|stream| shut_down(stream));
+        }
+        received
+    }
+
+    fn state(&self) -> MutexGuard<'_, PubSubState> {
+        lock(&self.state)
+    }
+}
+
+impl PubSubState {
+    /// Forgets the subscriber `id`, and the channels no one listens to any longer, and returns
+    /// its stream, to be shut down once the state is unlocked.
+    fn unsubscribe(&mut self, id: u64) -> Option<Arc<Mutex<TcpStream>>> {
+        let subscriber = self.subscribers.remove(&id)?;
+        for channel in subscriber.channels {
+            if let Some(ids) = self.channels.get_mut(&channel) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.channels.remove(&channel);
+                }
+            }
+        }
+        Some(subscriber.stream)
+    }
+}
+
+/// Shuts a closed connection down, which ends the thread watching it, once no push holds it.
+fn shut_down(stream: &Mutex<TcpStream>) {
+    let stream = stream.lock().unwrap_or_else(PoisonError::into_inner);
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+// Nothing panics while holding the lock, but a poisoned state would still be consistent.
+fn lock(state: &Mutex<PubSubState>) -> MutexGuard<'_, PubSubState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,42 @@
+//! Export of the spans of the server to an OpenTelemetry collector over OTLP/HTTP.
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::Layer;
+
+use kvs::KvsError;
+
+use crate::ExportLayer;
+
+/// Creates a provider batching the spans it is given and sending them to the OTLP/HTTP
+/// `endpoint`, e.g. `http://localhost:4318/v1/traces`.
+pub fn tracer_provider(endpoint: &str) -> kvs::Result<SdkTracerProvider> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| KvsError::Internal(format!("cannot create the OTLP exporter: {}", e)))?;
+
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("kvs-server").build())
+        .build())
+}
+
+/// Returns a layer handing the spans of the server to `provider`.
+pub fn layer(provider: &SdkTracerProvider) -> ExportLayer {
+    tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("kvs-server"))
+        .boxed()
+}
+
+/// Sends the spans still buffered by `provider` before the server exits.
+pub fn shutdown(provider: Option<SdkTracerProvider>) {
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("Failed to flush the spans: {}", e);
+        }
+    }
+}
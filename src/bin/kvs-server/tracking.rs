@@ -0,0 +1,161 @@
+//! The tracking of the keys read by the clients caching values, which the server pushes the
+//! invalidation of once they are written, as Redis does in its redirect mode.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use crate::watch;
+
+/// How long a push may wait for a client reading its invalidations too slowly, after which the
+/// client is forgotten.
+const PUSH_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The connections opened by TRACKING, and the keys read through each of them by TGET.
+pub struct Tracking {
+    next_id: AtomicU64,
+    /// Shared with the threads watching the connections, which close them once their clients
+    /// do.
+    state: Arc<Mutex<TrackingState>>,
+}
+
+#[derive(Default)]
+struct TrackingState {
+    /// The connections the invalidations are pushed to, by id.
+    connections: HashMap<u64, Connection>,
+    /// The connections which read every key, until it is written.
+    readers: HashMap<String, HashSet<u64>>,
+}
+
+struct Connection {
+    /// Locked by every push, so that the pushes of concurrent writes do not interleave.
+    stream: Arc<Mutex<TcpStream>>,
+    /// The keys tracked for the connection, until they are written.
+    keys: HashSet<String>,
+}
+
+impl Tracking {
+    pub fn new() -> Tracking {
+        Tracking {
+            next_id: AtomicU64::new(1),
+            state: Arc::default(),
+        }
+    }
+
+    /// Keeps `stream` open to push invalidations to, once it was sent the success response with
+    /// its id, so that no invalidation comes before it. The connection is closed once its
+    /// client closes it.
+    pub fn open(&self, mut stream: TcpStream) -> io::Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        stream.set_write_timeout(Some(PUSH_TIMEOUT))?;
+        let watched = stream.try_clone()?;
+        let mut state = self.state();
+        stream.write_all(format!("Success\r\n{}\r\n", id).as_bytes())?;
+        let connection = Connection {
+            stream: Arc::new(Mutex::new(stream)),
+            keys: HashSet::new(),
+        };
+        state.connections.insert(id, connection);
+        drop(state);
+
+        let tracking = Arc::clone(&self.state);
+        watch::on_close(watched, "kvs-tracking", move || {
+            debug!(tracking_id = id, "A tracking connection was closed.");
+            let closed = lock(&tracking).close(id);
+            closed.iter().for_each(|stream| shut_down(stream));
+        })?;
+        Ok(id)
+    }
+
+    /// Tracks `key` for the connection `id`, before it is read. Returns false if there is no
+    /// such connection, or no longer.
+    pub fn track(&self, id: u64, key: &str) -> bool {
+        let mut state = self.state();
+        match state.connections.get_mut(&id) {
+            Some(connection) => connection.keys.insert(key.to_owned()),
+            None => return false,
+        };
+        state.readers.entry(key.to_owned()).or_default().insert(id);
+        true
+    }
+
+    /// Pushes the invalidation of `key`, which was just written, to the connections which read
+    /// it, and stops tracking it until it is read again. A connection which cannot be pushed to
+    /// is closed, so that its client drops its whole cache.
+    ///
+    /// The pushes happen once the state is unlocked, so that a slow client holds up neither the
+    /// other writes nor the reads tracked meanwhile.
+    pub fn invalidate(&self, key: &str) {
+        let targets = {
+            let mut state = self.state();
+            let readers = match state.readers.remove(key) {
+                Some(readers) => readers,
+                None => return,
+            };
+            readers
+                .into_iter()
+                .filter_map(|id| {
+                    let connection = state.connections.get_mut(&id)?;
+                    connection.keys.remove(key);
+                    Some((id, Arc::clone(&connection.stream)))
+                })
+                .collect::<Vec<_>>()
+        };
+        let push = format!("INVALIDATE\r\n{}\r\n", key);
+        let mut failed = Vec::new();
+        for (id, stream) in targets {
+            let mut stream = stream.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Err(e) = stream.write_all(push.as_bytes()) {
+                warn!(tracking_id = id, error = %e, "Closed a tracking connection.");
+                failed.push(id);
+            }
+        }
+        if !failed.is_empty() {
+            let closed: Vec<_> = {
+                let mut state = self.state();
+                failed
+                    .into_iter()
+                    .filter_map(|id| state.close(id))
+                    .collect()
+            };
+            closed.iter().for_each(|stream| shut_down(stream));
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, TrackingState> {
+        lock(&self.state)
+    }
+}
+
+impl TrackingState {
+    /// Forgets the connection `id` and the keys tracked for it, and returns its stream, to be
+    /// shut down once the state is unlocked.
+    fn close(&mut self, id: u64) -> Option<Arc<Mutex<TcpStream>>> {
+        let connection = self.connections.remove(&id)?;
+        for key in connection.keys {
+            if let Some(ids) = self.readers.get_mut(&key) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.readers.remove(&key);
+                }
+            }
+        }
+        Some(connection.stream)
+    }
+}
+
+/// Shuts a closed connection down, which ends the thread watching it, once no push holds it.
+fn shut_down(stream: &Mutex<TcpStream>) {
+    let stream = stream.lock().unwrap_or_else(PoisonError::into_inner);
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+// Nothing panics while holding the lock, but a poisoned state would still be consistent.
+fn lock(state: &Mutex<TrackingState>) -> MutexGuard<'_, TrackingState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
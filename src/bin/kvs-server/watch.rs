@@ -0,0 +1,27 @@
+//! The watch kept on the connections the server pushes to, which their clients never write to
+//! again, so that they are forgotten as soon as their clients close them instead of on the next
+//! push that fails.
+
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::thread;
+
+/// Calls `closed` from a thread named `name` once the client closes `stream`, or once the
+/// server shuts it down. Whatever the client sends meanwhile is ignored.
+pub fn on_close<F>(mut stream: TcpStream, name: &str, closed: F) -> io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    thread::Builder::new()
+        .name(name.to_owned())
+        .spawn(move || {
+            let mut buf = [0; 64];
+            while let Ok(read) = stream.read(&mut buf) {
+                if read == 0 {
+                    break;
+                }
+            }
+            closed();
+        })?;
+    Ok(())
+}
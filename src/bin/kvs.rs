@@ -0,0 +1,257 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+
+use structopt::StructOpt;
+
+#[cfg(feature = "sled")]
+use kvs::SledKvsEngine;
+use kvs::{KvStore, KvsEngine, KvsError};
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs",
+    about = "Inspect and modify a local Key-Value database without a server",
+    raw(setting = "structopt::clap::AppSettings::VersionlessSubcommands")
+)]
+struct Kvs {
+    #[structopt(subcommand)]
+    option: Opt,
+
+    /// The directory holding the database, e.g. the working directory of a kvs-server.
+    #[structopt(
+        long = "data-dir",
+        default_value = ".",
+        parse(from_os_str),
+        raw(set = "structopt::clap::ArgSettings::Global")
+    )]
+    data_dir: PathBuf,
+
+    /// The engine of the database, either "kvs" or "sled". Defaults to the engine recorded
+    /// in the data directory, or "kvs" for a new database.
+    #[structopt(long = "engine", raw(set = "structopt::clap::ArgSettings::Global"))]
+    engine: Option<Engine>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Opt {
+    ///Insert the <key> with <value> into dataset.
+    ///If the <key> already exists, update the associated value to <value>.
+    #[structopt(name = "set")]
+    Set { key: String, value: String },
+
+    ///Get the associated value of <key>, or print "Key not found" if it doesn't exist.
+    #[structopt(name = "get")]
+    Get { key: String },
+
+    ///Remove <key> and its associated value.
+    #[structopt(name = "rm")]
+    Remove { key: String },
+
+    ///Print the keys in the dataset, one per line.
+    #[structopt(name = "scan")]
+    Scan {
+        /// Only print the keys starting with <prefix>.
+        #[structopt(long = "prefix")]
+        prefix: Option<String>,
+    },
+
+    ///Compact the log of a kvs database, rewrite its index and print the number of bytes
+    ///reclaimed.
+    #[structopt(name = "compact")]
+    Compact,
+
+    ///Rebuild the index of a kvs database from its log, dropping corrupted records and
+    ///truncating torn ones, and print what was recovered.
+    #[structopt(name = "repair")]
+    Repair,
+
+    ///Write every key-value pair of the dataset to <output> as JSON lines, as a Redis dump
+    ///file with "--format rdb", as a Bitcask data file with "--format bitcask", or as a RocksDB
+    ///SST file to ingest with "--format sst".
+    #[structopt(name = "dump")]
+    Dump {
+        #[structopt(long = "output", parse(from_os_str))]
+        output: PathBuf,
+        /// The format of the file written, either "json", "rdb", "bitcask" or "sst".
+        #[structopt(long = "format", default_value = "json")]
+        format: DumpFormat,
+    },
+
+    ///Insert every key-value pair of a file written by "dump" into the dataset, or apply the
+    ///records of a Bitcask data file with "--format bitcask". The dataset keeps the format of
+    ///kvs on disk either way.
+    #[structopt(name = "restore")]
+    Restore {
+        #[structopt(long = "input", parse(from_os_str))]
+        input: PathBuf,
+        /// The format of the file read, either "json" or "bitcask".
+        #[structopt(long = "format", default_value = "json")]
+        format: DumpFormat,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DumpFormat {
+    Json,
+    Rdb,
+    Bitcask,
+    Sst,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "json" => Ok(DumpFormat::Json),
+            "rdb" => Ok(DumpFormat::Rdb),
+            "bitcask" => Ok(DumpFormat::Bitcask),
+            "sst" => Ok(DumpFormat::Sst),
+            _ => Err(format!("Unknown dump format: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Engine {
+    Kvs,
+    Sled,
+}
+
+impl FromStr for Engine {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "kvs" => Ok(Engine::Kvs),
+            "sled" => Ok(Engine::Sled),
+            _ => Err(KvsError::ParseEngineError),
+        }
+    }
+}
+
+impl Engine {
+    fn name(self) -> &'static str {
+        match self {
+            Engine::Kvs => "kvs",
+            Engine::Sled => "sled",
+        }
+    }
+}
+
+fn main() -> kvs::Result<()> {
+    let opt = Kvs::from_args();
+    let engine = select_engine(&opt.data_dir, opt.engine)?;
+
+    match (engine, opt.option) {
+        (Engine::Kvs, Opt::Compact) => {
+            let reclaimed = KvStore::open(&opt.data_dir)?.compact()?;
+            println!("{} bytes reclaimed", reclaimed);
+            Ok(())
+        }
+        (Engine::Kvs, Opt::Repair) => {
+            let report = KvStore::repair(&opt.data_dir)?;
+            println!("Recovered {} records", report.recovered_records);
+            println!("Dropped {} corrupted records", report.corrupted_records);
+            println!("Truncated {} bytes", report.truncated_bytes);
+            println!("{} keys in the rebuilt index", report.keys);
+            Ok(())
+        }
+        (Engine::Sled, Opt::Compact) | (Engine::Sled, Opt::Repair) => {
+            eprintln!("This command is only supported by the kvs engine.");
+            exit(1);
+        }
+        (Engine::Kvs, option) => run(KvStore::open(&opt.data_dir)?, option),
+        #[cfg(feature = "sled")]
+        (Engine::Sled, option) => run(SledKvsEngine::open(&opt.data_dir)?, option),
+        #[cfg(not(feature = "sled"))]
+        (Engine::Sled, _) => {
+            eprintln!("The sled engine is not compiled in, build kvs with the sled feature.");
+            exit(1);
+        }
+    }
+}
+
+/// Picks the engine recorded in the `db.type` file of `dir`, refusing an explicitly requested
+/// engine that differs from it. A new database is marked with the engine used to create it,
+/// the same way kvs-server does.
+fn select_engine(dir: &Path, requested: Option<Engine>) -> kvs::Result<Engine> {
+    let persisted_engine = dir.join("db.type");
+    if persisted_engine.exists() {
+        let engine = fs::read_to_string(&persisted_engine)?.parse()?;
+        if matches!(requested, Some(requested) if requested != engine) {
+            eprintln!(
+                "Engines are not compatible: {} was created with {}.",
+                dir.display(),
+                engine.name()
+            );
+            exit(1);
+        }
+        Ok(engine)
+    } else {
+        let engine = requested.unwrap_or(Engine::Kvs);
+        fs::create_dir_all(dir)?;
+        fs::write(persisted_engine, engine.name())?;
+        Ok(engine)
+    }
+}
+
+/// Runs a command against `store`. The index is saved after every write, as it would be on a
+/// clean shutdown of kvs-server, so that it never goes stale.
+fn run<E: KvsEngine>(store: E, opt: Opt) -> kvs::Result<()> {
+    match opt {
+        Opt::Set { key, value } => {
+            store.set(key, value)?;
+            store.save_index_log()?;
+        }
+        Opt::Get { key } => match store.get(key)? {
+            Some(value) => println!("{}", value),
+            None => println!("{}", KvsError::KeyNotFound),
+        },
+        Opt::Remove { key } => match store.remove(key) {
+            Err(KvsError::KeyNotFound) => KvsError::KeyNotFound.exit(1),
+            result => {
+                result?;
+                store.save_index_log()?;
+            }
+        },
+        Opt::Scan { prefix } => {
+            let mut keys = store.scan();
+            if let Some(prefix) = prefix {
+                keys.retain(|key| key.starts_with(&prefix));
+            }
+            keys.sort();
+            for key in keys {
+                println!("{}", key);
+            }
+        }
+        Opt::Dump { output, format } => {
+            let writer = BufWriter::new(File::create(output)?);
+            let count = match format {
+                DumpFormat::Json => store.export(writer)?,
+                DumpFormat::Rdb => store.export_rdb(writer)?,
+                DumpFormat::Bitcask => store.export_bitcask(writer)?,
+                DumpFormat::Sst => store.export_sst(writer)?,
+            };
+            println!("Dumped {} keys", count);
+        }
+        Opt::Restore { input, format } => {
+            let count = match format {
+                DumpFormat::Json => store.import(BufReader::new(File::open(input)?))?,
+                DumpFormat::Bitcask => store.import_bitcask(input)?,
+                DumpFormat::Rdb | DumpFormat::Sst => {
+                    eprintln!("Only JSON lines and Bitcask data files can be restored.");
+                    exit(1);
+                }
+            };
+            store.save_index_log()?;
+            println!("Restored {} keys", count);
+        }
+        Opt::Compact | Opt::Repair => unreachable!("maintenance is handled per engine"),
+    }
+
+    Ok(())
+}
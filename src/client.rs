@@ -0,0 +1,617 @@
+//! A client of kvs-server, opening a connection per request like kvs-client.
+
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{KvsError, Result, ServerAddr};
+
+/// How long a connection to a server may take to open.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a replica which could not be reached is left out of the reads.
+const REPLICA_RETRY: Duration = Duration::from_secs(5);
+
+/// Configuration for a [`KvsClient`](struct.KvsClient.html).
+///
+/// ```no_run
+/// use kvs::{KvsClientBuilder, ServerAddr};
+///
+/// let replicas = vec!["replica1:4000".parse().unwrap(), "replica2:4000".parse().unwrap()];
+/// let client = KvsClientBuilder::new()
+///     .replicas(replicas)
+///     .cache(1000)
+///     .build("primary:4000".parse().unwrap());
+/// client.set("key".to_owned(), "value".to_owned()).unwrap();
+/// assert_eq!(client.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct KvsClientBuilder {
+    replicas: Vec<ServerAddr>,
+    cache: usize,
+}
+
+impl KvsClientBuilder {
+    /// Creates a configuration sending every request to the server it is built for.
+    pub fn new() -> Self {
+        KvsClientBuilder::default()
+    }
+
+    /// Spreads the reads over `replicas` of the server, in turn, the writes still going to the
+    /// server as the primary. A replica which cannot be reached is left out for a few seconds,
+    /// and the reads go to the primary while no replica can.
+    pub fn replicas(mut self, replicas: Vec<ServerAddr>) -> Self {
+        self.replicas = replicas;
+        self
+    }
+
+    /// Caches the values of up to `capacity` keys read, which the primary tracks and tells the
+    /// client about as soon as they are written, over a connection kept open. The cached reads
+    /// all go to the primary, and the keys bound to expire are not cached. The whole cache is
+    /// dropped if the connection closes, and the primary is asked again. Nothing is cached if
+    /// `capacity` is 0, the default.
+    pub fn cache(mut self, capacity: usize) -> Self {
+        self.cache = capacity;
+        self
+    }
+
+    /// Creates a client of the kvs-server listening on `primary`.
+    pub fn build(self, primary: ServerAddr) -> KvsClient {
+        let capacity = self.cache;
+        let servers = Servers {
+            primary,
+            replicas: self
+                .replicas
+                .into_iter()
+                .map(|addr| Replica {
+                    addr,
+                    down_until: Mutex::new(None),
+                })
+                .collect(),
+            next_replica: AtomicUsize::new(0),
+            cache: (capacity > 0).then(|| ClientCache::new(capacity)),
+        };
+        KvsClient {
+            servers: Arc::new(servers),
+            interceptors: Mutex::new(Arc::new(Vec::new())),
+        }
+    }
+}
+
+/// A client of a kvs-server, configured by a [`KvsClientBuilder`](struct.KvsClientBuilder.html).
+///
+/// An error response of the server is returned as `KvsError::KeyNotFound` for a missing key,
+/// and as `KvsError::Server` otherwise. Every call goes through the
+/// [`Interceptor`](trait.Interceptor.html)s added to the client, if any.
+pub struct KvsClient {
+    servers: Arc<Servers>,
+    /// The interceptors of the calls, the first added outermost.
+    interceptors: Mutex<Arc<Vec<Arc<dyn Interceptor>>>>,
+}
+
+/// The servers a client sends its calls to, once through its interceptors.
+struct Servers {
+    primary: ServerAddr,
+    replicas: Vec<Replica>,
+    /// The replica the next read starts with.
+    next_replica: AtomicUsize,
+    cache: Option<ClientCache>,
+}
+
+/// A replica a client reads from.
+struct Replica {
+    addr: ServerAddr,
+    /// Until when it is left out of the reads, since it could not be reached.
+    down_until: Mutex<Option<Instant>>,
+}
+
+/// A call of a method of a [`KvsClient`](struct.KvsClient.html), as its interceptors see it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    /// [`KvsClient::set`](struct.KvsClient.html#method.set)
+    Set { key: String, value: String },
+    /// [`KvsClient::get`](struct.KvsClient.html#method.get)
+    Get { key: String },
+    /// [`KvsClient::remove`](struct.KvsClient.html#method.remove)
+    Remove { key: String },
+    /// [`KvsClient::keys`](struct.KvsClient.html#method.keys)
+    Keys,
+    /// [`KvsClient::save`](struct.KvsClient.html#method.save)
+    Save,
+}
+
+impl Call {
+    /// The name of the method called, e.g. "set".
+    pub fn name(&self) -> &'static str {
+        match self {
+            Call::Set { .. } => "set",
+            Call::Get { .. } => "get",
+            Call::Remove { .. } => "remove",
+            Call::Keys => "keys",
+            Call::Save => "save",
+        }
+    }
+}
+
+/// What a call returns, by the method called.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// The reply of the methods returning nothing: set, remove and save.
+    Done,
+    /// The value of the key got, `None` if it is not in the store.
+    Value(Option<String>),
+    /// The keys of the store.
+    Keys(Vec<String>),
+}
+
+/// Wraps the calls of a [`KvsClient`](struct.KvsClient.html), e.g. to log, time or retry them,
+/// or to change their arguments or their replies.
+///
+/// ```no_run
+/// use kvs::{Call, KvsClient, Next};
+///
+/// let client = KvsClient::new("127.0.0.1:4000".parse().unwrap());
+/// // Keeps the keys of the client apart from the others.
+/// client.add_interceptor(|call: Call, next: &Next| {
+///     let call = match call {
+///         Call::Set { key, value } => Call::Set { key: format!("app:{}", key), value },
+///         Call::Get { key } => Call::Get { key: format!("app:{}", key) },
+///         Call::Remove { key } => Call::Remove { key: format!("app:{}", key) },
+///         call => call,
+///     };
+///     next.run(call)
+/// });
+/// ```
+pub trait Interceptor: Send + Sync {
+    /// Makes `call` through `next`, as many times as needed and with the arguments wanted, and
+    /// returns its reply, or replies without making it.
+    ///
+    /// # Errors
+    /// The errors of the calls made through `next`, or any other, e.g. `KvsError::Interceptor`
+    /// for a call refused.
+    fn intercept(&self, call: Call, next: &Next) -> Result<Reply>;
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(Call, &Next) -> Result<Reply> + Send + Sync,
+{
+    fn intercept(&self, call: Call, next: &Next) -> Result<Reply> {
+        self(call, next)
+    }
+}
+
+/// The rest of a call, handed to an interceptor to make it through the interceptors after it.
+#[derive(Clone)]
+pub struct Next {
+    servers: Arc<Servers>,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    /// The index of the interceptor it calls next.
+    depth: usize,
+}
+
+impl Next {
+    /// Makes `call` through the interceptors after the one it was handed to, and then sends it
+    /// to the servers.
+    pub fn run(&self, call: Call) -> Result<Reply> {
+        match self.interceptors.get(self.depth) {
+            Some(interceptor) => {
+                let next = Next {
+                    depth: self.depth + 1,
+                    ..self.clone()
+                };
+                interceptor.intercept(call, &next)
+            }
+            None => self.servers.execute(call),
+        }
+    }
+}
+
+impl KvsClient {
+    /// Creates a client of the kvs-server listening on `addr`, sending it every request.
+    pub fn new(addr: ServerAddr) -> KvsClient {
+        KvsClientBuilder::new().build(addr)
+    }
+
+    /// Adds `interceptor`, called by the ones added before it. The calls in progress keep going
+    /// through the interceptors they started with.
+    pub fn add_interceptor<I: Interceptor + 'static>(&self, interceptor: I) {
+        let mut interceptors = self.interceptors.lock().unwrap_or_else(|e| e.into_inner());
+        let mut added = Vec::clone(&interceptors);
+        added.push(Arc::new(interceptor));
+        *interceptors = Arc::new(added);
+    }
+
+    /// Sets the value of `key` to `value`.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        match self.call(Call::Set { key, value })? {
+            Reply::Done => Ok(()),
+            reply => Err(mismatched("set", reply)),
+        }
+    }
+
+    /// Returns the value of `key`, or `None` if it is not in the store.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.call(Call::Get { key })? {
+            Reply::Value(value) => Ok(value),
+            reply => Err(mismatched("get", reply)),
+        }
+    }
+
+    /// Removes `key`.
+    ///
+    /// # Errors
+    /// Returns `KvsError::KeyNotFound` if the key is not in the store.
+    pub fn remove(&self, key: String) -> Result<()> {
+        match self.call(Call::Remove { key })? {
+            Reply::Done => Ok(()),
+            reply => Err(mismatched("remove", reply)),
+        }
+    }
+
+    /// Returns the keys of the store.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        match self.call(Call::Keys)? {
+            Reply::Keys(keys) => Ok(keys),
+            reply => Err(mismatched("keys", reply)),
+        }
+    }
+
+    /// Makes the primary write a checkpoint of its index.
+    pub fn save(&self) -> Result<()> {
+        match self.call(Call::Save)? {
+            Reply::Done => Ok(()),
+            reply => Err(mismatched("save", reply)),
+        }
+    }
+
+    /// Makes `call` through the interceptors, and returns its reply.
+    pub fn call(&self, call: Call) -> Result<Reply> {
+        let interceptors = {
+            let interceptors = self.interceptors.lock().unwrap_or_else(|e| e.into_inner());
+            Arc::clone(&interceptors)
+        };
+        let next = Next {
+            servers: Arc::clone(&self.servers),
+            interceptors,
+            depth: 0,
+        };
+        next.run(call)
+    }
+}
+
+impl Servers {
+    /// Sends `call` to the servers, once through the interceptors.
+    fn execute(&self, call: Call) -> Result<Reply> {
+        match call {
+            Call::Set { key, value } => {
+                let request = format!("SET\r\n{}\r\n{}\r\n{}\r\n", key, value.len(), value);
+                self.write_request(&key, &request)?;
+                Ok(Reply::Done)
+            }
+            Call::Get { key } => self.get(&key).map(Reply::Value),
+            Call::Remove { key } => {
+                self.write_request(&key, &format!("RM\r\n{}\r\n", key))?;
+                Ok(Reply::Done)
+            }
+            Call::Keys => self.keys().map(Reply::Keys),
+            Call::Save => {
+                self.request("SAVE\r\n")?;
+                Ok(Reply::Done)
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if let Some(cache) = &self.cache {
+            return cache.get(&self.primary, key);
+        }
+        let mut reader = self.read_request(&format!("GET\r\n{}\r\n", key))?;
+        let len = read_line(&mut reader)?;
+        if len == "-1" {
+            return Ok(None);
+        }
+        Ok(Some(read_value(&mut reader, &len)?))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut reader = self.read_request("SCAN\r\n")?;
+        let mut keys = String::new();
+        reader.read_to_string(&mut keys)?;
+        Ok(keys
+            .split("\r\n")
+            .filter(|key| !key.is_empty())
+            .map(str::to_owned)
+            .collect())
+    }
+
+    /// Sends `request` to the primary, and returns the rest of its response once it succeeded.
+    fn request(&self, request: &str) -> Result<BufReader<TcpStream>> {
+        send(self.primary.connect(CONNECT_TIMEOUT)?, request)
+    }
+
+    /// Sends the `request` writing `key` to the primary, and forgets the value cached, if any,
+    /// once it succeeded.
+    fn write_request(&self, key: &str, request: &str) -> Result<()> {
+        self.request(request)?;
+        if let Some(cache) = &self.cache {
+            cache.forget(key);
+        }
+        Ok(())
+    }
+
+    /// Sends the read `request` to the next replica which can be reached, or to the primary if
+    /// none can, and returns the rest of its response once it succeeded.
+    fn read_request(&self, request: &str) -> Result<BufReader<TcpStream>> {
+        let count = self.replicas.len();
+        let first = self.next_replica.fetch_add(1, Ordering::Relaxed);
+        for i in 0..count {
+            let replica = &self.replicas[(first + i) % count];
+            if replica.is_down() {
+                continue;
+            }
+            // Connecting is not done under the lock, so that the other reads skip the replica
+            // rather than wait for it.
+            match replica.addr.connect(CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    replica.set_down_until(None);
+                    return send(stream, request);
+                }
+                Err(_) => replica.set_down_until(Some(Instant::now() + REPLICA_RETRY)),
+            }
+        }
+        self.request(request)
+    }
+}
+
+impl Replica {
+    /// Whether the replica is left out of the reads.
+    fn is_down(&self) -> bool {
+        let down_until = self.down_until.lock().unwrap_or_else(|e| e.into_inner());
+        down_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    fn set_down_until(&self, until: Option<Instant>) {
+        *self.down_until.lock().unwrap_or_else(|e| e.into_inner()) = until;
+    }
+}
+
+/// The values cached by a client, kept consistent by the invalidations the primary pushes over
+/// a tracking connection.
+struct ClientCache {
+    capacity: usize,
+    state: Arc<Mutex<CacheState>>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// The tracking connection with its id, `None` until it is opened, and once it closed.
+    tracking: Option<(u64, TcpStream)>,
+    /// The values cached, `None` for a key known to be missing.
+    values: HashMap<String, Option<String>>,
+    /// The keys being read from the primary, by any number of reads at once.
+    reading: HashMap<String, Reading>,
+}
+
+/// The reads of a key from the primary under way.
+#[derive(Default)]
+struct Reading {
+    readers: usize,
+    /// Raised whenever the key is written, so that a read only caches the value it got if the
+    /// key was not written since it started.
+    generation: u64,
+}
+
+impl CacheState {
+    fn tracking_id(&self) -> Option<u64> {
+        self.tracking.as_ref().map(|(id, _)| *id)
+    }
+
+    /// Forgets `key`, written since it was cached or while it is being read.
+    fn invalidate(&mut self, key: &str) {
+        self.values.remove(key);
+        if let Some(reading) = self.reading.get_mut(key) {
+            reading.generation += 1;
+        }
+    }
+
+    /// Starts a read of `key` from the primary, and returns the generation it started from.
+    fn start_reading(&mut self, key: &str) -> u64 {
+        let reading = self.reading.entry(key.to_owned()).or_default();
+        reading.readers += 1;
+        reading.generation
+    }
+
+    /// Ends a read of `key` started from `generation`, and returns whether the key was written
+    /// since.
+    fn end_reading(&mut self, key: &str, generation: u64) -> bool {
+        let reading = match self.reading.get_mut(key) {
+            Some(reading) => reading,
+            None => return true,
+        };
+        let written = reading.generation != generation;
+        reading.readers -= 1;
+        if reading.readers == 0 {
+            self.reading.remove(key);
+        }
+        written
+    }
+}
+
+impl ClientCache {
+    fn new(capacity: usize) -> ClientCache {
+        ClientCache {
+            capacity,
+            state: Arc::new(Mutex::new(CacheState::default())),
+        }
+    }
+
+    /// Returns the value of `key` cached, or reads it from `primary` and caches it, unless it
+    /// was written in the meantime.
+    fn get(&self, primary: &ServerAddr, key: &str) -> Result<Option<String>> {
+        let tracking_id = {
+            let state = self.state();
+            if let Some(value) = state.values.get(key) {
+                return Ok(value.clone());
+            }
+            state.tracking_id()
+        };
+        let id = match tracking_id {
+            Some(id) => id,
+            None => self.track(primary)?,
+        };
+        let generation = self.state().start_reading(key);
+
+        let request = format!("TGET\r\n{}\r\n{}\r\n", id, key);
+        let response = primary
+            .connect(CONNECT_TIMEOUT)
+            .map_err(KvsError::from)
+            .and_then(|stream| send(stream, &request))
+            .and_then(|mut reader| {
+                let cacheable = read_line(&mut reader)? == "1";
+                let len = read_line(&mut reader)?;
+                if len == "-1" {
+                    return Ok((cacheable, None));
+                }
+                Ok((cacheable, Some(read_value(&mut reader, &len)?)))
+            });
+
+        let mut state = self.state();
+        let written = state.end_reading(key, generation);
+        let (cacheable, value) = response?;
+        if cacheable && !written && state.tracking_id() == Some(id) {
+            if state.values.len() >= self.capacity {
+                if let Some(evicted) = state.values.keys().next().cloned() {
+                    state.values.remove(&evicted);
+                }
+            }
+            state.values.insert(key.to_owned(), value.clone());
+        }
+        Ok(value)
+    }
+
+    /// Forgets the value of `key` the client just wrote.
+    fn forget(&self, key: &str) {
+        self.state().invalidate(key);
+    }
+
+    /// Opens a tracking connection to `primary`, and starts the thread applying the
+    /// invalidations it pushes. Returns its id, or that of the one another read opened
+    /// meanwhile. It is opened without holding the lock, so that the cached reads do not wait
+    /// for it.
+    fn track(&self, primary: &ServerAddr) -> Result<u64> {
+        let stream = primary.connect(CONNECT_TIMEOUT)?;
+        let mut reader = send(stream.try_clone()?, "TRACKING\r\n")?;
+        let id = read_line(&mut reader)?
+            .parse::<u64>()
+            .map_err(|_| malformed("response"))?;
+        let mut state = self.state();
+        if let Some(tracking_id) = state.tracking_id() {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Ok(tracking_id);
+        }
+        let cache = Arc::clone(&self.state);
+        thread::Builder::new()
+            .name("kvs-tracking".to_owned())
+            .spawn(move || {
+                while let Ok(key) = read_line(&mut reader).and_then(|_| read_line(&mut reader)) {
+                    lock(&cache).invalidate(&key);
+                }
+                // Anything may have been written since, unbeknownst to the client.
+                let mut state = lock(&cache);
+                if state.tracking_id() == Some(id) {
+                    state.tracking = None;
+                    state.values.clear();
+                    state
+                        .reading
+                        .values_mut()
+                        .for_each(|reading| reading.generation += 1);
+                }
+            })?;
+        state.tracking = Some((id, stream));
+        Ok(id)
+    }
+
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        lock(&self.state)
+    }
+}
+
+impl Drop for ClientCache {
+    /// Closes the tracking connection, which ends its thread.
+    fn drop(&mut self) {
+        if let Some((_, stream)) = self.state().tracking.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn lock(state: &Mutex<CacheState>) -> MutexGuard<'_, CacheState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Sends `request` over `stream`, and returns the rest of the response once it succeeded.
+fn send(mut stream: TcpStream, request: &str) -> Result<BufReader<TcpStream>> {
+    stream.write_all(request.as_bytes())?;
+    let mut reader = BufReader::new(stream);
+    match read_line(&mut reader)?.as_ref() {
+        "Success" => Ok(reader),
+        "Error" => {
+            let error = read_line(&mut reader)?;
+            let mut parts = error.splitn(2, ' ');
+            let code = parts.next().unwrap_or_default();
+            let message = parts.next().unwrap_or_default();
+            if code == KvsError::KeyNotFound.code() {
+                return Err(KvsError::KeyNotFound);
+            }
+            Err(KvsError::Server {
+                code: code.to_owned(),
+                message: message.to_owned(),
+            })
+        }
+        _ => Err(malformed("response").into()),
+    }
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.ends_with("\r\n") {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed by the server",
+        ));
+    }
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+/// Reads a value of `len` bytes, as given by the line before it, and the line break after it.
+fn read_value(reader: &mut BufReader<TcpStream>, len: &str) -> io::Result<String> {
+    let len = len.parse::<usize>().map_err(|_| malformed("value"))?;
+    let mut value = vec![0u8; len + 2];
+    reader.read_exact(&mut value)?;
+    if !value.ends_with(b"\r\n") {
+        return Err(malformed("value"));
+    }
+    value.truncate(len);
+    String::from_utf8(value).map_err(|_| malformed("value"))
+}
+
+/// The error of a call whose interceptors replied to `method` with the reply of another.
+fn mismatched(method: &str, reply: Reply) -> KvsError {
+    KvsError::Internal(format!("{} replied with {:?}", method, reply))
+}
+
+/// The error of a `what` of the server which does not parse.
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed {} of the server", what),
+    )
+}
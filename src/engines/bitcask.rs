@@ -0,0 +1,124 @@
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use crate::{KvsError, Result};
+
+/// The size of the header of a record: its CRC32, timestamp, key size and value size.
+const HEADER_SIZE: usize = 14;
+
+/// What the values of the records removing their key start with: the tombstones of the first
+/// versions of Bitcask are this exact value, later ones append a version and a file id.
+const TOMBSTONE_PREFIX: &[u8] = b"bitcask_tombstone";
+
+/// The longest tombstone, with its version and file id.
+const MAX_TOMBSTONE_SIZE: usize = 22;
+
+/// A record of a Bitcask data file.
+pub(crate) enum Record {
+    Put { key: String, value: String },
+    Delete { key: String },
+}
+
+/// Writes a record of a Bitcask data file to `writer`: a big-endian CRC32 of the rest of the
+/// record, then the timestamp in seconds, the key size and the value size, the key and the
+/// value.
+pub(crate) fn write_record<W: Write>(
+    writer: &mut W,
+    timestamp: u32,
+    key: &str,
+    value: &str,
+) -> Result<()> {
+    let key_size = u16::try_from(key.len()).map_err(|_| KvsError::InvalidKeySize)?;
+    let value_size = u32::try_from(value.len()).map_err(|_| KvsError::InvalidValueSize)?;
+    let mut record = Vec::with_capacity(HEADER_SIZE + key.len() + value.len());
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&timestamp.to_be_bytes());
+    record.extend_from_slice(&key_size.to_be_bytes());
+    record.extend_from_slice(&value_size.to_be_bytes());
+    record.extend_from_slice(key.as_bytes());
+    record.extend_from_slice(value.as_bytes());
+    let crc = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_be_bytes());
+    writer.write_all(&record)?;
+    Ok(())
+}
+
+/// Reads the records of a Bitcask data file in order.
+pub(crate) struct RecordReader<R: Read> {
+    reader: R,
+    /// The path of the file, reported by the errors.
+    path: PathBuf,
+    offset: u64,
+}
+
+impl<R: Read> RecordReader<R> {
+    pub(crate) fn new(reader: R, path: PathBuf) -> RecordReader<R> {
+        RecordReader {
+            reader,
+            path,
+            offset: 0,
+        }
+    }
+
+    /// Returns the next record, or `None` at the end of the file. A record cut short by the
+    /// end of the file, as left by a crash in the middle of a write, ends it as well.
+    pub(crate) fn next_record(&mut self) -> Result<Option<Record>> {
+        let mut header = [0; HEADER_SIZE];
+        if !self.read_or_eof(&mut header)? {
+            return Ok(None);
+        }
+        let expected = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let key_size = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let value_size = u32::from_be_bytes([header[10], header[11], header[12], header[13]]);
+        let mut body = vec![0; key_size + value_size as usize];
+        if !self.read_or_eof(&mut body)? {
+            return Ok(None);
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&body);
+        let actual = hasher.finalize();
+        let offset = self.offset;
+        self.offset += (HEADER_SIZE + body.len()) as u64;
+        if actual != expected {
+            return Err(KvsError::Corruption {
+                path: self.path.clone(),
+                offset,
+                expected,
+                actual,
+            });
+        }
+
+        let value = body.split_off(key_size);
+        let key = self.utf8(body, offset, "key")?;
+        if value.starts_with(TOMBSTONE_PREFIX) && value.len() <= MAX_TOMBSTONE_SIZE {
+            return Ok(Some(Record::Delete { key }));
+        }
+        let value = self.utf8(value, offset, "value")?;
+        Ok(Some(Record::Put { key, value }))
+    }
+
+    /// Fills `buf`, and returns false if the file ends first.
+    fn read_or_eof(&mut self, buf: &mut [u8]) -> Result<bool> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Decodes the key or value of the record at `offset`, which Bitcask does not require to be
+    /// UTF-8 while the engines do.
+    fn utf8(&self, bytes: Vec<u8>, offset: u64, what: &str) -> Result<String> {
+        String::from_utf8(bytes).map_err(|e| {
+            KvsError::from(io::Error::new(io::ErrorKind::InvalidData, e)).with_context(format!(
+                "the {} of the record of {} at offset {}",
+                what,
+                self.path.display(),
+                offset
+            ))
+        })
+    }
+}
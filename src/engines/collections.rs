@@ -0,0 +1,99 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use serde::de::DeserializeOwned;
+
+use super::lease::Lease;
+use super::ValueType;
+use crate::Result;
+
+/// A value made of several strings, kept whole in the value of its key in JSON, e.g.
+/// `["b","a"]` for a list, with its type along with it, and rewritten on every update by
+/// [`KvsEngine::update_typed`](trait.KvsEngine.html#method.update_typed).
+#[derive(Debug)]
+pub(crate) enum Collection {
+    /// The elements of a list, from its head.
+    List(VecDeque<String>),
+    /// The members of a set, in ascending order.
+    Set(BTreeSet<String>),
+    /// The fields of a hash and their values.
+    Hash(BTreeMap<String, String>),
+}
+
+impl Collection {
+    /// Encodes the collection into the value of its key, along with its type.
+    pub(crate) fn encode(&self) -> (ValueType, String) {
+        let (value_type, json) = match self {
+            Collection::List(list) => (ValueType::List, serde_json::to_string(list)),
+            Collection::Set(set) => (ValueType::Set, serde_json::to_string(set)),
+            Collection::Hash(hash) => (ValueType::Hash, serde_json::to_string(hash)),
+        };
+        (
+            value_type,
+            json.expect("a collection of strings serializes"),
+        )
+    }
+}
+
+/// Decodes the value of `key` into a list, empty if the key does not exist.
+///
+/// # Errors
+/// Returns `KvsError::WrongType` if the key holds a string or another type of collection.
+pub(crate) fn decode_list(key: &str, value: Option<(ValueType, &str)>) -> Result<VecDeque<String>> {
+    decode(key, value, ValueType::List)
+}
+
+/// Decodes the value of `key` into a set, as [`decode_list`](fn.decode_list.html) does.
+pub(crate) fn decode_set(key: &str, value: Option<(ValueType, &str)>) -> Result<BTreeSet<String>> {
+    decode(key, value, ValueType::Set)
+}
+
+/// Decodes the value of `key` into a hash, as [`decode_list`](fn.decode_list.html) does.
+pub(crate) fn decode_hash(
+    key: &str,
+    value: Option<(ValueType, &str)>,
+) -> Result<BTreeMap<String, String>> {
+    decode(key, value, ValueType::Hash)
+}
+
+fn decode<T>(key: &str, value: Option<(ValueType, &str)>, expected: ValueType) -> Result<T>
+where
+    T: DeserializeOwned + Default,
+{
+    match value {
+        Some((value_type, json)) => {
+            value_type.check(key, expected)?;
+            Ok(serde_json::from_str(json)?)
+        }
+        None => Ok(T::default()),
+    }
+}
+
+/// Checks that `value` encodes a collection or a lock of `value_type`, for the values which do
+/// not come from [`Collection::encode`](enum.Collection.html#method.encode) or a lease, as the
+/// imported ones.
+pub(crate) fn validate(key: &str, value_type: ValueType, value: &str) -> Result<()> {
+    let typed = Some((value_type, value));
+    match value_type {
+        ValueType::String => Ok(()),
+        ValueType::List => decode_list(key, typed).map(drop),
+        ValueType::Set => decode_set(key, typed).map(drop),
+        ValueType::Hash => decode_hash(key, typed).map(drop),
+        ValueType::Lock => Lease::parse(key, value).map(drop),
+    }
+}
+
+/// Returns the elements of `list` from `start` to `stop` included, both counted from the head
+/// of the list, or from its tail if negative, -1 being the last element. The range is clamped
+/// to the list.
+pub(crate) fn range(list: &VecDeque<String>, start: i64, stop: i64) -> Vec<String> {
+    let len = list.len() as i64;
+    let resolve = |index: i64| if index < 0 { len + index } else { index };
+    let start = resolve(start).max(0);
+    let stop = resolve(stop).min(len - 1);
+    if start > stop {
+        return Vec::new();
+    }
+    list.range(start as usize..=stop as usize)
+        .cloned()
+        .collect()
+}
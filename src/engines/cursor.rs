@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+/// A cursor over the keys of an engine in ascending order, which can be moved to any key without
+/// iterating up to it, like the iterators of RocksDB. It goes over the keys there were when it
+/// was created, the writes made since are not seen.
+///
+/// ```
+/// use kvs::{KvsEngine, MemKvsEngine};
+///
+/// let engine = MemKvsEngine::new();
+/// for key in &["a", "b", "d"] {
+///     engine.set(key.to_string(), "value".to_owned())?;
+/// }
+///
+/// let mut cursor = engine.cursor();
+/// cursor.seek("c");
+/// assert_eq!(cursor.next().as_deref(), Some("d"));
+/// assert_eq!(cursor.next(), None);
+/// cursor.seek_to_first();
+/// assert_eq!(cursor.collect::<Vec<_>>(), vec!["a", "b", "d"]);
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct KeyCursor {
+    keys: Vec<String>,
+    /// The position of the key returned next.
+    next: usize,
+    snapshot: Option<Snapshot>,
+}
+
+/// Where a [`KeyCursor`](struct.KeyCursor.html) is, which can be saved, e.g. as JSON, and
+/// handed to [`KvsEngine::resume_cursor`](trait.KvsEngine.html#method.resume_cursor) later,
+/// even after a restart.
+///
+/// ```
+/// use kvs::{CursorToken, KvsEngine, MemKvsEngine};
+///
+/// let engine = MemKvsEngine::new();
+/// for key in &["a", "b", "c"] {
+///     engine.set(key.to_string(), "value".to_owned())?;
+/// }
+///
+/// let mut cursor = engine.cursor();
+/// assert_eq!(cursor.next().as_deref(), Some("a"));
+/// let saved = serde_json::to_string(&cursor.token())?;
+///
+/// let token: CursorToken = serde_json::from_str(&saved)?;
+/// let cursor = engine.resume_cursor(&token)?;
+/// assert_eq!(cursor.collect::<Vec<_>>(), vec!["b", "c"]);
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CursorToken {
+    /// The key before the one the cursor returns next, `None` if it is at the first key.
+    after: Option<String>,
+    pub(crate) snapshot: Option<Snapshot>,
+}
+
+/// The keys a cursor goes over, for the engines which can find them again: the end of the log of
+/// a `KvStore` when the cursor was created, with the checksum of the bytes before it, which
+/// tells whether the log was compacted since.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct Snapshot {
+    pub(crate) offset: u64,
+    pub(crate) check: u32,
+}
+
+impl KeyCursor {
+    /// Creates a cursor over `keys`, in any order, positioned at the first of them.
+    pub(crate) fn new(keys: Vec<String>) -> KeyCursor {
+        KeyCursor::with_snapshot(keys, None)
+    }
+
+    /// Creates a cursor over `keys`, which are those of `snapshot`.
+    pub(crate) fn with_snapshot(mut keys: Vec<String>, snapshot: Option<Snapshot>) -> KeyCursor {
+        keys.sort_unstable();
+        KeyCursor {
+            keys,
+            next: 0,
+            snapshot,
+        }
+    }
+
+    /// Creates a cursor over `keys` where the cursor which returned `token` was.
+    pub(crate) fn resume(keys: Vec<String>, token: &CursorToken) -> KeyCursor {
+        let mut cursor = KeyCursor::with_snapshot(keys, token.snapshot);
+        if let Some(after) = &token.after {
+            cursor.next = cursor.keys.partition_point(|k| k <= after);
+        }
+        cursor
+    }
+
+    /// Returns where the cursor is, to resume it later.
+    pub fn token(&self) -> CursorToken {
+        CursorToken {
+            after: self.next.checked_sub(1).map(|last| self.keys[last].clone()),
+            snapshot: self.snapshot,
+        }
+    }
+
+    /// Moves the cursor to the first key at or after `key`, so that a scan stopped after some
+    /// key can be resumed from the next one.
+    pub fn seek(&mut self, key: &str) {
+        self.next = self.keys.partition_point(|k| k.as_str() < key);
+    }
+
+    /// Moves the cursor to the first key.
+    pub fn seek_to_first(&mut self) {
+        self.next = 0;
+    }
+
+    /// Moves the cursor to the last key, the only one left to return.
+    pub fn seek_to_last(&mut self) {
+        self.next = self.keys.len().saturating_sub(1);
+    }
+}
+
+impl Iterator for KeyCursor {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let key = self.keys.get(self.next)?.clone();
+        self.next += 1;
+        Some(key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.keys.len().saturating_sub(self.next);
+        (left, Some(left))
+    }
+}
@@ -0,0 +1,98 @@
+/// Whether the whole of `key` matches the glob-style `pattern`, as described by
+/// [`KvsEngine::keys`](trait.KvsEngine.html#method.keys).
+pub(crate) fn matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+
+    let (mut p, mut k) = (0, 0);
+    // The position after the last `*` met, and the position in the key it resumes from when
+    // the rest of the pattern does not match.
+    let mut star = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+        if let Some(next) = match_one(&pattern, p, key[k]) {
+            p = next;
+            k += 1;
+            continue;
+        }
+        match star {
+            // Let the last `*` eat one more character.
+            Some((after, from)) => {
+                p = after;
+                k = from + 1;
+                star = Some((after, k));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the element of `pattern` at `p`, other than `*`. Returns the position
+/// after the element if it matches.
+fn match_one(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    let matched = match *pattern.get(p)? {
+        '?' => return Some(p + 1),
+        '[' => match match_class(pattern, p + 1, c) {
+            Some((matched, next)) => return if matched { Some(next) } else { None },
+            // An unclosed `[` is a literal.
+            None => c == '[',
+        },
+        '\\' if p + 1 < pattern.len() => {
+            return if pattern[p + 1] == c {
+                Some(p + 2)
+            } else {
+                None
+            }
+        }
+        literal => c == literal,
+    };
+    if matched {
+        Some(p + 1)
+    } else {
+        None
+    }
+}
+
+/// Matches `c` against the class of `pattern` starting at `p`, right after its `[`. Returns
+/// whether it matches and the position after the closing `]`, or `None` if the class is not
+/// closed.
+fn match_class(pattern: &[char], mut p: usize, c: char) -> Option<(bool, usize)> {
+    let negated = matches!(pattern.get(p), Some('^') | Some('!'));
+    if negated {
+        p += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut low = *pattern.get(p)?;
+        // A `]` first in the class is a member of it.
+        if low == ']' && !first {
+            return Some((matched != negated, p + 1));
+        }
+        first = false;
+        if low == '\\' {
+            p += 1;
+            low = *pattern.get(p)?;
+        }
+        match (pattern.get(p + 1), pattern.get(p + 2)) {
+            (Some('-'), Some(&high)) if high != ']' => {
+                p += 2;
+                let high = if high == '\\' {
+                    p += 1;
+                    *pattern.get(p)?
+                } else {
+                    high
+                };
+                matched |= low <= c && c <= high;
+            }
+            _ => matched |= low == c,
+        }
+        p += 1;
+    }
+}
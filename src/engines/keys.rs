@@ -0,0 +1,117 @@
+use std::str::FromStr;
+
+use crate::{KvsError, Result};
+
+/// Restricts the keys accepted by a store, on top of the 256B limit of every key. Set with
+/// [`KvStoreBuilder::key_policy`](struct.KvStoreBuilder.html#method.key_policy), and enforced by
+/// `kvs-server` for every engine.
+///
+/// ```
+/// use kvs::{KeyCharset, KeyPolicy};
+///
+/// let policy = KeyPolicy::new()
+///     .charset(KeyCharset::UrlSafe)
+///     .max_len(64)
+///     .reserve_prefix("_internal");
+/// assert!(policy.validate("user-42").is_ok());
+/// assert!(policy.validate("user 42").is_err());
+/// assert!(policy.validate("_internal.config").is_err());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct KeyPolicy {
+    charset: KeyCharset,
+    max_len: Option<usize>,
+    reserved_prefixes: Vec<String>,
+}
+
+/// The characters allowed in keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyCharset {
+    /// Any character.
+    #[default]
+    Any,
+    /// The printable ASCII characters, space included.
+    Printable,
+    /// The characters left unescaped in URLs: ASCII letters and digits, `-`, `.`, `_` and `~`.
+    UrlSafe,
+}
+
+impl KeyCharset {
+    fn allows(self, c: char) -> bool {
+        match self {
+            KeyCharset::Any => true,
+            KeyCharset::Printable => (' '..='~').contains(&c),
+            KeyCharset::UrlSafe => c.is_ascii_alphanumeric() || "-._~".contains(c),
+        }
+    }
+}
+
+impl FromStr for KeyCharset {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "any" => Ok(KeyCharset::Any),
+            "printable" => Ok(KeyCharset::Printable),
+            "url-safe" => Ok(KeyCharset::UrlSafe),
+            _ => Err(format!("Unknown key charset: {}", s)),
+        }
+    }
+}
+
+impl KeyPolicy {
+    /// Creates a policy accepting every key.
+    pub fn new() -> Self {
+        KeyPolicy::default()
+    }
+
+    /// Only accepts the keys made of the characters of `charset`.
+    pub fn charset(mut self, charset: KeyCharset) -> Self {
+        self.charset = charset;
+        self
+    }
+
+    /// Only accepts the keys of at most `max_len` bytes.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Rejects the keys starting with `prefix`, e.g. to keep them for internal use.
+    pub fn reserve_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.reserved_prefixes.push(prefix.into());
+        self
+    }
+
+    /// Checks `key` against the policy.
+    ///
+    /// # Errors
+    /// Returns `KvsError::InvalidKey` telling why the key is rejected.
+    pub fn validate(&self, key: &str) -> Result<()> {
+        if let Some(max_len) = self.max_len {
+            if key.len() > max_len {
+                return Err(KvsError::InvalidKey(format!(
+                    "longer than {} bytes",
+                    max_len
+                )));
+            }
+        }
+        if let Some(c) = key.chars().find(|&c| !self.charset.allows(c)) {
+            return Err(KvsError::InvalidKey(format!(
+                "character {:?} is not {:?}",
+                c, self.charset
+            )));
+        }
+        if let Some(prefix) = self
+            .reserved_prefixes
+            .iter()
+            .find(|prefix| key.starts_with(prefix.as_str()))
+        {
+            return Err(KvsError::InvalidKey(format!(
+                "prefix {:?} is reserved",
+                prefix
+            )));
+        }
+        Ok(())
+    }
+}
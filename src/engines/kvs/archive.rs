@@ -0,0 +1,73 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{Result, ResultExt};
+
+/// The directory, next to the log, holding the archived logs.
+const ARCHIVE_DIR: &str = "archive";
+
+/// Links the log at `log_path` into the archive directory under a name carrying the current
+/// time, or copies it where hard links are not supported, then removes the oldest archived logs
+/// so that at most `retention` are kept. The log itself stays in place, until the compacted log
+/// is renamed over it.
+pub(super) fn archive_log(log_path: &Path, retention: usize) -> Result<PathBuf> {
+    let archive_dir = archive_dir(log_path);
+    fs::create_dir_all(&archive_dir)
+        .with_context(|| format!("creating archive directory {}", archive_dir.display()))?;
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0);
+    // Zero-padded so that the archived logs sort by name in the order they were archived.
+    let archived = archive_dir.join(format!("log.{:020}", nanos));
+    fs::hard_link(log_path, &archived)
+        .or_else(|_| fs::copy(log_path, &archived).map(|_| ()))
+        .with_context(|| {
+            format!(
+                "archiving log {} to {}",
+                log_path.display(),
+                archived.display()
+            )
+        })?;
+
+    let mut archived_logs = archived_logs(log_path)?;
+    let excess = archived_logs.len().saturating_sub(retention);
+    for old in archived_logs.drain(..excess) {
+        fs::remove_file(&old)
+            .with_context(|| format!("removing archived log {}", old.display()))?;
+    }
+    Ok(archived)
+}
+
+/// Returns the logs archived next to `log_path`, oldest first.
+pub(super) fn archived_logs(log_path: &Path) -> Result<Vec<PathBuf>> {
+    let archive_dir = archive_dir(log_path);
+    if !archive_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut logs = Vec::new();
+    for entry in fs::read_dir(&archive_dir)
+        .with_context(|| format!("listing archive directory {}", archive_dir.display()))?
+    {
+        let path = entry?.path();
+        let is_log = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("log."));
+        if is_log {
+            logs.push(path);
+        }
+    }
+    logs.sort();
+    Ok(logs)
+}
+
+fn archive_dir(log_path: &Path) -> PathBuf {
+    log_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(ARCHIVE_DIR)
+}
@@ -0,0 +1,117 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+
+use crate::error::{KvsError, Result, ResultExt};
+
+/// The number of bytes before the offset of an incremental backup compared with the backup,
+/// to make sure the log was not compacted since.
+const OVERLAP: u64 = 4096;
+
+/// Where the backups of a store are kept, given to
+/// [`KvStore::backup_to`](struct.KvStore.html#method.backup_to). A backup is made of the files
+/// of the store, `log` and the value log if any, which only ever grow: every incremental backup
+/// appends to them what was appended to the store since the previous one.
+pub trait BackupTarget {
+    /// Returns how many bytes of the file `name` the backup holds, 0 if none.
+    fn size(&mut self, name: &str) -> Result<u64>;
+
+    /// Returns the `len` bytes at `pos` of the backup of the file `name`.
+    fn read(&mut self, name: &str, pos: u64, len: u64) -> Result<Vec<u8>>;
+
+    /// Appends the `len` bytes read from `data` to the backup of the file `name`, which holds
+    /// `pos` bytes so far, and makes them durable.
+    fn append(&mut self, name: &str, pos: u64, data: &mut dyn Read, len: u64) -> Result<()>;
+}
+
+/// A backup in a local directory, which holds a copy of the files of the store and can be
+/// opened as a store.
+#[derive(Clone, Debug)]
+pub struct DirTarget {
+    dir: PathBuf,
+}
+
+impl DirTarget {
+    /// Keeps the backup in `dir`, created if needed.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> DirTarget {
+        DirTarget { dir: dir.into() }
+    }
+}
+
+impl BackupTarget for DirTarget {
+    fn size(&mut self, name: &str) -> Result<u64> {
+        let path = self.dir.join(name);
+        if path.exists() {
+            Ok(fs::metadata(&path)?.len())
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn read(&mut self, name: &str, pos: u64, len: u64) -> Result<Vec<u8>> {
+        let path = self.dir.join(name);
+        let mut backup =
+            File::open(&path).with_context(|| format!("opening backup log {}", path.display()))?;
+        read_range(&mut backup, pos, len)
+    }
+
+    fn append(&mut self, name: &str, pos: u64, data: &mut dyn Read, _len: u64) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("creating backup directory {}", self.dir.display()))?;
+        let backup_path = self.dir.join(name);
+        let mut backup = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&backup_path)
+            .with_context(|| format!("opening backup log {}", backup_path.display()))?;
+        if backup.metadata()?.len() != pos {
+            return Err(KvsError::StaleOffset(pos));
+        }
+        io::copy(data, &mut backup)
+            .with_context(|| format!("writing backup log {}", backup_path.display()))?;
+        backup
+            .sync_all()
+            .with_context(|| format!("syncing backup log {}", backup_path.display()))?;
+        Ok(())
+    }
+}
+
+/// Appends the bytes of `log` from `since` to `end` to its backup, the file `name` of `target`,
+/// which must hold exactly the first `since` bytes of `log`.
+pub(super) fn append_range<L: Read + Seek, T: BackupTarget + ?Sized>(
+    log: &mut L,
+    name: &str,
+    since: u64,
+    end: u64,
+    target: &mut T,
+) -> Result<()> {
+    let backup_len = target.size(name)?;
+    if backup_len != since || end < since {
+        return Err(KvsError::StaleOffset(since));
+    }
+    // A compaction rewrites the log from the start, which is caught by comparing the end of
+    // the backup with the same bytes of the log.
+    let overlap = since.min(OVERLAP);
+    if overlap > 0
+        && read_range(log, since - overlap, overlap)?
+            != target.read(name, since - overlap, overlap)?
+    {
+        return Err(KvsError::StaleOffset(since));
+    }
+
+    log.seek(SeekFrom::Start(since))?;
+    target.append(
+        name,
+        since,
+        &mut Read::by_ref(log).take(end - since),
+        end - since,
+    )
+}
+
+fn read_range<F: Read + Seek>(file: &mut F, pos: u64, len: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len as usize];
+    file.seek(SeekFrom::Start(pos))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
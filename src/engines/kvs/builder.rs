@@ -0,0 +1,296 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::replay::Progress;
+use super::storage::{LogStorage, StorageOpener};
+use super::{KvStore, REDUNDANCY_THRESHOLD};
+use crate::{KeyPolicy, Result};
+
+/// Configuration for opening a [`KvStore`](struct.KvStore.html).
+///
+/// ```
+/// use kvs::{KvStoreBuilder, KvsEngine};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let db = KvStoreBuilder::new().versioned(true).open(&temp_dir).unwrap();
+/// db.set("key1".to_owned(), "value1".to_owned()).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreBuilder {
+    pub(crate) versioned: bool,
+    pub(crate) tombstone_policy: TombstonePolicy,
+    pub(crate) archive_retention: Option<usize>,
+    pub(crate) sync_writes: bool,
+    pub(crate) commit_delay: Duration,
+    pub(crate) separate_values: Option<usize>,
+    pub(crate) replay_threads: Option<usize>,
+    pub(crate) checkpoint_every: Option<u64>,
+    pub(crate) index_budget: Option<usize>,
+    pub(crate) index_shards: Option<usize>,
+    pub(crate) key_policy: KeyPolicy,
+    pub(crate) sweep_interval: Option<Duration>,
+    pub(crate) preallocate: Option<u64>,
+    pub(crate) read_buffer: Option<usize>,
+    pub(crate) write_buffer: Option<usize>,
+    pub(crate) sequential_buffer: Option<usize>,
+    pub(crate) value_cache: Option<usize>,
+    pub(crate) warm_up_recent: Option<usize>,
+    pub(crate) warm_up_keys: Vec<String>,
+    pub(crate) write_throttle: Option<(u64, u64)>,
+    pub(crate) compaction_ratio: Option<f64>,
+    pub(crate) log_storage: StorageOpener,
+}
+
+/// The capacity of the buffers the log is read and written through, unless configured.
+const DEFAULT_BUFFER: usize = 8 << 10;
+
+/// The number of shards of the index, unless configured.
+const DEFAULT_SHARDS: usize = 16;
+
+/// When compaction physically drops the `Rm` records of removed keys. Keeping them lets
+/// consumers of the log, such as replicas, learn about removals they have not seen yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TombstonePolicy {
+    /// Drop them at the next compaction.
+    #[default]
+    Drop,
+    /// Keep them for at least the given time after the removal.
+    Retain(Duration),
+    /// Never drop them.
+    Keep,
+}
+
+impl KvStoreBuilder {
+    /// Creates a builder with the default configuration, the one used by
+    /// [`KvStore::open`](struct.KvStore.html#method.open).
+    pub fn new() -> Self {
+        KvStoreBuilder::default()
+    }
+
+    /// Gives every write a monotonically increasing sequence number and keeps the versions it
+    /// supersedes reachable through [`KvStore::get_history`](struct.KvStore.html#method.get_history)
+    /// until the next compaction. A versioned store always rebuilds its index from the log
+    /// when opened.
+    pub fn versioned(mut self, versioned: bool) -> Self {
+        self.versioned = versioned;
+        self
+    }
+
+    /// Sets when compaction drops the records of removed keys, `TombstonePolicy::Drop` by
+    /// default. Unless they are dropped right away, the store rebuilds its index from the log
+    /// when opened to find them.
+    pub fn tombstone_policy(mut self, policy: TombstonePolicy) -> Self {
+        self.tombstone_policy = policy;
+        self
+    }
+
+    /// Moves the log replaced by a compaction into the `archive` directory next to it, under a
+    /// name carrying the time of the compaction, instead of removing it. Only the `retention`
+    /// most recent archived logs are kept.
+    pub fn archive(mut self, retention: usize) -> Self {
+        self.archive_retention = Some(retention);
+        self
+    }
+
+    /// Makes every write durable before it returns, by syncing the log to disk. Concurrent
+    /// writes are committed as a group, with a single sync for all of them.
+    pub fn sync_writes(mut self, sync_writes: bool) -> Self {
+        self.sync_writes = sync_writes;
+        self
+    }
+
+    /// Sets how long the first of concurrent durable writes waits for the others before
+    /// syncing the log, zero by default. A longer delay syncs larger groups of writes, at the
+    /// cost of the latency of each of them.
+    pub fn commit_delay(mut self, delay: Duration) -> Self {
+        self.commit_delay = delay;
+        self
+    }
+
+    /// Stores the values of at least `min_size` bytes in a value log next to the log, which
+    /// only keeps pointers to them. Compaction then copies small records instead of the values,
+    /// and only rewrites the value log once its garbage makes up most of it. A store can be
+    /// reopened without this option, the values already separated are still found.
+    pub fn separate_values(mut self, min_size: usize) -> Self {
+        self.separate_values = Some(min_size);
+        self
+    }
+
+    /// Sets how many threads replay the log when the store is opened, one per CPU by default.
+    /// Each of them decodes a part of at least 4MB of the log, so that only large logs are
+    /// replayed in parallel.
+    pub fn replay_threads(mut self, threads: usize) -> Self {
+        self.replay_threads = Some(threads.max(1));
+        self
+    }
+
+    /// Writes a [`checkpoint`](struct.KvStore.html#method.checkpoint) of the index every
+    /// `records` writes, so that a crash never costs the replay of more than about that many
+    /// records when the store is reopened.
+    pub fn checkpoint_every(mut self, records: u64) -> Self {
+        self.checkpoint_every = Some(records.max(1));
+        self
+    }
+
+    /// Bounds the memory taken by the index to about `bytes`, shared evenly by its
+    /// [shards](#method.index_shards). Past its share of the budget, the entries of a shard are
+    /// spilled to its `index.<shard>.spill` file, sorted by key, and only every 64th key of the
+    /// file is kept in memory: looking a spilled key up then costs a read of the file. The index
+    /// is rebuilt into its shards when the store is opened, which spill as they go, and the log
+    /// is then replayed on a single thread.
+    pub fn index_budget(mut self, bytes: usize) -> Self {
+        self.index_budget = Some(bytes);
+        self
+    }
+
+    /// Splits the index in `shards` by the hash of the keys, 16 by default, each locked on its
+    /// own, so that a write only holds back the reads of the keys of its shard.
+    pub fn index_shards(mut self, shards: usize) -> Self {
+        self.index_shards = Some(shards.max(1));
+        self
+    }
+
+    /// Rejects the writes of the keys `policy` does not accept, with `KvsError::InvalidKey`.
+    /// Every key is accepted by default.
+    pub fn key_policy(mut self, policy: KeyPolicy) -> Self {
+        self.key_policy = policy;
+        self
+    }
+
+    /// Sets how often a background thread looks for the keys whose
+    /// [time to live](struct.KvStore.html#method.set_with_ttl) is over and appends their `Rm`
+    /// records, so that compaction reclaims them. Every second by default, and never if zero.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = Some(interval);
+        self
+    }
+
+    /// Throttles the writes while compaction falls behind, rather than letting the log grow
+    /// without bound. Once the redundant bytes of the log reach `slowdown`, every write is
+    /// delayed, by up to 100ms as they approach `stop`. From `stop` on, the writes are rejected
+    /// with `KvsError::WriteStall` until compaction reclaims enough. Both are raised to the 1MB
+    /// from which compaction starts if lower. Writes are never throttled by default.
+    pub fn throttle_writes(mut self, slowdown: u64, stop: u64) -> Self {
+        let slowdown = slowdown.max(REDUNDANCY_THRESHOLD);
+        self.write_throttle = Some((slowdown, stop.max(slowdown)));
+        self
+    }
+
+    /// Also compacts the log once its redundant bytes make up `ratio` of it, e.g. 0.5 for half,
+    /// rather than only from the 1MB of redundant bytes on, which suits the small stores and
+    /// the large ones alike. Logs with less than 64KB of redundant bytes are left as they are,
+    /// so that a tiny log is not compacted on every write.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = Some(ratio);
+        self
+    }
+
+    /// Reserves the disk space of the log `bytes` at a time ahead of the writes, so that the
+    /// file system does not allocate blocks on every append and keeps the log in one piece.
+    /// The size of the log is left as it is. Only supported on Linux, by the file systems
+    /// implementing `fallocate`: elsewhere the log grows as usual.
+    pub fn preallocate(mut self, bytes: u64) -> Self {
+        self.preallocate = Some(bytes);
+        self
+    }
+
+    /// Sets the capacity of the buffer the [tails](struct.KvStore.html#method.tail) read the log
+    /// through, 8KB by default. The reads of a key fetch just the bytes of its record.
+    pub fn read_buffer(mut self, bytes: usize) -> Self {
+        self.read_buffer = Some(bytes);
+        self
+    }
+
+    /// Sets the capacity of the buffer the writes go through before reaching the log, 8KB by
+    /// default. A write filling the buffer up flushes it, and the reads of the records still in
+    /// it are served from it.
+    pub fn write_buffer(mut self, bytes: usize) -> Self {
+        self.write_buffer = Some(bytes);
+        self
+    }
+
+    /// Sets the capacity of the buffers the log is read and written through when it is
+    /// replayed or compacted, 8KB by default. Both go through most of the log in order, and
+    /// are faster with buffers of a few hundred KB.
+    pub fn sequential_buffer(mut self, bytes: usize) -> Self {
+        self.sequential_buffer = Some(bytes);
+        self
+    }
+
+    /// Keeps the values of the keys read most recently in memory, up to about `bytes`, so that
+    /// reading them again does not go to disk. The cache is split like the
+    /// [index](#method.index_shards), in shards of at least 64 KiB, each evicting its least
+    /// recently read values first. Nothing is cached by default.
+    pub fn value_cache(mut self, bytes: usize) -> Self {
+        self.value_cache = Some(bytes);
+        self
+    }
+
+    /// Reads the values of the `records` keys written most recently into the
+    /// [value cache](#method.value_cache) when the store is opened, so that they are served from
+    /// memory right away.
+    pub fn warm_up_recent(mut self, records: usize) -> Self {
+        self.warm_up_recent = Some(records);
+        self
+    }
+
+    /// Reads the values of `keys` into the [value cache](#method.value_cache) when the store is
+    /// opened, after the ones of [`warm_up_recent`](#method.warm_up_recent). The keys that do
+    /// not exist are skipped.
+    pub fn warm_up_keys<I>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.warm_up_keys.extend(keys);
+        self
+    }
+
+    /// Keeps the logs in the storage `open` returns for the path of each of them, instead of
+    /// in a [`FileStorage`](struct.FileStorage.html). Every append, read and sync of the log
+    /// goes through it, but the store still renames the compacted log over the old one and
+    /// archives it as files, so the storage must keep the bytes of a log in the file at its
+    /// path, as a backend doing direct or asynchronous I/O on the file does.
+    pub fn log_storage<F>(mut self, open: F) -> Self
+    where
+        F: Fn(&Path) -> io::Result<Box<dyn LogStorage>> + Send + Sync + 'static,
+    {
+        self.log_storage = StorageOpener::new(open);
+        self
+    }
+
+    pub(super) fn shard_count(&self) -> usize {
+        self.index_shards.unwrap_or(DEFAULT_SHARDS)
+    }
+
+    pub(super) fn read_capacity(&self) -> usize {
+        self.read_buffer.unwrap_or(DEFAULT_BUFFER)
+    }
+
+    pub(super) fn write_capacity(&self) -> usize {
+        self.write_buffer.unwrap_or(DEFAULT_BUFFER)
+    }
+
+    pub(super) fn sequential_capacity(&self) -> usize {
+        self.sequential_buffer.unwrap_or(DEFAULT_BUFFER)
+    }
+
+    /// Opens the KvStore in the directory `path` with this configuration.
+    pub fn open<P: AsRef<Path>>(self, path: P) -> Result<KvStore> {
+        KvStore::open_with(path.as_ref(), self, None)
+    }
+
+    /// Opens the KvStore in the directory `path` with this configuration, reporting the progress
+    /// of the replay of its log like
+    /// [`KvStore::open_with_progress`](struct.KvStore.html#method.open_with_progress).
+    pub fn open_with_progress<P, F>(self, path: P, progress: F) -> Result<KvStore>
+    where
+        P: AsRef<Path>,
+        F: Fn(u64, u64) + Send + Sync + 'static,
+    {
+        let progress = Arc::new(Progress::new(progress));
+        KvStore::open_with(path.as_ref(), self, Some(progress))
+    }
+}
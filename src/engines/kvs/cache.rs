@@ -0,0 +1,149 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+
+use super::lock;
+
+/// What an entry of the cache costs on top of its key and value, as counted against its
+/// capacity.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// The least capacity of a shard of the cache, so that a small cache is not split into shards
+/// too small to hold a value.
+const MIN_SHARD_CAPACITY: usize = 64 << 10;
+
+/// The values of the keys read most recently, up to a capacity in bytes. The cache is split in
+/// shards by the hash of the keys, like the index, each locked on its own and evicting its own
+/// least recently read values first, so that the reads of different keys seldom wait for each
+/// other. A cache of no capacity has no shards and takes no lock.
+pub(super) struct ValueCache {
+    shards: Vec<Mutex<Lru>>,
+    hasher: RandomState,
+}
+
+impl ValueCache {
+    /// Creates a cache holding up to about `capacity` bytes, split in up to `shards` shards,
+    /// which caches nothing if zero.
+    pub(super) fn new(capacity: usize, shards: usize) -> ValueCache {
+        let count = match capacity {
+            0 => 0,
+            _ => shards.min(capacity / MIN_SHARD_CAPACITY).max(1),
+        };
+        ValueCache {
+            shards: (0..count)
+                .map(|_| Mutex::new(Lru::new(capacity / count)))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> Option<&Mutex<Lru>> {
+        match self.shards.len() {
+            0 => None,
+            count => Some(&self.shards[self.hasher.hash_one(key) as usize % count]),
+        }
+    }
+
+    /// Returns the value of `key` if it is cached, which makes it the most recently used.
+    pub(super) fn get(&self, key: &str) -> Option<String> {
+        lock(self.shard(key)?).get(key)
+    }
+
+    /// Caches `value` as the value of `key`, evicting the least recently used values of its
+    /// shard to make room for it. A value larger than a shard is not cached.
+    pub(super) fn insert(&self, key: &str, value: &str) {
+        if let Some(shard) = self.shard(key) {
+            lock(shard).insert(key, value);
+        }
+    }
+
+    /// Drops the value of `key`, e.g. because it was overwritten.
+    pub(super) fn remove(&self, key: &str) {
+        if let Some(shard) = self.shard(key) {
+            lock(shard).remove(key);
+        }
+    }
+}
+
+/// A shard of the cache, which evicts the least recently read values first.
+struct Lru {
+    capacity: usize,
+    bytes: usize,
+    entries: HashMap<String, Entry>,
+    /// The keys by the tick of their last use, least recently used first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+struct Entry {
+    value: String,
+    used: u64,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Lru {
+        Lru {
+            capacity,
+            bytes: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<String> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        let key = self.recency.remove(&entry.used)?;
+        entry.used = tick;
+        self.recency.insert(tick, key);
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        let cost = cost(key, value);
+        if cost > self.capacity {
+            return;
+        }
+        while self.bytes + cost > self.capacity {
+            match self.recency.pop_first() {
+                Some((_, oldest)) => self.drop_entry(&oldest),
+                None => break,
+            }
+        }
+        let tick = self.next_tick();
+        self.recency.insert(tick, key.to_owned());
+        self.entries.insert(
+            key.to_owned(),
+            Entry {
+                value: value.to_owned(),
+                used: tick,
+            },
+        );
+        self.bytes += cost;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.get(key) {
+            self.recency.remove(&entry.used);
+            self.drop_entry(key);
+        }
+    }
+
+    fn drop_entry(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= cost(key, &entry.value);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+fn cost(key: &str, value: &str) -> usize {
+    key.len() + value.len() + ENTRY_OVERHEAD
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::path::Path;
+
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::replay::ReplayIndex;
+use super::CommandPos;
+use crate::error::{KvsError, Result, ResultExt};
+
+/// The version of the index files written, raised whenever the entries of the index gain what
+/// the older ones lack.
+const INDEX_FORMAT: u32 = 2;
+
+/// The index as of a checkpoint, which covers the log up to `offset`, with the expiries of the
+/// keys set with a time to live. The fields are written in this order, so that the format of the
+/// file is known before its entries are loaded.
+#[derive(Serialize)]
+struct IndexSnapshot<'a, I> {
+    format: u32,
+    offset: u64,
+    index: &'a I,
+    expiries: &'a HashMap<String, u64>,
+}
+
+/// What a checkpoint loaded into the index restores besides it.
+pub(super) struct Checkpoint {
+    /// The end of the log the checkpoint covers, from which the log is replayed.
+    pub(super) offset: u64,
+    /// When the keys set with a time to live expire.
+    pub(super) expiries: HashMap<String, u64>,
+}
+
+/// Loads the entries of the index file at `path` into `index` one by one, so that it is never
+/// held in memory as a whole. Returns `None`, leaving `index` as it is, if the file was written
+/// before the current format, or if it holds the bare index saved on shutdown before the
+/// checkpoints: the log is replayed in full instead.
+pub(super) fn load_index<I: ReplayIndex>(path: &Path, index: &mut I) -> Result<Option<Checkpoint>> {
+    let context = || format!("loading index file {}", path.display());
+    let index_handle = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .with_context(|| format!("opening index file {}", path.display()))?;
+    let mut de = serde_json::Deserializer::from_reader(BufReader::new(index_handle));
+    let mut failed = None;
+    let loaded = CheckpointSeed {
+        index,
+        failed: &mut failed,
+    }
+    .deserialize(&mut de)
+    .and_then(|loaded| de.end().map(|()| loaded));
+    let checkpoint = match (loaded, failed) {
+        (_, Some(e)) => return Err(e.with_context(context())),
+        (loaded, None) => loaded.with_context(context)?,
+    };
+    match checkpoint {
+        Loaded::Current(checkpoint) => Ok(Some(checkpoint)),
+        Loaded::Outdated(format) => {
+            info!(
+                format,
+                "Replaying the log in full, the index file is outdated."
+            );
+            Ok(None)
+        }
+        Loaded::Legacy => {
+            info!("Replaying the log in full, the index file predates checkpoints.");
+            Ok(None)
+        }
+    }
+}
+
+/// Writes the snapshot of `index` and `expiries` covering the log up to `offset` to a temporary
+/// file, forces it to disk, then renames it over the index file at `path`, so that a crash never
+/// leaves a partially written index behind.
+pub(super) fn write_index<I: Serialize>(
+    path: &Path,
+    index: &I,
+    expiries: &HashMap<String, u64>,
+    offset: u64,
+) -> Result<()> {
+    let context = || format!("writing index file {}", path.display());
+    let tmp_path = path.with_extension("tmp");
+    let mut index_writer = BufWriter::new(File::create(&tmp_path).with_context(context)?);
+    let snapshot = IndexSnapshot {
+        format: INDEX_FORMAT,
+        offset,
+        index,
+        expiries,
+    };
+    serde_json::to_writer(&mut index_writer, &snapshot).with_context(context)?;
+    let index_handle = index_writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .with_context(context)?;
+    index_handle.sync_all().with_context(context)?;
+    fs::rename(&tmp_path, path).with_context(context)?;
+    Ok(())
+}
+
+/// What was found in an index file.
+enum Loaded {
+    Current(Checkpoint),
+    /// A checkpoint of an older format, 0 for the ones written before it was recorded.
+    Outdated(u32),
+    /// A bare index.
+    Legacy,
+}
+
+/// A number written in a checkpoint, or whatever a bare index has under the same key.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Number {
+    Number(u64),
+    Other(IgnoredAny),
+}
+
+/// Loads an index file, inserting the entries of a current checkpoint into `index`. The error
+/// of an insertion is kept in `failed`, the deserializer only carrying its message.
+struct CheckpointSeed<'a, I> {
+    index: &'a mut I,
+    failed: &'a mut Option<KvsError>,
+}
+
+impl<'de, I: ReplayIndex> DeserializeSeed<'de> for CheckpointSeed<'_, I> {
+    type Value = Loaded;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Loaded, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, I: ReplayIndex> Visitor<'de> for CheckpointSeed<'_, I> {
+    type Value = Loaded;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "an index file")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Loaded, A::Error> {
+        let mut format = 0;
+        let mut offset = None;
+        let mut expiries = HashMap::new();
+        let mut legacy = false;
+        while let Some(key) = map.next_key::<String>()? {
+            let current = !legacy && format == INDEX_FORMAT;
+            match key.as_str() {
+                "format" | "offset" if !legacy => match map.next_value()? {
+                    Number::Number(number) if key == "format" => format = number as u32,
+                    Number::Number(number) => offset = Some(number),
+                    Number::Other(_) => legacy = true,
+                },
+                "index" if current => map.next_value_seed(EntriesSeed {
+                    index: &mut *self.index,
+                    failed: &mut *self.failed,
+                })?,
+                "expiries" if current => expiries = map.next_value()?,
+                "index" | "expiries" if !legacy => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+                _ => {
+                    legacy = true;
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        match offset {
+            _ if legacy => Ok(Loaded::Legacy),
+            Some(offset) if format == INDEX_FORMAT => {
+                Ok(Loaded::Current(Checkpoint { offset, expiries }))
+            }
+            Some(_) => Ok(Loaded::Outdated(format)),
+            None => Ok(Loaded::Legacy),
+        }
+    }
+}
+
+/// Inserts the entries of the index of a checkpoint into `index` as they are read.
+struct EntriesSeed<'a, I> {
+    index: &'a mut I,
+    failed: &'a mut Option<KvsError>,
+}
+
+impl<'de, I: ReplayIndex> DeserializeSeed<'de> for EntriesSeed<'_, I> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, I: ReplayIndex> Visitor<'de> for EntriesSeed<'_, I> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the entries of an index")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<(), A::Error> {
+        while let Some((key, cmd_pos)) = map.next_entry::<String, CommandPos>()? {
+            if let Err(e) = self.index.insert(key, cmd_pos) {
+                let message = e.to_string();
+                *self.failed = Some(e);
+                return Err(de::Error::custom(message));
+            }
+        }
+        Ok(())
+    }
+}
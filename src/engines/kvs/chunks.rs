@@ -0,0 +1,146 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::values::ValuePtr;
+use super::{Command, LogEntry, ValueType};
+use crate::error::{KvsError, Result};
+
+/// The largest part of a value held by a record of the log. Larger values are split across
+/// several records.
+pub(super) const CHUNK_SIZE: usize = 1 << 12;
+
+/// The records of a value split in chunks: the chunks in order, immediately followed by the
+/// record setting the key, all appended by the writer thread at once. The index points to the
+/// first chunk, with the length of all the records.
+#[derive(Deserialize, Serialize)]
+pub(super) enum ChunkCommand {
+    /// The part number `part` of the value.
+    Chunk { part: u32, data: String },
+    /// Sets `key` to the concatenation of the chunks before it, whose CRC32 is `crc`, until
+    /// `expires_at` if given.
+    SetChunked {
+        key: String,
+        crc: u32,
+        #[serde(default, skip_serializing_if = "ValueType::is_string")]
+        value_type: ValueType,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        written_at: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        created_at: Option<u64>,
+    },
+}
+
+/// Splits `value` in parts of at most `CHUNK_SIZE` bytes, on character boundaries.
+pub(super) fn split(value: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = value;
+    while rest.len() > CHUNK_SIZE {
+        let mut end = CHUNK_SIZE;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (part, tail) = rest.split_at(end);
+        parts.push(part);
+        rest = tail;
+    }
+    parts.push(rest);
+    parts
+}
+
+/// Reassembles the commands from the entries of the log, fed in order.
+#[derive(Default)]
+pub(super) struct Assembler {
+    /// The offset of the first chunk of the value being reassembled.
+    start: Option<u64>,
+    parts: u32,
+    value: String,
+}
+
+impl Assembler {
+    /// Feeds the entry at `offset` in the log at `path`. Returns the command it completes with
+    /// the offset of its first record, along with the pointer to its value if it is stored in
+    /// the value log.
+    ///
+    /// Chunks left without the record setting their key, by a crash in the middle of a write,
+    /// are dropped once the next record is fed.
+    pub(super) fn push(
+        &mut self,
+        entry: LogEntry,
+        path: &Path,
+        offset: u64,
+    ) -> Result<Option<(u64, Command, Option<ValuePtr>)>> {
+        let record = match entry {
+            LogEntry::Chunk(record) => record,
+            entry => {
+                self.reset();
+                let (cmd, ptr) = entry.into_command(path, offset)?;
+                return Ok(Some((offset, cmd, ptr)));
+            }
+        };
+
+        match record.verify(path, offset)? {
+            ChunkCommand::Chunk { part, data } => {
+                if part == 0 {
+                    self.reset();
+                    self.start = Some(offset);
+                }
+                if self.start.is_some() && part == self.parts {
+                    self.parts += 1;
+                    self.value.push_str(&data);
+                } else {
+                    self.reset();
+                }
+                Ok(None)
+            }
+            ChunkCommand::SetChunked {
+                key,
+                crc,
+                value_type,
+                seq,
+                expires_at,
+                written_at,
+                created_at,
+            } => {
+                let start = self.start.take().unwrap_or(offset);
+                let value = std::mem::take(&mut self.value);
+                self.parts = 0;
+                let actual = crc32fast::hash(value.as_bytes());
+                if actual != crc {
+                    return Err(KvsError::Corruption {
+                        path: path.to_path_buf(),
+                        offset,
+                        expected: crc,
+                        actual,
+                    });
+                }
+                let cmd = Command::Set {
+                    key,
+                    value,
+                    value_type,
+                    seq,
+                    expires_at,
+                    written_at,
+                    created_at,
+                };
+                Ok(Some((start, cmd, None)))
+            }
+        }
+    }
+
+    /// Drops the chunks fed so far, e.g. because one of them is corrupted.
+    pub(super) fn reset(&mut self) {
+        self.start = None;
+        self.parts = 0;
+        self.value.clear();
+    }
+
+    /// The offset of the first chunk of a value still waiting for the rest of its records.
+    pub(super) fn pending(&self) -> Option<u64> {
+        self.start
+    }
+}
@@ -0,0 +1,37 @@
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use tracing::error;
+
+use super::KvStore;
+use crate::error::{Result, ResultExt};
+
+/// The largest number of expired keys collected at once by a sweep, which looks for more once
+/// they are removed.
+pub(super) const SWEEP_BATCH: usize = 64;
+
+/// A thread removing the expired keys of a store in the background, stopped once dropped.
+pub(super) struct Sweeper {
+    _stop: Sender<()>,
+}
+
+impl Sweeper {
+    /// Starts sweeping the expired keys of `store` every `interval`.
+    pub(super) fn start(store: KvStore, interval: Duration) -> Result<Sweeper> {
+        let (stop, stopped) = bounded::<()>(0);
+        thread::Builder::new()
+            .name("kvs-sweeper".to_owned())
+            .spawn(move || loop {
+                // Nothing is ever sent: the channel is only disconnected by the drop.
+                if let Err(RecvTimeoutError::Disconnected) = stopped.recv_timeout(interval) {
+                    return;
+                }
+                if let Err(e) = store.sweep_expired() {
+                    error!(error = %e, "Failed to sweep the expired keys.");
+                }
+            })
+            .context("starting the sweeper thread")?;
+        Ok(Sweeper { _stop: stop })
+    }
+}
@@ -0,0 +1,223 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::lock;
+use super::storage::LogStorage;
+
+/// Faults injected into the logs of a store, so that its recovery from failed or torn appends,
+/// failed syncs and crashes can be tested deterministically. Only built with the
+/// `fault-injection` feature.
+///
+/// The logs are kept in files, as by a [`FileStorage`](struct.FileStorage.html), once the plan
+/// is handed to [`KvStoreBuilder::log_storage`](struct.KvStoreBuilder.html#method.log_storage)
+/// through [`opener`](#method.opener). Every fault is injected once, into the log whose file has
+/// the name given: "log" for the log of the store, and "log.tmp" for the log written by a
+/// compaction. The index checkpoints and the value logs are not logs, and are not affected.
+///
+/// ```
+/// use kvs::{FaultPlan, KvStoreBuilder, KvsEngine};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let faults = FaultPlan::new();
+/// let store = KvStoreBuilder::new()
+///     .log_storage(faults.opener())
+///     .open(&temp_dir)?;
+/// store.set("key1".to_owned(), "value1".to_owned())?;
+/// store.checkpoint()?;
+/// store.set("key2".to_owned(), "value2".to_owned())?;
+/// faults.crash()?;
+/// drop(store);
+///
+/// let store = KvStoreBuilder::new().open(&temp_dir)?;
+/// assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+/// assert_eq!(store.get("key2".to_owned())?, None);
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FaultPlan {
+    state: Arc<Mutex<PlanState>>,
+}
+
+#[derive(Debug, Default)]
+struct PlanState {
+    /// The faults left to inject, with the name of the log they are injected into.
+    faults: Vec<(String, Fault)>,
+    /// The logs opened through the plan, which a crash reverts to what was synced.
+    logs: Vec<Arc<FaultyLog>>,
+}
+
+#[derive(Debug)]
+enum Fault {
+    /// The append which would write the byte at this offset fails, writing nothing.
+    FailAppend(u64),
+    /// The append which would write the byte at this offset only writes the bytes before it,
+    /// then fails.
+    TornAppend(u64),
+    /// The next sync fails, making nothing durable.
+    FailSync,
+}
+
+impl FaultPlan {
+    /// Creates a plan injecting no fault yet.
+    pub fn new() -> FaultPlan {
+        FaultPlan::default()
+    }
+
+    /// Fails the append to the log named `log` which would write its byte at `offset`, without
+    /// writing any of it.
+    pub fn fail_append_at(&self, log: &str, offset: u64) -> &Self {
+        self.inject(log, Fault::FailAppend(offset))
+    }
+
+    /// Tears the append to the log named `log` which would write its byte at `offset`: only the
+    /// bytes before `offset` are written, then the append fails, as when the disk fills up.
+    pub fn tear_append_at(&self, log: &str, offset: u64) -> &Self {
+        self.inject(log, Fault::TornAppend(offset))
+    }
+
+    /// Fails the next sync of the log named `log`, which leaves what was appended since the
+    /// previous sync to be lost by a crash.
+    pub fn fail_next_sync(&self, log: &str) -> &Self {
+        self.inject(log, Fault::FailSync)
+    }
+
+    /// Simulates a crash of the machine: every log opened so far loses the bytes appended since
+    /// it was last synced, and fails every operation from then on. The logs opened afterwards,
+    /// by the store reopened after the crash, work again.
+    pub fn crash(&self) -> io::Result<()> {
+        for log in &lock(&self.state).logs {
+            log.crashed.store(true, Ordering::SeqCst);
+            log.file.set_len(*lock(&log.synced))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the function opening the logs through the plan, to hand to
+    /// [`KvStoreBuilder::log_storage`](struct.KvStoreBuilder.html#method.log_storage).
+    pub fn opener(
+        &self,
+    ) -> impl Fn(&Path) -> io::Result<Box<dyn LogStorage>> + Send + Sync + 'static {
+        let plan = self.clone();
+        move |path| {
+            let file = OpenOptions::new()
+                .append(true)
+                .read(true)
+                .create(true)
+                .open(path)?;
+            let log = Arc::new(FaultyLog {
+                name: path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                synced: Mutex::new(file.metadata()?.len()),
+                file,
+                crashed: AtomicBool::new(false),
+            });
+            lock(&plan.state).logs.push(Arc::clone(&log));
+            Ok(Box::new(FaultyStorage {
+                log,
+                plan: plan.clone(),
+            }) as Box<dyn LogStorage>)
+        }
+    }
+
+    fn inject(&self, log: &str, fault: Fault) -> &Self {
+        lock(&self.state).faults.push((log.to_owned(), fault));
+        self
+    }
+}
+
+/// A log opened through a plan.
+#[derive(Debug)]
+struct FaultyLog {
+    name: String,
+    file: File,
+    /// The length of the log as of its last sync.
+    synced: Mutex<u64>,
+    crashed: AtomicBool,
+}
+
+/// The storage of a log injecting the faults of a plan.
+struct FaultyStorage {
+    log: Arc<FaultyLog>,
+    plan: FaultPlan,
+}
+
+impl FaultyStorage {
+    /// Fails if the machine crashed.
+    fn check_alive(&self) -> io::Result<()> {
+        if self.log.crashed.load(Ordering::SeqCst) {
+            return Err(io::Error::other("simulated crash"));
+        }
+        Ok(())
+    }
+}
+
+impl LogStorage for FaultyStorage {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        self.check_alive()?;
+        // Held while appending, so that the offsets of the faults cannot be raced past.
+        let mut state = lock(&self.plan.state);
+        let start = self.log.file.metadata()?.len();
+        let written = start..start + buf.len() as u64;
+        let fault = state.faults.iter().position(|(name, fault)| {
+            *name == self.log.name
+                && match fault {
+                    Fault::FailAppend(offset) | Fault::TornAppend(offset) => {
+                        written.contains(offset)
+                    }
+                    Fault::FailSync => false,
+                }
+        });
+        match fault.map(|i| state.faults.remove(i).1) {
+            Some(Fault::TornAppend(offset)) => {
+                (&self.log.file).write_all(&buf[..(offset - start) as usize])?;
+                Err(io::Error::other("injected torn append"))
+            }
+            Some(_) => Err(io::Error::other("injected append failure")),
+            None => (&self.log.file).write_all(buf),
+        }
+    }
+
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_alive()?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileExt;
+
+            self.log.file.read_at(buf, pos)
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::FileExt;
+
+            self.log.file.seek_read(buf, pos)
+        }
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.check_alive()?;
+        let mut state = lock(&self.plan.state);
+        let fault = state
+            .faults
+            .iter()
+            .position(|(name, fault)| *name == self.log.name && matches!(fault, Fault::FailSync));
+        if let Some(i) = fault {
+            state.faults.remove(i);
+            return Err(io::Error::other("injected sync failure"));
+        }
+        let len = self.log.file.metadata()?.len();
+        self.log.file.sync_data()?;
+        *lock(&self.log.synced) = len;
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.check_alive()?;
+        Ok(self.log.file.metadata()?.len())
+    }
+}
@@ -0,0 +1,810 @@
+use std::collections::btree_map::{self, BTreeMap};
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File};
+use std::hash::BuildHasher;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::iter::Peekable;
+use std::mem;
+use std::ops::{Bound, Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use serde::ser::{Error, SerializeMap};
+use serde::{Serialize, Serializer};
+use tracing::{debug, warn};
+
+use super::replay::ReplayIndex;
+use super::{lock, read_lock, write_lock, CommandPos, ValueType};
+use crate::error::{KvsError, Result, ResultExt};
+
+/// How many entries of the spill file follow every entry of the sparse index.
+const SPARSE_INTERVAL: usize = 64;
+
+/// The creation time of the spilled entries which have none.
+const NO_TIME: u64 = u64::MAX;
+
+/// An estimate of the memory taken by an entry of the index besides the bytes of its key.
+const ENTRY_OVERHEAD: usize = mem::size_of::<String>() + mem::size_of::<Option<CommandPos>>() + 16;
+
+/// Where the live record of every key is in the log.
+///
+/// Without a memory budget, the whole index is a `BTreeMap`, so that its keys can be listed in
+/// order from any of them. With one, the entries are spilled to a file sorted by key once they
+/// take more memory than the budget, and only the key of every 64th entry of the file is kept in
+/// memory to find the others. The entries written since stay in memory, where removing a spilled
+/// key leaves a marker until the next spill.
+pub(super) struct Index {
+    memory: BTreeMap<String, Option<CommandPos>>,
+    /// The estimated memory taken by `memory`.
+    memory_bytes: usize,
+    budget: Option<usize>,
+    spill_path: PathBuf,
+    spill: Option<Spill>,
+    len: usize,
+}
+
+impl Index {
+    /// Creates an empty index, spilled to the file at `spill_path` once its entries exceed
+    /// `budget`. A spill file left there by a previous process is removed.
+    pub(super) fn new(spill_path: PathBuf, budget: Option<usize>) -> Result<Index> {
+        for path in &[
+            spill_path.clone(),
+            spill_path.with_extension("spill.staged"),
+        ] {
+            if path.exists() {
+                fs::remove_file(path)
+                    .with_context(|| format!("removing spilled index {}", path.display()))?;
+            }
+        }
+        Ok(Index {
+            memory: BTreeMap::new(),
+            memory_bytes: 0,
+            budget,
+            spill_path,
+            spill: None,
+            len: 0,
+        })
+    }
+
+    pub(super) fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        match self.memory.get(key) {
+            Some(cmd_pos) => Ok(*cmd_pos),
+            None => self.spilled(key),
+        }
+    }
+
+    /// Points `key` to `cmd_pos` and returns the position it replaces.
+    pub(super) fn insert(
+        &mut self,
+        key: String,
+        cmd_pos: CommandPos,
+    ) -> Result<Option<CommandPos>> {
+        let superseded = self.get(&key)?;
+        if superseded.is_none() {
+            self.len += 1;
+        }
+        let key_bytes = key.len() + ENTRY_OVERHEAD;
+        if self.memory.insert(key, Some(cmd_pos)).is_none() {
+            self.memory_bytes += key_bytes;
+        }
+        self.spill_if_needed()?;
+        Ok(superseded)
+    }
+
+    /// Removes `key` and returns its position.
+    pub(super) fn remove(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        let spilled = self.spilled(key)?;
+        let removed = match self.memory.get(key) {
+            Some(cmd_pos) => *cmd_pos,
+            None => spilled,
+        };
+        if removed.is_some() {
+            self.len -= 1;
+            if spilled.is_some() {
+                if self.memory.insert(key.to_owned(), None).is_none() {
+                    self.memory_bytes += key.len() + ENTRY_OVERHEAD;
+                }
+                self.spill_if_needed()?;
+            } else {
+                self.memory.remove(key);
+                self.memory_bytes -= key.len() + ENTRY_OVERHEAD;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Calls `f` with every key of the index and its position, in no particular order.
+    pub(super) fn for_each<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, CommandPos) -> Result<()>,
+    {
+        for (key, cmd_pos) in &self.memory {
+            if let Some(cmd_pos) = cmd_pos {
+                f(key, *cmd_pos)?;
+            }
+        }
+        if let Some(spill) = &self.spill {
+            for entry in spill.entries()? {
+                let (key, cmd_pos) = entry?;
+                // The entries in memory are more recent.
+                if !self.memory.contains_key(&key) {
+                    f(&key, cmd_pos)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(super) fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::with_capacity(self.len);
+        self.for_each(|key, _| {
+            keys.push(key.to_owned());
+            Ok(())
+        })?;
+        Ok(keys)
+    }
+
+    pub(super) fn positions(&self) -> Result<Vec<CommandPos>> {
+        let mut positions = Vec::with_capacity(self.len);
+        self.for_each(|_, cmd_pos| {
+            positions.push(cmd_pos);
+            Ok(())
+        })?;
+        Ok(positions)
+    }
+
+    /// Returns the keys following `after`, or all of them if `None`, in ascending order. Only the
+    /// keys of the block of the spill file where `after` would be are read past.
+    pub(super) fn keys_after(&self, after: Option<&str>) -> Result<KeysAfter<'_>> {
+        let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let memory = self.memory.range::<str, _>((lower, Bound::Unbounded));
+        let spill = match &self.spill {
+            Some(spill) => {
+                let mut entries = spill.entries_after(after)?.peekable();
+                // The block of `after` starts with the keys up to it.
+                while let Some(Ok((key, _))) = entries.peek() {
+                    if after.is_none_or(|after| key.as_str() > after) {
+                        break;
+                    }
+                    entries.next();
+                }
+                Some(entries)
+            }
+            None => None,
+        };
+        Ok(KeysAfter {
+            memory: memory.peekable(),
+            spill,
+        })
+    }
+
+    /// Computes the position `f` returns for every position of the index, writing the spill
+    /// file they make aside, so that a failure leaves the index as it was. The index must not
+    /// change until the positions are applied by [`apply_positions`](#method.apply_positions).
+    pub(super) fn stage_positions<F>(&self, mut f: F) -> Result<StagedPositions>
+    where
+        F: FnMut(CommandPos) -> Result<CommandPos>,
+    {
+        let mut live_bytes = 0;
+        let mut memory = Vec::with_capacity(self.memory.len());
+        for cmd_pos in self.memory.values() {
+            let cmd_pos = cmd_pos.map(&mut f).transpose()?;
+            live_bytes += cmd_pos.map_or(0, |cmd_pos| cmd_pos.len);
+            memory.push(cmd_pos);
+        }
+        let mut staged = StagedPositions {
+            memory,
+            spill: None,
+            live_bytes,
+        };
+        if let Some(spill) = &self.spill {
+            // Written next to the spill file in use, which stays valid until applied.
+            let path = if spill.path == self.spill_path {
+                self.spill_path.with_extension("spill.staged")
+            } else {
+                self.spill_path.clone()
+            };
+            let mut writer = SpillWriter::create(&path)?;
+            for entry in spill.entries()? {
+                let (key, cmd_pos) = entry?;
+                // Superseded, their records may well be gone.
+                if !self.memory.contains_key(&key) {
+                    let cmd_pos = f(cmd_pos)?;
+                    staged.live_bytes += cmd_pos.len;
+                    writer.push(&key, cmd_pos)?;
+                }
+            }
+            staged.spill = Some(writer.finish(&path)?);
+        }
+        Ok(staged)
+    }
+
+    /// Replaces the positions of the index by the ones staged for it, which cannot fail.
+    pub(super) fn apply_positions(&mut self, mut staged: StagedPositions) {
+        // The map has not changed since, so it is iterated in the same order.
+        for (cmd_pos, staged) in self.memory.values_mut().zip(staged.memory.drain(..)) {
+            *cmd_pos = staged;
+        }
+        if let Some(spill) = staged.spill.take() {
+            self.replace_spill(spill);
+        }
+    }
+
+    /// Switches to the spill file `spill`, removing the previous one if it was elsewhere.
+    fn replace_spill(&mut self, spill: Spill) {
+        if let Some(old) = self.spill.replace(spill) {
+            if old.path != self.spill.as_ref().unwrap().path {
+                remove_spill_file(&old.path);
+            }
+        }
+    }
+
+    /// Looks `key` up in the spill file.
+    fn spilled(&self, key: &str) -> Result<Option<CommandPos>> {
+        match &self.spill {
+            Some(spill) => spill.get(key),
+            None => Ok(None),
+        }
+    }
+
+    /// Merges the entries in memory into the spill file once they exceed the budget.
+    fn spill_if_needed(&mut self) -> Result<()> {
+        match self.budget {
+            Some(budget) if self.memory_bytes > budget => (),
+            _ => return Ok(()),
+        }
+
+        // The entries stay in memory until the spill holding them is in place, since a spill
+        // which fails must not lose them.
+        let mut old = match &self.spill {
+            Some(spill) => Some(spill.entries()?),
+            None => None,
+        };
+        let mut next_old = || -> Result<Option<(String, CommandPos)>> {
+            old.as_mut().and_then(Iterator::next).transpose()
+        };
+
+        let mut writer = SpillWriter::create(&self.spill_path)?;
+        let mut pending = next_old()?;
+        for (key, cmd_pos) in &self.memory {
+            while let Some((old_key, old_pos)) = pending.take() {
+                if old_key < *key {
+                    writer.push(&old_key, old_pos)?;
+                    pending = next_old()?;
+                } else {
+                    if old_key > *key {
+                        pending = Some((old_key, old_pos));
+                    } else {
+                        pending = next_old()?;
+                    }
+                    break;
+                }
+            }
+            if let Some(cmd_pos) = *cmd_pos {
+                writer.push(key, cmd_pos)?;
+            }
+        }
+        while let Some((old_key, old_pos)) = pending {
+            writer.push(&old_key, old_pos)?;
+            pending = next_old()?;
+        }
+
+        let spill = writer.finish(&self.spill_path)?;
+        debug!(
+            keys = self.len,
+            sparse = spill.sparse.len(),
+            "Spilled the index."
+        );
+        self.replace_spill(spill);
+        self.memory.clear();
+        self.memory_bytes = 0;
+        Ok(())
+    }
+}
+
+/// The positions of an index staged by
+/// [`Index::stage_positions`](struct.Index.html#method.stage_positions). The spill file they
+/// make is removed if they are dropped without being applied.
+pub(super) struct StagedPositions {
+    /// The positions of the entries in memory, in the order of the map.
+    memory: Vec<Option<CommandPos>>,
+    spill: Option<Spill>,
+    /// The bytes of the records the positions point to.
+    live_bytes: u64,
+}
+
+impl StagedPositions {
+    pub(super) fn live_bytes(&self) -> u64 {
+        self.live_bytes
+    }
+}
+
+impl Drop for StagedPositions {
+    fn drop(&mut self) {
+        if let Some(spill) = self.spill.take() {
+            remove_spill_file(&spill.path);
+        }
+    }
+}
+
+/// Removes a spill file no longer in use, which is only worth a warning if it fails.
+fn remove_spill_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        warn!(error = %e, path = %path.display(), "Failed to remove a spilled index.");
+    }
+}
+
+/// A file of index entries sorted by key. Every entry is the length of the key as 4 bytes,
+/// the key, then the offset and the length of the record, the creation time of the key and the
+/// length of its value as 8 bytes each, in little endian. A creation time not known is written
+/// as `NO_TIME`.
+struct Spill {
+    path: PathBuf,
+    /// Shared by the lookups, which seek it.
+    reader: Mutex<BufReader<File>>,
+    /// The key of every `SPARSE_INTERVAL`th entry, with its offset in the file.
+    sparse: Vec<(String, u64)>,
+    end: u64,
+}
+
+impl Spill {
+    fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        let block = match self
+            .sparse
+            .binary_search_by(|(sparse_key, _)| sparse_key.as_str().cmp(key))
+        {
+            Ok(block) => block,
+            Err(0) => return Ok(None),
+            Err(next) => next - 1,
+        };
+        let mut offset = self.sparse[block].1;
+        let mut reader = lock(&self.reader);
+        reader.seek(SeekFrom::Start(offset))?;
+        for _ in 0..SPARSE_INTERVAL {
+            if offset >= self.end {
+                break;
+            }
+            let (entry_key, cmd_pos) = read_entry(&mut *reader)?;
+            if entry_key == key {
+                return Ok(Some(cmd_pos));
+            } else if entry_key.as_str() > key {
+                break;
+            }
+            offset += entry_len(&entry_key);
+        }
+        Ok(None)
+    }
+
+    /// Returns an iterator over the entries of the file, in key order.
+    fn entries(&self) -> Result<SpillEntries> {
+        self.entries_from(0)
+    }
+
+    /// Returns an iterator over the entries of the file from the start of the block where
+    /// `after` would be, or from the first one if `None`.
+    fn entries_after(&self, after: Option<&str>) -> Result<SpillEntries> {
+        let block = after.and_then(|after| {
+            match self
+                .sparse
+                .binary_search_by(|(sparse_key, _)| sparse_key.as_str().cmp(after))
+            {
+                Ok(block) => Some(block),
+                Err(0) => None,
+                Err(next) => Some(next - 1),
+            }
+        });
+        self.entries_from(block.map_or(0, |block| self.sparse[block].1))
+    }
+
+    /// Returns an iterator over the entries of the file from the one at `offset`.
+    fn entries_from(&self, offset: u64) -> Result<SpillEntries> {
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("opening spilled index {}", self.path.display()))?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(SpillEntries {
+            reader: BufReader::new(file),
+            remaining: self.end - offset,
+        })
+    }
+}
+
+struct SpillEntries {
+    reader: BufReader<File>,
+    remaining: u64,
+}
+
+impl Iterator for SpillEntries {
+    type Item = Result<(String, CommandPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let entry = read_entry(&mut self.reader);
+        match &entry {
+            Ok((key, _)) => self.remaining = self.remaining.saturating_sub(entry_len(key)),
+            Err(_) => self.remaining = 0,
+        }
+        Some(entry)
+    }
+}
+
+/// The keys of an index following a key, in ascending order, returned by
+/// [`Index::keys_after`](struct.Index.html#method.keys_after). The keys in memory are merged
+/// with the spilled ones, which they supersede.
+pub(super) struct KeysAfter<'a> {
+    memory: Peekable<btree_map::Range<'a, String, Option<CommandPos>>>,
+    spill: Option<Peekable<SpillEntries>>,
+}
+
+impl Iterator for KeysAfter<'_> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        loop {
+            let take_spilled = match (self.memory.peek(), self.spill.as_mut().map(Peekable::peek)) {
+                (Some((key, _)), Some(Some(Ok((spilled, _))))) => spilled < *key,
+                (Some(_), None) | (Some(_), Some(None)) => false,
+                // A failed read is returned at once.
+                _ => true,
+            };
+            if take_spilled {
+                let entry = self.spill.as_mut()?.next()?;
+                return Some(entry.map(|(key, _)| key));
+            }
+            let (key, cmd_pos) = self.memory.next()?;
+            if let Some(spill) = &mut self.spill {
+                // Superseded by the entry in memory.
+                if matches!(spill.peek(), Some(Ok((spilled, _))) if spilled == key) {
+                    spill.next();
+                }
+            }
+            if cmd_pos.is_some() {
+                return Some(Ok(key.clone()));
+            }
+        }
+    }
+}
+
+/// Writes a spill file next to its final path, which it replaces once complete.
+struct SpillWriter {
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    sparse: Vec<(String, u64)>,
+    offset: u64,
+    entries: usize,
+}
+
+impl SpillWriter {
+    fn create(path: &Path) -> Result<SpillWriter> {
+        let tmp_path = path.with_extension("spill.tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("creating spilled index {}", tmp_path.display()))?;
+        Ok(SpillWriter {
+            tmp_path,
+            writer: BufWriter::new(file),
+            sparse: Vec::new(),
+            offset: 0,
+            entries: 0,
+        })
+    }
+
+    /// Appends an entry, whose key must follow the keys of the previous ones.
+    fn push(&mut self, key: &str, cmd_pos: CommandPos) -> Result<()> {
+        if self.entries.is_multiple_of(SPARSE_INTERVAL) {
+            self.sparse.push((key.to_owned(), self.offset));
+        }
+        self.writer.write_all(&(key.len() as u32).to_le_bytes())?;
+        self.writer.write_all(key.as_bytes())?;
+        self.writer.write_all(&cmd_pos.pos.to_le_bytes())?;
+        self.writer.write_all(&cmd_pos.len.to_le_bytes())?;
+        let created_at = cmd_pos.created_at.unwrap_or(NO_TIME);
+        self.writer.write_all(&created_at.to_le_bytes())?;
+        self.writer.write_all(&cmd_pos.value_len.to_le_bytes())?;
+        self.writer.write_all(&[cmd_pos.value_type.tag()])?;
+        self.offset += entry_len(key);
+        self.entries += 1;
+        Ok(())
+    }
+
+    fn finish(mut self, path: &Path) -> Result<Spill> {
+        self.writer.flush()?;
+        fs::rename(&self.tmp_path, path).with_context(|| {
+            format!("renaming {} to {}", self.tmp_path.display(), path.display())
+        })?;
+        let file = File::open(path)
+            .with_context(|| format!("opening spilled index {}", path.display()))?;
+        Ok(Spill {
+            path: path.to_path_buf(),
+            reader: Mutex::new(BufReader::new(file)),
+            sparse: self.sparse,
+            end: self.offset,
+        })
+    }
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> Result<(String, CommandPos)> {
+    let mut word = [0u8; 8];
+    reader.read_exact(&mut word[..4])?;
+    let mut key = vec![0u8; u32::from_le_bytes([word[0], word[1], word[2], word[3]]) as usize];
+    reader.read_exact(&mut key)?;
+    reader.read_exact(&mut word)?;
+    let pos = u64::from_le_bytes(word);
+    reader.read_exact(&mut word)?;
+    let len = u64::from_le_bytes(word);
+    reader.read_exact(&mut word)?;
+    let created_at = Some(u64::from_le_bytes(word)).filter(|&time| time != NO_TIME);
+    reader.read_exact(&mut word)?;
+    let value_len = u64::from_le_bytes(word);
+    reader.read_exact(&mut word[..1])?;
+    let value_type = ValueType::from_tag(word[0])
+        .ok_or_else(|| KvsError::Internal(format!("unknown type {} in spilled index", word[0])))?;
+    let key = String::from_utf8(key).map_err(|e| KvsError::Internal(e.to_string()))?;
+    let cmd_pos = CommandPos {
+        pos,
+        len,
+        created_at,
+        value_len,
+        value_type,
+    };
+    Ok((key, cmd_pos))
+}
+
+/// The size of the entry of `key` in a spill file.
+fn entry_len(key: &str) -> u64 {
+    4 + key.len() as u64 + 33
+}
+
+/// The index split in shards by the hash of the keys, each behind a lock of its own, so that
+/// writing a key only holds back the reads of the keys of its shard. The bytes of the records
+/// superseded since the last compaction are counted by shard as well.
+pub(super) struct ShardedIndex {
+    shards: Vec<Shard>,
+    hasher: RandomState,
+}
+
+struct Shard {
+    index: RwLock<Index>,
+    redundant_bytes: AtomicU64,
+}
+
+impl ShardedIndex {
+    /// Creates an empty index of `count` shards, each spilled to a file of its own in `dir` past
+    /// its share of `budget`. The index is rebuilt into it entry by entry when the store is
+    /// opened, so that the shards spill as they go.
+    pub(super) fn new(dir: &Path, count: usize, budget: Option<usize>) -> Result<ShardedIndex> {
+        let shards = (0..count)
+            .map(|i| {
+                let spill_path = dir.join(format!("index.{}.spill", i));
+                Ok(Shard {
+                    index: RwLock::new(Index::new(spill_path, budget.map(|b| b / count))?),
+                    redundant_bytes: AtomicU64::new(0),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(ShardedIndex {
+            shards,
+            hasher: RandomState::new(),
+        })
+    }
+
+    fn shard_of(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize % self.shards.len()
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        &self.shards[self.shard_of(key)]
+    }
+
+    /// Locks the shard of `key` for reading.
+    pub(super) fn read(&self, key: &str) -> RwLockReadGuard<'_, Index> {
+        read_lock(&self.shard(key).index)
+    }
+
+    /// Locks the shard of `key` for writing.
+    pub(super) fn write(&self, key: &str) -> RwLockWriteGuard<'_, Index> {
+        write_lock(&self.shard(key).index)
+    }
+
+    /// Locks the shards of `keys` for reading, in the order of the shards like
+    /// [`read_all`](#method.read_all), each of them once.
+    pub(super) fn read_keys(&self, keys: &[String]) -> KeyShards<'_> {
+        let mut numbers: Vec<usize> = keys.iter().map(|key| self.shard_of(key)).collect();
+        numbers.sort_unstable();
+        numbers.dedup();
+        KeyShards {
+            index: self,
+            guards: numbers
+                .into_iter()
+                .map(|number| (number, read_lock(&self.shards[number].index)))
+                .collect(),
+        }
+    }
+
+    /// Locks every shard for reading, one after the other.
+    pub(super) fn read_all(&self) -> Shards<RwLockReadGuard<'_, Index>> {
+        Shards(
+            self.shards
+                .iter()
+                .map(|shard| read_lock(&shard.index))
+                .collect(),
+        )
+    }
+
+    /// Locks every shard for writing, one after the other.
+    pub(super) fn write_all(&self) -> Shards<RwLockWriteGuard<'_, Index>> {
+        Shards(
+            self.shards
+                .iter()
+                .map(|shard| write_lock(&shard.index))
+                .collect(),
+        )
+    }
+
+    /// Counts `bytes` of superseded records of `key`.
+    pub(super) fn add_redundant(&self, key: &str, bytes: u64) {
+        self.shard(key)
+            .redundant_bytes
+            .fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// The bytes of the records superseded since the last compaction.
+    pub(super) fn redundant_bytes(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.redundant_bytes.load(Ordering::SeqCst))
+            .sum()
+    }
+
+    /// Starts counting again after a compaction, which leaves `bytes` of superseded records in
+    /// the log. Their keys are not known, so they are counted in the first shard.
+    pub(super) fn reset_redundant(&self, bytes: u64) {
+        for (i, shard) in self.shards.iter().enumerate() {
+            let bytes = if i == 0 { bytes } else { 0 };
+            shard.redundant_bytes.store(bytes, Ordering::SeqCst);
+        }
+    }
+}
+
+impl ReplayIndex for ShardedIndex {
+    fn insert(&mut self, key: String, cmd_pos: CommandPos) -> Result<Option<CommandPos>> {
+        self.write(&key).insert(key, cmd_pos)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        self.write(key).remove(key)
+    }
+}
+
+/// Every shard of an index, locked.
+pub(super) struct Shards<G>(Vec<G>);
+
+/// The keys of every shard following a key, in ascending order, returned by
+/// [`Shards::keys_after`](struct.Shards.html#method.keys_after).
+pub(super) struct MergedKeys<'a>(Vec<Peekable<KeysAfter<'a>>>);
+
+impl Iterator for MergedKeys<'_> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        // A key is in a single shard, and a failed read is returned at once.
+        let mut least: Option<(usize, &str)> = None;
+        for (i, keys) in self.0.iter_mut().enumerate() {
+            match keys.peek() {
+                Some(Err(_)) => return keys.next(),
+                Some(Ok(key)) if least.is_none_or(|(_, least)| key.as_str() < least) => {
+                    least = Some((i, key))
+                }
+                _ => {}
+            }
+        }
+        let (i, _) = least?;
+        self.0[i].next()
+    }
+}
+
+/// The shards of some keys, locked for reading by
+/// [`ShardedIndex::read_keys`](struct.ShardedIndex.html#method.read_keys).
+pub(super) struct KeyShards<'a> {
+    index: &'a ShardedIndex,
+    guards: BTreeMap<usize, RwLockReadGuard<'a, Index>>,
+}
+
+impl KeyShards<'_> {
+    /// Returns the position of `key`, one of the keys whose shards were locked.
+    pub(super) fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        match self.guards.get(&self.index.shard_of(key)) {
+            Some(index) => index.get(key),
+            None => Err(KvsError::Internal(format!(
+                "the shard of key {:?} is not locked",
+                key
+            ))),
+        }
+    }
+}
+
+impl<G: Deref<Target = Index>> Shards<G> {
+    /// Calls `f` with every key of the index and its position, in no particular order.
+    pub(super) fn for_each<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, CommandPos) -> Result<()>,
+    {
+        self.0.iter().try_for_each(|index| index.for_each(&mut f))
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.0.iter().map(|index| index.len()).sum()
+    }
+
+    pub(super) fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::with_capacity(self.len());
+        for index in &self.0 {
+            keys.extend(index.keys()?);
+        }
+        Ok(keys)
+    }
+
+    pub(super) fn positions(&self) -> Result<Vec<CommandPos>> {
+        let mut positions = Vec::with_capacity(self.len());
+        for index in &self.0 {
+            positions.extend(index.positions()?);
+        }
+        Ok(positions)
+    }
+
+    /// Returns the keys following `after`, or all of them if `None`, in ascending order, merged
+    /// from the ordered keys of every shard.
+    pub(super) fn keys_after(&self, after: Option<&str>) -> Result<MergedKeys<'_>> {
+        let shards = self
+            .0
+            .iter()
+            .map(|index| Ok(index.keys_after(after)?.peekable()))
+            .collect::<Result<_>>()?;
+        Ok(MergedKeys(shards))
+    }
+
+    /// Stages the position `f` returns for every position of the index, shard by shard,
+    /// without changing it.
+    pub(super) fn stage_positions<F>(&self, mut f: F) -> Result<Vec<StagedPositions>>
+    where
+        F: FnMut(CommandPos) -> Result<CommandPos>,
+    {
+        self.0
+            .iter()
+            .map(|index| index.stage_positions(&mut f))
+            .collect()
+    }
+}
+
+impl<G: DerefMut<Target = Index>> Shards<G> {
+    /// Replaces the positions of the index by the ones staged for its shards by
+    /// [`stage_positions`](#method.stage_positions), which cannot fail.
+    pub(super) fn apply_positions(&mut self, staged: Vec<StagedPositions>) {
+        for (index, staged) in self.0.iter_mut().zip(staged) {
+            index.apply_positions(staged);
+        }
+    }
+}
+
+impl<G: Deref<Target = Index>> Serialize for Shards<G> {
+    /// Serializes the index as a single map, the way a bare `HashMap` would be.
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        let mut failed = None;
+        self.for_each(|key, cmd_pos| match map.serialize_entry(key, &cmd_pos) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                failed = Some(e);
+                Err(KvsError::Internal("serializing the index".to_owned()))
+            }
+        })
+        .map_err(|e| failed.take().unwrap_or_else(|| S::Error::custom(e)))?;
+        map.end()
+    }
+}
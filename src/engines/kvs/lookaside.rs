@@ -0,0 +1,89 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use super::storage::{read_exact_at, LogStorage};
+use super::{read_lock, write_lock};
+
+/// The bytes appended to a log but not flushed to its storage yet, which the reads of the
+/// records among them are served from meanwhile. Only the writer thread appends and flushes
+/// them.
+pub(super) struct Lookaside {
+    /// The end of the bytes flushed to the storage, the last offset the reads find there.
+    flushed: AtomicU64,
+    /// The bytes after it.
+    buffer: RwLock<Vec<u8>>,
+}
+
+impl Lookaside {
+    /// Creates the lookaside of a log whose storage ends at `flushed`.
+    pub(super) fn new(flushed: u64) -> Lookaside {
+        Lookaside {
+            flushed: AtomicU64::new(flushed),
+            buffer: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Appends `bytes` after those buffered.
+    pub(super) fn append(&self, bytes: &[u8]) {
+        write_lock(&self.buffer).extend_from_slice(bytes);
+    }
+
+    /// The number of bytes buffered.
+    pub(super) fn buffered(&self) -> usize {
+        read_lock(&self.buffer).len()
+    }
+
+    /// Appends the bytes buffered to `storage`, and returns the offset flushed up to. They stay
+    /// readable from the buffer while they are appended. If the append fails, the bytes it
+    /// wrote all the same, as found from the length of the storage, count as flushed, and only
+    /// the ones after them are kept to be flushed again, so that a retry resumes where the
+    /// append stopped instead of writing its records twice.
+    pub(super) fn flush(&self, storage: &dyn LogStorage) -> io::Result<u64> {
+        let buffer = read_lock(&self.buffer);
+        if buffer.is_empty() {
+            return Ok(self.flushed.load(Ordering::Acquire));
+        }
+        let appended = storage.append(&buffer);
+        drop(buffer);
+
+        let mut buffer = write_lock(&self.buffer);
+        let start = self.flushed.load(Ordering::Acquire);
+        let written = match &appended {
+            Ok(()) => buffer.len(),
+            Err(_) => match storage.len() {
+                Ok(len) => len.saturating_sub(start).min(buffer.len() as u64) as usize,
+                Err(_) => 0,
+            },
+        };
+        self.flushed
+            .store(start + written as u64, Ordering::Release);
+        buffer.drain(..written);
+        appended.map(|()| start + written as u64)
+    }
+
+    /// Fills `buf` with the bytes of the log at `pos`, read from `storage` up to the flushed
+    /// offset and from the buffer past it.
+    pub(super) fn read_exact_at(
+        &self,
+        storage: &dyn LogStorage,
+        pos: u64,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let end = pos + buf.len() as u64;
+        if end <= self.flushed.load(Ordering::Acquire) {
+            return read_exact_at(storage, pos, buf);
+        }
+        let buffer = read_lock(&self.buffer);
+        let flushed = self.flushed.load(Ordering::Acquire);
+        let split = flushed.clamp(pos, end);
+        let (in_storage, in_buffer) = buf.split_at_mut((split - pos) as usize);
+        let start = split.saturating_sub(flushed) as usize;
+        match buffer.get(start..start + in_buffer.len()) {
+            Some(bytes) => in_buffer.copy_from_slice(bytes),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+        drop(buffer);
+        read_exact_at(storage, pos, in_storage)
+    }
+}
@@ -0,0 +1,2677 @@
+//! A Simple Key-Value DataBase in memory.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter, SeekFrom};
+use std::mem;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::cursor::Snapshot;
+use super::lease::released;
+use super::{
+    glob, lock, read_lock, write_lock, CursorToken, KeyCursor, KeyMetadata, KvsEngine, ValueType,
+};
+use crate::error::{KvsError, Result, ResultExt};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use tracing::{debug, debug_span, error, info, info_span, warn};
+
+pub use self::backup::{BackupTarget, DirTarget};
+pub use self::builder::{KvStoreBuilder, TombstonePolicy};
+#[cfg(feature = "fault-injection")]
+pub use self::faults::FaultPlan;
+#[cfg(feature = "s3")]
+pub use self::s3::S3Target;
+use self::stats::CompactionHistory;
+pub use self::stats::{CompactionStats, CompactionTrigger, SizeHistogram, StoreStats};
+pub use self::storage::{FileStorage, LogStorage};
+pub use self::tail::Tail;
+
+use self::cache::ValueCache;
+use self::checkpoint::{write_index, Checkpoint};
+use self::chunks::{Assembler, ChunkCommand, CHUNK_SIZE};
+use self::expiry::Sweeper;
+use self::index::{ShardedIndex, StagedPositions};
+use self::lookaside::Lookaside;
+use self::replay::{Progress, Replayed};
+use self::storage::{read_exact_at, StorageReader, StorageWriter};
+use self::tail::LogHead;
+use self::values::{PointerCommand, ValueLog, ValuePtr, ValueReader};
+use self::writer::{Request, Writer};
+
+mod archive;
+mod backup;
+mod builder;
+mod cache;
+mod checkpoint;
+mod chunks;
+mod expiry;
+#[cfg(feature = "fault-injection")]
+mod faults;
+mod index;
+mod lookaside;
+mod prealloc;
+mod replay;
+#[cfg(feature = "s3")]
+mod s3;
+mod stats;
+mod storage;
+mod tail;
+mod values;
+mod writer;
+
+/// The largest value accepted. Values larger than a record holds are split across several.
+const MAX_VALUE_SIZE: usize = 1 << 24;
+
+/// How often the expired keys are swept by default.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+const REDUNDANCY_THRESHOLD: u64 = 1 << 20; // threshold that trigger log compacting, default 1MB.
+
+/// The least redundant bytes from which the compaction ratio starts a compaction.
+const MIN_RATIO_REDUNDANCY: u64 = 64 << 10;
+
+/// The longest a write is delayed by the write throttle.
+const MAX_THROTTLE_DELAY: Duration = Duration::from_millis(100);
+
+/// The struct of Key-Value DataBase implemented with
+/// [HashMap](https://doc.rust-lang.org/std/collections/hash_map/struct.HashMap.html).
+///
+/// The key can be up to 256B and the value can be up to 16MB, values larger than 4KB being split
+/// across several records of the log.
+///
+/// The writes of every handle are appended to the log by a thread of the store, one after the
+/// other, while the reads run on the threads of their callers.
+#[derive(Clone)]
+pub struct KvStore {
+    index: Arc<ShardedIndex>,
+    /// The version of the log the positions of the index point into.
+    version: Arc<RwLock<Arc<LogVersion>>>,
+    index_path: Arc<PathBuf>,
+    log_path: Arc<PathBuf>,
+    builder: Arc<KvStoreBuilder>,
+    /// The sequence number of the next write of a versioned store.
+    next_seq: Arc<AtomicU64>,
+    /// The superseded records of every key, oldest first, kept by a versioned store until
+    /// the next compaction.
+    history: Arc<Mutex<HashMap<String, Vec<CommandPos>>>>,
+    /// The `Rm` records of the removed keys, which compaction keeps according to the
+    /// tombstone policy.
+    tombstones: Arc<Mutex<HashMap<String, Tombstone>>>,
+    /// When the keys set with a time to live expire, in milliseconds since the Unix epoch.
+    expiries: Arc<RwLock<HashMap<String, u64>>>,
+    /// The end of the log, watched by the tails.
+    head: Arc<LogHead>,
+    /// The values stored apart from the log.
+    values: Arc<Mutex<ValueLog>>,
+    /// Held by the running compaction, while it copies the live records apart from the writer
+    /// thread.
+    compaction: Arc<Mutex<()>>,
+    /// The compactions completed since the store was opened.
+    compactions: Arc<Mutex<CompactionHistory>>,
+    /// The sizes of the keys and values replayed and written since the store was opened.
+    sizes: Arc<Mutex<StoreStats>>,
+    /// Removes the expired keys in the background until the last handle to the store is
+    /// dropped. The sweeper holds a handle of its own, without it.
+    sweeper: Option<Arc<Sweeper>>,
+    /// The values of the keys read most recently.
+    cache: Arc<ValueCache>,
+    /// Owns the writer of the log, which it appends the mutations to one after the other, until
+    /// the last handle to the store is dropped. The writer thread holds a handle of its own,
+    /// without it.
+    writer: Option<Arc<Writer>>,
+}
+
+impl KvStore {
+    /// Open a KvStore DataBase from the directory contains logfile and index file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<KvStore> {
+        KvStoreBuilder::new().open(path)
+    }
+
+    /// Opens a KvStore like [`open`](#method.open), calling `progress` with the number of bytes
+    /// of the log replayed so far and the number of bytes to replay while the index is rebuilt.
+    /// It may be called from several threads when the log is replayed in parallel.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let db = KvStore::open_with_progress(&temp_dir, |replayed, total| {
+    ///     println!("replayed {} of {} bytes", replayed, total);
+    /// })
+    /// .unwrap();
+    /// ```
+    pub fn open_with_progress<P, F>(path: P, progress: F) -> Result<KvStore>
+    where
+        P: AsRef<Path>,
+        F: Fn(u64, u64) + Send + Sync + 'static,
+    {
+        KvStoreBuilder::new().open_with_progress(path, progress)
+    }
+
+    fn open_with(
+        path: &Path,
+        builder: KvStoreBuilder,
+        progress: Option<Arc<Progress>>,
+    ) -> Result<KvStore> {
+        let log_file = Arc::new(path.join("log"));
+        let index_file = Arc::new(path.join("index"));
+
+        // The compacted log is renamed over the log, but earlier versions removed the log first,
+        // and a crash in between left the records in the compacted log only.
+        let tmp_log = path.join("log.tmp");
+        if !log_file.exists() && tmp_log.exists() {
+            warn!(path = %tmp_log.display(), "Restoring the log from the compacted log.");
+            fs::rename(&tmp_log, log_file.deref()).with_context(|| {
+                format!("renaming {} to {}", tmp_log.display(), log_file.display())
+            })?;
+            sync_dir(path)?;
+        }
+        let storage = builder
+            .log_storage
+            .open(&log_file)
+            .with_context(|| format!("opening log file {}", log_file.display()))?;
+
+        let values = ValueLog::open(path)?;
+        let value_reader = Arc::new(values.reader());
+        let values = Arc::new(Mutex::new(values));
+        let log_len = storage.len()?;
+        let lookaside = Arc::new(Lookaside::new(log_len));
+        let version = Arc::new(RwLock::new(Arc::new(LogVersion::new(
+            LogReader::new(
+                Arc::clone(&storage),
+                Arc::clone(&lookaside),
+                log_file.to_path_buf(),
+                value_reader,
+                builder.read_capacity(),
+            ),
+            0,
+        ))));
+        let head = Arc::new(LogHead::new(log_len));
+        let logwriter = LogWriter::new(
+            Arc::clone(&storage),
+            lookaside,
+            Arc::clone(&values),
+            Arc::clone(&head),
+            &builder,
+        );
+        // The parts of a log replayed in parallel each fill a map of their own, which a budget
+        // for the index would not bound, so the log is then replayed on a single thread.
+        let threads = match builder.index_budget {
+            Some(_) => 1,
+            None => builder.replay_threads.unwrap_or_else(num_cpus::get),
+        };
+        let buffer = builder.sequential_capacity();
+        let index = ShardedIndex::new(path, builder.shard_count(), builder.index_budget)?;
+        let mut replayed = Replayed::new(index);
+
+        // The index file only describes the live keys, so the log has to be replayed to find
+        // the history and the tombstones.
+        let replay_log = builder.versioned || builder.tombstone_policy != TombstonePolicy::Drop;
+        let checkpoint = if index_file.exists() && !replay_log {
+            checkpoint::load_index(&index_file, &mut replayed.index)?
+        } else {
+            None
+        };
+        if let Some(Checkpoint { offset, expiries }) = checkpoint {
+            replayed.expiries = expiries;
+            // Catch up with the writes made after the checkpoint.
+            let progress = progress.as_ref();
+            replay::replay(
+                &storage,
+                &log_file,
+                offset,
+                threads,
+                buffer,
+                &mut replayed,
+                progress,
+            )?;
+        } else {
+            if builder.versioned {
+                replayed.history = Some(HashMap::new());
+            }
+            let progress = progress.as_ref();
+            replay::replay(
+                &storage,
+                &log_file,
+                0,
+                threads,
+                buffer,
+                &mut replayed,
+                progress,
+            )?;
+        }
+        let Replayed {
+            index,
+            tombstones,
+            expiries,
+            history,
+            last_seq,
+            sizes,
+        } = replayed;
+
+        let sweep_interval = builder.sweep_interval.unwrap_or(SWEEP_INTERVAL);
+        let cache = ValueCache::new(builder.value_cache.unwrap_or(0), builder.shard_count());
+
+        let mut store = KvStore {
+            index: Arc::new(index),
+            version,
+            index_path: index_file,
+            log_path: log_file,
+            next_seq: Arc::new(AtomicU64::new(last_seq + 1)),
+            history: Arc::new(Mutex::new(history.unwrap_or_default())),
+            tombstones: Arc::new(Mutex::new(tombstones)),
+            expiries: Arc::new(RwLock::new(expiries)),
+            head,
+            builder: Arc::new(builder),
+            values,
+            compaction: Arc::new(Mutex::new(())),
+            compactions: Arc::default(),
+            sizes: Arc::new(Mutex::new(sizes)),
+            sweeper: None,
+            cache: Arc::new(cache),
+            writer: None,
+        };
+        store.writer = Some(Arc::new(Writer::start(store.clone(), logwriter)?));
+        store.warm_up()?;
+        if sweep_interval > Duration::from_secs(0) {
+            store.sweeper = Some(Arc::new(Sweeper::start(store.clone(), sweep_interval)?));
+        }
+        Ok(store)
+    }
+
+    /// Returns up to `n` versions of `key`, newest first, including its removals. Only a
+    /// versioned store keeps the versions superseded since the last compaction, otherwise
+    /// only the current value is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStoreBuilder, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let db = KvStoreBuilder::new().versioned(true).open(&temp_dir).unwrap();
+    ///
+    /// db.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// db.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    /// let history = db.get_history("key1".to_owned(), 10).unwrap();
+    /// assert_eq!(history[0].value, Some("value2".to_owned()));
+    /// assert_eq!(history[1].value, Some("value1".to_owned()));
+    /// assert!(history[0].seq > history[1].seq);
+    /// ```
+    pub fn get_history(&self, key: String, n: usize) -> Result<Vec<Version>> {
+        let (log, positions) = {
+            let version = read_lock(&self.version);
+            let index = self.index.read(&key);
+            let history = lock(&self.history);
+
+            let current = index.get(&key)?;
+            let positions: Vec<CommandPos> = history
+                .get(&key)
+                .into_iter()
+                .flatten()
+                .chain(current.as_ref())
+                .rev()
+                .take(n)
+                .copied()
+                .collect();
+            (Arc::clone(&version), positions)
+        };
+        positions
+            .into_iter()
+            .map(|cmd_pos| {
+                let version = match log.reader.read_in_pos(cmd_pos.pos, cmd_pos.len)? {
+                    Command::Set { value, seq, .. } => Version {
+                        seq,
+                        value: Some(value),
+                    },
+                    Command::Rm { seq, .. } => Version { seq, value: None },
+                };
+                Ok(version)
+            })
+            .collect()
+    }
+
+    /// Returns the logs archived by compaction, oldest first. Any of them can be restored by
+    /// copying it in place of the log of a store and removing its index file.
+    pub fn archived_logs(&self) -> Result<Vec<PathBuf>> {
+        archive::archived_logs(&self.log_path)
+    }
+
+    /// Appends the `Rm` records of the expired keys right away, so that the next compaction
+    /// reclaims them, and returns how many were removed. The sweeper of the store does it every
+    /// [`sweep_interval`](struct.KvStoreBuilder.html#method.sweep_interval).
+    pub fn sweep_expired(&self) -> Result<usize> {
+        let mut removed = 0;
+        loop {
+            let now = unix_time_ms();
+            let expired: Vec<String> = read_lock(&self.expiries)
+                .iter()
+                .filter(|(_, &expires_at)| expires_at <= now)
+                .map(|(key, _)| key.clone())
+                .take(expiry::SWEEP_BATCH)
+                .collect();
+            // The locks are taken for every key, so that the writers are only held back
+            // by one removal at a time.
+            for key in &expired {
+                if self.remove_key(key.clone(), true)?.is_some() {
+                    removed += 1;
+                }
+            }
+            if expired.len() < expiry::SWEEP_BATCH {
+                break;
+            }
+        }
+        if removed > 0 {
+            debug!(removed, "Swept the expired keys.");
+        }
+        Ok(removed)
+    }
+
+    /// Reads the values the builder asks for into the value cache: those of the most recently
+    /// written keys, least recent first so that they are the last ones evicted, then the ones
+    /// of the keys listed.
+    fn warm_up(&self) -> Result<()> {
+        if self.builder.value_cache.unwrap_or(0) == 0 {
+            return Ok(());
+        }
+        let mut keys = Vec::new();
+        if let Some(records) = self.builder.warm_up_recent {
+            let mut recent = Vec::new();
+            self.index.read_all().for_each(|key, cmd_pos| {
+                recent.push((cmd_pos.pos, key.to_owned()));
+                Ok(())
+            })?;
+            recent.sort_unstable_by_key(|&(pos, _)| std::cmp::Reverse(pos));
+            recent.truncate(records);
+            keys.extend(recent.into_iter().rev().map(|(_, key)| key));
+        }
+        keys.extend(self.builder.warm_up_keys.iter().cloned());
+
+        let count = keys.len();
+        for key in keys {
+            self.get(key)?;
+        }
+        debug!(keys = count, "Warmed the value cache up.");
+        Ok(())
+    }
+
+    /// Whether `key` was set with a time to live which is over.
+    fn is_expired(&self, key: &str) -> bool {
+        let now = unix_time_ms();
+        read_lock(&self.expiries)
+            .get(key)
+            .is_some_and(|&expires_at| expires_at <= now)
+    }
+
+    /// Returns the number of keys of the store with histograms of the sizes of its keys and
+    /// values. The histograms are rebuilt from the records replayed when the store is opened,
+    /// which are all of them unless it is opened from a checkpoint, and count every write since,
+    /// superseded or not. The statistics of the compactions since the store was opened come
+    /// along.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{CompactionTrigger, KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let db = KvStore::open(&temp_dir).unwrap();
+    ///
+    /// db.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// let stats = db.stats();
+    /// assert_eq!(stats.keys, 1);
+    /// assert_eq!(stats.value_sizes.percentile(0.5), 7);
+    ///
+    /// db.compact().unwrap();
+    /// let stats = db.stats();
+    /// assert_eq!(stats.compactions, 1);
+    /// assert_eq!(stats.recent_compactions[0].trigger, CompactionTrigger::Manual);
+    /// ```
+    pub fn stats(&self) -> StoreStats {
+        let keys = self.index.read_all().len();
+        StoreStats {
+            keys,
+            ..lock(&self.sizes).clone()
+        }
+        .with_compactions(&lock(&self.compactions))
+    }
+
+    /// Returns the sequence number of the next write if the store is versioned.
+    fn next_seq(&self) -> Option<u64> {
+        if self.builder.versioned {
+            Some(self.next_seq.fetch_add(1, Ordering::SeqCst))
+        } else {
+            None
+        }
+    }
+
+    /// Keeps the superseded record `cmd_pos` of `key` in the history of a versioned store.
+    fn supersede(&self, key: &str, cmd_pos: CommandPos) {
+        if self.builder.versioned {
+            lock(&self.history)
+                .entry(key.to_owned())
+                .or_default()
+                .push(cmd_pos);
+        }
+    }
+
+    /// Flushes the log, forces it to disk and atomically replaces the index file with a snapshot
+    /// of the index. Returns the offset in the log the snapshot covers: when the store is
+    /// reopened, only the records written after it are replayed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let db = KvStore::open(&temp_dir).unwrap();
+    ///
+    /// let empty = db.checkpoint().unwrap();
+    /// db.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// assert!(db.checkpoint().unwrap() > empty);
+    /// ```
+    pub fn checkpoint(&self) -> Result<u64> {
+        let _span = debug_span!("checkpoint").entered();
+        self.writer().call(Request::Checkpoint)
+    }
+
+    /// Writes a checkpoint on the writer thread, which no write can come in between.
+    fn write_checkpoint(&self, logwriter: &mut LogWriter) -> Result<u64> {
+        let index = self.index.read_all();
+        logwriter
+            .sync()
+            .with_context(|| format!("syncing log {}", self.log_path.display()))?;
+        let offset = logwriter.offset;
+        write_index(&self.index_path, &index, &read_lock(&self.expiries), offset)?;
+        logwriter.checkpointed = logwriter.records;
+        debug!(offset, "Wrote a checkpoint.");
+        Ok(offset)
+    }
+
+    /// Commits the batch of writes the writer thread just ran: flushes the log, so that a crash
+    /// of the process loses none of them once they return, or syncs it if the writes have to be
+    /// durable. Then writes a checkpoint once `checkpoint_every` records were written since the
+    /// last one, if the store was opened with it.
+    fn commit_batch(&self, logwriter: &mut LogWriter) -> Result<()> {
+        let uncommitted = mem::take(&mut logwriter.uncommitted);
+        let committed = if self.builder.sync_writes {
+            logwriter
+                .sync()
+                .with_context(|| format!("syncing log {}", self.log_path.display()))
+        } else {
+            logwriter
+                .flush()
+                .with_context(|| format!("flushing log {}", self.log_path.display()))
+        };
+        if let Err(e) = committed {
+            self.roll_back(uncommitted);
+            return Err(e);
+        }
+        if self.builder.sync_writes {
+            debug!(records = logwriter.records, "Committed a group of records.");
+        }
+        if let Some(every) = self.builder.checkpoint_every {
+            if logwriter.records - logwriter.checkpointed >= every {
+                self.write_checkpoint(logwriter)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remembers that `key` was indexed to `cmd_pos` before the batch being written changes it,
+    /// unless an earlier write of the batch already did. Called under the lock of its shard.
+    fn keep_uncommitted(&self, logwriter: &mut LogWriter, key: &str, cmd_pos: Option<CommandPos>) {
+        if logwriter.uncommitted.contains_key(key) {
+            return;
+        }
+        let uncommitted = Uncommitted {
+            cmd_pos,
+            expires_at: read_lock(&self.expiries).get(key).copied(),
+            tombstone: lock(&self.tombstones).get(key).copied(),
+            history_len: lock(&self.history).get(key).map_or(0, Vec::len),
+        };
+        logwriter.uncommitted.insert(key.to_owned(), uncommitted);
+    }
+
+    /// Restores what the writes of a batch which failed to commit replaced, so that the reads
+    /// never find them. Their records are left in the log, unindexed, to be flushed along with
+    /// the next batch: like a write interrupted by a crash, a failed write may be found again
+    /// once the store is reopened.
+    fn roll_back(&self, uncommitted: HashMap<String, Uncommitted>) {
+        for (key, before) in uncommitted {
+            let mut index = self.index.write(&key);
+            let restored = match before.cmd_pos {
+                Some(cmd_pos) => index.insert(key.clone(), cmd_pos),
+                None => index.remove(&key),
+            };
+            if let Err(e) = restored {
+                error!(key = %key, error = %e, "Failed to roll back the index entry of a key.");
+            }
+            self.cache.remove(&key);
+            match before.expires_at {
+                Some(expires_at) => write_lock(&self.expiries).insert(key.clone(), expires_at),
+                None => write_lock(&self.expiries).remove(&key),
+            };
+            match before.tombstone {
+                Some(tombstone) => lock(&self.tombstones).insert(key.clone(), tombstone),
+                None => lock(&self.tombstones).remove(&key),
+            };
+            if let Some(history) = lock(&self.history).get_mut(&key) {
+                history.truncate(before.history_len);
+            }
+        }
+    }
+
+    /// Returns an iterator over the records of the log from `from_offset` on, which blocks
+    /// waiting for new records once it has caught up with the log. Each record comes with its
+    /// offset, and [`Tail::offset`](struct.Tail.html#method.offset) tells where to resume
+    /// later.
+    ///
+    /// # Errors
+    /// Returns `KvsError::StaleOffset` if `from_offset` is past the end of the log, which
+    /// happens once the log has been compacted. `from_offset` must otherwise be the offset of
+    /// a record, such as 0 or one returned by a tail.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{Command, KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let db = KvStore::open(&temp_dir).unwrap();
+    /// let mut tail = db.tail(0).unwrap();
+    ///
+    /// db.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// match tail.next().unwrap().unwrap() {
+    ///     (0, Command::Set { key, .. }) => assert_eq!(key, "key1"),
+    ///     _ => panic!("unexpected record"),
+    /// }
+    /// ```
+    pub fn tail(&self, from_offset: u64) -> Result<Tail> {
+        Tail::new(self, from_offset)
+    }
+
+    /// Copies the records appended to the log since `since_offset` to the backup in the
+    /// directory `dest`, and returns the offset to pass to the next incremental backup. A full
+    /// backup is taken from offset 0 into an empty directory, and the backup directory can be
+    /// opened as a store.
+    ///
+    /// # Errors
+    /// Returns `KvsError::StaleOffset` if the log was compacted since the previous backup, or if
+    /// `dest` does not hold the backup taken up to `since_offset`. A full backup is needed then.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let db = KvStore::open(&temp_dir).unwrap();
+    ///
+    /// db.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// let offset = db.backup_incremental(&backup_dir, 0).unwrap();
+    /// db.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    /// db.backup_incremental(&backup_dir, offset).unwrap();
+    /// ```
+    pub fn backup_incremental<P: AsRef<Path>>(&self, dest: P, since_offset: u64) -> Result<u64> {
+        self.backup_to(&mut DirTarget::new(dest.as_ref()), since_offset)
+    }
+
+    /// Copies the records appended to the log since `since_offset` to the backup kept by
+    /// `target`, like [`backup_incremental`](#method.backup_incremental) does to a local
+    /// directory, and returns the offset to pass to the next incremental backup.
+    ///
+    /// # Errors
+    /// Returns `KvsError::StaleOffset` if the log was compacted since the previous backup, or if
+    /// `target` does not hold the backup taken up to `since_offset`.
+    pub fn backup_to<T: BackupTarget>(&self, target: &mut T, since_offset: u64) -> Result<u64> {
+        let _span = info_span!("backup", since_offset).entered();
+        let BackupSource { log, end, values } = self.writer().call(Request::Backup)?;
+        let mut log = StorageReader::new(log);
+
+        // The values go first, so that the records of the backup never point past them.
+        if let Some((mut values, path, size)) = values {
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default();
+            let since = target.size(name)?;
+            backup::append_range(&mut values, name, since, size, target)?;
+        }
+        backup::append_range(&mut log, "log", since_offset, end, target)?;
+        info!(offset = end, "Backed up the log.");
+        Ok(end)
+    }
+
+    /// Flushes the logs on the writer thread for a backup, which then copies them without
+    /// blocking the writer, through handles which keep reading these logs even if a compaction
+    /// replaces them meanwhile.
+    fn backup_source(&self, logwriter: &mut LogWriter) -> Result<BackupSource> {
+        logwriter
+            .flush()
+            .with_context(|| format!("flushing log {}", self.log_path.display()))?;
+        let mut values = lock(&self.values);
+        let values = match values.size()? {
+            0 => None,
+            size => Some((File::open(values.path())?, values.path(), size)),
+        };
+        Ok(BackupSource {
+            log: Arc::clone(logwriter.storage()),
+            end: logwriter.offset,
+            values,
+        })
+    }
+
+    /// Rebuilds the index of the KvStore in `path` from its log, ignoring the index file which
+    /// may be stale or damaged.
+    ///
+    /// Every record is verified against its checksum and corrupted ones are dropped. The log is
+    /// truncated at the first record that cannot be decoded, which is usually one torn by a
+    /// crash in the middle of a write. The repaired log is then compacted and a fresh index is
+    /// written.
+    pub fn repair<P: AsRef<Path>>(path: P) -> Result<RepairReport> {
+        let log_path = path.as_ref().join("log");
+        let index_path = path.as_ref().join("index");
+        let log_handle = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&log_path)
+            .with_context(|| format!("opening log file {}", log_path.display()))?;
+
+        let mut report = RepairReport::default();
+        let mut index = HashMap::new();
+        let mut expiries = HashMap::new();
+        let mut reader = BufReader::new(&log_handle);
+        let mut log_stream = Deserializer::from_reader(&mut reader).into_iter::<LogEntry>();
+
+        let mut chunks = Assembler::default();
+        let mut curr_head_pos: u64 = 0;
+        while let Some(Ok(entry)) = log_stream.next() {
+            let offset = curr_head_pos;
+            curr_head_pos = log_stream.byte_offset() as u64;
+
+            match chunks.push(entry, &log_path, offset) {
+                Ok(None) => continue,
+                Ok(Some((pos, cmd, ptr))) => {
+                    let cmd_pos = CommandPos::of(pos, curr_head_pos - pos, &cmd, ptr);
+                    match cmd {
+                        Command::Set {
+                            key, expires_at, ..
+                        } => {
+                            match expires_at {
+                                Some(expires_at) => expiries.insert(key.clone(), expires_at),
+                                None => expiries.remove(&key),
+                            };
+                            index.insert(key, cmd_pos);
+                        }
+                        Command::Rm { key, .. } => {
+                            expiries.remove(&key);
+                            index.remove(&key);
+                        }
+                    }
+                }
+                Err(KvsError::Corruption { .. }) => {
+                    chunks.reset();
+                    report.corrupted_records += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+            report.recovered_records += 1;
+        }
+        // The chunks of a value torn by a crash are cut along.
+        let end = chunks.pending().unwrap_or(curr_head_pos);
+
+        report.truncated_bytes = log_handle.metadata()?.len() - end;
+        log_handle
+            .set_len(end)
+            .with_context(|| format!("truncating log {}", log_path.display()))?;
+        log_handle.sync_all()?;
+        report.keys = index.len();
+        write_index(&index_path, &index, &expiries, end)?;
+
+        // Compaction drops the corrupted records left in the log, as they are not indexed.
+        KvStore::open(path)?.compact()?;
+        Ok(report)
+    }
+
+    /// Compacts the log right away, dropping every record that is no longer referenced by the
+    /// index, and writes a checkpoint of the compacted log. Returns the number of bytes
+    /// reclaimed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let db = KvStore::open(&temp_dir).unwrap();
+    ///
+    /// db.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// db.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    /// assert!(db.compact().unwrap() > 0);
+    /// assert_eq!(db.get("key1".to_owned()).unwrap(), Some("value2".to_owned()));
+    /// ```
+    pub fn compact(&self) -> Result<u64> {
+        let _compaction = lock(&self.compaction);
+        self.compact_log(CompactionTrigger::Manual)
+    }
+
+    /// Sets `key` to `value`, until `expires_at` if given.
+    fn set_entry(&self, key: String, value: String, expires_at: Option<u64>) -> Result<()> {
+        let _span = debug_span!("set").entered();
+        check_length(&key, 256, KvsError::InvalidKeySize)?;
+        self.builder.key_policy.validate(&key)?;
+        check_length(&value, MAX_VALUE_SIZE, KvsError::InvalidValueSize)?;
+        self.throttle()?;
+
+        let written = self.on_writer(move |store, logwriter| {
+            store.append_set(logwriter, key, value, ValueType::String, expires_at)
+        })?;
+        self.finish_write(written)
+    }
+
+    /// Sets `key` to what `f` returns given its current value and type, or removes it, on the
+    /// writer thread, so that no other write of the key comes in between. A key set with a time
+    /// to live keeps it. Nothing is written if `f` fails.
+    fn update_entry<F>(&self, key: String, f: F) -> Result<Option<(ValueType, String)>>
+    where
+        F: FnOnce(Option<(ValueType, String)>) -> Result<Option<(ValueType, String)>>
+            + Send
+            + 'static,
+    {
+        check_length(&key, 256, KvsError::InvalidKeySize)?;
+        self.builder.key_policy.validate(&key)?;
+        self.throttle()?;
+        let (typed, written) = self.on_writer(move |store, logwriter| {
+            let old = store.get_typed(key.clone())?;
+            // The expiry of a key which expired already is not carried over.
+            let expires_at = match old {
+                Some(_) => read_lock(&store.expiries).get(&key).copied(),
+                None => None,
+            };
+            match f(old)? {
+                Some((value_type, value)) => {
+                    check_length(&value, MAX_VALUE_SIZE, KvsError::InvalidValueSize)?;
+                    let written =
+                        store.append_set(logwriter, key, value.clone(), value_type, expires_at)?;
+                    Ok((Some((value_type, value)), Some(written)))
+                }
+                None => {
+                    let removed = store.append_rm(logwriter, key, false)?;
+                    Ok((None, removed.map(|(_, written)| written)))
+                }
+            }
+        })?;
+        if let Some(written) = written {
+            self.finish_write(written)?;
+        }
+        Ok(typed)
+    }
+
+    /// Appends the record setting `key` to `value` of `value_type` to the log and indexes it.
+    fn append_set(
+        &self,
+        logwriter: &mut LogWriter,
+        key: String,
+        value: String,
+        value_type: ValueType,
+        expires_at: Option<u64>,
+    ) -> Result<Written> {
+        let mut index = self.index.write(&key);
+        let indexed = index.get(&key)?;
+        if let Some(old_pos) = &indexed {
+            old_pos.value_type.check_overwrite(&key, value_type)?;
+        }
+
+        let value_len = value.len() as u64;
+        let seq = self.next_seq();
+        let written_at = Some(unix_time_ms());
+        // An overwritten key keeps the creation time of the record it supersedes.
+        let created_at = match indexed.filter(|_| !self.is_expired(&key)) {
+            Some(old_pos) => old_pos.created_at,
+            None => written_at,
+        };
+        let cmd_head_pos = match self.builder.separate_values {
+            Some(min_size) if value.len() >= min_size => {
+                let ptr = lock(&self.values).append(&value)?;
+                let key = key.clone();
+                logwriter.write(&PointerCommand::SetRef {
+                    key,
+                    ptr,
+                    value_type,
+                    seq,
+                    expires_at,
+                    written_at,
+                    created_at,
+                })
+            }
+            _ if value.len() > CHUNK_SIZE => logwriter.write_chunked(
+                &value,
+                ChunkCommand::SetChunked {
+                    key: key.clone(),
+                    crc: crc32fast::hash(value.as_bytes()),
+                    value_type,
+                    seq,
+                    expires_at,
+                    written_at,
+                    created_at,
+                },
+            ),
+            _ => {
+                let key = key.clone();
+                logwriter.write(&Command::Set {
+                    key,
+                    value,
+                    value_type,
+                    seq,
+                    expires_at,
+                    written_at,
+                    created_at,
+                })
+            }
+        }
+        .with_context(|| format!("appending to log {}", self.log_path.display()))?;
+
+        let cmd_pos = CommandPos {
+            pos: cmd_head_pos,
+            len: logwriter.offset - cmd_head_pos,
+            created_at,
+            value_len,
+            value_type,
+        };
+
+        self.keep_uncommitted(logwriter, &key, indexed);
+        if let Some(old_pos) = index.insert(key.clone(), cmd_pos)? {
+            self.index.add_redundant(&key, old_pos.len);
+            self.supersede(&key, old_pos);
+        }
+        self.cache.remove(&key);
+        if let Some(tombstone) = lock(&self.tombstones).remove(&key) {
+            self.index.add_redundant(&key, tombstone.pos.len);
+        }
+        lock(&self.sizes).record(key.len(), value_len);
+        match expires_at {
+            Some(expires_at) => write_lock(&self.expiries).insert(key, expires_at),
+            None => write_lock(&self.expiries).remove(&key),
+        };
+
+        Ok(Written {
+            seq,
+            compact: self.needs_compaction(),
+        })
+    }
+
+    /// Appends the `Rm` record of `key` if it is in the index, only if it expired when
+    /// `only_expired`. Returns `None` if the key was not removed, and otherwise whether it had
+    /// expired. A lock, which never expires, is released instead, keeping its token.
+    fn remove_key(&self, key: String, only_expired: bool) -> Result<Option<bool>> {
+        let _span = debug_span!("remove").entered();
+        match self.on_writer(move |store, logwriter| {
+            if !only_expired {
+                if let Some((ValueType::Lock, value)) = store.get_typed(key.clone())? {
+                    let value = released(&key, &value)?;
+                    let written = store.append_set(logwriter, key, value, ValueType::Lock, None)?;
+                    return Ok(Some((false, written)));
+                }
+            }
+            store.append_rm(logwriter, key, only_expired)
+        })? {
+            Some((expired, written)) => {
+                self.finish_write(written)?;
+                Ok(Some(expired))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Appends the `Rm` record of `key` to the log and unindexes the key, if it is in the index
+    /// and, when `only_expired`, expired. Returns whether it had expired.
+    fn append_rm(
+        &self,
+        logwriter: &mut LogWriter,
+        key: String,
+        only_expired: bool,
+    ) -> Result<Option<(bool, Written)>> {
+        let mut index = self.index.write(&key);
+
+        let expired = self.is_expired(&key);
+        if only_expired && !expired {
+            return Ok(None);
+        }
+        if let Some(old_cmd_pos) = index.remove(&key)? {
+            self.keep_uncommitted(logwriter, &key, Some(old_cmd_pos));
+            let deleted_at = unix_time();
+            let seq = self.next_seq();
+            let cmd = Command::Rm {
+                key: key.clone(),
+                seq,
+                time: Some(deleted_at),
+            };
+            let cmd_head_pos = logwriter
+                .write(&cmd)
+                .with_context(|| format!("appending to log {}", self.log_path.display()))?;
+
+            let cmd_pos = CommandPos {
+                pos: cmd_head_pos,
+                len: logwriter.offset - cmd_head_pos,
+                created_at: None,
+                value_len: 0,
+                value_type: ValueType::String,
+            };
+
+            self.index
+                .add_redundant(&key, old_cmd_pos.len + cmd_pos.len);
+            self.supersede(&key, old_cmd_pos);
+            self.supersede(&key, cmd_pos);
+            write_lock(&self.expiries).remove(&key);
+            self.cache.remove(&key);
+            lock(&self.tombstones).insert(
+                key,
+                Tombstone {
+                    pos: cmd_pos,
+                    deleted_at: Some(deleted_at),
+                },
+            );
+            let written = Written {
+                seq,
+                compact: self.needs_compaction(),
+            };
+            Ok(Some((expired, written)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Runs the mutation `f` with the writer of the log on the writer thread, and returns its
+    /// result once it is committed.
+    fn on_writer<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&KvStore, &mut LogWriter) -> Result<T> + Send + 'static,
+    {
+        self.writer().write(f)
+    }
+
+    /// The writer thread, which only its own handle of the store lacks. That handle never sends
+    /// it requests.
+    fn writer(&self) -> &Writer {
+        self.writer
+            .as_deref()
+            .expect("the writer thread sends itself no requests")
+    }
+
+    /// Compacts the log if the committed write made it needed. The writer thread goes on with
+    /// the next mutations meanwhile.
+    fn finish_write(&self, written: Written) -> Result<()> {
+        if written.compact {
+            self.compact_if_needed()?;
+        }
+        Ok(())
+    }
+
+    /// Delays or rejects a write, before it reaches the writer thread, while the redundant bytes
+    /// are past the limits of the write throttle.
+    fn throttle(&self) -> Result<()> {
+        let (slowdown, stop) = match self.builder.write_throttle {
+            Some(limits) => limits,
+            None => return Ok(()),
+        };
+        let redundant_bytes = self.index.redundant_bytes();
+        if redundant_bytes >= stop {
+            warn!(
+                redundant_bytes,
+                "Stalled a write until compaction catches up."
+            );
+            return Err(KvsError::WriteStall(redundant_bytes));
+        }
+        if redundant_bytes >= slowdown {
+            let behind = (redundant_bytes - slowdown) as f64 / (stop - slowdown) as f64;
+            thread::sleep(MAX_THROTTLE_DELAY.mul_f64(behind));
+        }
+        Ok(())
+    }
+
+    /// Compacts the log if it needs to and no compaction is running yet. Called by the writers
+    /// once their write is committed.
+    fn compact_if_needed(&self) -> Result<()> {
+        let _compaction = match self.compaction.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return Ok(()),
+        };
+        if self.needs_compaction() {
+            self.compact_log(CompactionTrigger::Automatic)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the redundant bytes of the log reached the threshold, or the compaction ratio of
+    /// the log if configured.
+    fn needs_compaction(&self) -> bool {
+        let redundant_bytes = self.index.redundant_bytes();
+        if redundant_bytes >= REDUNDANCY_THRESHOLD {
+            return true;
+        }
+        match self.builder.compaction_ratio {
+            Some(ratio) => {
+                redundant_bytes >= MIN_RATIO_REDUNDANCY
+                    && redundant_bytes as f64 >= ratio * self.head.offset() as f64
+            }
+            None => false,
+        }
+    }
+
+    /// Caches the values `read` from `version` along with their key and the position of their
+    /// record, unless a write or a compaction moved the key since. Cached and evicted under the
+    /// index lock, so that a value read before a write cannot be cached after it.
+    fn cache_if_current(
+        &self,
+        version: &LogVersion,
+        read: &[(&str, CommandPos, &str)],
+    ) -> Result<()> {
+        if read.is_empty() {
+            return Ok(());
+        }
+        let current = read_lock(&self.version);
+        if current.generation != version.generation {
+            return Ok(());
+        }
+        let keys: Vec<String> = read.iter().map(|(key, _, _)| key.to_string()).collect();
+        let index = self.index.read_keys(&keys);
+        for &(key, cmd_pos, value) in read {
+            if index
+                .get(key)?
+                .is_some_and(|current| current.pos == cmd_pos.pos)
+            {
+                self.cache.insert(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the values referenced by the records at `live` take less than half of the value
+    /// log, which makes rewriting them worth it.
+    fn value_log_mostly_garbage(&self, live: &[CommandPos], logreader: &LogReader) -> Result<bool> {
+        let size = lock(&self.values).size()?;
+        if size == 0 {
+            return Ok(false);
+        }
+        let mut live_values = 0;
+        for cmd_pos in live {
+            if let (_, Some(ptr)) = logreader.read_entry_in_pos(cmd_pos.pos, cmd_pos.len)? {
+                live_values += ptr.len;
+            }
+        }
+        Ok(live_values * 2 < size)
+    }
+
+    /// Rewrites the live records into a new log without blocking the writer thread and the
+    /// readers, which only wait for the final switch. Returns the number of bytes reclaimed.
+    ///
+    /// The live records are first copied from a snapshot of the index, while the writer thread
+    /// keeps appending to the log. The records it appended meanwhile are then copied as well by
+    /// the writer thread, under the locks, and every index entry is swung to the new position of
+    /// its record.
+    fn compact_log(&self, trigger: CompactionTrigger) -> Result<u64> {
+        let _span = info_span!("compaction", log = %self.log_path.display()).entered();
+        let started_at = SystemTime::now();
+        let started = Instant::now();
+
+        let CompactionStart {
+            live,
+            end: snapshot_end,
+            log,
+            lookaside,
+        } = self.writer().call(Request::StartCompaction)?;
+        let reader = LogReader::new(
+            log,
+            lookaside,
+            self.log_path.to_path_buf(),
+            Arc::clone(&read_lock(&self.version).reader.values),
+            self.builder.sequential_capacity(),
+        );
+
+        let tmp_log = format!("{}.tmp", self.log_path.display());
+        // A compacted log left over by a failed compaction or a crash was never renamed over
+        // the log, which is still in place, and is started over rather than appended to.
+        if Path::new(&tmp_log).exists() {
+            warn!(path = %tmp_log, "Removing the compacted log left over by a failed compaction.");
+            fs::remove_file(&tmp_log)
+                .with_context(|| format!("removing compacted log {}", tmp_log))?;
+        }
+        let new_storage = self
+            .builder
+            .log_storage
+            .open(Path::new(&tmp_log))
+            .with_context(|| format!("creating compacted log {}", tmp_log))?;
+        let mut new_log = CompactedLog {
+            writer: BufWriter::with_capacity(
+                self.builder.sequential_capacity(),
+                StorageWriter(Arc::clone(&new_storage)),
+            ),
+            path: tmp_log,
+            // Archived logs keep pointing to the values they were compacted with.
+            values: if self.builder.archive_retention.is_none()
+                && self.value_log_mostly_garbage(&live, &reader)?
+            {
+                Some(lock(&self.values).create_next()?)
+            } else {
+                None
+            },
+            moved: HashMap::new(),
+            end: 0,
+            read: 0,
+            written: 0,
+            buf: Vec::new(),
+        };
+        for cmd_pos in live {
+            new_log.copy(&reader, cmd_pos)?;
+        }
+
+        let compaction = Compaction {
+            trigger,
+            started_at,
+            started,
+            reader,
+            snapshot_end,
+            new_log,
+        };
+        self.writer()
+            .call(|reply| Request::SwitchLog(Box::new(compaction), reply))
+    }
+
+    /// Lists the keys of a cursor on the writer thread, so that they match the end of the log
+    /// recorded along with them.
+    fn cursor_snapshot(&self, logwriter: &mut LogWriter) -> Result<(Vec<String>, Snapshot)> {
+        let keys = self.scan();
+        let offset = logwriter.offset;
+        logwriter.flush()?;
+        let check = log_check(&**logwriter.storage(), offset)?;
+        Ok((keys, Snapshot { offset, check }))
+    }
+
+    /// Flushes the log on the writer thread for a compaction, and returns the positions of the
+    /// records it keeps.
+    fn start_compaction(&self, logwriter: &mut LogWriter) -> Result<CompactionStart> {
+        let index = self.index.read_all();
+        logwriter
+            .flush()
+            .with_context(|| format!("flushing log {}", self.log_path.display()))?;
+
+        let policy = self.builder.tombstone_policy;
+        let now = unix_time();
+        let tombstones = lock(&self.tombstones);
+        let retained = tombstones
+            .values()
+            .filter(|tombstone| policy.retains(tombstone, now))
+            .map(|tombstone| tombstone.pos);
+        let live = index.positions()?.into_iter().chain(retained).collect();
+        Ok(CompactionStart {
+            live,
+            end: logwriter.offset,
+            log: Arc::clone(logwriter.storage()),
+            lookaside: Arc::clone(&logwriter.lookaside),
+        })
+    }
+
+    /// Ends `compaction` on the writer thread: copies the records written since it started,
+    /// then switches the writer, the index and the readers to the compacted log.
+    fn switch_log(&self, logwriter: &mut LogWriter, compaction: Compaction) -> Result<u64> {
+        let Compaction {
+            trigger,
+            started_at,
+            started,
+            reader,
+            snapshot_end,
+            mut new_log,
+        } = compaction;
+        let tmp_log = new_log.path.clone();
+        let new_storage = Arc::clone(&new_log.writer.get_ref().0);
+
+        let mut version = write_lock(&self.version);
+        let mut index = self.index.write_all();
+        logwriter
+            .flush()
+            .with_context(|| format!("flushing log {}", self.log_path.display()))?;
+        let old_end = logwriter.offset;
+        for cmd_pos in reader.positions_from(snapshot_end)? {
+            new_log.copy(&reader, cmd_pos)?;
+        }
+
+        if let Some(new_values) = new_log.values.as_mut() {
+            new_values
+                .sync()
+                .with_context(|| format!("syncing value log {}", new_values.path().display()))?;
+        }
+        new_log
+            .writer
+            .flush()
+            .and_then(|()| new_storage.sync())
+            .with_context(|| format!("syncing compacted log {}", tmp_log))?;
+
+        // Everything which can fail is done before the rename, so that a failure leaves the
+        // store as it was, on the old log.
+        let staged = index.stage_positions(|cmd_pos| {
+            new_log.moved.get(&cmd_pos.pos).copied().ok_or_else(|| {
+                KvsError::Internal(format!(
+                    "record at offset {} was not compacted",
+                    cmd_pos.pos
+                ))
+            })
+        })?;
+
+        // The offsets of the index file are about to go stale: without it, a crash before the
+        // new checkpoint is written only costs a full replay of the log.
+        let dir = self.log_path.parent().unwrap_or_else(|| Path::new("."));
+        if self.index_path.exists() {
+            fs::remove_file(self.index_path.deref())
+                .with_context(|| format!("removing index file {}", self.index_path.display()))?;
+            sync_dir(dir)?;
+        }
+        if let Some(retention) = self.builder.archive_retention {
+            let archived = archive::archive_log(&self.log_path, retention)?;
+            info!(archived = %archived.display(), "Archived the compacted log.");
+        }
+        // Renamed over the log at once, so that a crash leaves one log or the other in place.
+        fs::rename(&tmp_log, self.log_path.deref())
+            .with_context(|| format!("renaming {} to {}", tmp_log, self.log_path.display()))?;
+
+        // From here on the old log is gone, so the store is switched whatever fails next.
+        let mut live_bytes: u64 = staged.iter().map(StagedPositions::live_bytes).sum();
+        index.apply_positions(staged);
+        let mut tombstones = lock(&self.tombstones);
+        tombstones.retain(|_, tombstone| match new_log.moved.get(&tombstone.pos.pos) {
+            Some(cmd_pos) => {
+                tombstone.pos = *cmd_pos;
+                true
+            }
+            None => false,
+        });
+        live_bytes += tombstones
+            .values()
+            .map(|tombstone| tombstone.pos.len)
+            .sum::<u64>();
+        drop(tombstones);
+
+        // The storage of the compacted log stays open when it is renamed over the old one.
+        let lookaside = Arc::new(Lookaside::new(new_log.end));
+        logwriter.storage = Arc::clone(&new_storage);
+        logwriter.lookaside = Arc::clone(&lookaside);
+        logwriter.offset = new_log.end;
+        logwriter.reserved = 0;
+        // The reads which started before the switch finish on the old version, whose storage
+        // and value logs go away with its last reader.
+        let generation = version.generation + 1;
+        let value_reader = match &new_log.values {
+            Some(new_values) => Arc::new(new_values.reader()),
+            None => Arc::clone(&version.reader.values),
+        };
+        let old_version = mem::replace(
+            &mut *version,
+            Arc::new(LogVersion::new(
+                LogReader::new(
+                    new_storage,
+                    lookaside,
+                    self.log_path.to_path_buf(),
+                    value_reader,
+                    self.builder.read_capacity(),
+                ),
+                generation,
+            )),
+        );
+
+        self.head.replace(new_log.end);
+        if let Some(new_values) = new_log.values {
+            let path = new_values.path();
+            *lock(&old_version.retired) = lock(&self.values).replace(new_values);
+            info!(value_log = %path.display(), "Rewrote the value log.");
+        }
+        drop(old_version);
+        // The records superseded during the compaction were copied along.
+        self.index.reset_redundant(new_log.end - live_bytes);
+        lock(&self.history).clear();
+        // The switch is complete, so these only fail to make it durable.
+        sync_dir(dir)?;
+        write_index(
+            &self.index_path,
+            &index,
+            &read_lock(&self.expiries),
+            new_log.end,
+        )?;
+        logwriter.checkpointed = logwriter.records;
+        let reclaimed = old_end.saturating_sub(new_log.end);
+        info!(live_bytes, reclaimed, "Compacted the log.");
+        lock(&self.compactions).record(CompactionStats {
+            trigger,
+            started_at,
+            finished_at: SystemTime::now(),
+            duration: started.elapsed(),
+            bytes_read: new_log.read,
+            bytes_written: new_log.written,
+            bytes_reclaimed: reclaimed,
+        });
+
+        Ok(reclaimed)
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Insert the `key`(up to 256B) with `value`(up to 16MB) to the DataBase.
+    ///
+    /// If the `key` already exists, update the associated value to `value` while keep the key
+    /// unchanged.
+    ///
+    /// # Errors
+    /// If the size of key or value exceeds the limitation, then an error is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// println!("{:?}", temp_dir);
+    /// let mut db = KvStore::open(&temp_dir).unwrap();
+    /// db.set("key1".to_owned(), "value1".to_owned()).unwrap(); // insert the record successfully.
+    ///
+    /// let big_key: Vec<u8> = vec![0; 257];
+    /// let big_key = String::from_utf8(big_key).unwrap(); // A key in size of 257B
+    ///
+    /// db.set(big_key, "value".to_owned()).expect_err("expect err there"); // set returns an error
+    /// ```
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_entry(key, value, None)
+    }
+
+    /// Returns the value associated with the key.
+    ///
+    /// # Errors
+    /// Return an error if the value in log file is not read successfully.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let mut db = KvStore::open(&temp_dir).unwrap();
+    ///
+    /// db.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// assert_eq!(db.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    /// assert_eq!(db.get("key2".to_owned()).unwrap(), None);
+    /// ```
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let _span = debug_span!("get").entered();
+        // The records not flushed yet are read from the lookaside of the log, so the reads
+        // neither wait for the writer thread nor flush the log.
+        let (version, cmd_pos) = {
+            let version = read_lock(&self.version);
+            let index = self.index.read(&key);
+            let cmd_pos = match index.get(&key)?.filter(|_| !self.is_expired(&key)) {
+                Some(cmd_pos) => cmd_pos,
+                None => return Ok(None),
+            };
+            cmd_pos.value_type.check(&key, ValueType::String)?;
+            if let Some(value) = self.cache.get(&key) {
+                return Ok(Some(value));
+            }
+            (Arc::clone(&version), cmd_pos)
+        };
+
+        // Read without the locks, from the version of the log the position points into, which
+        // stays readable if a compaction switches logs meanwhile.
+        let cmd = version
+            .reader
+            .read_in_pos(cmd_pos.pos, cmd_pos.len)
+            .with_context(|| {
+                format!(
+                    "reading key {:?} from log {} at offset {}",
+                    key,
+                    self.log_path.display(),
+                    cmd_pos.pos
+                )
+            })?;
+        match cmd {
+            Command::Set { value, .. } => {
+                self.cache_if_current(&version, &[(key.as_str(), cmd_pos, value.as_str())])?;
+                Ok(Some(value))
+            }
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Looks all the keys up in a single pass over the index, holding the locks of their shards
+    /// once, and reads the records of the values not cached in the order of the log.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let _span = debug_span!("get_many", keys = keys.len()).entered();
+        let mut values = vec![None; keys.len()];
+        let mut reads = Vec::new();
+        let version = {
+            let version = read_lock(&self.version);
+            let index = self.index.read_keys(&keys);
+            for (i, key) in keys.iter().enumerate() {
+                if let Some(cmd_pos) = index.get(key)?.filter(|_| !self.is_expired(key)) {
+                    cmd_pos.value_type.check(key, ValueType::String)?;
+                    match self.cache.get(key) {
+                        Some(value) => values[i] = Some(value),
+                        None => reads.push((cmd_pos, i)),
+                    }
+                }
+            }
+            Arc::clone(&version)
+        };
+
+        reads.sort_by_key(|(cmd_pos, _)| cmd_pos.pos);
+        for &(cmd_pos, i) in &reads {
+            let key = &keys[i];
+            let cmd = version
+                .reader
+                .read_in_pos(cmd_pos.pos, cmd_pos.len)
+                .with_context(|| {
+                    format!(
+                        "reading key {:?} from log {} at offset {}",
+                        key,
+                        self.log_path.display(),
+                        cmd_pos.pos
+                    )
+                })?;
+            match cmd {
+                Command::Set { value, .. } => values[i] = Some(value),
+                _ => return Err(KvsError::KeyNotFound),
+            }
+        }
+        let read: Vec<(&str, CommandPos, &str)> = reads
+            .iter()
+            .filter_map(|&(cmd_pos, i)| {
+                let value = values[i].as_deref()?;
+                Some((keys[i].as_str(), cmd_pos, value))
+            })
+            .collect();
+        self.cache_if_current(&version, &read)?;
+        Ok(values)
+    }
+
+    /// Returns the length in bytes of the value of `key`, kept in the index along with the
+    /// position of its record, which is not read.
+    fn strlen(&self, key: String) -> Result<Option<u64>> {
+        let _span = debug_span!("strlen").entered();
+        let index = self.index.read(&key);
+        match index.get(&key)?.filter(|_| !self.is_expired(&key)) {
+            Some(cmd_pos) => {
+                cmd_pos.value_type.check(&key, ValueType::String)?;
+                Ok(Some(cmd_pos.value_len))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the times and the version from the record of the key. The version is 0 unless the store is
+    /// [`versioned`](struct.KvStoreBuilder.html#method.versioned).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let db = KvStore::open(&temp_dir).unwrap();
+    ///
+    /// db.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// let created = db.get_metadata("key1".to_owned()).unwrap().unwrap();
+    /// db.set("key1".to_owned(), "value10".to_owned()).unwrap();
+    /// let modified = db.get_metadata("key1".to_owned()).unwrap().unwrap();
+    /// assert_eq!(modified.created_at, created.created_at);
+    /// assert!(modified.modified_at >= created.modified_at);
+    /// assert_eq!(modified.size, 7);
+    /// ```
+    fn get_metadata(&self, key: String) -> Result<Option<KeyMetadata>> {
+        let _span = debug_span!("get_metadata").entered();
+        let (version, cmd_pos) = {
+            let version = read_lock(&self.version);
+            let index = self.index.read(&key);
+            match index.get(&key)?.filter(|_| !self.is_expired(&key)) {
+                Some(cmd_pos) => (Arc::clone(&version), cmd_pos),
+                None => return Ok(None),
+            }
+        };
+        let entry = version
+            .reader
+            .read_entry_in_pos(cmd_pos.pos, cmd_pos.len)
+            .with_context(|| {
+                format!(
+                    "reading key {:?} from log {} at offset {}",
+                    key,
+                    self.log_path.display(),
+                    cmd_pos.pos
+                )
+            })?;
+        match entry {
+            (
+                Command::Set {
+                    value,
+                    seq,
+                    written_at,
+                    created_at,
+                    ..
+                },
+                ptr,
+            ) => Ok(Some(KeyMetadata {
+                created_at,
+                modified_at: written_at,
+                version: seq.unwrap_or(0),
+                size: ptr.map_or(value.len() as u64, |ptr| ptr.len),
+            })),
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Removes the key and associated value from the DataBase.
+    ///
+    /// # Errors
+    /// Return an error if the key does not exist or is not removed successfully.
+    ///
+    /// # Examples
+    /// ```
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let mut db = KvStore::open(&temp_dir).unwrap();
+    ///
+    /// db.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// db.remove("key1".to_owned()).expect("Expect Ok(()) here"); // Removes "key1" from the DataBase
+    ///
+    /// db.remove("key2".to_owned()).expect_err("Expect KeyNotFound Err."); // "key2" doesn't in DataBase.
+    /// ```
+    fn remove(&self, key: String) -> Result<()> {
+        self.throttle()?;
+        match self.remove_key(key, false)? {
+            Some(false) => Ok(()),
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Returns an iterator of all the keys in the DataBase. If the DataBase is empty, returns an
+    /// empty iterator. The order of the keys is arbitrary.
+    /// # Examples
+    /// ```
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let mut db = KvStore::open(&temp_dir).unwrap();
+    ///
+    /// db.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// db.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    ///
+    /// for k in db.scan() {
+    ///     println!("key: {}", k); // print all the keys in the DataBase
+    /// }
+    /// ```
+    fn scan(&self) -> Vec<String> {
+        let keys = self.index.read_all().keys().unwrap_or_else(|e| {
+            error!(error = %e, "Failed to read the spilled index.");
+            Vec::new()
+        });
+        let now = unix_time_ms();
+        let expiries = read_lock(&self.expiries);
+        keys.into_iter()
+            .filter(|key| expiries.get(key).is_none_or(|&expires_at| expires_at > now))
+            .collect()
+    }
+
+    /// Walks the keys of the index in order from `after`, so that a page only reads the keys it
+    /// lists and the expired ones among them, besides a block of every spilled shard.
+    fn list_keys(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        let index = self.index.read_all();
+        let now = unix_time_ms();
+        let expiries = read_lock(&self.expiries);
+        let mut page = Vec::new();
+        for key in index.keys_after(after)? {
+            if page.len() == limit {
+                break;
+            }
+            let key = key?;
+            if expiries
+                .get(&key)
+                .is_none_or(|&expires_at| expires_at > now)
+            {
+                page.push(key);
+            }
+        }
+        Ok(page)
+    }
+
+    /// Matches `pattern` against the keys of the index as it walks it, so that only the
+    /// matching ones are copied.
+    fn keys(&self, pattern: &str) -> Vec<String> {
+        let mut keys = Vec::new();
+        let walked = self.index.read_all().for_each(|key, _| {
+            if glob::matches(pattern, key) {
+                keys.push(key.to_owned());
+            }
+            Ok(())
+        });
+        if let Err(e) = walked {
+            error!(error = %e, "Failed to read the spilled index.");
+            return Vec::new();
+        }
+        let now = unix_time_ms();
+        let expiries = read_lock(&self.expiries);
+        keys.retain(|key| expiries.get(key).is_none_or(|&expires_at| expires_at > now));
+        keys
+    }
+
+    /// Records the end of the log along with the keys, so that the cursor can be resumed over
+    /// the same keys, even once the store is reopened, until the log is compacted.
+    fn cursor(&self) -> KeyCursor {
+        match self.writer().call(Request::Cursor) {
+            Ok((keys, snapshot)) => KeyCursor::with_snapshot(keys, Some(snapshot)),
+            Err(e) => {
+                error!(error = %e, "Failed to record the snapshot of a cursor.");
+                KeyCursor::with_snapshot(self.scan(), None)
+            }
+        }
+    }
+
+    /// Replays the log up to the end recorded by the cursor to find its keys again, leaving out
+    /// those which expired since.
+    ///
+    /// # Errors
+    /// Returns `KvsError::StaleOffset` if the log was compacted since the cursor was created.
+    fn resume_cursor(&self, token: &CursorToken) -> Result<KeyCursor> {
+        let snapshot = match token.snapshot {
+            Some(snapshot) => snapshot,
+            None => return Ok(KeyCursor::resume(self.scan(), token)),
+        };
+        let _span = debug_span!("resume_cursor", offset = snapshot.offset).entered();
+        // The version is kept, so that a compaction meanwhile does not pull the log away.
+        let version = Arc::clone(&read_lock(&self.version));
+        let reader = &version.reader;
+        match log_check(&*reader.storage, snapshot.offset) {
+            Ok(check) if check == snapshot.check => (),
+            Ok(_) => return Err(KvsError::StaleOffset(snapshot.offset)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(KvsError::StaleOffset(snapshot.offset))
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let now = unix_time_ms();
+        let mut keys = HashMap::new();
+        for cmd_pos in reader.positions_from(0)? {
+            if cmd_pos.pos >= snapshot.offset {
+                break;
+            }
+            match reader.read_entry_in_pos(cmd_pos.pos, cmd_pos.len)?.0 {
+                Command::Set {
+                    key, expires_at, ..
+                } => keys.insert(key, expires_at),
+                Command::Rm { key, .. } => keys.remove(&key),
+            };
+        }
+        let keys = keys
+            .into_iter()
+            .filter(|(_, expires_at)| expires_at.is_none_or(|expires_at| expires_at > now))
+            .map(|(key, _)| key)
+            .collect();
+        Ok(KeyCursor::resume(keys, token))
+    }
+
+    /// Reports the number of keys and the compactions since the store was opened, with the
+    /// statistics of the last one. Times are in milliseconds since the Unix epoch.
+    fn info_lines(&self) -> Vec<String> {
+        let stats = self.stats();
+        let mut lines = vec![
+            format!("keys:{}", stats.keys),
+            format!("compactions:{}", stats.compactions),
+        ];
+        if let Some(last) = stats.recent_compactions.last() {
+            let trigger = match last.trigger {
+                CompactionTrigger::Automatic => "automatic",
+                CompactionTrigger::Manual => "manual",
+            };
+            let unix_ms = |time: SystemTime| {
+                time.duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_millis())
+            };
+            lines.extend(vec![
+                format!("last_compaction_trigger:{}", trigger),
+                format!("last_compaction_started_at:{}", unix_ms(last.started_at)),
+                format!("last_compaction_finished_at:{}", unix_ms(last.finished_at)),
+                format!("last_compaction_duration_us:{}", last.duration.as_micros()),
+                format!("last_compaction_bytes_read:{}", last.bytes_read),
+                format!("last_compaction_bytes_written:{}", last.bytes_written),
+                format!("last_compaction_bytes_reclaimed:{}", last.bytes_reclaimed),
+            ]);
+        }
+        lines
+    }
+
+    /// Sets `key` to `value` like [`set`](#method.set), for `ttl` only. Once expired, the key
+    /// is no longer found, and a background sweeper removes it from the log.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let db = KvStore::open(&temp_dir).unwrap();
+    ///
+    /// let ttl = Duration::from_secs(60);
+    /// db.set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl).unwrap();
+    /// assert_eq!(db.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    /// assert!(db.ttl("key1".to_owned()).unwrap().unwrap() <= ttl);
+    /// ```
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = unix_time_ms().saturating_add(ttl.as_millis() as u64);
+        self.set_entry(key, value, Some(expires_at))
+    }
+
+    /// Makes the existing `key` expire after `ttl`, by writing its value again with the time to
+    /// live, on the writer thread so that no other write of the key comes in between.
+    ///
+    /// # Errors
+    /// Returns `KvsError::KeyNotFound` if the key does not exist or expired, and
+    /// `KvsError::WrongType` if it is a lock, which would lose its token once expired.
+    fn expire(&self, key: String, ttl: Duration) -> Result<()> {
+        let _span = debug_span!("expire").entered();
+        let expires_at = unix_time_ms().saturating_add(ttl.as_millis() as u64);
+        self.throttle()?;
+        let written = self.on_writer(move |store, logwriter| {
+            let (value_type, value) = store.get_typed(key.clone())?.ok_or(KvsError::KeyNotFound)?;
+            if value_type == ValueType::Lock {
+                return Err(KvsError::WrongType {
+                    key,
+                    expected: ValueType::String.name(),
+                });
+            }
+            store.append_set(logwriter, key, value, value_type, Some(expires_at))
+        })?;
+        self.finish_write(written)
+    }
+
+    /// Applies `f` on the writer thread, so that no other write of the key comes in between. A
+    /// key set with a time to live keeps it.
+    ///
+    /// # Errors
+    /// Returns an error if the key or the new value is too large, as `set` does.
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String> + Send + 'static,
+    {
+        let _span = debug_span!("update").entered();
+        let checked = key.clone();
+        let typed = self.update_entry(key, move |old| {
+            let old = match old {
+                Some((value_type, value)) => {
+                    value_type.check(&checked, ValueType::String)?;
+                    Some(value)
+                }
+                None => None,
+            };
+            Ok(f(old).map(|value| (ValueType::String, value)))
+        })?;
+        Ok(typed.map(|(_, value)| value))
+    }
+
+    /// Reads the value of `key` and its type like `get_versioned`, past the cache of the
+    /// strings.
+    fn get_typed(&self, key: String) -> Result<Option<(ValueType, String)>> {
+        let _span = debug_span!("get_typed").entered();
+        let (version, cmd_pos) = {
+            let version = read_lock(&self.version);
+            let index = self.index.read(&key);
+            match index.get(&key)?.filter(|_| !self.is_expired(&key)) {
+                Some(cmd_pos) => (Arc::clone(&version), cmd_pos),
+                None => return Ok(None),
+            }
+        };
+        let cmd = version
+            .reader
+            .read_in_pos(cmd_pos.pos, cmd_pos.len)
+            .with_context(|| {
+                format!(
+                    "reading key {:?} from log {} at offset {}",
+                    key,
+                    self.log_path.display(),
+                    cmd_pos.pos
+                )
+            })?;
+        match cmd {
+            Command::Set {
+                value, value_type, ..
+            } => Ok(Some((value_type, value))),
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Applies `f` on the writer thread, as [`update`](#method.update) does.
+    ///
+    /// # Errors
+    /// Returns an error if the key or the new value is too large, as `set` does.
+    fn update_typed<F>(&self, key: String, f: F) -> Result<Option<(ValueType, String)>>
+    where
+        F: FnOnce(Option<(ValueType, String)>) -> Option<(ValueType, String)> + Send + 'static,
+    {
+        let _span = debug_span!("update_typed").entered();
+        self.update_entry(key, move |old| Ok(f(old)))
+    }
+
+    /// Returns the value of `key` with the sequence number of the write which set it, 0 if it was
+    /// written before the store was versioned.
+    ///
+    /// # Errors
+    /// Returns `KvsError::CmdNotSupport` unless the store is
+    /// [`versioned`](struct.KvStoreBuilder.html#method.versioned).
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        if !self.builder.versioned {
+            return Err(KvsError::CmdNotSupport);
+        }
+        let _span = debug_span!("get_versioned").entered();
+        // Read like `get`, but past the cache, which does not keep the sequence numbers.
+        let (version, cmd_pos) = {
+            let version = read_lock(&self.version);
+            let index = self.index.read(&key);
+            match index.get(&key)?.filter(|_| !self.is_expired(&key)) {
+                Some(cmd_pos) => (Arc::clone(&version), cmd_pos),
+                None => return Ok(None),
+            }
+        };
+        cmd_pos.value_type.check(&key, ValueType::String)?;
+        let cmd = version
+            .reader
+            .read_in_pos(cmd_pos.pos, cmd_pos.len)
+            .with_context(|| {
+                format!(
+                    "reading key {:?} from log {} at offset {}",
+                    key,
+                    self.log_path.display(),
+                    cmd_pos.pos
+                )
+            })?;
+        match cmd {
+            Command::Set { value, seq, .. } => Ok(Some((value, seq.unwrap_or(0)))),
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Compares the versions and sets the key on the writer thread, so that no other write of
+    /// the key comes in between. The key loses its time to live, as with `set`.
+    ///
+    /// # Errors
+    /// Returns `KvsError::CmdNotSupport` unless the store is
+    /// [`versioned`](struct.KvStoreBuilder.html#method.versioned).
+    fn set_if_version(&self, key: String, value: String, expected: Option<u64>) -> Result<u64> {
+        if !self.builder.versioned {
+            return Err(KvsError::CmdNotSupport);
+        }
+        let _span = debug_span!("set_if_version").entered();
+        check_length(&key, 256, KvsError::InvalidKeySize)?;
+        self.builder.key_policy.validate(&key)?;
+        check_length(&value, MAX_VALUE_SIZE, KvsError::InvalidValueSize)?;
+        self.throttle()?;
+        let written = self.on_writer(move |store, logwriter| {
+            let actual = store
+                .get_versioned(key.clone())?
+                .map(|(_, version)| version);
+            if actual != expected {
+                return Err(KvsError::VersionMismatch { expected, actual });
+            }
+            store.append_set(logwriter, key, value, ValueType::String, None)
+        })?;
+        let seq = written.seq.expect("a versioned store numbers its writes");
+        self.finish_write(written)?;
+        Ok(seq)
+    }
+
+    /// Returns how long `key` has left to live, `None` if it was set without a time to live.
+    ///
+    /// # Errors
+    /// Returns `KvsError::KeyNotFound` if the key does not exist or expired.
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        if self.index.read(&key).get(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        let now = unix_time_ms();
+        match read_lock(&self.expiries).get(&key) {
+            Some(&expires_at) if expires_at <= now => Err(KvsError::KeyNotFound),
+            Some(&expires_at) => Ok(Some(Duration::from_millis(expires_at - now))),
+            None => Ok(None),
+        }
+    }
+
+    /// Store index file of DataBase to disk, see [`checkpoint`](#method.checkpoint).
+    fn save_index_log(&self) -> Result<()> {
+        self.checkpoint().map(|_| ())
+    }
+}
+
+/// A version of a key, as returned by [`KvStore::get_history`](struct.KvStore.html#method.get_history).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    /// The sequence number of the write, `None` if it was written by a store that was not
+    /// versioned.
+    pub seq: Option<u64>,
+    /// The value set by the write, `None` if the key was removed.
+    pub value: Option<String>,
+}
+
+/// A mutation recorded in the log, as returned by [`KvStore::tail`](struct.KvStore.html#method.tail).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Command {
+    /// `key` was set to `value`.
+    Set {
+        /// The key written.
+        key: String,
+        /// The value written.
+        value: String,
+        /// The type of the value, unless it is a string.
+        #[serde(default, skip_serializing_if = "ValueType::is_string")]
+        value_type: ValueType,
+        /// The sequence number of the write, only recorded by a versioned store.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        /// When the key expires, in milliseconds since the Unix epoch, if it was set with a
+        /// time to live.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        /// When the key was written, in milliseconds since the Unix epoch, unless the record
+        /// predates the timestamps.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        written_at: Option<u64>,
+        /// When the key was first set since it last did not exist, in milliseconds since the
+        /// Unix epoch, if known.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        created_at: Option<u64>,
+    },
+    /// `key` was removed.
+    Rm {
+        /// The key removed.
+        key: String,
+        /// The sequence number of the write, only recorded by a versioned store.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        /// When the key was removed, in seconds since the Unix epoch.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        time: Option<u64>,
+    },
+}
+
+/// The `Rm` record of a removed key.
+#[derive(Clone, Copy)]
+struct Tombstone {
+    pos: CommandPos,
+    /// When the key was removed, `None` for records written before removals were timestamped.
+    deleted_at: Option<u64>,
+}
+
+impl TombstonePolicy {
+    fn retains(self, tombstone: &Tombstone, now: u64) -> bool {
+        match self {
+            TombstonePolicy::Drop => false,
+            TombstonePolicy::Keep => true,
+            TombstonePolicy::Retain(window) => tombstone.deleted_at.is_some_and(|deleted_at| {
+                Duration::from_secs(now.saturating_sub(deleted_at)) < window
+            }),
+        }
+    }
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Command {
+    /// Returns the sequence number of the write, if the store is versioned.
+    pub fn seq(&self) -> Option<u64> {
+        match self {
+            Command::Set { seq, .. } | Command::Rm { seq, .. } => *seq,
+        }
+    }
+}
+
+/// What [`KvStore::repair`](struct.KvStore.html#method.repair) found in the log.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// The number of intact records replayed into the index.
+    pub recovered_records: u64,
+    /// The number of records dropped because their checksum did not match.
+    pub corrupted_records: u64,
+    /// The number of bytes cut from the end of the log because they could not be decoded.
+    pub truncated_bytes: u64,
+    /// The number of keys in the rebuilt index.
+    pub keys: usize,
+}
+
+/// A command framed with the CRC32 of its serialized form, so that corrupted records are
+/// detected when they are read back.
+#[derive(Deserialize, Serialize)]
+struct Record<C> {
+    crc: u32,
+    cmd: C,
+}
+
+impl<C: Serialize> Record<C> {
+    /// Returns the command of the record located at `offset` in the log at `path`, verifying
+    /// its checksum.
+    fn verify(self, path: &Path, offset: u64) -> Result<C> {
+        let actual = crc32fast::hash(&serde_json::to_vec(&self.cmd)?);
+        if actual == self.crc {
+            Ok(self.cmd)
+        } else {
+            Err(KvsError::Corruption {
+                path: path.to_path_buf(),
+                offset,
+                expected: self.crc,
+                actual,
+            })
+        }
+    }
+}
+
+/// Serializes `cmd` as a record of the log.
+fn encode<C: Serialize>(cmd: &C) -> Result<Vec<u8>> {
+    let crc = crc32fast::hash(&serde_json::to_vec(cmd)?);
+    Ok(serde_json::to_vec(&Record { crc, cmd })?)
+}
+
+/// An entry of the log. Logs written before records were checksummed hold bare commands.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LogEntry {
+    Record(Record<Command>),
+    Pointer(Record<PointerCommand>),
+    Chunk(Record<ChunkCommand>),
+    Legacy(Command),
+}
+
+impl LogEntry {
+    /// Returns the command of the entry located at `offset` in the log at `path`, verifying its
+    /// checksum. The value of a `Set` stored in the value log is left empty, and its pointer
+    /// returned alongside. The chunks of a value are reassembled by an
+    /// [`Assembler`](chunks/struct.Assembler.html) instead.
+    fn into_command(self, path: &Path, offset: u64) -> Result<(Command, Option<ValuePtr>)> {
+        match self {
+            LogEntry::Record(record) => Ok((record.verify(path, offset)?, None)),
+            LogEntry::Pointer(record) => {
+                let PointerCommand::SetRef {
+                    key,
+                    ptr,
+                    value_type,
+                    seq,
+                    expires_at,
+                    written_at,
+                    created_at,
+                } = record.verify(path, offset)?;
+                let value = String::new();
+                let cmd = Command::Set {
+                    key,
+                    value,
+                    value_type,
+                    seq,
+                    expires_at,
+                    written_at,
+                    created_at,
+                };
+                Ok((cmd, Some(ptr)))
+            }
+            LogEntry::Chunk(_) => Err(KvsError::Internal(format!(
+                "chunk at offset {} of log {} read on its own",
+                offset,
+                path.display()
+            ))),
+            LogEntry::Legacy(cmd) => Ok((cmd, None)),
+        }
+    }
+}
+
+/// Returns `cmd` with its value read from `values` if `ptr` points to it.
+fn resolve(cmd: Command, ptr: Option<ValuePtr>, values: &ValueReader) -> Result<Command> {
+    match (cmd, ptr) {
+        (
+            Command::Set {
+                key,
+                value_type,
+                seq,
+                expires_at,
+                written_at,
+                created_at,
+                ..
+            },
+            Some(ptr),
+        ) => {
+            let value = values.read(ptr)?;
+            Ok(Command::Set {
+                key,
+                value,
+                value_type,
+                seq,
+                expires_at,
+                written_at,
+                created_at,
+            })
+        }
+        (cmd, _) => Ok(cmd),
+    }
+}
+
+/// Where a record is in the log, with what is known of the key it writes without reading it.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+struct CommandPos {
+    pos: u64,
+    len: u64,
+    /// When the key set by the record was created, in milliseconds since the Unix epoch, if
+    /// known. `None` for a removal.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+    /// The length in bytes of the value set by the record, 0 for a removal.
+    #[serde(default)]
+    value_len: u64,
+    /// The type of the value set by the record, so that the string reads of a key of another
+    /// type fail without reading it.
+    #[serde(default, skip_serializing_if = "ValueType::is_string")]
+    value_type: ValueType,
+}
+
+impl CommandPos {
+    /// The position of the `len` bytes of the record of `cmd` at `pos`, whose value is stored in
+    /// the value log if `ptr` points to it.
+    fn of(pos: u64, len: u64, cmd: &Command, ptr: Option<ValuePtr>) -> CommandPos {
+        let (created_at, value_len, value_type) = match cmd {
+            // A record written before the creation times were kept was the first one of its key
+            // for all that is known.
+            Command::Set {
+                created_at,
+                written_at,
+                value,
+                value_type,
+                ..
+            } => (
+                created_at.or(*written_at),
+                ptr.map_or(value.len() as u64, |ptr| ptr.len),
+                *value_type,
+            ),
+            Command::Rm { .. } => (None, 0, ValueType::String),
+        };
+        CommandPos {
+            pos,
+            len,
+            created_at,
+            value_len,
+            value_type,
+        }
+    }
+}
+
+/// A record appended by the writer thread.
+struct Written {
+    /// The sequence number of the record, if the store is versioned.
+    seq: Option<u64>,
+    /// Whether the redundant bytes reached the compaction threshold.
+    compact: bool,
+}
+
+/// What a backup copies, as of the end of the log when it started.
+struct BackupSource {
+    log: Arc<dyn LogStorage>,
+    end: u64,
+    /// The value log with its path and its size, unless it is empty.
+    values: Option<(File, PathBuf, u64)>,
+}
+
+/// The records a compaction keeps from the log ending at `end`.
+struct CompactionStart {
+    live: Vec<CommandPos>,
+    end: u64,
+    log: Arc<dyn LogStorage>,
+    /// The records written after `end`, until they are flushed.
+    lookaside: Arc<Lookaside>,
+}
+
+/// A compaction which copied the records it started with, for the writer thread to finish.
+struct Compaction {
+    trigger: CompactionTrigger,
+    started_at: SystemTime,
+    started: Instant,
+    /// Reads the log being compacted, from `snapshot_end` on for the records written since.
+    reader: LogReader,
+    snapshot_end: u64,
+    new_log: CompactedLog,
+}
+
+/// Forces the entries of the directory at `path` to disk, so that the files renamed in it or
+/// removed from it stay so after a crash. Only done on Unix, where a directory can be opened.
+fn sync_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("syncing directory {}", path.display()))?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Appends the records to the log, through a lookaside flushed once it fills up or when asked
+/// to. Owned by the writer thread.
+struct LogWriter {
+    storage: Arc<dyn LogStorage>,
+    /// The records not flushed yet, shared with the readers of the current version of the log.
+    lookaside: Arc<Lookaside>,
+    /// How many bytes the lookaside holds before it is flushed.
+    capacity: usize,
+    values: Arc<Mutex<ValueLog>>,
+    /// The end of the flushed log, advanced by every flush.
+    head: Arc<LogHead>,
+    /// The number of records written since the store was opened.
+    records: u64,
+    /// The end of the log, including the bytes still in the lookaside, so that the writers learn
+    /// the positions of their records without flushing it.
+    offset: u64,
+    /// How many bytes of disk are reserved ahead of the writes, if any.
+    preallocate: Option<u64>,
+    /// The end of the space reserved in the log, reset when compaction replaces it.
+    reserved: u64,
+    /// The number of records written when the index file was last written.
+    checkpointed: u64,
+    /// What the writes of the batch not committed yet replaced, by key.
+    uncommitted: HashMap<String, Uncommitted>,
+}
+
+/// What a key was indexed to before the batch being written changed it, restored if the batch
+/// fails to commit.
+struct Uncommitted {
+    cmd_pos: Option<CommandPos>,
+    expires_at: Option<u64>,
+    tombstone: Option<Tombstone>,
+    /// The number of superseded records of the key in the history of a versioned store.
+    history_len: usize,
+}
+
+impl LogWriter {
+    /// Creates the writer of the log in `storage`, whose records not flushed yet are kept in
+    /// `lookaside`, empty so far.
+    fn new(
+        storage: Arc<dyn LogStorage>,
+        lookaside: Arc<Lookaside>,
+        values: Arc<Mutex<ValueLog>>,
+        head: Arc<LogHead>,
+        builder: &KvStoreBuilder,
+    ) -> LogWriter {
+        LogWriter {
+            storage,
+            offset: head.offset(),
+            lookaside,
+            capacity: builder.write_capacity(),
+            values,
+            head,
+            records: 0,
+            preallocate: builder.preallocate,
+            reserved: 0,
+            checkpointed: 0,
+            uncommitted: HashMap::new(),
+        }
+    }
+
+    /// The storage of the log.
+    fn storage(&self) -> &Arc<dyn LogStorage> {
+        &self.storage
+    }
+
+    /// Appends the record of `cmd` to the lookaside, flushed if it is full, and returns its
+    /// offset.
+    fn write<C: Serialize>(&mut self, cmd: &C) -> Result<u64> {
+        let cmd_head_pos = self.offset;
+        let bytes = encode(cmd)?;
+        self.reserve(cmd_head_pos + bytes.len() as u64)?;
+        self.lookaside.append(&bytes);
+        self.offset += bytes.len() as u64;
+        self.records += 1;
+        if self.lookaside.buffered() >= self.capacity {
+            self.flush()?;
+        }
+        Ok(cmd_head_pos)
+    }
+
+    /// Reserves the next `preallocate` bytes of the log once the writes reach `end`, past the
+    /// space reserved so far. Preallocation is given up on if the storage does not support it.
+    fn reserve(&mut self, end: u64) -> Result<()> {
+        if let Some(step) = self.preallocate {
+            if end > self.reserved {
+                if self.storage().reserve(end + step)? {
+                    self.reserved = end + step;
+                } else {
+                    debug!("The storage cannot preallocate the log.");
+                    self.preallocate = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `value` split in chunks, followed by `set`, the record setting its key to it, and
+    /// returns the offset of the first chunk.
+    fn write_chunked(&mut self, value: &str, set: ChunkCommand) -> Result<u64> {
+        let mut start = None;
+        for (part, data) in chunks::split(value).into_iter().enumerate() {
+            let data = data.to_owned();
+            let pos = self.write(&ChunkCommand::Chunk {
+                part: part as u32,
+                data,
+            })?;
+            start.get_or_insert(pos);
+        }
+        let pos = self.write(&set)?;
+        Ok(start.unwrap_or(pos))
+    }
+
+    /// Flushes the value log, then the log, and moves the head of the log past the records
+    /// flushed.
+    fn flush(&mut self) -> Result<()> {
+        lock(&self.values).flush()?;
+        let flushed = self.lookaside.flush(&*self.storage)?;
+        self.head.advance(flushed);
+        debug!("Flushed the log.");
+        Ok(())
+    }
+
+    /// Flushes the value log and the log and forces them to disk.
+    fn sync(&mut self) -> Result<()> {
+        lock(&self.values).sync()?;
+        self.flush()?;
+        self.storage().sync()?;
+        Ok(())
+    }
+}
+
+/// The capacity kept by a buffer reused across reads. The buffer of a larger record is shrunk
+/// back once done with, so that a single huge value does not pin its memory.
+const MAX_KEPT_BUFFER: usize = 64 << 10;
+
+/// How many bytes before the end of the log a cursor takes the checksum of.
+const CURSOR_CHECK_LEN: u64 = 64;
+
+thread_local! {
+    /// The buffer records are read into, reused from one read to the next of a thread.
+    static READ_BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A version of the log, which the positions of the index point into until a compaction
+/// replaces it along with them. The reads copy it, so that they can finish on it after the
+/// switch.
+struct LogVersion {
+    reader: LogReader,
+    /// The number of compactions which replaced the log before this version.
+    generation: u64,
+    /// The value logs superseded by the compaction which replaced this version, removed once
+    /// it is no longer read.
+    retired: Mutex<Vec<PathBuf>>,
+}
+
+impl LogVersion {
+    fn new(reader: LogReader, generation: u64) -> LogVersion {
+        LogVersion {
+            reader,
+            generation,
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Drop for LogVersion {
+    fn drop(&mut self) {
+        for path in lock(&self.retired).drain(..) {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!(value_log = %path.display(), error = %e, "Failed to remove a value log.");
+            }
+        }
+    }
+}
+
+/// Reads the records of a log by their position, which any number of threads can do at once.
+struct LogReader {
+    storage: Arc<dyn LogStorage>,
+    /// The records written past the end of the storage, until they are flushed.
+    lookaside: Arc<Lookaside>,
+    path: PathBuf,
+    /// The values the records of the log point to.
+    values: Arc<ValueReader>,
+    /// The capacity of the buffer the log is read through in order.
+    capacity: usize,
+}
+
+impl LogReader {
+    /// Creates the reader of the log at `path` kept in `storage` and `lookaside`, reading it in
+    /// order through a buffer of `capacity` bytes.
+    fn new(
+        storage: Arc<dyn LogStorage>,
+        lookaside: Arc<Lookaside>,
+        path: PathBuf,
+        values: Arc<ValueReader>,
+        capacity: usize,
+    ) -> LogReader {
+        LogReader {
+            storage,
+            lookaside,
+            path,
+            values,
+            capacity,
+        }
+    }
+
+    fn read_in_pos(&self, pos: u64, len: u64) -> Result<Command> {
+        let (cmd, ptr) = self.read_entry_in_pos(pos, len)?;
+        resolve(cmd, ptr, &self.values)
+    }
+
+    /// Reads the record at `pos`, or the records of a value split in chunks starting there,
+    /// without reading its value from the value log.
+    fn read_entry_in_pos(&self, pos: u64, len: u64) -> Result<(Command, Option<ValuePtr>)> {
+        READ_BUF.with(|buf| {
+            let mut buf = buf.borrow_mut();
+            let entry = self
+                .read_raw_in_pos(pos, len, &mut buf)
+                .and_then(|()| self.decode_entry(pos, &buf));
+            recycle(&mut buf);
+            entry
+        })
+    }
+
+    /// Decodes the record read at `pos` into `bytes`, or the records of a value split in chunks.
+    fn decode_entry(&self, pos: u64, bytes: &[u8]) -> Result<(Command, Option<ValuePtr>)> {
+        let mut log_stream = Deserializer::from_slice(bytes).into_iter::<LogEntry>();
+        let mut chunks = Assembler::default();
+        let mut offset = pos;
+        while let Some(entry) = log_stream.next() {
+            if let Some((_, cmd, ptr)) = chunks.push(entry?, &self.path, offset)? {
+                return Ok((cmd, ptr));
+            }
+            offset = pos + log_stream.byte_offset() as u64;
+        }
+        Err(KvsError::Internal(format!(
+            "no complete record at offset {} of log {}",
+            pos,
+            self.path.display()
+        )))
+    }
+
+    /// Reads the `len` bytes at `pos` into `buf`, replacing its content, from the lookaside if
+    /// they were not flushed yet.
+    fn read_raw_in_pos(&self, pos: u64, len: u64, buf: &mut Vec<u8>) -> Result<()> {
+        buf.clear();
+        buf.resize(len as usize, 0);
+        self.lookaside.read_exact_at(&*self.storage, pos, buf)?;
+        Ok(())
+    }
+
+    /// Returns the positions of the records from offset `from` to the end of the flushed log,
+    /// those of the chunks of a value being grouped with the record setting its key.
+    fn positions_from(&self, from: u64) -> Result<Vec<CommandPos>> {
+        let mut reader =
+            BufReader::with_capacity(self.capacity, StorageReader::new(Arc::clone(&self.storage)));
+        reader.seek(SeekFrom::Start(from))?;
+        let mut log_stream = Deserializer::from_reader(reader).into_iter::<LogEntry>();
+        let mut chunks = Assembler::default();
+        let mut positions = Vec::new();
+        let mut curr_head_pos = from;
+        while let Some(Ok(entry)) = log_stream.next() {
+            let offset = curr_head_pos;
+            curr_head_pos = from + log_stream.byte_offset() as u64;
+            if let Some((pos, cmd, ptr)) = chunks.push(entry, &self.path, offset)? {
+                positions.push(CommandPos::of(pos, curr_head_pos - pos, &cmd, ptr));
+            }
+        }
+        Ok(positions)
+    }
+}
+
+/// A log being written by a compaction.
+struct CompactedLog {
+    writer: BufWriter<StorageWriter>,
+    path: String,
+    /// The value log the values are moved to, if it is rewritten as well.
+    values: Option<ValueLog>,
+    /// The new position of every record copied, by its offset in the old log.
+    moved: HashMap<u64, CommandPos>,
+    end: u64,
+    /// The bytes read from the logs and written to the new ones so far.
+    read: u64,
+    written: u64,
+    /// The buffer records are copied through, reused from one record to the next.
+    buf: Vec<u8>,
+}
+
+impl CompactedLog {
+    /// Appends the record at `cmd_pos` in the log read by `reader`, moving its value if the
+    /// value log is rewritten.
+    fn copy(&mut self, reader: &LogReader, cmd_pos: CommandPos) -> Result<()> {
+        reader
+            .read_raw_in_pos(cmd_pos.pos, cmd_pos.len, &mut self.buf)
+            .with_context(|| {
+                format!(
+                    "reading log {} at offset {}",
+                    reader.path.display(),
+                    cmd_pos.pos
+                )
+            })?;
+        let rewritten;
+        let mut cmd_bytes = &self.buf[..];
+        if let Some(new_values) = self.values.as_mut() {
+            // The records of a value split in chunks are copied as they are.
+            let entry = Deserializer::from_slice(cmd_bytes)
+                .into_iter::<LogEntry>()
+                .next()
+                .transpose()?;
+            if let Some(LogEntry::Pointer(record)) = entry {
+                let PointerCommand::SetRef {
+                    key,
+                    ptr,
+                    value_type,
+                    seq,
+                    expires_at,
+                    written_at,
+                    created_at,
+                } = record.verify(&reader.path, cmd_pos.pos)?;
+                let value = reader.values.read(ptr)?;
+                let ptr = new_values.append(&value)?;
+                self.read += ptr.len;
+                self.written += ptr.len;
+                rewritten = encode(&PointerCommand::SetRef {
+                    key,
+                    ptr,
+                    value_type,
+                    seq,
+                    expires_at,
+                    written_at,
+                    created_at,
+                })?;
+                cmd_bytes = &rewritten;
+            }
+        }
+
+        self.writer
+            .write_all(cmd_bytes)
+            .with_context(|| format!("writing compacted log {}", self.path))?;
+        let new_pos = CommandPos {
+            pos: self.end,
+            len: cmd_bytes.len() as u64,
+            ..cmd_pos
+        };
+        self.moved.insert(cmd_pos.pos, new_pos);
+        self.end += new_pos.len;
+        self.read += cmd_pos.len;
+        self.written += new_pos.len;
+        recycle(&mut self.buf);
+        Ok(())
+    }
+}
+
+/// The checksum of the bytes of the log before `offset`, up to `CURSOR_CHECK_LEN` of them, which
+/// differ once the log is compacted.
+fn log_check(storage: &dyn LogStorage, offset: u64) -> io::Result<u32> {
+    let len = offset.min(CURSOR_CHECK_LEN);
+    let mut buf = vec![0; len as usize];
+    read_exact_at(storage, offset - len, &mut buf)?;
+    Ok(crc32fast::hash(&buf))
+}
+
+/// Gets `buf` ready to be reused, giving back the memory of a record larger than most.
+fn recycle(buf: &mut Vec<u8>) {
+    buf.clear();
+    buf.shrink_to(MAX_KEPT_BUFFER);
+}
+
+fn check_length(s: &str, max_len_in_bytes: usize, err: KvsError) -> Result<()> {
+    if s.len() <= max_len_in_bytes {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
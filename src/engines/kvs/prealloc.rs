@@ -0,0 +1,35 @@
+use std::fs::File;
+use std::io;
+
+/// Reserves the disk blocks of `file` up to `len` bytes without changing its size, so that the
+/// appends within them neither allocate blocks nor fragment the file. Returns `false` if the
+/// file system does not support it.
+#[cfg(target_os = "linux")]
+pub(super) fn reserve(file: &File, len: u64) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // Safety: the descriptor is owned by `file`, which outlives the call.
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+/// Only Linux can reserve blocks without changing the size of the file, which the replay of the
+/// log relies on.
+#[cfg(not(target_os = "linux"))]
+pub(super) fn reserve(_file: &File, _len: u64) -> io::Result<bool> {
+    Ok(false)
+}
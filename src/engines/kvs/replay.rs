@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crossbeam_channel::unbounded;
+use serde_json::Deserializer;
+use tracing::debug;
+
+use super::chunks::Assembler;
+use super::stats::StoreStats;
+use super::storage::{LogStorage, StorageReader};
+use super::{Command, CommandPos, LogEntry, Tombstone};
+use crate::error::{KvsError, Result};
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+
+/// The least number of bytes of the log replayed by each thread.
+const CHUNK_SIZE: u64 = 4 << 20;
+
+/// How every record starts, except the ones of logs written before records were checksummed.
+/// JSON escapes the quotes in strings, so it cannot appear in the middle of a record.
+const RECORD_START: &[u8] = b"{\"crc\":";
+
+/// How many bytes a thread replays between two progress reports.
+const PROGRESS_STEP: u64 = 1 << 20;
+
+/// Reports the progress of the replay to the callback given to
+/// [`KvStore::open_with_progress`](struct.KvStore.html#method.open_with_progress).
+pub(super) struct Progress {
+    callback: Box<dyn Fn(u64, u64) + Send + Sync>,
+    replayed: AtomicU64,
+    total: AtomicU64,
+}
+
+impl Progress {
+    pub(super) fn new<F>(callback: F) -> Progress
+    where
+        F: Fn(u64, u64) + Send + Sync + 'static,
+    {
+        Progress {
+            callback: Box::new(callback),
+            replayed: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+        }
+    }
+
+    fn start(&self, total: u64) {
+        self.replayed.store(0, Ordering::SeqCst);
+        self.total.store(total, Ordering::SeqCst);
+        (self.callback)(0, total);
+    }
+
+    fn advance(&self, bytes: u64) {
+        let replayed = self.replayed.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        (self.callback)(replayed, self.total.load(Ordering::SeqCst));
+    }
+}
+
+/// The index of a store as its log is replayed into it.
+pub(super) trait ReplayIndex {
+    /// Points `key` to `cmd_pos` and returns the position it replaces.
+    fn insert(&mut self, key: String, cmd_pos: CommandPos) -> Result<Option<CommandPos>>;
+
+    /// Removes `key` and returns its position.
+    fn remove(&mut self, key: &str) -> Result<Option<CommandPos>>;
+}
+
+impl ReplayIndex for HashMap<String, CommandPos> {
+    fn insert(&mut self, key: String, cmd_pos: CommandPos) -> Result<Option<CommandPos>> {
+        Ok(HashMap::insert(self, key, cmd_pos))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        Ok(HashMap::remove(self, key))
+    }
+}
+
+/// The state of a store rebuilt from its log, into `index`. The parts of a log replayed in
+/// parallel are each rebuilt into a map, merged into the index in the order of the log.
+#[derive(Default)]
+pub(super) struct Replayed<I = HashMap<String, CommandPos>> {
+    pub(super) index: I,
+    pub(super) tombstones: HashMap<String, Tombstone>,
+    /// When the keys set with a time to live expire.
+    pub(super) expiries: HashMap<String, u64>,
+    /// The superseded records of every key, oldest first, only kept if given.
+    pub(super) history: Option<HashMap<String, Vec<CommandPos>>>,
+    /// The highest sequence number found.
+    pub(super) last_seq: u64,
+    /// The sizes of the keys and values of the `Set` records found.
+    pub(super) sizes: StoreStats,
+}
+
+impl<I: ReplayIndex> Replayed<I> {
+    /// Creates the state rebuilt into `index`, empty so far.
+    pub(super) fn new(index: I) -> Replayed<I> {
+        Replayed {
+            index,
+            tombstones: HashMap::new(),
+            expiries: HashMap::new(),
+            history: None,
+            last_seq: 0,
+            sizes: StoreStats::default(),
+        }
+    }
+
+    /// Applies `cmd`, whose record is at `cmd_pos`.
+    fn apply(&mut self, cmd: Command, cmd_pos: CommandPos) -> Result<()> {
+        self.last_seq = self.last_seq.max(cmd.seq().unwrap_or(0));
+
+        let (key, superseded) = match cmd {
+            Command::Set {
+                key, expires_at, ..
+            } => {
+                self.sizes.record(key.len(), cmd_pos.value_len);
+                self.tombstones.remove(&key);
+                match expires_at {
+                    Some(expires_at) => self.expiries.insert(key.clone(), expires_at),
+                    None => self.expiries.remove(&key),
+                };
+                let superseded = self.index.insert(key.clone(), cmd_pos)?;
+                (key, superseded.into_iter().collect::<Vec<_>>())
+            }
+            Command::Rm { key, time, .. } => {
+                let tombstone = Tombstone {
+                    pos: cmd_pos,
+                    deleted_at: time,
+                };
+                self.tombstones.insert(key.clone(), tombstone);
+                self.expiries.remove(&key);
+                let superseded = self.index.remove(&key)?;
+                (key, superseded.into_iter().chain(Some(cmd_pos)).collect())
+            }
+        };
+        if let Some(history) = self.history.as_mut() {
+            history.entry(key).or_default().extend(superseded);
+        }
+        Ok(())
+    }
+
+    /// Applies `chunk`, replayed from the records following the ones applied so far. Every key
+    /// the chunk ends up setting or removing supersedes its current record.
+    fn merge(&mut self, chunk: Replayed) -> Result<()> {
+        self.last_seq = self.last_seq.max(chunk.last_seq);
+        self.sizes.merge(&chunk.sizes);
+        let mut chunk_history = chunk.history.unwrap_or_default();
+        let mut supersede = |state: &mut Replayed<I>, key: &str| -> Result<()> {
+            let superseded = state.index.remove(key)?;
+            if let Some(history) = state.history.as_mut() {
+                let key_history = history.entry(key.to_owned()).or_default();
+                key_history.extend(superseded);
+                key_history.extend(chunk_history.remove(key).into_iter().flatten());
+            }
+            Ok(())
+        };
+
+        for (key, cmd_pos) in chunk.index {
+            supersede(self, &key)?;
+            self.tombstones.remove(&key);
+            self.expiries.remove(&key);
+            self.index.insert(key, cmd_pos)?;
+        }
+        for (key, tombstone) in chunk.tombstones {
+            supersede(self, &key)?;
+            self.expiries.remove(&key);
+            self.tombstones.insert(key, tombstone);
+        }
+        self.expiries.extend(chunk.expiries);
+        Ok(())
+    }
+}
+
+/// Applies the records of the log at `path`, read from `storage`, from offset `from` on, to
+/// `state`. Decoding stops
+/// at the first record that cannot be read, as it can only be the last one, torn by a crash.
+///
+/// A log larger than a chunk is split at record boundaries across up to `threads` threads,
+/// which decode their part into a state of their own, merged in the order of the log. Each of
+/// them reads the log through a buffer of `buffer` bytes.
+pub(super) fn replay<I: ReplayIndex>(
+    storage: &Arc<dyn LogStorage>,
+    path: &Path,
+    from: u64,
+    threads: usize,
+    buffer: usize,
+    state: &mut Replayed<I>,
+    progress: Option<&Arc<Progress>>,
+) -> Result<()> {
+    let mut log = StorageReader::new(Arc::clone(storage));
+    let end = storage.len()?.max(from);
+    let chunks = threads
+        .min(end.saturating_sub(from).div_ceil(CHUNK_SIZE) as usize)
+        .max(1);
+    let bounds = chunk_bounds(&mut log, from, end, chunks)?;
+    if let Some(progress) = progress {
+        progress.start(end - from);
+    }
+    if bounds.len() <= 2 {
+        let progress = progress.map(Deref::deref);
+        replay_range(path, log, (from, end), buffer, state, progress)?;
+        return Ok(());
+    }
+    debug!(chunks = bounds.len() - 1, "Replaying the log in parallel.");
+
+    let pool = SharedQueueThreadPool::new(bounds.len() - 1)?;
+    let (tx, rx) = unbounded();
+    for (i, range) in bounds.windows(2).enumerate() {
+        let (storage, path, tx) = (Arc::clone(storage), path.to_path_buf(), tx.clone());
+        let (start, end) = (range[0], range[1]);
+        let keep_history = state.history.is_some();
+        let progress = progress.cloned();
+        pool.spawn(move || {
+            let log = StorageReader::new(storage);
+            let mut chunk = Replayed {
+                history: keep_history.then(HashMap::new),
+                ..Replayed::default()
+            };
+            let progress = progress.as_deref();
+            let result = replay_range(&path, log, (start, end), buffer, &mut chunk, progress)
+                .map(|complete| (chunk, complete));
+            let _ = tx.send((i, result));
+        });
+    }
+    drop(tx);
+
+    let mut results: Vec<_> = (1..bounds.len()).map(|_| None).collect();
+    for (i, result) in rx {
+        results[i] = Some(result);
+    }
+    for result in results {
+        let result = result
+            .ok_or_else(|| KvsError::Internal("a thread replaying the log panicked".to_owned()))?;
+        let (chunk, complete) = result?;
+        state.merge(chunk)?;
+        // The records after an undecodable one are ignored, as in a sequential replay.
+        if !complete {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the records of `log` from offset `from` up to `end` to `state`, and returns whether
+/// all of them could be decoded.
+fn replay_range<I: ReplayIndex>(
+    path: &Path,
+    mut log: StorageReader,
+    (from, end): (u64, u64),
+    buffer: usize,
+    state: &mut Replayed<I>,
+    progress: Option<&Progress>,
+) -> Result<bool> {
+    log.seek(SeekFrom::Start(from))?;
+    let reader = BufReader::with_capacity(buffer, log).take(end - from);
+    let mut log_stream = Deserializer::from_reader(reader).into_iter::<LogEntry>();
+
+    let mut chunks = Assembler::default();
+    let mut curr_head_pos = from;
+    let mut reported = from;
+    while let Some(Ok(entry)) = log_stream.next() {
+        let offset = curr_head_pos;
+        curr_head_pos = from + log_stream.byte_offset() as u64;
+        if let Some((pos, cmd, ptr)) = chunks.push(entry, path, offset)? {
+            let cmd_pos = CommandPos::of(pos, curr_head_pos - pos, &cmd, ptr);
+            state.apply(cmd, cmd_pos)?;
+        }
+
+        if let Some(progress) = progress {
+            if curr_head_pos - reported >= PROGRESS_STEP {
+                progress.advance(curr_head_pos - reported);
+                reported = curr_head_pos;
+            }
+        }
+    }
+    // The bytes that could not be decoded are done with as well.
+    if let Some(progress) = progress {
+        if end > reported {
+            progress.advance(end - reported);
+        }
+    }
+    Ok(curr_head_pos == end && chunks.pending().is_none())
+}
+
+/// Splits the log between `from` and `end` into at most `chunks` ranges starting at records,
+/// and returns their bounds. A range is only cut at the start of a checksummed record found
+/// after the even split point.
+fn chunk_bounds(log: &mut StorageReader, from: u64, end: u64, chunks: usize) -> Result<Vec<u64>> {
+    let mut bounds = vec![from];
+    for k in 1..chunks as u64 {
+        let target = from + (end - from) * k / chunks as u64;
+        let after = target.max(bounds[bounds.len() - 1] + 1);
+        if let Some(start) = find_record_start(log, after, end)? {
+            bounds.push(start);
+        }
+    }
+    bounds.push(end);
+    bounds.dedup();
+    Ok(bounds)
+}
+
+/// Returns the offset of the first record start at or after `from` in `log`, before `end`,
+/// skipping the records of values split in chunks, which cannot be replayed apart.
+fn find_record_start(log: &mut StorageReader, from: u64, end: u64) -> Result<Option<u64>> {
+    let mut window = vec![0u8; 64 << 10];
+    let mut offset = from;
+    while offset < end {
+        log.seek(SeekFrom::Start(offset))?;
+        let len = ((end - offset) as usize).min(window.len());
+        log.read_exact(&mut window[..len])?;
+        if let Some(i) = window[..len]
+            .windows(RECORD_START.len())
+            .position(|bytes| bytes == RECORD_START)
+        {
+            let start = offset + i as u64;
+            if !is_chunk(log, start)? {
+                return Ok(Some(start));
+            }
+            offset = start + 1;
+            continue;
+        }
+        if len < RECORD_START.len() {
+            break;
+        }
+        // The start of a record may straddle the end of the window.
+        offset += (len - RECORD_START.len() + 1) as u64;
+    }
+    Ok(None)
+}
+
+/// Whether the record at `start` in `log` is one of the records of a value split in chunks.
+fn is_chunk(log: &mut StorageReader, start: u64) -> Result<bool> {
+    // Enough for the checksum and the name of the command.
+    let mut head = Vec::with_capacity(48);
+    log.seek(SeekFrom::Start(start))?;
+    Read::by_ref(log).take(48).read_to_end(&mut head)?;
+    let head = String::from_utf8_lossy(&head);
+    Ok(head.contains("\"cmd\":{\"Chunk\"") || head.contains("\"cmd\":{\"SetChunked\""))
+}
@@ -0,0 +1,347 @@
+use std::env;
+use std::io::Read;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac_sha256::{Hash, HMAC};
+
+use super::backup::BackupTarget;
+use crate::error::{KvsError, Result};
+
+/// The hash sent in place of the one of the body of the uploads, so that they are streamed
+/// without being read twice.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// The hash of an empty body.
+const EMPTY_PAYLOAD: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// A backup in an S3 bucket, or in any object storage speaking its API. Objects cannot be
+/// appended to, so every incremental backup of a file is uploaded as an object of its own,
+/// named after the file and the offset it starts at, e.g. `log.00000000000000004096`, which
+/// are concatenated in order to restore the file.
+///
+/// ```no_run
+/// use kvs::{KvStore, S3Target};
+///
+/// let db = KvStore::open(".").unwrap();
+/// let mut target = S3Target::from_env("my-bucket", "backups/kvs/").unwrap();
+/// let offset = db.backup_to(&mut target, 0).unwrap();
+/// ```
+pub struct S3Target {
+    agent: ureq::Agent,
+    endpoint: String,
+    region: String,
+    bucket: String,
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+/// An object holding the part of a file starting at `start`.
+struct Segment {
+    key: String,
+    start: u64,
+    size: u64,
+}
+
+impl S3Target {
+    /// Keeps the backup under the keys starting with `prefix` in `bucket`, with the credentials
+    /// and the region given by the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+    /// `AWS_SESSION_TOKEN` and `AWS_REGION` environment variables, `us-east-1` by default. The
+    /// endpoint is the one of the region, unless `AWS_ENDPOINT_URL` gives another one.
+    pub fn from_env(bucket: &str, prefix: &str) -> Result<S3Target> {
+        let var = |name: &str| {
+            env::var(name).map_err(|_| KvsError::Internal(format!("{} is not set", name)))
+        };
+        let region = env::var("AWS_REGION")
+            .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_owned());
+        let endpoint = env::var("AWS_ENDPOINT_URL")
+            .unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region));
+        Ok(S3Target {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(10))
+                .build(),
+            endpoint: endpoint.trim_end_matches('/').to_owned(),
+            region,
+            bucket: bucket.to_owned(),
+            prefix: prefix.to_owned(),
+            access_key: var("AWS_ACCESS_KEY_ID")?,
+            secret_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    /// Sends the requests to `endpoint`, e.g. `http://localhost:9000`, with the bucket in the
+    /// path of the URLs.
+    pub fn endpoint(mut self, endpoint: &str) -> S3Target {
+        self.endpoint = endpoint.trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Signs the requests for `region`.
+    pub fn region(mut self, region: &str) -> S3Target {
+        self.region = region.to_owned();
+        self
+    }
+
+    /// Returns the objects holding the parts of the file `name`, in order.
+    fn segments(&self, name: &str) -> Result<Vec<Segment>> {
+        let prefix = format!("{}{}.", self.prefix, name);
+        let mut segments = Vec::new();
+        let mut token = None;
+        loop {
+            let mut query = vec![("list-type", "2".to_owned()), ("prefix", prefix.clone())];
+            if let Some(token) = token.take() {
+                query.push(("continuation-token", token));
+            }
+            let listing = self
+                .call("GET", "", &query, EMPTY_PAYLOAD)
+                .call()
+                .map_err(|e| s3_error("listing", &prefix, e))?
+                .into_string()?;
+            for contents in elements(&listing, "Contents") {
+                let key = unescape(element(contents, "Key").unwrap_or_default());
+                let size = element(contents, "Size").and_then(|size| size.parse().ok());
+                let start = key[prefix.len().min(key.len())..].parse().ok();
+                if let (Some(start), Some(size)) = (start, size) {
+                    segments.push(Segment { key, start, size });
+                }
+            }
+            match element(&listing, "NextContinuationToken") {
+                Some(next) if element(&listing, "IsTruncated") == Some("true") => {
+                    token = Some(unescape(next));
+                }
+                _ => break,
+            }
+        }
+        segments.sort_by_key(|segment| segment.start);
+        Ok(segments)
+    }
+
+    /// Returns a request for the object `key` of the bucket, or for the bucket itself if empty,
+    /// signed with version 4 of the AWS signature.
+    fn call(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, String)],
+        payload_hash: &str,
+    ) -> ureq::Request {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        let amz_date = format_timestamp(now);
+        let date = &amz_date[..8];
+        let host = self
+            .endpoint
+            .split("://")
+            .nth(1)
+            .unwrap_or(&self.endpoint)
+            .split('/')
+            .next()
+            .unwrap_or_default();
+
+        let mut path = format!("/{}", uri_encode(&self.bucket, false));
+        if !key.is_empty() {
+            path.push('/');
+            path.push_str(&uri_encode(key, true));
+        }
+        let mut query: Vec<_> = query
+            .iter()
+            .map(|(name, value)| {
+                format!("{}={}", uri_encode(name, false), uri_encode(value, false))
+            })
+            .collect();
+        query.sort();
+        let query = query.join("&");
+
+        let mut headers = vec![
+            ("host", host.to_owned()),
+            ("x-amz-content-sha256", payload_hash.to_owned()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, path, query, canonical_headers, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Hash::hash(canonical_request.as_bytes()))
+        );
+        let key = HMAC::mac(date, format!("AWS4{}", self.secret_key));
+        let key = HMAC::mac(&self.region, key);
+        let key = HMAC::mac("s3", key);
+        let key = HMAC::mac("aws4_request", key);
+        let signature = hex(&HMAC::mac(string_to_sign, key));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let mut url = format!("{}{}", self.endpoint, path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+        let mut request = self
+            .agent
+            .request(method, &url)
+            .set("Authorization", &authorization);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.set(name, value);
+        }
+        request
+    }
+}
+
+impl BackupTarget for S3Target {
+    fn size(&mut self, name: &str) -> Result<u64> {
+        Ok(self
+            .segments(name)?
+            .last()
+            .map_or(0, |segment| segment.start + segment.size))
+    }
+
+    fn read(&mut self, name: &str, pos: u64, len: u64) -> Result<Vec<u8>> {
+        let end = pos + len;
+        let mut buf = Vec::with_capacity(len as usize);
+        for segment in self.segments(name)? {
+            let (from, to) = (
+                pos.max(segment.start),
+                end.min(segment.start + segment.size),
+            );
+            if from >= to {
+                continue;
+            }
+            let range = format!("bytes={}-{}", from - segment.start, to - segment.start - 1);
+            self.call("GET", &segment.key, &[], EMPTY_PAYLOAD)
+                .set("Range", &range)
+                .call()
+                .map_err(|e| s3_error("reading", &segment.key, e))?
+                .into_reader()
+                .take(to - from)
+                .read_to_end(&mut buf)?;
+        }
+        if buf.len() as u64 != len {
+            return Err(KvsError::Internal(format!(
+                "the backup of {} holds {} bytes at offset {}, not {}",
+                name,
+                buf.len(),
+                pos,
+                len
+            )));
+        }
+        Ok(buf)
+    }
+
+    fn append(&mut self, name: &str, pos: u64, data: &mut dyn Read, len: u64) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let key = format!("{}{}.{:020}", self.prefix, name, pos);
+        self.call("PUT", &key, &[], UNSIGNED_PAYLOAD)
+            .set("Content-Length", &len.to_string())
+            .send(data)
+            .map_err(|e| s3_error("uploading", &key, e))?;
+        Ok(())
+    }
+}
+
+fn s3_error(doing: &str, key: &str, error: ureq::Error) -> KvsError {
+    let message = match error {
+        ureq::Error::Status(status, response) => {
+            let body = response.into_string().unwrap_or_default();
+            let code = element(&body, "Code").unwrap_or_default().to_owned();
+            format!("status {} {}", status, code)
+        }
+        error => error.to_string(),
+    };
+    KvsError::Internal(format!("{} S3 object {}: {}", doing, key, message))
+}
+
+/// Returns the content of every `<name>` element of `xml`.
+fn elements<'a>(xml: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    let mut rest = xml;
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let end = start + rest[start..].find(&close)?;
+        let content = &rest[start..end];
+        rest = &rest[end + close.len()..];
+        Some(content)
+    })
+}
+
+fn element<'a>(xml: &'a str, name: &'a str) -> Option<&'a str> {
+    elements(xml, name).next()
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Percent-encodes every byte of `s` but the unreserved characters, and the slashes of an
+/// object key if `keep_slashes`.
+fn uri_encode(s: &str, keep_slashes: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if keep_slashes => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Formats `secs` since the Unix epoch as an ISO 8601 basic timestamp, e.g. `20240131T235959Z`.
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+    // The civil date of a number of days since the epoch, from Howard Hinnant's algorithms.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
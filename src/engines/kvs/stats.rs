@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+/// The number of buckets of a size histogram: one for the empty sizes, then one per power of two.
+const BUCKETS: usize = 65;
+
+/// A histogram of sizes in bytes, with a bucket per power of two.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SizeHistogram {
+    counts: Vec<u64>,
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        SizeHistogram {
+            counts: vec![0; BUCKETS],
+        }
+    }
+}
+
+impl SizeHistogram {
+    pub(super) fn record(&mut self, size: u64) {
+        self.counts[bucket_of(size)] += 1;
+    }
+
+    pub(super) fn merge(&mut self, other: &SizeHistogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+    }
+
+    /// Returns the number of sizes recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the smallest size that `quantile` of the recorded sizes do not exceed, rounded
+    /// up to the upper bound of its bucket, or 0 if nothing was recorded.
+    pub fn percentile(&self, quantile: f64) -> u64 {
+        let total = self.count();
+        if total == 0 {
+            return 0;
+        }
+        let rank = ((quantile * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return upper_bound_of(bucket);
+            }
+        }
+        upper_bound_of(BUCKETS - 1)
+    }
+
+    /// Returns the upper bound and the count of every non-empty bucket, smallest sizes first.
+    /// A bucket holds the sizes above the upper bound of the previous one.
+    pub fn buckets(&self) -> Vec<(u64, u64)> {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(bucket, &count)| (upper_bound_of(bucket), count))
+            .collect()
+    }
+}
+
+fn bucket_of(size: u64) -> usize {
+    64 - size.leading_zeros() as usize
+}
+
+fn upper_bound_of(bucket: usize) -> u64 {
+    match bucket {
+        0 => 0,
+        64 => u64::MAX,
+        _ => (1 << bucket) - 1,
+    }
+}
+
+/// The number of compactions whose statistics are kept.
+const RECENT_COMPACTIONS: usize = 16;
+
+/// What started a compaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionTrigger {
+    /// The redundant bytes of the log reached the threshold, or the
+    /// [ratio](struct.KvStoreBuilder.html#method.compaction_ratio) of the log configured.
+    Automatic,
+    /// [`KvStore::compact`](struct.KvStore.html#method.compact) was called.
+    Manual,
+}
+
+/// The statistics of a completed compaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// What started it.
+    pub trigger: CompactionTrigger,
+    /// When it started.
+    pub started_at: SystemTime,
+    /// When it finished.
+    pub finished_at: SystemTime,
+    /// How long it ran.
+    pub duration: Duration,
+    /// The bytes of the live records read from the log, and of the values read from the value
+    /// log if it was rewritten too.
+    pub bytes_read: u64,
+    /// The bytes written to the compacted log, and to the new value log if any.
+    pub bytes_written: u64,
+    /// How much smaller the compacted log is than the log it replaced.
+    pub bytes_reclaimed: u64,
+}
+
+/// The compactions completed since a store was opened.
+#[derive(Debug, Default)]
+pub(super) struct CompactionHistory {
+    count: u64,
+    recent: VecDeque<CompactionStats>,
+}
+
+impl CompactionHistory {
+    pub(super) fn record(&mut self, stats: CompactionStats) {
+        self.count += 1;
+        if self.recent.len() == RECENT_COMPACTIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(stats);
+    }
+}
+
+/// A snapshot of the content of a store, as returned by
+/// [`KvStore::stats`](struct.KvStore.html#method.stats).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// The number of keys in the index, including the expired ones not swept yet.
+    pub keys: usize,
+    /// The lengths of the keys set by the records replayed when the store was opened and by
+    /// the writes since.
+    pub key_sizes: SizeHistogram,
+    /// The lengths of the values set by the same records.
+    pub value_sizes: SizeHistogram,
+    /// The number of compactions completed since the store was opened.
+    pub compactions: u64,
+    /// The statistics of the last 16 of them, oldest first.
+    pub recent_compactions: Vec<CompactionStats>,
+}
+
+impl StoreStats {
+    /// Records a write of a key of `key_len` bytes with a value of `value_len` bytes.
+    pub(super) fn record(&mut self, key_len: usize, value_len: u64) {
+        self.key_sizes.record(key_len as u64);
+        self.value_sizes.record(value_len);
+    }
+
+    /// Adds the compactions of `history`.
+    pub(super) fn with_compactions(mut self, history: &CompactionHistory) -> StoreStats {
+        self.compactions = history.count;
+        self.recent_compactions = history.recent.iter().cloned().collect();
+        self
+    }
+
+    pub(super) fn merge(&mut self, other: &StoreStats) {
+        self.key_sizes.merge(&other.key_sizes);
+        self.value_sizes.merge(&other.value_sizes);
+    }
+}
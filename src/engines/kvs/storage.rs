@@ -0,0 +1,195 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::prealloc;
+
+/// The bytes of a log, which only ever grows by appends. The log of a
+/// [`KvStore`](struct.KvStore.html) is written, read, replayed and backed up through it, from
+/// several threads at once, so that another backend than a file can be plugged in with
+/// [`KvStoreBuilder::log_storage`](struct.KvStoreBuilder.html#method.log_storage).
+pub trait LogStorage: Send + Sync {
+    /// Appends `buf` to the end of the log.
+    fn append(&self, buf: &[u8]) -> io::Result<()>;
+
+    /// Reads the bytes of the log at `pos` into `buf`, and returns how many were read, 0 past
+    /// the end of the log.
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Makes the bytes appended so far durable.
+    fn sync(&self) -> io::Result<()>;
+
+    /// Returns the length of the log in bytes.
+    fn len(&self) -> io::Result<u64>;
+
+    /// Whether the log is empty.
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Reserves the space of the log up to `len` bytes ahead of the appends, if the backend can.
+    /// Returns `false` if it cannot, and then is not asked again.
+    fn reserve(&self, _len: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// A log kept in a file, the storage of a store unless configured otherwise.
+#[derive(Debug)]
+pub struct FileStorage {
+    file: File,
+}
+
+impl FileStorage {
+    /// Opens the file at `path` for appending, created if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileStorage> {
+        let file = OpenOptions::new()
+            .append(true)
+            .read(true)
+            .create(true)
+            .open(path)?;
+        Ok(FileStorage { file })
+    }
+}
+
+impl LogStorage for FileStorage {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        (&self.file).write_all(buf)
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+
+        self.file.read_at(buf, pos)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        use std::os::windows::fs::FileExt;
+
+        self.file.seek_read(buf, pos)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn reserve(&self, len: u64) -> io::Result<bool> {
+        prealloc::reserve(&self.file, len)
+    }
+}
+
+/// Opens the storage of the log at a path.
+type OpenFn = dyn Fn(&Path) -> io::Result<Box<dyn LogStorage>> + Send + Sync;
+
+/// How a store opens the storage of its logs, a [`FileStorage`](struct.FileStorage.html) by
+/// default.
+#[derive(Clone, Default)]
+pub(crate) struct StorageOpener(Option<Arc<OpenFn>>);
+
+impl StorageOpener {
+    pub(crate) fn new<F>(open: F) -> StorageOpener
+    where
+        F: Fn(&Path) -> io::Result<Box<dyn LogStorage>> + Send + Sync + 'static,
+    {
+        StorageOpener(Some(Arc::new(open)))
+    }
+
+    pub(super) fn open(&self, path: &Path) -> io::Result<Arc<dyn LogStorage>> {
+        match &self.0 {
+            Some(open) => open(path).map(Arc::from),
+            None => Ok(Arc::new(FileStorage::open(path)?)),
+        }
+    }
+}
+
+impl fmt::Debug for StorageOpener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "StorageOpener(custom)"),
+            None => write!(f, "StorageOpener(file)"),
+        }
+    }
+}
+
+/// Fills `buf` with the bytes of `storage` at `pos`, failing if the log ends before.
+pub(super) fn read_exact_at(
+    storage: &dyn LogStorage,
+    mut pos: u64,
+    mut buf: &mut [u8],
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        match storage.read_at(pos, buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                pos += read as u64;
+                buf = &mut buf[read..];
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Reads a log in order from a position of its own, so that any number of them can read the
+/// same storage at once.
+pub(super) struct StorageReader {
+    storage: Arc<dyn LogStorage>,
+    pos: u64,
+}
+
+impl StorageReader {
+    pub(super) fn new(storage: Arc<dyn LogStorage>) -> StorageReader {
+        StorageReader { storage, pos: 0 }
+    }
+}
+
+impl Read for StorageReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.storage.read_at(self.pos, buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for StorageReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => {
+                self.pos = pos;
+                return Ok(pos);
+            }
+            SeekFrom::End(offset) => (self.storage.len()?, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the log",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Appends what is written to it to a log, through the buffer of the writer of the store.
+pub(super) struct StorageWriter(pub(super) Arc<dyn LogStorage>);
+
+impl Write for StorageWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::sync::{Arc, Condvar, Mutex};
+
+use serde_json::Deserializer;
+
+use super::chunks::Assembler;
+use super::storage::StorageReader;
+use super::values::ValueReader;
+use super::{lock, read_lock, resolve, Command, KvStore, LogEntry};
+use crate::error::{KvsError, Result};
+
+/// The end of the log, which tails wait on for new records. The generation changes whenever
+/// compaction replaces the log, which invalidates every offset in it.
+pub(super) struct LogHead {
+    state: Mutex<HeadState>,
+    appended: Condvar,
+}
+
+#[derive(Clone, Copy)]
+struct HeadState {
+    offset: u64,
+    generation: u64,
+}
+
+impl LogHead {
+    pub(super) fn new(offset: u64) -> LogHead {
+        LogHead {
+            state: Mutex::new(HeadState {
+                offset,
+                generation: 0,
+            }),
+            appended: Condvar::new(),
+        }
+    }
+
+    /// Records that the log now ends at `offset` and wakes the tails up.
+    pub(super) fn advance(&self, offset: u64) {
+        lock(&self.state).offset = offset;
+        self.appended.notify_all();
+    }
+
+    /// Records that compaction replaced the log by one ending at `offset`.
+    pub(super) fn replace(&self, offset: u64) {
+        let mut state = lock(&self.state);
+        state.offset = offset;
+        state.generation += 1;
+        self.appended.notify_all();
+    }
+
+    /// The end of the log.
+    pub(super) fn offset(&self) -> u64 {
+        self.state().offset
+    }
+
+    fn state(&self) -> HeadState {
+        *lock(&self.state)
+    }
+
+    /// Blocks until the log of `generation` grows past `offset`, or fails with
+    /// `KvsError::StaleOffset` if it was replaced.
+    fn wait_past(&self, offset: u64, generation: u64) -> Result<()> {
+        let mut state = lock(&self.state);
+        while state.generation == generation && state.offset <= offset {
+            state = self
+                .appended
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        if state.generation == generation {
+            Ok(())
+        } else {
+            Err(KvsError::StaleOffset(offset))
+        }
+    }
+}
+
+/// An iterator over the records appended to the log of a [`KvStore`](struct.KvStore.html),
+/// created by [`KvStore::tail`](struct.KvStore.html#method.tail).
+///
+/// Every item is the offset of a record in the log with its command. Once the records already
+/// written are consumed, `next` blocks until a new one is appended. A compaction replaces the
+/// log and ends the iteration with `KvsError::StaleOffset`.
+pub struct Tail {
+    store: KvStore,
+    reader: BufReader<StorageReader>,
+    /// The values the records of the log tailed point to.
+    values: Arc<ValueReader>,
+    generation: u64,
+    offset: u64,
+    pending: VecDeque<(u64, Command)>,
+    failed: bool,
+}
+
+impl Tail {
+    pub(super) fn new(store: &KvStore, from_offset: u64) -> Result<Tail> {
+        // Taken under the lock of the version, which compaction holds while it replaces the log,
+        // so that the storage and the generation match.
+        let version = read_lock(&store.version);
+        let head = store.head.state();
+        if from_offset > head.offset {
+            return Err(KvsError::StaleOffset(from_offset));
+        }
+        let log = StorageReader::new(Arc::clone(&version.reader.storage));
+        let values = Arc::clone(&version.reader.values);
+        drop(version);
+
+        Ok(Tail {
+            store: store.clone(),
+            reader: BufReader::with_capacity(store.builder.read_capacity(), log),
+            values,
+            generation: head.generation,
+            offset: from_offset,
+            pending: VecDeque::new(),
+            failed: false,
+        })
+    }
+
+    /// The offset of the next record to be returned, from which tailing can be resumed later.
+    pub fn offset(&self) -> u64 {
+        self.pending.front().map_or(self.offset, |(pos, _)| *pos)
+    }
+
+    /// Returns the next record if one was already appended, without blocking.
+    pub fn try_next(&mut self) -> Option<Result<(u64, Command)>> {
+        if self.pending.is_empty() && !self.failed {
+            if let Err(e) = self.read_appended() {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+
+    /// Reads every complete record after the current offset into `pending`. The head only
+    /// moves past the records once the writer thread flushed them, and the log replaced by a
+    /// compaction stays readable through the storage of the tail.
+    fn read_appended(&mut self) -> Result<()> {
+        let head = self.store.head.state();
+        if head.generation != self.generation {
+            return Err(KvsError::StaleOffset(self.offset));
+        }
+        if head.offset <= self.offset {
+            return Ok(());
+        }
+
+        self.reader.seek(SeekFrom::Start(self.offset))?;
+        let mut log_stream = Deserializer::from_reader(&mut self.reader).into_iter::<LogEntry>();
+        let mut chunks = Assembler::default();
+        let mut pos = self.offset;
+        // A record being written is not complete yet, it is read again on the next call.
+        while let Some(Ok(entry)) = log_stream.next() {
+            if let Some((start, cmd, ptr)) = chunks.push(entry, &self.store.log_path, pos)? {
+                let cmd = resolve(cmd, ptr, &self.values)?;
+                self.pending.push_back((start, cmd));
+            }
+            pos = self.offset + log_stream.byte_offset() as u64;
+        }
+        self.offset = chunks.pending().unwrap_or(pos);
+        Ok(())
+    }
+}
+
+impl Iterator for Tail {
+    type Item = Result<(u64, Command)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.try_next() {
+                return Some(item);
+            }
+            if self.failed {
+                return None;
+            }
+            if let Err(e) = self.store.head.wait_past(self.offset, self.generation) {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
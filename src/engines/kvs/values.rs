@@ -0,0 +1,236 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+use super::storage::{read_exact_at, FileStorage};
+use super::ValueType;
+use crate::error::{KvsError, Result, ResultExt};
+
+/// The prefix of the names of the value logs, followed by their number.
+const VALUE_LOG_PREFIX: &str = "vlog.";
+
+/// Where a value stored in a value log is, with the CRC32 of its bytes.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub(super) struct ValuePtr {
+    /// The number of the value log.
+    file: u32,
+    pos: u64,
+    pub(super) len: u64,
+    crc: u32,
+}
+
+/// A `Set` record of the log whose value is stored in the value log.
+#[derive(Deserialize, Serialize)]
+pub(super) enum PointerCommand {
+    SetRef {
+        key: String,
+        ptr: ValuePtr,
+        #[serde(default, skip_serializing_if = "ValueType::is_string")]
+        value_type: ValueType,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        written_at: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        created_at: Option<u64>,
+    },
+}
+
+/// The values separated from the log: they are appended one after the other to the current
+/// value log, and found through the pointers in the log. Only the writer thread appends them,
+/// while the reads go through a [`ValueReader`](struct.ValueReader.html).
+///
+/// Compaction rewrites the live values to a value log with the next number once the garbage
+/// makes up most of the current one, so that a crash in the middle leaves the values of the
+/// old log intact.
+pub(super) struct ValueLog {
+    dir: PathBuf,
+    file: u32,
+    /// The writer of the current value log, opened on the first access.
+    writer: Option<BufWriter<File>>,
+}
+
+impl ValueLog {
+    /// Opens the latest value log in `dir`, if any.
+    pub(super) fn open(dir: &Path) -> Result<ValueLog> {
+        let mut file = 0;
+        for entry in fs::read_dir(dir)
+            .with_context(|| format!("listing data directory {}", dir.display()))?
+        {
+            let name = entry?.file_name();
+            let number = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(VALUE_LOG_PREFIX))
+                .and_then(|number| number.parse().ok());
+            if let Some(number) = number {
+                file = file.max(number);
+            }
+        }
+        Ok(ValueLog {
+            dir: dir.to_path_buf(),
+            file,
+            writer: None,
+        })
+    }
+
+    /// Creates the reader of the values of the current value log.
+    pub(super) fn reader(&self) -> ValueReader {
+        ValueReader {
+            dir: self.dir.clone(),
+            file: self.file,
+            handle: OnceLock::new(),
+        }
+    }
+
+    /// Creates the value log following this one, replacing a leftover of an interrupted
+    /// compaction.
+    pub(super) fn create_next(&self) -> Result<ValueLog> {
+        let mut next = ValueLog {
+            dir: self.dir.clone(),
+            file: self.file + 1,
+            writer: None,
+        };
+        File::create(next.path())
+            .with_context(|| format!("creating value log {}", next.path().display()))?;
+        next.writer()?;
+        Ok(next)
+    }
+
+    /// The path of the current value log.
+    pub(super) fn path(&self) -> PathBuf {
+        value_log_path(&self.dir, self.file)
+    }
+
+    fn writer(&mut self) -> Result<&mut BufWriter<File>> {
+        if self.writer.is_none() {
+            let path = self.path();
+            let handle = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&path)
+                .with_context(|| format!("opening value log {}", path.display()))?;
+            self.writer = Some(BufWriter::new(handle));
+        }
+        Ok(self.writer.as_mut().unwrap())
+    }
+
+    /// Appends `value` and returns where it was written. The value is flushed right away, like
+    /// the records of the log, so that a record never points past the end of the file.
+    pub(super) fn append(&mut self, value: &str) -> Result<ValuePtr> {
+        let file = self.file;
+        let writer = self.writer()?;
+        let pos = writer.seek(SeekFrom::End(0))?;
+        writer.write_all(value.as_bytes())?;
+        writer.flush()?;
+        Ok(ValuePtr {
+            file,
+            pos,
+            len: value.len() as u64,
+            crc: crc32fast::hash(value.as_bytes()),
+        })
+    }
+
+    /// Flushes the current value log.
+    pub(super) fn flush(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the current value log and returns its size.
+    pub(super) fn size(&mut self) -> Result<u64> {
+        let path = self.path();
+        match &mut self.writer {
+            Some(writer) => Ok(writer.seek(SeekFrom::End(0))?),
+            None if path.exists() => Ok(fs::metadata(path)?.len()),
+            None => Ok(0),
+        }
+    }
+
+    /// Flushes the current value log and returns a handle to it, to be synced.
+    pub(super) fn flushed_file(&mut self) -> Result<Option<File>> {
+        self.flush()?;
+        match &self.writer {
+            Some(writer) => Ok(Some(writer.get_ref().try_clone()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Flushes the current value log and forces it to disk.
+    pub(super) fn sync(&mut self) -> Result<()> {
+        if let Some(file) = self.flushed_file()? {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Switches to `next`, created by [`create_next`](#method.create_next), and returns the
+    /// paths of the value logs before it, which the caller removes once no reader needs them.
+    pub(super) fn replace(&mut self, next: ValueLog) -> Vec<PathBuf> {
+        *self = next;
+        (0..self.file)
+            .map(|file| value_log_path(&self.dir, file))
+            .filter(|old| old.exists())
+            .collect()
+    }
+}
+
+/// Reads the values of a value log by their position, which any number of threads can do at
+/// once through the same handle, without a lock. Each version of the log has its own, reading
+/// the value log its pointers point into.
+pub(super) struct ValueReader {
+    dir: PathBuf,
+    file: u32,
+    /// The value log, opened on the first read.
+    handle: OnceLock<FileStorage>,
+}
+
+impl ValueReader {
+    /// Reads the value `ptr` points to, verifying its checksum.
+    pub(super) fn read(&self, ptr: ValuePtr) -> Result<String> {
+        let path = value_log_path(&self.dir, ptr.file);
+        let context = || format!("reading value log {} at offset {}", path.display(), ptr.pos);
+        let mut buf = vec![0u8; ptr.len as usize];
+        if ptr.file == self.file {
+            read_exact_at(self.handle()?, ptr.pos, &mut buf).with_context(context)?;
+        } else {
+            // Only left behind by a compaction interrupted before it switched logs.
+            let file = FileStorage::open(&path).with_context(context)?;
+            read_exact_at(&file, ptr.pos, &mut buf).with_context(context)?;
+        }
+
+        let actual = crc32fast::hash(&buf);
+        if actual != ptr.crc {
+            return Err(KvsError::Corruption {
+                path,
+                offset: ptr.pos,
+                expected: ptr.crc,
+                actual,
+            });
+        }
+        String::from_utf8(buf).map_err(|e| KvsError::Internal(e.to_string()))
+    }
+
+    /// The handle to the value log. Threads opening it at once each open one, only the first
+    /// of which is kept.
+    fn handle(&self) -> Result<&FileStorage> {
+        if let Some(handle) = self.handle.get() {
+            return Ok(handle);
+        }
+        let path = value_log_path(&self.dir, self.file);
+        let handle = FileStorage::open(&path)
+            .with_context(|| format!("opening value log {}", path.display()))?;
+        Ok(self.handle.get_or_init(|| handle))
+    }
+}
+
+fn value_log_path(dir: &Path, file: u32) -> PathBuf {
+    dir.join(format!("{}{}", VALUE_LOG_PREFIX, file))
+}
@@ -0,0 +1,205 @@
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use tracing::{error, Span};
+
+use super::{BackupSource, Compaction, CompactionStart, KvStore, LogWriter, Snapshot};
+use crate::error::{KvsError, Result, ResultExt};
+
+/// The most writes committed together, so that a steady stream of them does not hold back the
+/// replies of the first ones.
+const MAX_BATCH: usize = 256;
+
+/// A mutation run by the writer thread, which returns what is left to send its result back once
+/// the batch it is part of is committed.
+type Job = Box<dyn FnOnce(&KvStore, &mut LogWriter) -> Reply + Send>;
+
+/// Sends the result of a mutation back, failed if the commit of its batch failed.
+type Reply = Box<dyn FnOnce(Option<&KvsError>) + Send>;
+
+/// What the writer thread is asked to do with the log it owns.
+pub(super) enum Request {
+    /// Runs a mutation, committed along with the others queued meanwhile.
+    Write(Job),
+    /// Syncs the log and writes a checkpoint of the index, replying with the offset it covers.
+    Checkpoint(Sender<Result<u64>>),
+    /// Flushes the logs and replies with what a backup of them copies.
+    Backup(Sender<Result<BackupSource>>),
+    /// Flushes the log and replies with the keys of a cursor and the end of the log they match.
+    Cursor(Sender<Result<(Vec<String>, Snapshot)>>),
+    /// Flushes the log and replies with the live records a compaction copies.
+    StartCompaction(Sender<Result<CompactionStart>>),
+    /// Copies the records written since the compaction started, then replaces the log with the
+    /// compacted one, replying with the number of bytes reclaimed.
+    SwitchLog(Box<Compaction>, Sender<Result<u64>>),
+}
+
+/// A thread owning the writer of the log of a store, which appends every mutation, compacts the
+/// log and writes the checkpoints one after the other, so that none of them waits on a lock
+/// of the log. Stopped once dropped.
+pub(super) struct Writer {
+    requests: Sender<(Span, Request)>,
+}
+
+impl Writer {
+    /// Starts writing to the log through `logwriter` what is sent to it, for `store`, a handle
+    /// without a writer.
+    pub(super) fn start(store: KvStore, logwriter: LogWriter) -> Result<Writer> {
+        let (requests, received) = unbounded();
+        thread::Builder::new()
+            .name("kvs-writer".to_owned())
+            .spawn(move || run(&store, logwriter, &received))
+            .context("starting the writer thread")?;
+        Ok(Writer { requests })
+    }
+
+    /// Runs the mutation `f` on the writer thread and waits for its result, which is only sent
+    /// once it is committed.
+    pub(super) fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&KvStore, &mut LogWriter) -> Result<T> + Send + 'static,
+    {
+        self.call(|reply| {
+            Request::Write(Box::new(move |store, logwriter| -> Reply {
+                let result = f(store, logwriter);
+                Box::new(move |failed: Option<&KvsError>| {
+                    let result = match failed {
+                        Some(e) if result.is_ok() => Err(failed_commit(e)),
+                        _ => result,
+                    };
+                    // The caller waits for the reply, unless it panicked meanwhile.
+                    let _ = reply.send(result);
+                })
+            }))
+        })
+    }
+
+    /// Sends the request made with the sender of its reply, to be handled within the span of
+    /// the caller, and waits for the reply.
+    pub(super) fn call<T, F>(&self, request: F) -> Result<T>
+    where
+        F: FnOnce(Sender<Result<T>>) -> Request,
+    {
+        let (reply, replied) = bounded(1);
+        let stopped = || KvsError::Internal("the writer thread stopped".to_owned());
+        self.requests
+            .send((Span::current(), request(reply)))
+            .map_err(|_| stopped())?;
+        replied.recv().map_err(|_| stopped())?
+    }
+}
+
+/// Handles the requests until the last handle of the store with the writer is dropped.
+fn run(store: &KvStore, mut logwriter: LogWriter, requests: &Receiver<(Span, Request)>) {
+    let mut next = None;
+    loop {
+        let (span, request) = match next.take() {
+            Some(request) => request,
+            None => match requests.recv() {
+                Ok(request) => request,
+                Err(_) => return,
+            },
+        };
+        let job = match request {
+            Request::Write(job) => job,
+            request => {
+                span.in_scope(|| handle(store, &mut logwriter, request));
+                continue;
+            }
+        };
+        next = write_batch(store, &mut logwriter, requests, span, job);
+    }
+}
+
+/// Handles a request other than a write.
+fn handle(store: &KvStore, logwriter: &mut LogWriter, request: Request) {
+    match request {
+        Request::Write(_) => unreachable!("the writes are run in batches"),
+        Request::Checkpoint(reply) => respond(reply, || store.write_checkpoint(logwriter)),
+        Request::Backup(reply) => respond(reply, || store.backup_source(logwriter)),
+        Request::Cursor(reply) => respond(reply, || store.cursor_snapshot(logwriter)),
+        Request::StartCompaction(reply) => respond(reply, || store.start_compaction(logwriter)),
+        Request::SwitchLog(compaction, reply) => {
+            respond(reply, || store.switch_log(logwriter, *compaction))
+        }
+    }
+}
+
+/// Runs `job` along with the writes queued after it, or sent within the commit delay of a store
+/// syncing its writes, then commits them together with a single flush of the log, and sends
+/// their replies. Returns the request which ended the batch, if any.
+fn write_batch(
+    store: &KvStore,
+    logwriter: &mut LogWriter,
+    requests: &Receiver<(Span, Request)>,
+    span: Span,
+    job: Job,
+) -> Option<(Span, Request)> {
+    let delay = if store.builder.sync_writes {
+        store.builder.commit_delay
+    } else {
+        Duration::from_secs(0)
+    };
+    let deadline = Instant::now() + delay;
+    let mut replies = Vec::new();
+    let mut next = None;
+    let mut jobs = 1;
+    replies.extend(run_job(store, logwriter, span, job));
+    while jobs < MAX_BATCH {
+        match requests.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok((span, Request::Write(job))) => {
+                jobs += 1;
+                replies.extend(run_job(store, logwriter, span, job));
+            }
+            Ok(request) => {
+                next = Some(request);
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+
+    let committed = store.commit_batch(logwriter);
+    if let Err(e) = &committed {
+        error!(error = %e, writes = jobs, "Failed to commit a batch of writes.");
+    }
+    for reply in replies {
+        reply(committed.as_ref().err());
+    }
+    next
+}
+
+/// Runs `job` within the span of its caller. A panicking job drops its reply, which its caller
+/// turns into an error, and leaves the locks to be recovered like on any other thread.
+fn run_job(store: &KvStore, logwriter: &mut LogWriter, span: Span, job: Job) -> Option<Reply> {
+    match panic::catch_unwind(AssertUnwindSafe(|| span.in_scope(|| job(store, logwriter)))) {
+        Ok(reply) => Some(reply),
+        Err(_) => {
+            error!("A write panicked.");
+            None
+        }
+    }
+}
+
+/// Sends the result of `f` back through `reply`, which is dropped if `f` panics.
+fn respond<T, F>(reply: Sender<Result<T>>, f: F)
+where
+    F: FnOnce() -> Result<T>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        // The caller waits for the reply, unless it panicked meanwhile.
+        Ok(result) => {
+            let _ = reply.send(result);
+        }
+        Err(_) => error!("A request to the writer thread panicked."),
+    }
+}
+
+/// The error of every write of a batch whose commit failed with `e`.
+fn failed_commit(e: &KvsError) -> KvsError {
+    KvsError::from(io::Error::other(e.to_string()))
+}
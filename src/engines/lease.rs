@@ -0,0 +1,71 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{KvsError, Result};
+
+/// The state of a lock, as kept in the value of its key of type
+/// [`ValueType::Lock`](enum.ValueType.html#variant.Lock) by
+/// [`KvsEngine::acquire_lock`](trait.KvsEngine.html#method.acquire_lock): the fencing token of
+/// its last hold, followed by when the hold expires while it is not released, e.g. "7" or
+/// "8 1700000000000".
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Lease {
+    pub(crate) token: u64,
+    /// When the hold expires, in milliseconds since the Unix epoch, `None` once released.
+    pub(crate) expires_at: Option<u64>,
+}
+
+impl Lease {
+    /// Parses the value of the lock `key`.
+    ///
+    /// # Errors
+    /// Returns `KvsError::Internal` if the value is not the state of a lock.
+    pub(crate) fn parse(key: &str, value: &str) -> Result<Lease> {
+        let malformed = || KvsError::Internal(format!("malformed lock {:?}: {:?}", key, value));
+        let mut fields = value.split(' ');
+        let token = fields
+            .next()
+            .and_then(|token| token.parse().ok())
+            .ok_or_else(malformed)?;
+        let expires_at = match fields.next() {
+            Some(expires_at) => Some(expires_at.parse().map_err(|_| malformed())?),
+            None => None,
+        };
+        if fields.next().is_some() {
+            return Err(malformed());
+        }
+        Ok(Lease { token, expires_at })
+    }
+
+    /// Whether the lock is held at `now`, in milliseconds since the Unix epoch.
+    pub(crate) fn is_held(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at > now)
+    }
+
+    /// Formats the value of the key of the lock.
+    pub(crate) fn to_value(&self) -> String {
+        match self.expires_at {
+            Some(expires_at) => format!("{} {}", self.token, expires_at),
+            None => self.token.to_string(),
+        }
+    }
+}
+
+/// Returns the value of the lock `key` at `value` once released, whatever its hold, which the
+/// engines keep instead of removing the key, so that the next hold still gets a greater token.
+pub(crate) fn released(key: &str, value: &str) -> Result<String> {
+    let lease = Lease::parse(key, value)?;
+    let released = Lease {
+        token: lease.token,
+        expires_at: None,
+    };
+    Ok(released.to_value())
+}
+
+/// Returns the current time in milliseconds since the Unix epoch, which the holds of the locks
+/// expire by.
+pub(crate) fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,161 @@
+use super::lease::released;
+use super::{lock, KvsEngine, ValueType};
+use crate::error::{KvsError, Result};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tracing::debug_span;
+
+/// An engine keeping the dataset in memory, lost when the last clone of the engine is dropped.
+/// It touches neither the filesystem nor threads, so it is the engine available on
+/// `wasm32-unknown-unknown`, and a cheap one for tests.
+#[derive(Clone, Debug, Default)]
+pub struct MemKvsEngine {
+    /// The value of every key.
+    map: Arc<Mutex<BTreeMap<String, Stored>>>,
+    /// The version of the last write, taken under the lock of the map.
+    version: Arc<AtomicU64>,
+}
+
+/// The value of a key, with the version of the write which set it.
+#[derive(Debug)]
+struct Stored {
+    value: String,
+    version: u64,
+    value_type: ValueType,
+}
+
+impl MemKvsEngine {
+    /// Creates an empty engine.
+    pub fn new() -> MemKvsEngine {
+        MemKvsEngine::default()
+    }
+
+    fn next_version(&self) -> u64 {
+        self.version.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Makes the entry of `value` of `value_type`, under a new version.
+    fn store(&self, value: String, value_type: ValueType) -> Stored {
+        Stored {
+            value,
+            version: self.next_version(),
+            value_type,
+        }
+    }
+}
+
+impl KvsEngine for MemKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let _span = debug_span!("set").entered();
+        let mut map = lock(&self.map);
+        if let Some(stored) = map.get(&key) {
+            stored.value_type.check_overwrite(&key, ValueType::String)?;
+        }
+        map.insert(key, self.store(value, ValueType::String));
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let _span = debug_span!("get").entered();
+        match lock(&self.map).get(&key) {
+            Some(stored) => {
+                stored.value_type.check(&key, ValueType::String)?;
+                Ok(Some(stored.value.clone()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn get_typed(&self, key: String) -> Result<Option<(ValueType, String)>> {
+        let _span = debug_span!("get_typed").entered();
+        Ok(lock(&self.map)
+            .get(&key)
+            .map(|stored| (stored.value_type, stored.value.clone())))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let _span = debug_span!("remove").entered();
+        let mut map = lock(&self.map);
+        let stored = map.remove(&key).ok_or(KvsError::KeyNotFound)?;
+        if stored.value_type == ValueType::Lock {
+            let value = released(&key, &stored.value)?;
+            map.insert(key, self.store(value, ValueType::Lock));
+        }
+        Ok(())
+    }
+
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String> + Send + 'static,
+    {
+        let _span = debug_span!("update").entered();
+        let mut map = lock(&self.map);
+        if let Some(stored) = map.get(&key) {
+            stored.value_type.check(&key, ValueType::String)?;
+        }
+        let value = f(map.remove(&key).map(|stored| stored.value));
+        if let Some(value) = &value {
+            map.insert(key, self.store(value.clone(), ValueType::String));
+        }
+        Ok(value)
+    }
+
+    fn update_typed<F>(&self, key: String, f: F) -> Result<Option<(ValueType, String)>>
+    where
+        F: FnOnce(Option<(ValueType, String)>) -> Option<(ValueType, String)> + Send + 'static,
+    {
+        let _span = debug_span!("update_typed").entered();
+        let mut map = lock(&self.map);
+        let old = map
+            .remove(&key)
+            .map(|stored| (stored.value_type, stored.value));
+        let typed = f(old);
+        if let Some((value_type, value)) = &typed {
+            map.insert(key, self.store(value.clone(), *value_type));
+        }
+        Ok(typed)
+    }
+
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        let _span = debug_span!("get_versioned").entered();
+        match lock(&self.map).get(&key) {
+            Some(stored) => {
+                stored.value_type.check(&key, ValueType::String)?;
+                Ok(Some((stored.value.clone(), stored.version)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn set_if_version(&self, key: String, value: String, expected: Option<u64>) -> Result<u64> {
+        let _span = debug_span!("set_if_version").entered();
+        let mut map = lock(&self.map);
+        let actual = map.get(&key).map(|stored| stored.version);
+        if actual != expected {
+            return Err(KvsError::VersionMismatch { expected, actual });
+        }
+        if let Some(stored) = map.get(&key) {
+            stored.value_type.check_overwrite(&key, ValueType::String)?;
+        }
+        let stored = self.store(value, ValueType::String);
+        let version = stored.version;
+        map.insert(key, stored);
+        Ok(version)
+    }
+
+    fn scan(&self) -> Vec<String> {
+        lock(&self.map).keys().cloned().collect()
+    }
+
+    fn list_keys(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        let map = lock(&self.map);
+        let keys = match after {
+            Some(after) => map.range::<str, _>((Bound::Excluded(after), Bound::Unbounded)),
+            None => map.range::<str, _>(..),
+        };
+        Ok(keys.take(limit).map(|(key, _)| key.clone()).collect())
+    }
+}
@@ -1,9 +1,46 @@
-pub use self::kvs::KvStore;
+use self::bitcask::{Record, RecordReader};
+use self::collections::Collection;
+pub use self::cursor::{CursorToken, KeyCursor};
+pub use self::keys::{KeyCharset, KeyPolicy};
+#[cfg(feature = "fault-injection")]
+pub use self::kvs::FaultPlan;
+#[cfg(feature = "s3")]
+pub use self::kvs::S3Target;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::kvs::{
+    BackupTarget, Command, CompactionStats, CompactionTrigger, DirTarget, FileStorage, KvStore,
+    KvStoreBuilder, LogStorage, RepairReport, SizeHistogram, StoreStats, Tail, TombstonePolicy,
+    Version,
+};
+use self::lease::{unix_time_ms, Lease};
+pub use self::memory::MemKvsEngine;
+use self::rdb::RdbWriter;
+pub use self::sled::SledFlushPolicy;
+#[cfg(feature = "sled")]
 pub use self::sled::SledKvsEngine;
-use crate::Result;
+use self::sst::SstWriter;
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+mod bitcask;
+mod collections;
+mod cursor;
+mod glob;
+mod keys;
+#[cfg(not(target_arch = "wasm32"))]
 mod kvs;
+mod lease;
+mod memory;
+mod rdb;
 mod sled;
+mod sst;
 
 /// An interface for representing the backend engine of kvs.
 pub trait KvsEngine: Clone + Send + 'static {
@@ -11,16 +48,741 @@ pub trait KvsEngine: Clone + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
 
     /// Get the string value of a string key. If the key does not exist, return `None`.
+    ///
+    /// # Errors
+    /// Returns `KvsError::WrongType` if the key holds another type than a string.
     fn get(&self, key: String) -> Result<Option<String>>;
 
-    /// Remove a given string key.
+    /// Returns the length in bytes of the value of `key`, or `None` if the key does not exist.
+    /// The engines which know it without reading the value answer without reading it.
+    fn strlen(&self, key: String) -> Result<Option<u64>> {
+        Ok(self.get(key)?.map(|value| value.len() as u64))
+    }
+
+    /// Gets the values of `keys`, in the same order, `None` for the keys that do not exist.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Remove a given string key. A lock is released instead, keeping the token of its last
+    /// hold, as [`acquire_lock`](#method.acquire_lock) tells.
     fn remove(&self, key: String) -> Result<()>;
 
+    /// Sets `key` to what `f` returns given its current value, or removes it if `f` returns
+    /// `None`, with no other write of the key in between. Returns the new value.
+    ///
+    /// ```
+    /// use kvs::{KvsEngine, MemKvsEngine};
+    ///
+    /// let engine = MemKvsEngine::new();
+    /// let incr = |old: Option<String>| {
+    ///     let count: u64 = old.map_or(0, |old| old.parse().unwrap());
+    ///     Some((count + 1).to_string())
+    /// };
+    /// engine.update("visits".to_owned(), incr)?;
+    /// assert_eq!(engine.update("visits".to_owned(), incr)?.as_deref(), Some("2"));
+    /// assert_eq!(engine.update("visits".to_owned(), |_| None)?, None);
+    /// assert_eq!(engine.get("visits".to_owned())?, None);
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    ///
+    /// # Errors
+    /// Returns `KvsError::CmdNotSupport` if the engine cannot apply `f` atomically.
+    fn update<F>(&self, _key: String, _f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String> + Send + 'static,
+    {
+        Err(KvsError::CmdNotSupport)
+    }
+
+    /// Gets the value of `key` along with its type, which the string reads do not take, e.g.
+    /// the elements of a list encoded in JSON. The engines which only keep strings return their
+    /// values as such. If the key does not exist, return `None`.
+    fn get_typed(&self, key: String) -> Result<Option<(ValueType, String)>> {
+        Ok(self.get(key)?.map(|value| (ValueType::String, value)))
+    }
+
+    /// Sets `key` to what `f` returns given its current value and type, or removes it if `f`
+    /// returns `None`, as [`update`](#method.update) does for the strings only. The type is
+    /// kept along with the value, not in it, so that no string can pass for another type.
+    ///
+    /// # Errors
+    /// Returns `KvsError::CmdNotSupport` if the engine cannot apply `f` atomically, or does not
+    /// keep the types.
+    fn update_typed<F>(&self, _key: String, _f: F) -> Result<Option<(ValueType, String)>>
+    where
+        F: FnOnce(Option<(ValueType, String)>) -> Option<(ValueType, String)> + Send + 'static,
+    {
+        Err(KvsError::CmdNotSupport)
+    }
+
     /// Returns an iterator of all the keys in the DataBase.
     fn scan(&self) -> Vec<String>;
 
+    /// Gets the value of `key` along with its version, which increases with every write of the
+    /// store, for [`set_if_version`](#method.set_if_version). If the key does not exist, return
+    /// `None`.
+    ///
+    /// # Errors
+    /// Returns `KvsError::CmdNotSupport` if the engine does not version the keys.
+    fn get_versioned(&self, _key: String) -> Result<Option<(String, u64)>> {
+        Err(KvsError::CmdNotSupport)
+    }
+
+    /// Sets `key` to `value` only if the key is still at the version `expected`, or still does
+    /// not exist if `None`, as returned by [`get_versioned`](#method.get_versioned), so that a
+    /// read-modify-write spanning several requests loses no concurrent update. Returns the new
+    /// version of the key.
+    ///
+    /// ```
+    /// use kvs::{KvsEngine, KvsError, MemKvsEngine};
+    ///
+    /// let engine = MemKvsEngine::new();
+    /// let version = engine.set_if_version("key".to_owned(), "a".to_owned(), None)?;
+    /// engine.set("key".to_owned(), "b".to_owned())?;
+    /// match engine.set_if_version("key".to_owned(), "c".to_owned(), Some(version)) {
+    ///     Err(KvsError::VersionMismatch { .. }) => {}
+    ///     _ => panic!("expected a version mismatch"),
+    /// }
+    /// let (value, version) = engine.get_versioned("key".to_owned())?.unwrap();
+    /// assert_eq!(value, "b");
+    /// engine.set_if_version("key".to_owned(), "c".to_owned(), Some(version))?;
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    ///
+    /// # Errors
+    /// Returns `KvsError::VersionMismatch` if the key is at another version, and
+    /// `KvsError::CmdNotSupport` if the engine does not version the keys.
+    fn set_if_version(&self, _key: String, _value: String, _expected: Option<u64>) -> Result<u64> {
+        Err(KvsError::CmdNotSupport)
+    }
+
+    /// Returns when `key` was created and last written, with its version and the size of its
+    /// value, or `None` if the key does not exist.
+    ///
+    /// # Errors
+    /// Returns `KvsError::CmdNotSupport` if the engine does not record them.
+    fn get_metadata(&self, _key: String) -> Result<Option<KeyMetadata>> {
+        Err(KvsError::CmdNotSupport)
+    }
+
+    /// Acquires the lock `name` for `ttl`, unless it is held and its hold did not expire yet,
+    /// in which case `None` is returned. Returns the fencing token of the hold, greater than
+    /// the tokens of every hold of the lock before it, which the resources guarded by the lock
+    /// can check to reject the writes of a holder whose hold expired meanwhile.
+    ///
+    /// The lock is kept in the key `name` through [`update_typed`](#method.update_typed), so
+    /// that two clients never both acquire it. The key is not removed once the lock is released,
+    /// nor by [`remove`](#method.remove), which releases it, but keeps the token of the last
+    /// hold, so that the next one is greater. Nothing but a lock is ever written over it.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use kvs::{KvsEngine, MemKvsEngine};
+    ///
+    /// let engine = MemKvsEngine::new();
+    /// let ttl = Duration::from_secs(30);
+    /// let token = engine.acquire_lock("leader".to_owned(), ttl)?.unwrap();
+    /// assert_eq!(engine.acquire_lock("leader".to_owned(), ttl)?, None);
+    /// assert!(engine.release_lock("leader".to_owned(), token)?);
+    /// assert!(engine.acquire_lock("leader".to_owned(), ttl)?.unwrap() > token);
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    ///
+    /// # Errors
+    /// Returns `KvsError::WrongType` if the key holds something else than a lock, and
+    /// `KvsError::CmdNotSupport` if the engine cannot update keys atomically.
+    fn acquire_lock(&self, name: String, ttl: Duration) -> Result<Option<u64>> {
+        update_with(self, name.clone(), move |old| {
+            let now = unix_time_ms();
+            let lease = match old {
+                Some((value_type, value)) => {
+                    value_type.check(&name, ValueType::Lock)?;
+                    Lease::parse(&name, value)?
+                }
+                None => Lease::default(),
+            };
+            if lease.is_held(now) {
+                return Ok((None, None));
+            }
+            let lease = Lease {
+                token: lease.token + 1,
+                expires_at: Some(now.saturating_add(ttl.as_millis() as u64)),
+            };
+            Ok((Some(lease.token), Some((ValueType::Lock, lease.to_value()))))
+        })
+    }
+
+    /// Releases the hold of the lock `name` whose fencing token is `token`, as returned by
+    /// [`acquire_lock`](#method.acquire_lock). Returns false if the lock is not held under
+    /// this token, because the hold expired or the lock was released already.
+    ///
+    /// # Errors
+    /// Returns `KvsError::WrongType` if the key holds something else than a lock, and
+    /// `KvsError::CmdNotSupport` if the engine cannot update keys atomically.
+    fn release_lock(&self, name: String, token: u64) -> Result<bool> {
+        update_with(self, name.clone(), move |old| {
+            let lease = match old {
+                Some((value_type, value)) => {
+                    value_type.check(&name, ValueType::Lock)?;
+                    Lease::parse(&name, value)?
+                }
+                None => return Ok((false, None)),
+            };
+            if lease.token != token || !lease.is_held(unix_time_ms()) {
+                return Ok((false, None));
+            }
+            let released = Lease {
+                token,
+                expires_at: None,
+            };
+            Ok((true, Some((ValueType::Lock, released.to_value()))))
+        })
+    }
+
+    /// Pushes `values` to the head of the list at `key` one after the other, so that the last
+    /// one ends up first, creating the list if the key does not exist. Returns the length of
+    /// the list.
+    ///
+    /// The lists, sets and hashes are kept whole in the value of their key, along with their
+    /// type, which [`update_typed`](#method.update_typed) rewrites on every change.
+    ///
+    /// ```
+    /// use kvs::{KvsEngine, MemKvsEngine};
+    ///
+    /// let engine = MemKvsEngine::new();
+    /// engine.list_push("queue".to_owned(), vec!["a".to_owned(), "b".to_owned()])?;
+    /// assert_eq!(engine.list_push("queue".to_owned(), vec!["c".to_owned()])?, 3);
+    /// assert_eq!(engine.list_range("queue".to_owned(), 0, -1)?, vec!["c", "b", "a"]);
+    /// assert_eq!(engine.list_range("queue".to_owned(), -2, 5)?, vec!["b", "a"]);
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    ///
+    /// # Errors
+    /// Returns `KvsError::WrongType` if the key holds something else than a list, and
+    /// `KvsError::CmdNotSupport` if the engine cannot update keys atomically.
+    fn list_push(&self, key: String, values: Vec<String>) -> Result<usize> {
+        update_with(self, key.clone(), move |old| {
+            let mut list = collections::decode_list(&key, old)?;
+            if values.is_empty() {
+                return Ok((list.len(), None));
+            }
+            for value in values {
+                list.push_front(value);
+            }
+            Ok((list.len(), Some(Collection::List(list).encode())))
+        })
+    }
+
+    /// Returns the elements of the list at `key` from `start` to `stop` included, both counted
+    /// from the head of the list, or from its tail if negative, -1 being the last element. The
+    /// list is empty if the key does not exist.
+    ///
+    /// # Errors
+    /// Returns `KvsError::WrongType` if the key holds something else than a list.
+    fn list_range(&self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        let value = self.get_typed(key.clone())?;
+        let list = collections::decode_list(&key, as_typed(&value))?;
+        Ok(collections::range(&list, start, stop))
+    }
+
+    /// Adds `members` to the set at `key`, creating the set if the key does not exist. Returns
+    /// the number of members which were not in the set yet.
+    ///
+    /// ```
+    /// use kvs::{KvsEngine, MemKvsEngine};
+    ///
+    /// let engine = MemKvsEngine::new();
+    /// let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect();
+    /// assert_eq!(engine.set_add("tags".to_owned(), tags(&["b", "a"]))?, 2);
+    /// assert_eq!(engine.set_add("tags".to_owned(), tags(&["a", "c"]))?, 1);
+    /// assert_eq!(engine.set_members("tags".to_owned())?, vec!["a", "b", "c"]);
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    ///
+    /// # Errors
+    /// Returns `KvsError::WrongType` if the key holds something else than a set, and
+    /// `KvsError::CmdNotSupport` if the engine cannot update keys atomically.
+    fn set_add(&self, key: String, members: Vec<String>) -> Result<usize> {
+        update_with(self, key.clone(), move |old| {
+            let mut set = collections::decode_set(&key, old)?;
+            let added = members
+                .into_iter()
+                .filter(|member| set.insert(member.clone()))
+                .count();
+            if added == 0 {
+                return Ok((0, None));
+            }
+            Ok((added, Some(Collection::Set(set).encode())))
+        })
+    }
+
+    /// Returns the members of the set at `key` in ascending order, none if the key does not
+    /// exist.
+    ///
+    /// # Errors
+    /// Returns `KvsError::WrongType` if the key holds something else than a set.
+    fn set_members(&self, key: String) -> Result<Vec<String>> {
+        let value = self.get_typed(key.clone())?;
+        let set = collections::decode_set(&key, as_typed(&value))?;
+        Ok(set.into_iter().collect())
+    }
+
+    /// Sets the `field` of the hash at `key` to `value`, creating the hash if the key does not
+    /// exist. Returns whether the field is new.
+    ///
+    /// ```
+    /// use kvs::{KvsEngine, MemKvsEngine};
+    ///
+    /// let engine = MemKvsEngine::new();
+    /// engine.hash_set("user:1".to_owned(), "name".to_owned(), "alice".to_owned())?;
+    /// let name = engine.hash_get("user:1".to_owned(), "name".to_owned())?;
+    /// assert_eq!(name.as_deref(), Some("alice"));
+    /// assert_eq!(engine.hash_get("user:1".to_owned(), "age".to_owned())?, None);
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    ///
+    /// # Errors
+    /// Returns `KvsError::WrongType` if the key holds something else than a hash, and
+    /// `KvsError::CmdNotSupport` if the engine cannot update keys atomically.
+    fn hash_set(&self, key: String, field: String, value: String) -> Result<bool> {
+        update_with(self, key.clone(), move |old| {
+            let mut hash = collections::decode_hash(&key, old)?;
+            let created = hash.insert(field, value).is_none();
+            Ok((created, Some(Collection::Hash(hash).encode())))
+        })
+    }
+
+    /// Returns the value of the `field` of the hash at `key`, `None` if the hash has no such
+    /// field or the key does not exist.
+    ///
+    /// # Errors
+    /// Returns `KvsError::WrongType` if the key holds something else than a hash.
+    fn hash_get(&self, key: String, field: String) -> Result<Option<String>> {
+        let value = self.get_typed(key.clone())?;
+        let mut hash = collections::decode_hash(&key, as_typed(&value))?;
+        Ok(hash.remove(&field))
+    }
+
+    /// Returns up to `limit` keys following `after`, or the first ones if `None`, in ascending
+    /// order, so that the keys can be listed a page at a time. By default, every page goes
+    /// through every key and sorts the ones following `after`, so that listing them all takes
+    /// time quadratic in their number. The engines keeping their keys in order, as `KvStore`,
+    /// `SledKvsEngine` and `MemKvsEngine` do, only read the keys of the page from where `after`
+    /// is.
+    ///
+    /// ```
+    /// use kvs::{KvsEngine, MemKvsEngine};
+    ///
+    /// let engine = MemKvsEngine::new();
+    /// for key in &["c", "a", "d", "b"] {
+    ///     engine.set(key.to_string(), "value".to_owned())?;
+    /// }
+    /// assert_eq!(engine.list_keys(None, 2)?, vec!["a", "b"]);
+    /// assert_eq!(engine.list_keys(Some("b"), 2)?, vec!["c", "d"]);
+    /// assert!(engine.list_keys(Some("d"), 2)?.is_empty());
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    fn list_keys(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        let mut keys = self.scan();
+        if let Some(after) = after {
+            keys.retain(|key| key.as_str() > after);
+        }
+        keys.sort();
+        keys.truncate(limit);
+        Ok(keys)
+    }
+
+    /// Returns a cursor over the keys, in ascending order.
+    fn cursor(&self) -> KeyCursor {
+        KeyCursor::new(self.scan())
+    }
+
+    /// Resumes the cursor which returned `token` after the last key it returned. It goes over
+    /// the keys there are now, unless the engine can find the ones of the cursor again, as
+    /// `KvStore` does.
+    fn resume_cursor(&self, token: &CursorToken) -> Result<KeyCursor> {
+        Ok(KeyCursor::resume(self.scan(), token))
+    }
+
+    /// Returns the keys matching the glob-style `pattern`, in no particular order. `*` matches
+    /// any run of characters, `?` any single one, `[abc]` one of those listed, `[a-z]` one of a
+    /// range and `[^a]` or `[!a]` any but those. `\` makes the character after it literal.
+    ///
+    /// ```
+    /// use kvs::{KvsEngine, MemKvsEngine};
+    ///
+    /// let engine = MemKvsEngine::new();
+    /// engine.set("user:1".to_owned(), "alice".to_owned())?;
+    /// engine.set("user:2".to_owned(), "bob".to_owned())?;
+    /// engine.set("order:1".to_owned(), "tea".to_owned())?;
+    ///
+    /// let mut keys = engine.keys("user:*");
+    /// keys.sort();
+    /// assert_eq!(keys, vec!["user:1", "user:2"]);
+    /// assert_eq!(engine.keys("*:[!2]").len(), 2);
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    fn keys(&self, pattern: &str) -> Vec<String> {
+        let mut keys = self.scan();
+        keys.retain(|key| glob::matches(pattern, key));
+        keys
+    }
+
     /// Store index file of DataBase to disk.
     fn save_index_log(&self) -> Result<()> {
         Ok(())
     }
+
+    /// Describes the state of the engine as `name:value` lines, reported by the INFO command of
+    /// `kvs-server` after its own. None by default.
+    fn info_lines(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Sets `key` to `value` for `ttl` only, after which the key is no longer found.
+    ///
+    /// # Errors
+    /// Returns `KvsError::CmdNotSupport` if the engine does not support expiring keys.
+    fn set_with_ttl(&self, _key: String, _value: String, _ttl: Duration) -> Result<()> {
+        Err(KvsError::CmdNotSupport)
+    }
+
+    /// Makes the existing `key` expire after `ttl`.
+    ///
+    /// # Errors
+    /// Returns `KvsError::KeyNotFound` if the key does not exist, and `KvsError::CmdNotSupport`
+    /// if the engine does not support expiring keys.
+    fn expire(&self, _key: String, _ttl: Duration) -> Result<()> {
+        Err(KvsError::CmdNotSupport)
+    }
+
+    /// Returns how long `key` has left to live, `None` if it does not expire, as no key of an
+    /// engine not supporting expiring keys does.
+    ///
+    /// # Errors
+    /// Returns `KvsError::KeyNotFound` if the key does not exist or expired.
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.get_typed(key)?
+            .map(|_| None)
+            .ok_or(KvsError::KeyNotFound)
+    }
+
+    /// Writes every key-value pair to `writer` as JSON lines, in key order, with the type of the
+    /// values which are not strings. Returns the number of pairs written.
+    fn export<W: Write>(&self, mut writer: W) -> Result<u64> {
+        let mut keys = self.scan();
+        keys.sort();
+
+        let mut count = 0;
+        for key in keys {
+            if let Some((value_type, value)) = self.get_typed(key.clone())? {
+                let entry = Entry {
+                    key,
+                    value,
+                    value_type,
+                };
+                serde_json::to_writer(&mut writer, &entry)?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Writes every key-value pair to `writer` as a Redis dump file, in key order, so that it can
+    /// be loaded by Redis 5.0 or later or read by the tools analyzing such files. Every pair
+    /// becomes a string key of database 0, the keys holding other types being left out. Returns
+    /// the number of pairs written.
+    fn export_rdb<W: Write>(&self, writer: W) -> Result<u64> {
+        let mut keys = self.scan();
+        keys.sort();
+
+        let mut rdb = RdbWriter::new(writer)?;
+        let mut count = 0;
+        for key in keys {
+            if let Some((ValueType::String, value)) = self.get_typed(key.clone())? {
+                rdb.write_string(&key, &value)?;
+                count += 1;
+            }
+        }
+        rdb.finish()?;
+        Ok(count)
+    }
+
+    /// Writes every key-value pair to `writer` as a Bitcask data file, in key order, so that it
+    /// can be read by the Bitcask tools once named like one, e.g. `1.bitcask.data`. Every record
+    /// is stamped with the current time. The keys holding other types than strings are left
+    /// out. Returns the number of pairs written.
+    ///
+    /// The data files are only exchanged with Bitcask this way and by
+    /// [`import_bitcask`](#method.import_bitcask): the engines keep their own format on disk,
+    /// which the Bitcask tools cannot open in place.
+    fn export_bitcask<W: Write>(&self, mut writer: W) -> Result<u64> {
+        let mut keys = self.scan();
+        keys.sort();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as u32)
+            .unwrap_or(0);
+        let mut count = 0;
+        for key in keys {
+            if let Some((ValueType::String, value)) = self.get_typed(key.clone())? {
+                bitcask::write_record(&mut writer, timestamp, &key, &value)?;
+                count += 1;
+            }
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Writes every key-value pair to `writer` as an SST file of RocksDB, in key order, so that
+    /// it can be bulk-loaded into RocksDB or TiKV with `IngestExternalFile`. The keys holding
+    /// other types than strings are left out. Returns the number of pairs written.
+    ///
+    /// # Errors
+    /// Returns an error if the engine is empty, as an SST file cannot be.
+    fn export_sst<W: Write>(&self, writer: W) -> Result<u64> {
+        let mut keys = self.scan();
+        keys.sort();
+
+        let mut sst = SstWriter::new(writer);
+        let mut count = 0;
+        for key in keys {
+            if let Some((ValueType::String, value)) = self.get_typed(key.clone())? {
+                sst.add(&key, &value)?;
+                count += 1;
+            }
+        }
+        sst.finish()?;
+        Ok(count)
+    }
+
+    /// Applies the records of the Bitcask data file at `path` in order, setting the keys they
+    /// put and removing the ones they delete. The keys and values have to be valid UTF-8.
+    /// Returns the number of pairs set.
+    ///
+    /// # Errors
+    /// Returns `KvsError::Corruption` for a record whose checksum does not match its content.
+    fn import_bitcask<P: AsRef<Path>>(&self, path: P) -> Result<u64> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+        let mut records = RecordReader::new(reader, path.to_owned());
+        let mut count = 0;
+        while let Some(record) = records.next_record()? {
+            match record {
+                Record::Put { key, value } => {
+                    self.set(key, value)?;
+                    count += 1;
+                }
+                Record::Delete { key } => match self.remove(key) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+        Ok(count)
+    }
+
+    /// Sets every key-value pair read from `reader`, in the format written by
+    /// [`export`](#method.export). Returns the number of pairs imported.
+    ///
+    /// # Errors
+    /// Returns `KvsError::CmdNotSupport` for a value of another type than a string if the engine
+    /// does not keep the types.
+    fn import<R: BufRead>(&self, reader: R) -> Result<u64> {
+        let mut count = 0;
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_str(&line)
+                .map_err(|e| KvsError::from(e).with_context(format!("line {}", line_no + 1)))?;
+            let Entry {
+                key,
+                value,
+                value_type,
+            } = entry;
+            if value_type.is_string() {
+                self.set(key, value)?;
+            } else {
+                collections::validate(&key, value_type, &value)?;
+                self.update_typed(key, move |_| Some((value_type, value)))?;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// What [`KvsEngine::get_metadata`](trait.KvsEngine.html#method.get_metadata) knows of a key.
+/// The times are in milliseconds since the Unix epoch, `None` for the writes made before they
+/// were recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyMetadata {
+    /// When the key was set while it did not exist.
+    pub created_at: Option<u64>,
+    /// When the key was last written.
+    pub modified_at: Option<u64>,
+    /// The version of the last write, as returned by
+    /// [`get_versioned`](trait.KvsEngine.html#method.get_versioned), 0 if the engine does not
+    /// version the keys.
+    pub version: u64,
+    /// The length in bytes of the value.
+    pub size: u64,
+}
+
+/// What the value of a key holds, kept along with the value by the engines rather than in it,
+/// so that the value of a string can be anything. The strings are the values of `set`, the
+/// other types those of the commands working on them, e.g.
+/// [`list_push`](trait.KvsEngine.html#method.list_push).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ValueType {
+    /// A string, read by `get`.
+    #[default]
+    String,
+    /// A list of strings, encoded as a JSON array from its head.
+    List,
+    /// A set of strings, encoded as a JSON array in ascending order.
+    Set,
+    /// A hash of fields to strings, encoded as a JSON object.
+    Hash,
+    /// A lock, kept by [`acquire_lock`](trait.KvsEngine.html#method.acquire_lock) along with
+    /// the fencing token of its last hold.
+    Lock,
+}
+
+impl ValueType {
+    /// The name of the type, as reported by `KvsError::WrongType`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueType::String => "string",
+            ValueType::List => "list",
+            ValueType::Set => "set",
+            ValueType::Hash => "hash",
+            ValueType::Lock => "lock",
+        }
+    }
+
+    pub(crate) fn is_string(&self) -> bool {
+        *self == ValueType::String
+    }
+
+    /// Fails with `KvsError::WrongType` unless the type is `expected`.
+    pub(crate) fn check(self, key: &str, expected: ValueType) -> Result<()> {
+        if self == expected {
+            Ok(())
+        } else {
+            Err(KvsError::WrongType {
+                key: key.to_owned(),
+                expected: expected.name(),
+            })
+        }
+    }
+
+    /// Fails with `KvsError::WrongType` if a value of type `new` cannot be written over one of
+    /// this type: a lock is only ever rewritten as a lock, so that its token is never lost.
+    pub(crate) fn check_overwrite(self, key: &str, new: ValueType) -> Result<()> {
+        if self == ValueType::Lock && new != ValueType::Lock {
+            return Err(KvsError::WrongType {
+                key: key.to_owned(),
+                expected: new.name(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The byte the type is stored as where it is not serialized.
+    pub(crate) fn tag(self) -> u8 {
+        self as u8
+    }
+
+    /// The type stored as `tag`, `None` if it is no type.
+    pub(crate) fn from_tag(tag: u8) -> Option<ValueType> {
+        [
+            ValueType::String,
+            ValueType::List,
+            ValueType::Set,
+            ValueType::Hash,
+            ValueType::Lock,
+        ]
+        .iter()
+        .copied()
+        .find(|value_type| value_type.tag() == tag)
+    }
+}
+
+/// A key-value pair as written by [`KvsEngine::export`](trait.KvsEngine.html#method.export),
+/// along with the type of the value unless it is a string.
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    key: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "ValueType::is_string")]
+    value_type: ValueType,
+}
+
+/// Applies `f` to the value of `key` and its type through
+/// [`KvsEngine::update_typed`](trait.KvsEngine.html#method.update_typed), and returns what it
+/// returns besides the new value. The value is kept as is if `f` gives no new value, or fails.
+fn update_with<E, T, F>(engine: &E, key: String, f: F) -> Result<T>
+where
+    E: KvsEngine,
+    T: Send + 'static,
+    F: FnOnce(Option<(ValueType, &str)>) -> Result<(T, Option<(ValueType, String)>)>
+        + Send
+        + 'static,
+{
+    let outcome = Arc::new(Mutex::new(None));
+    let recorded = Arc::clone(&outcome);
+    engine.update_typed(key, move |old| {
+        let (result, value) = match f(as_typed(&old)) {
+            Ok((result, Some(value))) => (Ok(result), Some(value)),
+            Ok((result, None)) => (Ok(result), old),
+            Err(e) => (Err(e), old),
+        };
+        *lock(&recorded) = Some(result);
+        value
+    })?;
+    let result = lock(&outcome).take();
+    result.expect("update applies the function")
+}
+
+/// Borrows the value of a typed read.
+fn as_typed(value: &Option<(ValueType, String)>) -> Option<(ValueType, &str)> {
+    value
+        .as_ref()
+        .map(|(value_type, value)| (*value_type, value.as_str()))
+}
+
+/// Locks `mutex`, recovering it if a thread panicked while holding it, so that a single
+/// panic does not turn every later operation on the engine into a panic as well. Every
+/// operation re-seeks the log and the index is only updated after a successful write, so
+/// the state behind the lock stays usable.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Locks `rwlock` for reading, recovering it like [`lock`](fn.lock.html).
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn read_lock<T>(rwlock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    rwlock.read().unwrap_or_else(|poisoned| {
+        rwlock.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Locks `rwlock` for writing, recovering it like [`lock`](fn.lock.html).
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn write_lock<T>(rwlock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    rwlock.write().unwrap_or_else(|poisoned| {
+        rwlock.clear_poison();
+        poisoned.into_inner()
+    })
 }
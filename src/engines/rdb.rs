@@ -0,0 +1,86 @@
+use std::io::{self, Write};
+
+/// The header of the files written, those of version 9 of the RDB format, read by Redis 5.0
+/// and later.
+const MAGIC: &[u8] = b"REDIS0009";
+
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+const TYPE_STRING: u8 = 0;
+
+/// The reversed polynomial of the CRC-64/Jones checksum ending the files.
+const CRC64_POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+
+/// Writes string keys to a Redis dump file, all in database 0.
+pub(crate) struct RdbWriter<W: Write> {
+    writer: W,
+    crc: u64,
+}
+
+impl<W: Write> RdbWriter<W> {
+    /// Writes the header of the file to `writer`.
+    pub(crate) fn new(writer: W) -> io::Result<RdbWriter<W>> {
+        let mut rdb = RdbWriter { writer, crc: 0 };
+        rdb.write(MAGIC)?;
+        rdb.write(&[OPCODE_SELECTDB])?;
+        rdb.write_length(0)?;
+        Ok(rdb)
+    }
+
+    /// Writes `key` set to `value`.
+    pub(crate) fn write_string(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.write(&[TYPE_STRING])?;
+        self.write_bytes(key.as_bytes())?;
+        self.write_bytes(value.as_bytes())
+    }
+
+    /// Writes the end of the file and its checksum, and flushes it.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.write(&[OPCODE_EOF])?;
+        let crc = self.crc;
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_length(bytes.len() as u64)?;
+        self.write(bytes)
+    }
+
+    /// Writes `len` in the variable-length encoding of the format: 6 bits, 14 bits, then 32 or
+    /// 64 bits after a byte of their own, big-endian.
+    fn write_length(&mut self, len: u64) -> io::Result<()> {
+        if len < 1 << 6 {
+            self.write(&[len as u8])
+        } else if len < 1 << 14 {
+            self.write(&[0x40 | (len >> 8) as u8, len as u8])
+        } else if len <= u64::from(u32::MAX) {
+            self.write(&[0x80])?;
+            self.write(&(len as u32).to_be_bytes())
+        } else {
+            self.write(&[0x81])?;
+            self.write(&len.to_be_bytes())
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.crc = crc64(self.crc, bytes);
+        self.writer.write_all(bytes)
+    }
+}
+
+/// Updates the CRC-64/Jones checksum `crc` with `bytes`, as Redis does for its dump files.
+fn crc64(mut crc: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        crc ^= u64::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
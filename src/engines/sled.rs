@@ -1,50 +1,293 @@
-use super::KvsEngine;
-use crate::error::{KvsError, Result};
+#[cfg(feature = "sled")]
+use super::lease::released;
+#[cfg(feature = "sled")]
+use super::{lock, KvsEngine, ValueType};
+#[cfg(feature = "sled")]
+use crate::error::{KvsError, Result, ResultExt};
+#[cfg(feature = "sled")]
+use std::ops::Bound;
+#[cfg(feature = "sled")]
 use std::path::Path;
+use std::str::FromStr;
+#[cfg(feature = "sled")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "sled")]
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use sled::Db;
+#[cfg(feature = "sled")]
+use sled::{ConfigBuilder, Db, IVec, Tree};
+#[cfg(feature = "sled")]
+use tracing::{debug, debug_span};
 
 /// Wrapper of the [sled](https://docs.rs/sled/0.24.1/sled/) backed engine.
+#[cfg(feature = "sled")]
 #[derive(Clone)]
 pub struct SledKvsEngine {
     database: Arc<Mutex<Db>>,
+    /// The type of every key holding something else than a string, kept apart from the values
+    /// so that no string can pass for another type. Only written under the lock of `database`.
+    types: Arc<Tree>,
+    flush_policy: SledFlushPolicy,
+    /// The number of writes since the engine was opened.
+    writes: Arc<AtomicU64>,
 }
 
+/// When the [`SledKvsEngine`](struct.SledKvsEngine.html) flushes its writes to disk. The writes
+/// not flushed yet are lost by a crash, but sled never leaves them half done. Available without
+/// the `sled` feature too, so that the options of kvs-server are the same in every build.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SledFlushPolicy {
+    /// Leave it to sled, which flushes in the background every 500ms.
+    #[default]
+    Background,
+    /// Flush in the background every given interval.
+    Interval(Duration),
+    /// Flush every given number of writes, before the last of them returns. Every write is
+    /// durable with `EveryWrites(1)`.
+    EveryWrites(u64),
+    /// Only flush when asked to by [`save_index_log`](trait.KvsEngine.html#method.save_index_log),
+    /// and when the engine is dropped.
+    Never,
+}
+
+impl FromStr for SledFlushPolicy {
+    type Err = String;
+
+    /// Parses "background", "never", "writes:N" or "interval:MS".
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        let mut parts = s.splitn(2, ':');
+        let policy = match (parts.next(), parts.next().map(str::parse)) {
+            (Some("background"), None) => SledFlushPolicy::Background,
+            (Some("never"), None) => SledFlushPolicy::Never,
+            (Some("writes"), Some(Ok(writes))) if writes > 0 => {
+                SledFlushPolicy::EveryWrites(writes)
+            }
+            (Some("interval"), Some(Ok(ms))) if ms > 0 => {
+                SledFlushPolicy::Interval(Duration::from_millis(ms))
+            }
+            _ => return Err(format!("Unknown flush policy: {}", s)),
+        };
+        Ok(policy)
+    }
+}
+
+#[cfg(feature = "sled")]
 impl SledKvsEngine {
     /// Open a SledKvsEngine from the directory contains the existing.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let db = Arc::new(Mutex::new(Db::start_default(path)?));
-        Ok(SledKvsEngine { database: db })
+        SledKvsEngine::open_with_flush_policy(path, SledFlushPolicy::default())
+    }
+
+    /// Opens a SledKvsEngine like [`open`](#method.open), flushing its writes according to
+    /// `policy`.
+    pub fn open_with_flush_policy<P: AsRef<Path>>(
+        path: P,
+        policy: SledFlushPolicy,
+    ) -> Result<Self> {
+        let mut config = ConfigBuilder::new().path(path.as_ref());
+        match policy {
+            SledFlushPolicy::Background => {}
+            SledFlushPolicy::Interval(interval) => {
+                config = config.flush_every_ms(Some(interval.as_millis().max(1) as u64));
+            }
+            SledFlushPolicy::EveryWrites(_) | SledFlushPolicy::Never => {
+                config = config.flush_every_ms(None);
+            }
+        }
+        let db = Db::start(config.build())
+            .with_context(|| format!("opening sled database {}", path.as_ref().display()))?;
+        let types = db.open_tree(TYPES_TREE)?;
+        Ok(SledKvsEngine {
+            database: Arc::new(Mutex::new(db)),
+            types,
+            flush_policy: policy,
+            writes: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Counts a write to `database`, and flushes it if the policy says so.
+    fn written(&self, database: &Db) -> Result<()> {
+        let writes = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        if let SledFlushPolicy::EveryWrites(every) = self.flush_policy {
+            if writes.is_multiple_of(every) {
+                database.flush()?;
+                debug!("Flushed the database.");
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the value of `key` and its type, under the lock of `database`.
+    fn read(&self, database: &Db, key: &str) -> Result<Option<(ValueType, String)>> {
+        let value = match database.get(key)? {
+            Some(value) => to_string(value, "value")?,
+            None => return Ok(None),
+        };
+        let value_type = match self.types.get(key)? {
+            Some(tag) => tag
+                .first()
+                .copied()
+                .and_then(ValueType::from_tag)
+                .ok_or_else(|| KvsError::Internal("stored type is unknown".to_string()))?,
+            None => ValueType::String,
+        };
+        Ok(Some((value_type, value)))
+    }
+
+    /// Writes the value of `key` and its type, under the lock of `database`. The type of a
+    /// collection is written before its value, and the type of a string removed after, so that
+    /// a crash in between never leaves a collection to be read as a string.
+    fn write(&self, database: &Db, key: &str, value_type: ValueType, value: &str) -> Result<()> {
+        if value_type.is_string() {
+            database.set(key, value.as_bytes())?;
+            self.types.del(key)?;
+        } else {
+            self.types.set(key, vec![value_type.tag()])?;
+            database.set(key, value.as_bytes())?;
+        }
+        Ok(())
     }
 }
 
+/// The tree of the types of the keys, apart from the default one of the values.
+#[cfg(feature = "sled")]
+const TYPES_TREE: &[u8] = b"types";
+
+#[cfg(feature = "sled")]
+fn to_string(bytes: IVec, what: &str) -> Result<String> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| KvsError::Internal(format!("stored {} is not valid UTF-8", what)))
+}
+
+#[cfg(feature = "sled")]
 impl KvsEngine for SledKvsEngine {
     fn set(&self, key: String, value: String) -> Result<()> {
-        let database = self.database.lock().unwrap();
-        database.set(key, value.as_bytes())?;
-        database.flush()?;
-        Ok(())
+        let _span = debug_span!("set").entered();
+        let database = lock(&self.database);
+        if let Some((value_type, _)) = self.read(&database, &key)? {
+            value_type.check_overwrite(&key, ValueType::String)?;
+        }
+        self.write(&database, &key, ValueType::String, &value)?;
+        self.written(&database)
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        let v = self.database.lock().unwrap().get(key)?;
-        Ok(v.and_then(|s| Some(String::from_utf8(s.to_vec()).unwrap())))
+        let _span = debug_span!("get").entered();
+        match self.read(&lock(&self.database), &key)? {
+            Some((value_type, value)) => {
+                value_type.check(&key, ValueType::String)?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn get_typed(&self, key: String) -> Result<Option<(ValueType, String)>> {
+        let _span = debug_span!("get_typed").entered();
+        self.read(&lock(&self.database), &key)
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        let database = self.database.lock().unwrap();
-        database.del(key)?.ok_or(KvsError::KeyNotFound)?;
-        database.flush()?;
-        Ok(())
+        let _span = debug_span!("remove").entered();
+        let database = lock(&self.database);
+        match self.read(&database, &key)? {
+            Some((ValueType::Lock, value)) => {
+                let value = released(&key, &value)?;
+                self.write(&database, &key, ValueType::Lock, &value)?;
+            }
+            Some(_) => {
+                database.del(&key)?;
+                self.types.del(&key)?;
+            }
+            None => return Err(KvsError::KeyNotFound),
+        }
+        self.written(&database)
+    }
+
+    /// Applies `f` under the lock every other operation of the engine takes.
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String> + Send + 'static,
+    {
+        let _span = debug_span!("update").entered();
+        let database = lock(&self.database);
+        let old = match self.read(&database, &key)? {
+            Some((value_type, value)) => {
+                value_type.check(&key, ValueType::String)?;
+                Some(value)
+            }
+            None => None,
+        };
+        let existed = old.is_some();
+        let value = f(old);
+        match &value {
+            Some(value) => {
+                database.set(key, value.as_bytes())?;
+            }
+            None if existed => {
+                database.del(key)?;
+            }
+            None => return Ok(None),
+        }
+        self.written(&database)?;
+        Ok(value)
+    }
+
+    /// Applies `f` under the lock every other operation of the engine takes.
+    fn update_typed<F>(&self, key: String, f: F) -> Result<Option<(ValueType, String)>>
+    where
+        F: FnOnce(Option<(ValueType, String)>) -> Option<(ValueType, String)> + Send + 'static,
+    {
+        let _span = debug_span!("update_typed").entered();
+        let database = lock(&self.database);
+        let old = self.read(&database, &key)?;
+        let existed = old.is_some();
+        let typed = f(old);
+        match &typed {
+            Some((value_type, value)) => self.write(&database, &key, *value_type, value)?,
+            None if existed => {
+                database.del(&key)?;
+                self.types.del(&key)?;
+            }
+            None => return Ok(None),
+        }
+        self.written(&database)?;
+        Ok(typed)
     }
 
     fn scan(&self) -> Vec<String> {
-        let database = self.database.lock().unwrap();
+        let database = lock(&self.database);
         database
             .iter()
             .keys()
-            .map(|s| String::from_utf8(s.unwrap()).unwrap())
+            .filter_map(|s| s.ok().and_then(|s| String::from_utf8(s).ok()))
+            .collect()
+    }
+
+    /// Walks the tree from the key following `after` on.
+    fn list_keys(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        let database = lock(&self.database);
+        let keys = match after {
+            Some(after) => {
+                database.range::<&[u8], _>((Bound::Excluded(after.as_bytes()), Bound::Unbounded))
+            }
+            None => database.iter(),
+        };
+        keys.keys()
+            .take(limit)
+            .map(|key| {
+                String::from_utf8(key?)
+                    .map_err(|_| KvsError::Internal("stored key is not valid UTF-8".to_string()))
+            })
             .collect()
     }
+
+    /// Flushes the database, whatever the flush policy.
+    fn save_index_log(&self) -> Result<()> {
+        lock(&self.database).flush()?;
+        debug!("Flushed the database.");
+        Ok(())
+    }
 }
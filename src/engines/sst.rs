@@ -0,0 +1,284 @@
+use std::io::Write;
+
+use crate::{KvsError, Result};
+
+/// The size the data blocks are cut at, as RocksDB does by default.
+const BLOCK_SIZE: usize = 4 << 10;
+
+/// How many entries of a block share the start of their key with the entry before them
+/// between two restart points.
+const RESTART_INTERVAL: usize = 16;
+
+/// The magic number ending the files, that of the tables written by LevelDB, which RocksDB reads
+/// as the tables of its format version 0.
+const MAGIC: u64 = 0xdb47_7524_8b80_fb57;
+
+/// The length of the handles of the footer, padded to the largest encoding of two of them.
+const FOOTER_HANDLES_SIZE: usize = 40;
+
+/// The type of the entries setting a value, at the end of the internal keys.
+const TYPE_VALUE: u64 = 1;
+
+/// The reversed polynomial of the CRC32C checksums of the blocks.
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+
+/// The version of the files written by the `SstFileWriter` of RocksDB, which
+/// `IngestExternalFile` expects to find in their properties.
+const EXTERNAL_SST_FILE_VERSION: u32 = 2;
+
+/// Writes a table in the block-based format of RocksDB, the one of the files created by its
+/// `SstFileWriter`, so that it can be ingested with `IngestExternalFile`. The keys have to be
+/// added in increasing byte order, and are written with the sequence number 0, which is
+/// replaced by the one assigned at ingestion.
+pub(crate) struct SstWriter<W: Write> {
+    writer: W,
+    offset: u64,
+    block: BlockBuilder,
+    /// The last key of the block being built.
+    last_key: Vec<u8>,
+    /// The entries of the index block: the last key of every data block with its handle.
+    index: Vec<(Vec<u8>, BlockHandle)>,
+    entries: u64,
+    raw_key_size: u64,
+    raw_value_size: u64,
+}
+
+impl<W: Write> SstWriter<W> {
+    pub(crate) fn new(writer: W) -> SstWriter<W> {
+        SstWriter {
+            writer,
+            offset: 0,
+            block: BlockBuilder::new(RESTART_INTERVAL),
+            last_key: Vec::new(),
+            index: Vec::new(),
+            entries: 0,
+            raw_key_size: 0,
+            raw_value_size: 0,
+        }
+    }
+
+    /// Adds `key` set to `value`, after the keys added so far.
+    pub(crate) fn add(&mut self, key: &str, value: &str) -> Result<()> {
+        if self.entries > 0 && key.as_bytes() <= user_key(&self.last_key) {
+            return Err(KvsError::Internal(format!(
+                "the keys of an SST file have to be added in order, found {:?} after {:?}",
+                key,
+                String::from_utf8_lossy(user_key(&self.last_key))
+            )));
+        }
+        let mut internal_key = key.as_bytes().to_vec();
+        put_fixed64(&mut internal_key, TYPE_VALUE);
+        self.block.add(&internal_key, value.as_bytes());
+        self.last_key = internal_key;
+        self.entries += 1;
+        self.raw_key_size += self.last_key.len() as u64;
+        self.raw_value_size += value.len() as u64;
+        if self.block.size() >= BLOCK_SIZE {
+            self.flush_data_block()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the last data block, the properties, the meta index, the index and the footer,
+    /// and flushes the file. A table cannot be empty.
+    pub(crate) fn finish(mut self) -> Result<W> {
+        if self.entries == 0 {
+            return Err(KvsError::Internal(
+                "an SST file cannot be created without keys".to_owned(),
+            ));
+        }
+        if !self.block.is_empty() {
+            self.flush_data_block()?;
+        }
+        let data_size = self.offset;
+
+        let mut index = BlockBuilder::new(1);
+        for (key, handle) in &self.index {
+            index.add(key, &handle.encode());
+        }
+        let index_size = index.size() as u64;
+        let num_data_blocks = self.index.len() as u64;
+
+        let mut properties = BlockBuilder::new(1);
+        let varint = |value| {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, value);
+            buf
+        };
+        // Sorted by name, as the entries of every block.
+        let external_version = EXTERNAL_SST_FILE_VERSION.to_le_bytes();
+        let global_seqno = 0u64.to_le_bytes();
+        let props: &[(&str, Vec<u8>)] = &[
+            ("rocksdb.comparator", b"leveldb.BytewiseComparator".to_vec()),
+            ("rocksdb.data.size", varint(data_size)),
+            (
+                "rocksdb.external_sst_file.global_seqno",
+                global_seqno.to_vec(),
+            ),
+            (
+                "rocksdb.external_sst_file.version",
+                external_version.to_vec(),
+            ),
+            ("rocksdb.filter.size", varint(0)),
+            ("rocksdb.index.size", varint(index_size)),
+            ("rocksdb.num.data.blocks", varint(num_data_blocks)),
+            ("rocksdb.num.entries", varint(self.entries)),
+            ("rocksdb.raw.key.size", varint(self.raw_key_size)),
+            ("rocksdb.raw.value.size", varint(self.raw_value_size)),
+        ];
+        for (name, value) in props {
+            properties.add(name.as_bytes(), value);
+        }
+        let properties_handle = self.write_block(properties.finish())?;
+
+        let mut meta_index = BlockBuilder::new(1);
+        meta_index.add(b"rocksdb.properties", &properties_handle.encode());
+        let meta_index_handle = self.write_block(meta_index.finish())?;
+        let index_handle = self.write_block(index.finish())?;
+
+        let mut footer = meta_index_handle.encode();
+        footer.extend(index_handle.encode());
+        footer.resize(FOOTER_HANDLES_SIZE, 0);
+        put_fixed64(&mut footer, MAGIC);
+        self.writer.write_all(&footer)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn flush_data_block(&mut self) -> Result<()> {
+        let block = std::mem::replace(&mut self.block, BlockBuilder::new(RESTART_INTERVAL));
+        let handle = self.write_block(block.finish())?;
+        self.index.push((self.last_key.clone(), handle));
+        Ok(())
+    }
+
+    /// Writes `block` uncompressed, followed by its trailer, and returns its handle.
+    fn write_block(&mut self, mut block: Vec<u8>) -> Result<BlockHandle> {
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: block.len() as u64,
+        };
+        // No compression.
+        block.push(0);
+        let crc = mask(crc32c(&block));
+        block.extend_from_slice(&crc.to_le_bytes());
+        self.writer.write_all(&block)?;
+        self.offset += block.len() as u64;
+        Ok(handle)
+    }
+}
+
+/// The position of a block in the file, without its trailer.
+#[derive(Clone, Copy)]
+struct BlockHandle {
+    offset: u64,
+    size: u64,
+}
+
+impl BlockHandle {
+    fn encode(self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, self.offset);
+        put_varint(&mut buf, self.size);
+        buf
+    }
+}
+
+/// Builds a block of sorted entries, each of them only holding the part of its key which
+/// differs from the key before it, except at the restart points listed at the end of the block.
+struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    restart_interval: usize,
+    /// The number of entries since the last restart point.
+    counter: usize,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    fn new(restart_interval: usize) -> BlockBuilder {
+        BlockBuilder {
+            buf: Vec::new(),
+            restarts: vec![0],
+            restart_interval,
+            counter: 0,
+            last_key: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) {
+        let shared = if self.counter < self.restart_interval {
+            key.iter()
+                .zip(&self.last_key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        } else {
+            self.restarts.push(self.buf.len() as u32);
+            self.counter = 0;
+            0
+        };
+        put_varint(&mut self.buf, shared as u64);
+        put_varint(&mut self.buf, (key.len() - shared) as u64);
+        put_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+        self.last_key = key.to_vec();
+        self.counter += 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// The size of the block once finished.
+    fn size(&self) -> usize {
+        self.buf.len() + (self.restarts.len() + 1) * 4
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for restart in &self.restarts {
+            self.buf.extend_from_slice(&restart.to_le_bytes());
+        }
+        self.buf
+            .extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        self.buf
+    }
+}
+
+/// The key an internal key was made of, without its sequence number and type.
+fn user_key(internal_key: &[u8]) -> &[u8] {
+    &internal_key[..internal_key.len().saturating_sub(8)]
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_fixed64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Masks a checksum the way LevelDB and RocksDB store them, so that the checksum of data
+/// embedding checksums is not degenerate.
+fn mask(crc: u32) -> u32 {
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
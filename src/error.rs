@@ -1,7 +1,9 @@
 use serde_json;
+#[cfg(feature = "sled")]
 use sled;
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use std::process::exit;
 use std::result;
 
@@ -11,13 +13,62 @@ pub type Result<T> = result::Result<T, KvsError>;
 #[derive(Debug)]
 pub enum KvsError {
     InvalidKeySize,
+    /// A key rejected by the key policy, with the reason.
+    InvalidKey(String),
     InvalidValueSize,
+    /// A pattern which does not parse, with the reason.
+    InvalidPattern(String),
     KeyNotFound,
     ParseEngineError,
     CmdNotSupport,
+    MalformedRequest,
+    /// A request larger than the server accepts, with the limit in bytes.
+    RequestTooLarge(u64),
+    /// A tracking connection which is not open, or no longer, with its id.
+    TrackingClosed(u64),
+    QueueFull,
+    Internal(String),
+    /// An error response of a kvs-server to a client, with its code and message.
+    Server {
+        code: String,
+        message: String,
+    },
+    /// An error of an interceptor of a client, e.g. refusing a call.
+    Interceptor(Box<dyn std::error::Error + Send + Sync>),
     IOError(io::Error),
     DeserError(serde_json::error::Error),
+    #[cfg(feature = "sled")]
     SledError(sled::Error),
+    /// A log record whose checksum does not match its content.
+    Corruption {
+        path: PathBuf,
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+    /// A log offset that no longer designates the same records, because the log was compacted
+    /// since it was recorded.
+    StaleOffset(u64),
+    /// A conditional write rejected because the key is not at the version expected, `None`
+    /// standing for a missing key.
+    VersionMismatch {
+        expected: Option<u64>,
+        actual: Option<u64>,
+    },
+    /// A key which does not hold the type of value the command works on, e.g. a lock.
+    WrongType {
+        key: String,
+        expected: &'static str,
+    },
+    /// A write rejected because compaction fell too far behind, with the redundant bytes of the
+    /// log it has yet to reclaim.
+    WriteStall(u64),
+    /// An error annotated with what was being done when it occurred, e.g. the file and byte
+    /// offset being read.
+    Context {
+        context: String,
+        source: Box<KvsError>,
+    },
 }
 
 impl KvsError {
@@ -25,19 +76,115 @@ impl KvsError {
         println!("{}", self);
         exit(err);
     }
+
+    /// Wraps the error with `context` describing the operation that failed.
+    pub fn with_context<C: fmt::Display>(self, context: C) -> KvsError {
+        KvsError::Context {
+            context: context.to_string(),
+            source: Box::new(self),
+        }
+    }
+
+    /// Returns the innermost error, skipping all the context attached to it.
+    pub fn root(&self) -> &KvsError {
+        match self {
+            KvsError::Context { source, .. } => source.root(),
+            _ => self,
+        }
+    }
+
+    /// A stable code identifying the kind of the error, sent along with the message in
+    /// error responses so that clients can tell errors apart without parsing messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            KvsError::Context { source, .. } => source.code(),
+            KvsError::InvalidKeySize | KvsError::InvalidValueSize | KvsError::InvalidPattern(_) => {
+                "INVALID_ARGUMENT"
+            }
+            KvsError::InvalidKey(_) => "INVALID_KEY",
+            KvsError::KeyNotFound => "NOT_FOUND",
+            KvsError::ParseEngineError => "INVALID_ENGINE",
+            KvsError::CmdNotSupport => "UNSUPPORTED",
+            KvsError::MalformedRequest => "BAD_REQUEST",
+            KvsError::RequestTooLarge(_) => "TOO_LARGE",
+            KvsError::TrackingClosed(_) => "NO_TRACKING",
+            KvsError::QueueFull | KvsError::WriteStall(_) => "BUSY",
+            KvsError::Internal(_) => "INTERNAL",
+            KvsError::Server { .. } => "SERVER",
+            KvsError::Interceptor(_) => "INTERCEPTED",
+            KvsError::IOError(_) => "IO",
+            KvsError::DeserError(_) => "ENCODING",
+            #[cfg(feature = "sled")]
+            KvsError::SledError(_) => "ENGINE",
+            KvsError::Corruption { .. } => "CORRUPTION",
+            KvsError::StaleOffset(_) => "STALE_OFFSET",
+            KvsError::VersionMismatch { .. } => "VERSION_MISMATCH",
+            KvsError::WrongType { .. } => "WRONG_TYPE",
+        }
+    }
 }
 
 impl fmt::Display for KvsError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> fmt::Result {
         match self {
             KvsError::InvalidKeySize => write!(f, "The key cannot be larger than 256B."),
-            KvsError::InvalidValueSize => write!(f, "The value cannot be larger than 4KB."),
+            KvsError::InvalidKey(reason) => write!(f, "Invalid key: {}.", reason),
+            KvsError::InvalidValueSize => write!(f, "The value cannot be larger than 16MB."),
+            KvsError::InvalidPattern(reason) => write!(f, "Invalid pattern: {}", reason),
             KvsError::KeyNotFound => write!(f, "Key not found"),
             KvsError::IOError(inner) => write!(f, "{}", inner),
             KvsError::DeserError(inner) => write!(f, "{}", inner),
             KvsError::ParseEngineError => write!(f, "Can not parse engine name."),
             KvsError::CmdNotSupport => write!(f, "Command not support."),
+            KvsError::MalformedRequest => write!(f, "Malformed request."),
+            KvsError::RequestTooLarge(limit) => {
+                write!(
+                    f,
+                    "The request is larger than the limit of {} bytes.",
+                    limit
+                )
+            }
+            KvsError::TrackingClosed(id) => write!(f, "No tracking connection {} is open.", id),
+            KvsError::Internal(msg) => write!(f, "Internal error: {}", msg),
+            KvsError::Server { message, .. } => write!(f, "{}", message),
+            KvsError::Interceptor(inner) => write!(f, "{}", inner),
+            KvsError::QueueFull => write!(f, "The job queue of the thread pool is full."),
+            #[cfg(feature = "sled")]
             KvsError::SledError(inner) => write!(f, "{}", inner),
+            KvsError::Corruption {
+                path,
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Corrupted record in {} at offset {}: expected checksum {:#010x}, found {:#010x}.",
+                path.display(),
+                offset,
+                expected,
+                actual
+            ),
+            KvsError::StaleOffset(offset) => {
+                write!(f, "The log was compacted since offset {}.", offset)
+            }
+            KvsError::VersionMismatch { expected, actual } => {
+                let version = |v: &Option<u64>| v.map_or("none".to_owned(), |v| v.to_string());
+                write!(
+                    f,
+                    "Version mismatch: expected {}, found {}.",
+                    version(expected),
+                    version(actual)
+                )
+            }
+            KvsError::WrongType { key, expected } => {
+                write!(f, "The key {:?} does not hold a {}.", key, expected)
+            }
+            KvsError::WriteStall(redundant_bytes) => write!(
+                f,
+                "Writes are stalled until compaction reclaims {} redundant bytes.",
+                redundant_bytes
+            ),
+            KvsError::Context { context, source } => write!(f, "{}: {}", context, source),
         }
     }
 }
@@ -60,10 +207,43 @@ impl From<KvsError> for String {
     }
 }
 
+#[cfg(feature = "sled")]
 impl From<sled::Error> for KvsError {
     fn from(error: sled::Error) -> Self {
         KvsError::SledError(error)
     }
 }
 
-impl std::error::Error for KvsError {}
+impl std::error::Error for KvsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KvsError::IOError(inner) => Some(inner),
+            KvsError::DeserError(inner) => Some(inner),
+            #[cfg(feature = "sled")]
+            KvsError::SledError(inner) => Some(inner),
+            KvsError::Interceptor(inner) => Some(inner.as_ref()),
+            KvsError::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Extension methods attaching context to the errors of a `Result`.
+pub trait ResultExt<T> {
+    /// Wraps the error, if any, with `context`.
+    fn context<C: fmt::Display>(self, context: C) -> Result<T>;
+
+    /// Wraps the error, if any, with the context returned by `f`, which is only called on
+    /// failure.
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<KvsError>> ResultExt<T> for result::Result<T, E> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.map_err(|e| e.into().with_context(context))
+    }
+
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|e| e.into().with_context(f()))
+    }
+}
@@ -0,0 +1,201 @@
+//! C bindings of [`KvStore`](../struct.KvStore.html), declared in `include/kvs.h`, so that
+//! C and C++ applications can embed the store. Build the shared library with:
+//!
+//! ```text
+//! cargo rustc --release --lib --features ffi --crate-type cdylib
+//! ```
+//!
+//! The functions return `KVS_OK` on success, or another `KVS_*` code on failure, with the
+//! message of the error left in [`kvs_last_error`](fn.kvs_last_error.html) until the next
+//! call on the same thread.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::{KvStore, KvsEngine, KvsError, Result};
+
+/// The call succeeded.
+pub const KVS_OK: c_int = 0;
+/// The key is not in the store.
+pub const KVS_NOT_FOUND: c_int = 1;
+/// An argument is a null pointer or not valid UTF-8.
+pub const KVS_INVALID_ARGUMENT: c_int = -1;
+/// The store failed, see `kvs_last_error`.
+pub const KVS_ERROR: c_int = -2;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning its error, or its panic which must not unwind into C, into a code.
+fn guard<F: FnOnce() -> Result<c_int>>(f: F) -> c_int {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(code)) => code,
+        Ok(Err(KvsError::KeyNotFound)) => KVS_NOT_FOUND,
+        Ok(Err(e @ KvsError::InvalidKeySize))
+        | Ok(Err(e @ KvsError::InvalidValueSize))
+        | Ok(Err(e @ KvsError::InvalidKey(_))) => {
+            set_last_error(e.to_string());
+            KVS_INVALID_ARGUMENT
+        }
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            KVS_ERROR
+        }
+        Err(_) => {
+            set_last_error("the store panicked".to_owned());
+            KVS_ERROR
+        }
+    }
+}
+
+/// Borrows the string `s` from C.
+///
+/// # Safety
+/// `s` must be null or point to a nul-terminated string valid for `'a`.
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(KvsError::Internal(format!("{} is null", name)));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| KvsError::Internal(format!("{} is not valid UTF-8", name)))
+}
+
+/// Borrows the store `store` from C.
+///
+/// # Safety
+/// `store` must be null or returned by `kvs_open` and not closed yet.
+unsafe fn store_arg<'a>(store: *const KvStore) -> Result<&'a KvStore> {
+    store
+        .as_ref()
+        .ok_or_else(|| KvsError::Internal("store is null".to_owned()))
+}
+
+/// Runs `f` on the arguments, reporting the ones that cannot be borrowed as invalid.
+fn with_args<T, F: FnOnce(T) -> Result<c_int>>(args: Result<T>, f: F) -> c_int {
+    match args {
+        Ok(args) => guard(|| f(args)),
+        Err(e) => {
+            set_last_error(e.to_string());
+            KVS_INVALID_ARGUMENT
+        }
+    }
+}
+
+/// Opens the store in the directory `path`, which must exist. Returns null on failure.
+///
+/// # Safety
+/// `path` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_open(path: *const c_char) -> *mut KvStore {
+    let mut store = ptr::null_mut();
+    with_args(str_arg(path, "path"), |path| {
+        store = Box::into_raw(Box::new(KvStore::open(path)?));
+        Ok(KVS_OK)
+    });
+    store
+}
+
+/// Closes `store`, which must not be used afterwards. Does nothing if `store` is null.
+///
+/// # Safety
+/// `store` must be null or returned by `kvs_open` and not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_close(store: *mut KvStore) {
+    if !store.is_null() {
+        drop(Box::from_raw(store));
+    }
+}
+
+/// Sets the value of `key` to `value`.
+///
+/// # Safety
+/// `store` must be returned by `kvs_open`, `key` and `value` must be nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_set(
+    store: *const KvStore,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    let args = (|| {
+        Ok((
+            store_arg(store)?,
+            str_arg(key, "key")?,
+            str_arg(value, "value")?,
+        ))
+    })();
+    with_args(args, |(store, key, value)| {
+        store.set(key.to_owned(), value.to_owned())?;
+        Ok(KVS_OK)
+    })
+}
+
+/// Stores in `*value` the value of `key`, to be freed with `kvs_free`, or returns
+/// `KVS_NOT_FOUND` and stores null if the key is not in the store.
+///
+/// # Safety
+/// `store` must be returned by `kvs_open`, `key` must be a nul-terminated string and `value`
+/// must point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_get(
+    store: *const KvStore,
+    key: *const c_char,
+    value: *mut *mut c_char,
+) -> c_int {
+    if value.is_null() {
+        set_last_error("value is null".to_owned());
+        return KVS_INVALID_ARGUMENT;
+    }
+    *value = ptr::null_mut();
+    let args = (|| Ok((store_arg(store)?, str_arg(key, "key")?)))();
+    with_args(args, |(store, key)| match store.get(key.to_owned())? {
+        Some(found) => {
+            let found = CString::new(found)
+                .map_err(|_| KvsError::Internal("the value holds a nul byte".to_owned()))?;
+            *value = found.into_raw();
+            Ok(KVS_OK)
+        }
+        None => Ok(KVS_NOT_FOUND),
+    })
+}
+
+/// Removes `key`, or returns `KVS_NOT_FOUND` if it is not in the store.
+///
+/// # Safety
+/// `store` must be returned by `kvs_open` and `key` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_remove(store: *const KvStore, key: *const c_char) -> c_int {
+    let args = (|| Ok((store_arg(store)?, str_arg(key, "key")?)))();
+    with_args(args, |(store, key)| {
+        store.remove(key.to_owned())?;
+        Ok(KVS_OK)
+    })
+}
+
+/// Frees a value returned by `kvs_get`. Does nothing if `value` is null.
+///
+/// # Safety
+/// `value` must be null or returned by `kvs_get` and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_free(value: *mut c_char) {
+    if !value.is_null() {
+        drop(CString::from_raw(value));
+    }
+}
+
+/// Returns the message of the error of the last failed call on this thread, or null. The
+/// message is valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn kvs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
@@ -1,9 +1,34 @@
 //! A Simple Key-Value DataBase in memory.
+mod addr;
+#[cfg(not(target_arch = "wasm32"))]
+mod client;
 #[deny(missing_docs)]
 mod engines;
 mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+mod python;
 pub mod thread_pool;
 
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
-pub use error::{KvsError, Result};
+pub use addr::{ServerAddr, DEFAULT_PORT};
+#[cfg(not(target_arch = "wasm32"))]
+pub use client::{Call, Interceptor, KvsClient, KvsClientBuilder, Next, Reply};
+#[cfg(feature = "fault-injection")]
+pub use engines::FaultPlan;
+#[cfg(feature = "s3")]
+pub use engines::S3Target;
+#[cfg(feature = "sled")]
+pub use engines::SledKvsEngine;
+#[cfg(not(target_arch = "wasm32"))]
+pub use engines::{
+    BackupTarget, Command, CompactionStats, CompactionTrigger, DirTarget, FileStorage, KvStore,
+    KvStoreBuilder, LogStorage, RepairReport, SizeHistogram, StoreStats, Tail, TombstonePolicy,
+    Version,
+};
+pub use engines::{
+    CursorToken, KeyCharset, KeyCursor, KeyMetadata, KeyPolicy, KvsEngine, MemKvsEngine,
+    SledFlushPolicy, ValueType,
+};
+pub use error::{KvsError, Result, ResultExt};
 pub use thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
@@ -0,0 +1,257 @@
+//! Python bindings of [`KvStore`](../struct.KvStore.html), and of a client of kvs-server, as
+//! the `kvs` extension module. Build it with:
+//!
+//! ```text
+//! cargo rustc --release --lib --features python --crate-type cdylib
+//! cp target/release/libkvs.so kvs.so
+//! ```
+//!
+//! ```python
+//! import kvs
+//!
+//! store = kvs.KvStore("/var/lib/kvs")
+//! store.set("key", "value")
+//! assert store.get("key") == "value"
+//!
+//! client = kvs.KvsClient("127.0.0.1:4000")
+//! client.set("key", "value")
+//!
+//! routed = kvs.KvsClient("primary:4000", replicas=["replica1:4000", "replica2:4000"])
+//! assert routed.get("key") == "value"
+//!
+//! cached = kvs.KvsClient("127.0.0.1:4000", cache=1000)
+//! assert cached.get("key") == "value"
+//!
+//! def log(command, args, proceed):
+//!     print(command, args)
+//!     return proceed(args)
+//!
+//! client.add_interceptor(log)
+//! ```
+//!
+//! A missing key raises `KeyError`, an invalid key or value `ValueError`, a failed read or
+//! write `OSError`, and any other error of the store or of the server `RuntimeError`.
+
+use std::path::PathBuf;
+
+use pyo3::exceptions::{PyKeyError, PyOSError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+
+use crate::{
+    Call, Interceptor, KvStore, KvsClient, KvsClientBuilder, KvsEngine, KvsError, Next, Reply,
+    Result, ServerAddr,
+};
+
+fn to_py_err(error: KvsError) -> PyErr {
+    // The exceptions raised by the Python interceptors come out of the calls unchanged.
+    let error = match error {
+        KvsError::Interceptor(raised) => match raised.downcast::<PyErr>() {
+            Ok(raised) => return *raised,
+            Err(raised) => KvsError::Interceptor(raised),
+        },
+        error => error,
+    };
+    match error.root() {
+        KvsError::KeyNotFound => PyKeyError::new_err(error.to_string()),
+        KvsError::InvalidKeySize | KvsError::InvalidValueSize | KvsError::InvalidKey(_) => {
+            PyValueError::new_err(error.to_string())
+        }
+        KvsError::Server { code, .. } if code == "INVALID_ARGUMENT" || code == "INVALID_KEY" => {
+            PyValueError::new_err(error.to_string())
+        }
+        KvsError::IOError(_) => PyOSError::new_err(error.to_string()),
+        _ => PyRuntimeError::new_err(error.to_string()),
+    }
+}
+
+/// A store in a data directory, opened by `KvStore(path)`.
+#[pyclass(name = "KvStore", module = "kvs")]
+struct PyKvStore {
+    store: KvStore,
+}
+
+#[pymethods]
+impl PyKvStore {
+    #[new]
+    fn new(py: Python<'_>, path: PathBuf) -> PyResult<PyKvStore> {
+        let store = py.detach(|| KvStore::open(path)).map_err(to_py_err)?;
+        Ok(PyKvStore { store })
+    }
+
+    /// Sets the value of `key` to `value`.
+    fn set(&self, py: Python<'_>, key: String, value: String) -> PyResult<()> {
+        py.detach(|| self.store.set(key, value)).map_err(to_py_err)
+    }
+
+    /// Returns the value of `key`, or `None` if it is not in the store.
+    fn get(&self, py: Python<'_>, key: String) -> PyResult<Option<String>> {
+        py.detach(|| self.store.get(key)).map_err(to_py_err)
+    }
+
+    /// Removes `key`, raising `KeyError` if it is not in the store.
+    fn remove(&self, py: Python<'_>, key: String) -> PyResult<()> {
+        py.detach(|| self.store.remove(key)).map_err(to_py_err)
+    }
+
+    /// Returns the keys of the store.
+    fn keys(&self, py: Python<'_>) -> Vec<String> {
+        py.detach(|| self.store.scan())
+    }
+
+    /// Rewrites the log without its stale records, and returns the number of bytes reclaimed.
+    fn compact(&self, py: Python<'_>) -> PyResult<u64> {
+        py.detach(|| self.store.compact()).map_err(to_py_err)
+    }
+}
+
+/// A client of the kvs-server listening on `addr`, `127.0.0.1:4000` by default, wrapping a
+/// [`KvsClient`](../struct.KvsClient.html), which reads from `replicas` of that server, if any,
+/// and caches the values of up to `cache` keys.
+///
+/// Every call goes through the interceptors added by `add_interceptor`, if any.
+#[pyclass(name = "KvsClient", module = "kvs")]
+struct PyKvsClient {
+    client: KvsClient,
+}
+
+#[pymethods]
+impl PyKvsClient {
+    #[new]
+    #[pyo3(signature = (addr = "127.0.0.1:4000", replicas = Vec::new(), cache = 0))]
+    fn new(addr: &str, replicas: Vec<String>, cache: usize) -> PyResult<PyKvsClient> {
+        let parse = |addr: &str| {
+            addr.parse::<ServerAddr>()
+                .map_err(|e| PyValueError::new_err(format!("invalid address {}: {}", addr, e)))
+        };
+        let replicas = replicas
+            .iter()
+            .map(|addr| parse(addr))
+            .collect::<PyResult<_>>()?;
+        Ok(PyKvsClient {
+            client: KvsClientBuilder::new()
+                .replicas(replicas)
+                .cache(cache)
+                .build(parse(addr)?),
+        })
+    }
+
+    /// Adds `interceptor`, called with the name of the method, its arguments as a list and a
+    /// function making the call with the arguments given, whose result it returns. It may log,
+    /// time or retry the calls, change their arguments or their results, as long as they are
+    /// of the type the method returns. The interceptors added later are called by the ones
+    /// added before them.
+    fn add_interceptor(&self, interceptor: Py<PyAny>) {
+        self.client.add_interceptor(PyInterceptor(interceptor));
+    }
+
+    /// Sets the value of `key` to `value`.
+    fn set(&self, py: Python<'_>, key: String, value: String) -> PyResult<()> {
+        py.detach(|| self.client.set(key, value)).map_err(to_py_err)
+    }
+
+    /// Returns the value of `key`, or `None` if it is not in the store.
+    fn get(&self, py: Python<'_>, key: String) -> PyResult<Option<String>> {
+        py.detach(|| self.client.get(key)).map_err(to_py_err)
+    }
+
+    /// Removes `key`, raising `KeyError` if it is not in the store.
+    fn remove(&self, py: Python<'_>, key: String) -> PyResult<()> {
+        py.detach(|| self.client.remove(key)).map_err(to_py_err)
+    }
+
+    /// Returns the keys of the store.
+    fn keys(&self, py: Python<'_>) -> PyResult<Vec<String>> {
+        py.detach(|| self.client.keys()).map_err(to_py_err)
+    }
+
+    /// Makes the server write a checkpoint of its index.
+    fn save(&self, py: Python<'_>) -> PyResult<()> {
+        py.detach(|| self.client.save()).map_err(to_py_err)
+    }
+}
+
+/// An interceptor written in Python, called with the GIL held. The exceptions it raises are
+/// returned as `KvsError::Interceptor`.
+struct PyInterceptor(Py<PyAny>);
+
+impl Interceptor for PyInterceptor {
+    fn intercept(&self, call: Call, next: &Next) -> Result<Reply> {
+        Python::attach(|py| {
+            let method = call.name();
+            let proceed = PyCall {
+                method,
+                next: next.clone(),
+            };
+            self.0
+                .call1(py, (method, call_args(call), proceed))
+                .and_then(|reply| from_py_reply(method, reply.bind(py)))
+                .map_err(|e| KvsError::Interceptor(Box::new(e)))
+        })
+    }
+}
+
+/// The rest of a call of `method`, handed to a Python interceptor to make it with the arguments
+/// given, through the interceptors after it.
+#[pyclass(name = "Call", module = "kvs")]
+struct PyCall {
+    method: &'static str,
+    next: Next,
+}
+
+#[pymethods]
+impl PyCall {
+    fn __call__(&self, py: Python<'_>, args: Vec<String>) -> PyResult<Py<PyAny>> {
+        let call = parse_call(self.method, args)?;
+        let reply = py.detach(|| self.next.run(call)).map_err(to_py_err)?;
+        Ok(match reply {
+            Reply::Done => py.None(),
+            Reply::Value(value) => value.into_pyobject(py)?.into_any().unbind(),
+            Reply::Keys(keys) => keys.into_pyobject(py)?.into_any().unbind(),
+        })
+    }
+}
+
+/// The arguments of `call`, as a Python interceptor is given them.
+fn call_args(call: Call) -> Vec<String> {
+    match call {
+        Call::Set { key, value } => vec![key, value],
+        Call::Get { key } | Call::Remove { key } => vec![key],
+        Call::Keys | Call::Save => Vec::new(),
+    }
+}
+
+/// The call of `method` with `args`, as a Python interceptor makes it.
+fn parse_call(method: &str, args: Vec<String>) -> PyResult<Call> {
+    Ok(match (method, args.as_slice()) {
+        ("set", [key, value]) => Call::Set {
+            key: key.clone(),
+            value: value.clone(),
+        },
+        ("get", [key]) => Call::Get { key: key.clone() },
+        ("remove", [key]) => Call::Remove { key: key.clone() },
+        ("keys", []) => Call::Keys,
+        ("save", []) => Call::Save,
+        _ => {
+            return Err(PyValueError::new_err(format!(
+                "invalid arguments of {}: {:?}",
+                method, args
+            )))
+        }
+    })
+}
+
+/// The reply of `method` returned by a Python interceptor.
+fn from_py_reply(method: &str, reply: &Bound<'_, PyAny>) -> PyResult<Reply> {
+    Ok(match method {
+        "get" => Reply::Value(reply.extract()?),
+        "keys" => Reply::Keys(reply.extract()?),
+        _ => Reply::Done,
+    })
+}
+
+#[pymodule]
+fn kvs(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyKvStore>()?;
+    m.add_class::<PyKvsClient>()?;
+    Ok(())
+}
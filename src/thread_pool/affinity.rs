@@ -0,0 +1,114 @@
+use std::io;
+use std::str::FromStr;
+
+/// The cores the threads of a pool are pinned to, which spares them the migrations between
+/// cores, and the caches and memory nodes left behind.
+///
+/// ```
+/// use kvs::thread_pool::CoreAffinity;
+///
+/// assert_eq!("spread".parse(), Ok(CoreAffinity::Spread));
+/// assert_eq!("0,2".parse(), Ok(CoreAffinity::Cores(vec![0, 2])));
+/// assert!("0,two".parse::<CoreAffinity>().is_err());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CoreAffinity {
+    /// The thread numbered `index` is pinned to the core `cores[index % cores.len()]`.
+    Cores(Vec<usize>),
+    /// The threads are pinned in turn to each of the cores the process may run on.
+    Spread,
+}
+
+impl CoreAffinity {
+    /// Replaces `Spread` by the cores the calling thread may run on, and checks that it may run
+    /// on every one of `Cores`, so that pinning the threads later cannot fail.
+    pub(crate) fn resolve(self) -> io::Result<CoreAffinity> {
+        let allowed = allowed_cores()?;
+        match self {
+            CoreAffinity::Spread => Ok(CoreAffinity::Cores(allowed)),
+            CoreAffinity::Cores(cores) => {
+                if cores.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "no core to pin the threads to",
+                    ));
+                }
+                if let Some(core) = cores.iter().find(|core| !allowed.contains(core)) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("core {} is not available to the process", core),
+                    ));
+                }
+                Ok(CoreAffinity::Cores(cores))
+            }
+        }
+    }
+
+    /// Pins the calling thread, numbered `index` in its pool, to its core. The affinity has to
+    /// be resolved.
+    pub(crate) fn pin(&self, index: usize) -> io::Result<()> {
+        match self {
+            CoreAffinity::Cores(cores) => pin_current_thread(cores[index % cores.len()]),
+            CoreAffinity::Spread => unreachable!("resolved when the pool is built"),
+        }
+    }
+}
+
+impl FromStr for CoreAffinity {
+    type Err = String;
+
+    /// Parses "spread", or a comma-separated list of core IDs.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("spread") {
+            return Ok(CoreAffinity::Spread);
+        }
+        s.split(',')
+            .map(|core| core.trim().parse())
+            .collect::<Result<Vec<usize>, _>>()
+            .map(CoreAffinity::Cores)
+            .map_err(|_| format!("Invalid core affinity: {}", s))
+    }
+}
+
+/// The cores the calling thread may run on, in ascending order.
+#[cfg(target_os = "linux")]
+fn allowed_cores() -> io::Result<Vec<usize>> {
+    // Safety: the set is a plain bit mask of the size passed along, zeroed before use.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|&core| libc::CPU_ISSET(core, &set))
+            .collect())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(core: usize) -> io::Result<()> {
+    // Safety: as above, and the pid 0 designates the calling thread.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Only Linux lets a thread be pinned to cores, where macOS only takes hints and Windows
+/// needs another API.
+#[cfg(not(target_os = "linux"))]
+fn allowed_cores() -> io::Result<Vec<usize>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pinning threads to cores is only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_core: usize) -> io::Result<()> {
+    unreachable!("rejected when the pool is built")
+}
@@ -0,0 +1,151 @@
+use std::any::Any;
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::{CoreAffinity, ThreadPool};
+use crate::Result;
+
+pub(crate) type PanicHandler = Arc<dyn Fn(Box<dyn Any + Send>) + Send + Sync>;
+pub(crate) type StartHandler = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// Configuration for creating a thread pool.
+///
+/// ```
+/// use kvs::thread_pool::{SharedQueueThreadPool, ThreadPoolBuilder};
+///
+/// let pool: SharedQueueThreadPool = ThreadPoolBuilder::new(4)
+///     .thread_name("kvs-worker")
+///     .stack_size(1 << 20)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct ThreadPoolBuilder {
+    pub(crate) threads: usize,
+    pub(crate) queue_capacity: Option<usize>,
+    pub(crate) panic_handler: Option<PanicHandler>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) thread_name: Option<String>,
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) on_thread_start: Option<StartHandler>,
+    pub(crate) core_affinity: Option<CoreAffinity>,
+}
+
+impl ThreadPoolBuilder {
+    /// Creates a builder for a pool with the specified number of threads.
+    pub fn new(threads: usize) -> Self {
+        ThreadPoolBuilder {
+            threads,
+            queue_capacity: None,
+            panic_handler: None,
+            idle_timeout: None,
+            thread_name: None,
+            stack_size: None,
+            on_thread_start: None,
+            core_affinity: None,
+        }
+    }
+
+    /// Bounds the job queue to `capacity` pending jobs. Pools without a queue ignore it.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Sets a callback invoked with the payload of every job that panics.
+    pub fn panic_handler<H>(mut self, handler: H) -> Self
+    where
+        H: Fn(Box<dyn Any + Send>) + Send + Sync + 'static,
+    {
+        self.panic_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Lets workers idle for longer than `timeout` exit, and spawns workers on demand up to
+    /// the number of threads instead of keeping them all alive. Pools that do not keep
+    /// workers around ignore it.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Names the threads of the pool `<prefix>-<index>`.
+    pub fn thread_name<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.thread_name = Some(prefix.into());
+        self
+    }
+
+    /// Sets the stack size in bytes of the threads of the pool.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Sets a callback run on every new thread of the pool with the index of the thread,
+    /// before it executes any job.
+    pub fn on_thread_start<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_thread_start = Some(Arc::new(callback));
+        self
+    }
+
+    /// Pins the threads of the pool to cores, before they execute any job. Building the pool
+    /// fails if the process may not run on one of the cores, or outside of Linux.
+    pub fn core_affinity(mut self, affinity: CoreAffinity) -> Self {
+        self.core_affinity = Some(affinity);
+        self
+    }
+
+    /// Creates the thread pool.
+    pub fn build<P: ThreadPool>(self) -> Result<P> {
+        P::with_builder(self)
+    }
+
+    pub(crate) fn name_of(&self, index: usize) -> Option<String> {
+        self.thread_name
+            .as_ref()
+            .map(|prefix| format!("{}-{}", prefix, index))
+    }
+
+    /// Resolves the cores the threads are pinned to, see `CoreAffinity::resolve`. Called by
+    /// the pools before spawning any thread.
+    pub(crate) fn resolve_affinity(mut self) -> io::Result<Self> {
+        if let Some(affinity) = self.core_affinity.take() {
+            self.core_affinity = Some(affinity.resolve()?);
+        }
+        Ok(self)
+    }
+
+    /// Prepares the new thread numbered `index` of the pool, from the thread itself.
+    pub(crate) fn start_thread(&self, index: usize) {
+        if let Some(affinity) = &self.core_affinity {
+            // The cores were checked when the pool was built, a thread left unpinned by a
+            // core going offline since then still runs jobs.
+            let _ = affinity.pin(index);
+        }
+        if let Some(on_thread_start) = &self.on_thread_start {
+            on_thread_start(index);
+        }
+    }
+
+    /// Spawns the thread numbered `index` of the pool, which runs `f`. It is up to `f` to call
+    /// [`start_thread`](#method.start_thread) first, once it is ready for the start callback
+    /// to panic.
+    pub(crate) fn spawn_thread<F>(&self, index: usize, f: F) -> io::Result<JoinHandle<()>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut builder = thread::Builder::new();
+        if let Some(name) = self.name_of(index) {
+            builder = builder.name(name);
+        }
+        if let Some(size) = self.stack_size {
+            builder = builder.stack_size(size);
+        }
+        builder.spawn(f)
+    }
+}
@@ -1,16 +1,42 @@
+mod affinity;
+mod builder;
 mod naive;
 mod rayon;
+mod scope;
 mod shared_queue;
+mod stats;
 
+pub use self::affinity::CoreAffinity;
+pub use self::builder::ThreadPoolBuilder;
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
+pub use self::scope::Scope;
 pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::stats::{ThreadPoolMetrics, ThreadPoolStats};
 use crate::Result;
+use std::sync::Arc;
+
+/// The priority of a job spawned into a thread pool. Queued jobs of a higher priority run
+/// before the ones of a lower priority.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
 
 /// An interface for representing the thread pool.
 pub trait ThreadPool {
     /// Creates a new thread pool with the specified number of threads.
     fn new(threads: usize) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::with_builder(ThreadPoolBuilder::new(threads))
+    }
+
+    /// Creates a new thread pool from the configuration of `builder`.
+    fn with_builder(builder: ThreadPoolBuilder) -> Result<Self>
     where
         Self: Sized;
 
@@ -18,4 +44,89 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawn a function into the thread pool with the given priority.
+    ///
+    /// The default implementation ignores the priority and behaves like `spawn`.
+    fn spawn_with_priority<F>(&self, job: F, priority: Priority)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let _ = priority;
+        self.spawn(job)
+    }
+
+    /// Spawns a function into the thread pool without blocking, failing with
+    /// `KvsError::QueueFull` if the pool is saturated, so that the caller can shed the load.
+    ///
+    /// The default implementation never fails and behaves like `spawn`, for pools which never
+    /// block.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        Ok(())
+    }
+
+    /// Like `try_spawn`, with the given priority.
+    ///
+    /// The default implementation ignores the priority and behaves like `try_spawn`.
+    fn try_spawn_with_priority<F>(&self, job: F, priority: Priority) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let _ = priority;
+        self.try_spawn(job)
+    }
+
+    /// Returns the counters of the pool, which can be shared with other threads.
+    fn metrics(&self) -> Arc<ThreadPoolMetrics>;
+
+    /// Returns a snapshot of the queue depth, the busy and idle workers, and the number of
+    /// jobs executed and panicked.
+    fn stats(&self) -> ThreadPoolStats {
+        self.metrics().snapshot()
+    }
+
+    /// Creates a scope in which jobs borrowing non-`'static` data can be spawned. Every job
+    /// spawned in the scope has finished when this returns, and a panic of any of them is
+    /// propagated.
+    ///
+    /// Calling this from a job of the same pool may deadlock if all workers end up waiting
+    /// on scopes.
+    ///
+    /// ```
+    /// use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// let pool = SharedQueueThreadPool::new(4).unwrap();
+    /// let numbers: Vec<usize> = (1..=100).collect();
+    /// let sum = AtomicUsize::new(0);
+    /// pool.scope(|s| {
+    ///     for chunk in numbers.chunks(10) {
+    ///         let sum = &sum;
+    ///         s.spawn(move || {
+    ///             sum.fetch_add(chunk.iter().sum(), Ordering::SeqCst);
+    ///         });
+    ///     }
+    /// });
+    /// assert_eq!(sum.into_inner(), 5050);
+    /// ```
+    fn scope<'scope, F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Scope<'_, 'scope>) -> R,
+        Self: Sized,
+    {
+        scope::run(&|job| self.spawn(job), f)
+    }
+
+    /// Stops accepting jobs and blocks until the queued ones have finished.
+    ///
+    /// The default implementation returns immediately without waiting.
+    fn shutdown(self)
+    where
+        Self: Sized,
+    {
+    }
 }
@@ -1,16 +1,150 @@
-use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use tracing::error;
 
-use super::ThreadPool;
-use crate::Result;
+use super::{ThreadPool, ThreadPoolBuilder, ThreadPoolMetrics};
+use crate::{KvsError, Result};
 
-pub struct NaiveThreadPool;
+/// A thread pool spawning a new thread for every job, with at most `threads` of them
+/// running at once. `spawn` blocks while the limit is reached.
+pub struct NaiveThreadPool {
+    builder: ThreadPoolBuilder,
+    spawned: AtomicUsize,
+    permits: Arc<Semaphore>,
+    metrics: Arc<ThreadPoolMetrics>,
+}
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(_: usize) -> Result<NaiveThreadPool> {
-        Ok(NaiveThreadPool)
+    fn with_builder(builder: ThreadPoolBuilder) -> Result<NaiveThreadPool> {
+        assert!(builder.threads > 0);
+        let builder = builder.resolve_affinity()?;
+        Ok(NaiveThreadPool {
+            permits: Arc::new(Semaphore::new(builder.threads)),
+            builder,
+            spawned: AtomicUsize::new(0),
+            metrics: Arc::new(ThreadPoolMetrics::default()),
+        })
     }
 
+    /// Drops the job if its thread cannot be spawned, as there is no caller to fail.
     fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
-        thread::spawn(job);
+        self.metrics.job_queued();
+        let permit = Permit::acquire(&self.permits);
+        if let Err(e) = self.spawn_thread(job, permit) {
+            error!(error = %e, "Failed to spawn a thread for a job, which is dropped.");
+        }
+    }
+
+    /// Fails while `threads` jobs are running.
+    fn try_spawn<F: FnOnce() + Send + 'static>(&self, job: F) -> Result<()> {
+        self.metrics.job_queued();
+        match Permit::try_acquire(&self.permits) {
+            Some(permit) => self.spawn_thread(job, permit),
+            None => {
+                self.metrics.job_rejected();
+                Err(KvsError::QueueFull)
+            }
+        }
+    }
+
+    fn metrics(&self) -> Arc<ThreadPoolMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    fn shutdown(self) {
+        self.permits.wait_all_released(self.builder.threads);
+    }
+}
+
+impl NaiveThreadPool {
+    /// Runs the queued `job` on a new thread, which gives `permit` back once done. The payload
+    /// of a panicking job goes to the panic handler of the pool, if any, and otherwise unwinds
+    /// its thread. If the thread cannot be spawned, the job is unqueued and the permit given
+    /// back.
+    fn spawn_thread<F: FnOnce() + Send + 'static>(&self, job: F, permit: Permit) -> Result<()> {
+        let index = self.spawned.fetch_add(1, Ordering::SeqCst);
+        let metrics = Arc::clone(&self.metrics);
+        let builder = self.builder.clone();
+        let spawned = self.builder.spawn_thread(index, move || {
+            let _permit = permit;
+            builder.start_thread(index);
+            metrics.worker_started();
+            metrics.job_started();
+            let result = panic::catch_unwind(AssertUnwindSafe(job));
+            metrics.job_finished(result.is_err());
+            metrics.worker_exited();
+            if let Err(payload) = result {
+                match &builder.panic_handler {
+                    Some(handler) => handler(payload),
+                    None => panic::resume_unwind(payload),
+                }
+            }
+        });
+        if let Err(e) = spawned {
+            self.metrics.job_rejected();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+/// A counting semaphore bounding the number of running threads.
+struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until `permits` permits are available, i.e. until no thread is running.
+    fn wait_all_released(&self, permits: usize) {
+        let mut available = self.available.lock().unwrap();
+        while *available < permits {
+            available = self.released.wait(available).unwrap();
+        }
+    }
+}
+
+/// A permit of the semaphore, given back when dropped, even if the job panicked.
+struct Permit {
+    semaphore: Arc<Semaphore>,
+}
+
+impl Permit {
+    fn acquire(semaphore: &Arc<Semaphore>) -> Permit {
+        let mut available = semaphore.available.lock().unwrap();
+        while *available == 0 {
+            available = semaphore.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        Permit {
+            semaphore: Arc::clone(semaphore),
+        }
+    }
+
+    /// Takes a permit if one is available, without blocking.
+    fn try_acquire(semaphore: &Arc<Semaphore>) -> Option<Permit> {
+        let mut available = semaphore.available.lock().unwrap();
+        if *available == 0 {
+            return None;
+        }
+        *available -= 1;
+        Some(Permit {
+            semaphore: Arc::clone(semaphore),
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_all();
     }
 }
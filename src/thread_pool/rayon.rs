@@ -1,22 +1,57 @@
 use rayon;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
 
-use super::ThreadPool;
-use crate::Result;
+use super::{ThreadPool, ThreadPoolBuilder, ThreadPoolMetrics};
+use crate::{KvsError, Result};
 
 pub struct RayonThreadPool {
     pool: rayon::ThreadPool,
+    metrics: Arc<ThreadPoolMetrics>,
 }
 
 impl ThreadPool for RayonThreadPool {
-    fn new(threads: usize) -> Result<RayonThreadPool> {
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(threads)
+    fn with_builder(builder: ThreadPoolBuilder) -> Result<RayonThreadPool> {
+        let builder = builder.resolve_affinity()?;
+        let mut rayon_builder = rayon::ThreadPoolBuilder::new().num_threads(builder.threads);
+        if builder.thread_name.is_some() {
+            let names = builder.clone();
+            rayon_builder = rayon_builder.thread_name(move |index| names.name_of(index).unwrap());
+        }
+        if let Some(size) = builder.stack_size {
+            rayon_builder = rayon_builder.stack_size(size);
+        }
+        if builder.on_thread_start.is_some() || builder.core_affinity.is_some() {
+            let starts = builder.clone();
+            rayon_builder = rayon_builder.start_handler(move |index| starts.start_thread(index));
+        }
+        if let Some(panic_handler) = builder.panic_handler {
+            rayon_builder = rayon_builder.panic_handler(move |payload| panic_handler(payload));
+        }
+        let pool = rayon_builder
             .build()
-            .unwrap();
-        Ok(RayonThreadPool { pool })
+            .map_err(|e| KvsError::Internal(format!("building the rayon pool: {}", e)))?;
+        let metrics = Arc::new(ThreadPoolMetrics::default());
+        for _ in 0..builder.threads {
+            metrics.worker_started();
+        }
+        Ok(RayonThreadPool { pool, metrics })
     }
 
     fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
-        self.pool.spawn(job);
+        let metrics = Arc::clone(&self.metrics);
+        metrics.job_queued();
+        self.pool.spawn(move || {
+            metrics.job_started();
+            let result = panic::catch_unwind(AssertUnwindSafe(job));
+            metrics.job_finished(result.is_err());
+            if let Err(payload) = result {
+                panic::resume_unwind(payload);
+            }
+        });
+    }
+
+    fn metrics(&self) -> Arc<ThreadPoolMetrics> {
+        Arc::clone(&self.metrics)
     }
 }
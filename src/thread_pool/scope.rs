@@ -0,0 +1,121 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A scope in which jobs borrowing from the enclosing stack frame can be spawned, created
+/// by `ThreadPool::scope`.
+pub struct Scope<'pool, 'scope> {
+    spawner: &'pool (dyn Fn(Job) + 'pool),
+    state: Arc<ScopeState>,
+    // Invariant in 'scope, so that jobs cannot borrow data shorter-lived than the scope.
+    _marker: PhantomData<Cell<&'scope mut ()>>,
+}
+
+impl<'pool, 'scope> Scope<'pool, 'scope> {
+    /// Spawns a job into the thread pool, which is guaranteed to finish before the scope
+    /// returns. A job the pool drops without running it, for instance because no thread
+    /// could be spawned for it, counts as finished.
+    pub fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        self.state.start();
+        let job = ScopedJob {
+            job,
+            finish: Finish(Arc::clone(&self.state)),
+        };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || job.run());
+        // Safety: `run` does not return before every job spawned in the scope has
+        // finished, so nothing borrowed for 'scope is used after it ends.
+        let job: Job = unsafe { mem::transmute(job) };
+        (self.spawner)(job);
+    }
+}
+
+/// Runs `f` with a scope spawning jobs through `spawner`, then waits for all of them.
+///
+/// The first panic of `f` or of a spawned job is propagated once every job has finished.
+pub(crate) fn run<'scope, F, R>(spawner: &dyn Fn(Job), f: F) -> R
+where
+    F: FnOnce(&Scope<'_, 'scope>) -> R,
+{
+    let scope = Scope {
+        spawner,
+        state: Arc::new(ScopeState {
+            pending: Mutex::new(0),
+            done: Condvar::new(),
+            panic: Mutex::new(None),
+        }),
+        _marker: PhantomData,
+    };
+
+    // The spawned jobs may borrow from the caller, so wait for them even if `f` panics.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+    scope.state.wait();
+
+    if let Some(payload) = scope.state.panic.lock().unwrap().take() {
+        panic::resume_unwind(payload);
+    }
+    match result {
+        Ok(result) => result,
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+
+/// A job spawned in a scope, which counts as finished once run, or once dropped by a pool
+/// which could not run it.
+struct ScopedJob<F> {
+    // Declared first to be dropped before the job is counted as finished.
+    job: F,
+    finish: Finish,
+}
+
+impl<F: FnOnce()> ScopedJob<F> {
+    fn run(self) {
+        let ScopedJob { job, finish } = self;
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+            finish.0.panic.lock().unwrap().get_or_insert(payload);
+        }
+    }
+}
+
+/// Counts a job of the scope as finished when dropped.
+struct Finish(Arc<ScopeState>);
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+struct ScopeState {
+    pending: Mutex<usize>,
+    done: Condvar,
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl ScopeState {
+    fn start(&self) {
+        *self.pending.lock().unwrap() += 1;
+    }
+
+    fn finish(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.done.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        while *pending > 0 {
+            pending = self.done.wait(pending).unwrap();
+        }
+    }
+}
@@ -1,58 +1,400 @@
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use std::thread;
+use crossbeam_channel::{unbounded, Receiver, Select, Sender, TryRecvError};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
 
-use super::ThreadPool;
-use crate::Result;
+use super::{Priority, ThreadPool, ThreadPoolBuilder, ThreadPoolMetrics};
+use crate::{KvsError, Result};
+
+/// The number of priority levels, each of which has its own job queue.
+const PRIORITIES: usize = 3;
 
 pub struct SharedQueueThreadPool {
-    sender: Sender<Job>,
+    /// The senders of the job queues, indexed by `Priority`.
+    senders: Vec<Sender<Job>>,
+    shared: Arc<Shared>,
+}
+
+impl SharedQueueThreadPool {
+    /// Stops accepting jobs and waits at most `timeout` for the queued ones to finish.
+    ///
+    /// Returns `false` if some workers were still running when the timeout elapsed.
+    pub fn join(self, timeout: Duration) -> bool {
+        let SharedQueueThreadPool { senders, shared } = self;
+        drop(senders);
+        shared.wait(Some(timeout))
+    }
+
+    /// Sends `job`, which already holds a slot of the queue, to the queue of `priority`.
+    ///
+    /// Fails, without queuing the job, if the pool shrinks its idle workers, has none left and
+    /// cannot spawn one to run it.
+    fn queue(&self, job: Job, priority: Priority) -> io::Result<()> {
+        // The job is sent under the lock on the workers, so that a retiring worker either sees
+        // it or is seen gone by `ensure_worker`.
+        let workers = match self.shared.ensure_worker() {
+            Ok(workers) => workers,
+            Err(e) => {
+                self.shared.metrics.job_rejected();
+                self.shared.release_slot();
+                return Err(e);
+            }
+        };
+        if let Err(e) = self.senders[priority as usize].send(job) {
+            // The pool keeps the receivers for as long as it lives, so this is only a bug.
+            error!(error = %e, "Failed to queue a job.");
+            self.shared.metrics.job_rejected();
+            self.shared.release_slot();
+        }
+        drop(workers);
+        Ok(())
+    }
 }
 
 impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: usize) -> Result<Self>
+    /// Creates the pool. Once `ThreadPoolBuilder::queue_capacity` jobs are queued, whatever
+    /// their priority, `spawn` blocks until a worker picks up a job, while `try_spawn` fails
+    /// with `KvsError::QueueFull`.
+    ///
+    /// A panicking job never takes its worker down, the panic is only counted and handed
+    /// to the panic handler of the builder.
+    ///
+    /// With `ThreadPoolBuilder::idle_timeout`, the pool starts without workers and spawns
+    /// them on demand, up to the configured number of threads.
+    fn with_builder(builder: ThreadPoolBuilder) -> Result<Self> {
+        assert!(builder.threads > 0);
+        assert_ne!(builder.queue_capacity, Some(0));
+        let builder = builder.resolve_affinity()?;
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..PRIORITIES).map(|_| unbounded()).unzip();
+        let slots = builder.queue_capacity.map(Slots::new);
+        let initial_workers = match builder.idle_timeout {
+            Some(_) => 0,
+            None => builder.threads,
+        };
+        let shared = Arc::new(Shared {
+            builder,
+            receivers,
+            slots,
+            workers: Mutex::new(0),
+            exited: Condvar::new(),
+            spawned: AtomicUsize::new(0),
+            metrics: Arc::new(ThreadPoolMetrics::default()),
+        });
+
+        {
+            let mut workers = shared.workers.lock().unwrap();
+            for _ in 0..initial_workers {
+                shared.spawn_worker(&mut workers)?;
+            }
+        }
+        Ok(SharedQueueThreadPool { senders, shared })
+    }
+
+    fn spawn<F>(&self, job: F)
     where
-        Self: Sized,
+        F: FnOnce() + Send + 'static,
     {
-        assert!(threads > 0);
-        let (sender, receiver) = unbounded();
+        self.spawn_with_priority(job, Priority::Normal)
+    }
 
-        for _ in 0..threads {
-            let receiver = JobReceiver {
-                receiver: receiver.clone(),
-            };
-            thread::spawn(move || {
-                while let Ok(job) = receiver.receiver.recv() {
-                    job();
-                }
-            });
+    fn spawn_with_priority<F>(&self, job: F, priority: Priority)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(slots) = &self.shared.slots {
+            slots.acquire();
+        }
+        self.shared.metrics.job_queued();
+        if let Err(e) = self.queue(Box::new(job), priority) {
+            error!(error = %e, "Failed to spawn a worker for a job, which is dropped.");
         }
-        Ok(SharedQueueThreadPool { sender })
     }
 
-    fn spawn<F>(&self, job: F)
+    /// Fails if the job queue bounded by `ThreadPoolBuilder::queue_capacity` is full, or if
+    /// no worker is left to run the job and none can be spawned.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_spawn_with_priority(job, Priority::Normal)
+    }
+
+    /// The queues of every priority share the bound, so that a full queue of low priority
+    /// jobs also refuses high priority ones.
+    fn try_spawn_with_priority<F>(&self, job: F, priority: Priority) -> Result<()>
     where
         F: FnOnce() + Send + 'static,
     {
-        self.sender.send(Box::new(job)).unwrap();
+        if let Some(slots) = &self.shared.slots {
+            if !slots.try_acquire() {
+                return Err(KvsError::QueueFull);
+            }
+        }
+        self.shared.metrics.job_queued();
+        self.queue(Box::new(job), priority)?;
+        Ok(())
+    }
+
+    fn metrics(&self) -> Arc<ThreadPoolMetrics> {
+        Arc::clone(&self.shared.metrics)
+    }
+
+    fn shutdown(self) {
+        let SharedQueueThreadPool { senders, shared } = self;
+        drop(senders);
+        shared.wait(None);
     }
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
-#[derive(Clone)]
-struct JobReceiver {
-    receiver: Receiver<Job>,
+/// The outcome of looking for the next job.
+enum NextJob {
+    Job(Job),
+    /// Every queue is empty, or no job arrived before the idle timeout.
+    Empty,
+    /// Every queue is drained and every sender has been dropped.
+    Disconnected,
 }
 
-impl Drop for JobReceiver {
+/// Runs jobs on the worker numbered `index` until the queues are drained and every sender has
+/// been dropped, or until the worker has been idle for too long.
+fn run_jobs(shared: &Shared, index: usize) {
+    let mut worker = Worker {
+        shared,
+        retired: false,
+    };
+    shared.builder.start_thread(index);
+    loop {
+        let job = match next_job(&shared.receivers, shared.builder.idle_timeout) {
+            NextJob::Job(job) => job,
+            NextJob::Empty => match shared.retire() {
+                Some(job) => job,
+                None => {
+                    worker.retired = true;
+                    return;
+                }
+            },
+            NextJob::Disconnected => return,
+        };
+        shared.release_slot();
+
+        shared.metrics.job_started();
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+        shared.metrics.job_finished(result.is_err());
+        if let Err(payload) = result {
+            if let Some(handler) = &shared.builder.panic_handler {
+                // A panicking handler must not take the worker down either.
+                if panic::catch_unwind(AssertUnwindSafe(|| handler(payload))).is_err() {
+                    error!("The panic handler of the thread pool panicked.");
+                }
+            }
+        }
+    }
+}
+
+/// A live worker, which unregisters once dropped unless it retired, whether it returns or
+/// unwinds, from the start callback for instance.
+struct Worker<'a> {
+    shared: &'a Shared,
+    /// Whether `Shared::retire` unregistered the worker already.
+    retired: bool,
+}
+
+impl Drop for Worker<'_> {
     fn drop(&mut self) {
-        if thread::panicking() {
-            let receiver = self.clone();
-            thread::spawn(move || {
-                while let Ok(job) = receiver.receiver.recv() {
-                    job();
+        if !self.retired {
+            self.shared.unregister();
+        }
+    }
+}
+
+/// Takes the next queued job without blocking, preferring the queues of higher priority.
+fn try_next_job(receivers: &[Receiver<Job>]) -> NextJob {
+    let mut disconnected = 0;
+    for receiver in receivers {
+        match receiver.try_recv() {
+            Ok(job) => return NextJob::Job(job),
+            Err(TryRecvError::Empty) => (),
+            Err(TryRecvError::Disconnected) => disconnected += 1,
+        }
+    }
+    if disconnected == receivers.len() {
+        NextJob::Disconnected
+    } else {
+        NextJob::Empty
+    }
+}
+
+/// Receives the next job, preferring the queues of higher priority, waiting at most
+/// `timeout` for one to arrive.
+fn next_job(receivers: &[Receiver<Job>], timeout: Option<Duration>) -> NextJob {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        match try_next_job(receivers) {
+            NextJob::Empty => (),
+            next => return next,
+        }
+
+        // Wait until any queue has a job, then pick by priority again.
+        let mut select = Select::new();
+        for receiver in receivers {
+            select.recv(receiver);
+        }
+        match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline || select.ready_timeout(deadline - now).is_err() {
+                    return NextJob::Empty;
+                }
+            }
+            None => {
+                select.ready();
+            }
+        }
+    }
+}
+
+/// The state shared between the pool and its workers.
+struct Shared {
+    builder: ThreadPoolBuilder,
+    receivers: Vec<Receiver<Job>>,
+    /// The room left in the queues, `None` if they are unbounded.
+    slots: Option<Slots>,
+    /// The number of live workers.
+    workers: Mutex<usize>,
+    exited: Condvar,
+    /// The number of workers spawned so far, used to name them.
+    spawned: AtomicUsize,
+    metrics: Arc<ThreadPoolMetrics>,
+}
+
+impl Shared {
+    /// Frees the slot of a job taken off the queues.
+    fn release_slot(&self) {
+        if let Some(slots) = &self.slots {
+            slots.release();
+        }
+    }
+
+    /// Spawns a new worker. `workers` is the guarded number of live workers.
+    fn spawn_worker(self: &Arc<Self>, workers: &mut usize) -> io::Result<()> {
+        let index = self.spawned.fetch_add(1, Ordering::SeqCst);
+        let shared = Arc::clone(self);
+        self.builder
+            .spawn_thread(index, move || run_jobs(&shared, index))?;
+        *workers += 1;
+        self.metrics.worker_started();
+        Ok(())
+    }
+
+    /// Spawns a worker for a job about to be queued if the pool shrinks its idle workers and
+    /// there are more queued jobs than idle workers to pick them up. Returns the lock on the
+    /// workers, held if the pool shrinks them, for the job to be sent under it.
+    ///
+    /// Fails if the worker cannot be spawned while no other is left to run the job.
+    fn ensure_worker(self: &Arc<Self>) -> io::Result<Option<MutexGuard<'_, usize>>> {
+        if self.builder.idle_timeout.is_none() {
+            return Ok(None);
+        }
+        let mut workers = self.workers.lock().unwrap();
+        let stats = self.metrics.snapshot();
+        if stats.queued > stats.idle && *workers < self.builder.threads {
+            if let Err(e) = self.spawn_worker(&mut workers) {
+                if *workers == 0 {
+                    return Err(e);
+                }
+                // The job is left to the live workers.
+                warn!(error = %e, "Failed to spawn a worker thread.");
+            }
+        }
+        Ok(Some(workers))
+    }
+
+    /// Lets an idle worker exit, unless a job has been queued meanwhile, which is returned
+    /// for the worker to run instead.
+    fn retire(&self) -> Option<Job> {
+        let mut workers = self.workers.lock().unwrap();
+        // Checking the queues under the lock guarantees that `ensure_worker` either sees
+        // this worker gone or this worker sees the job.
+        if let NextJob::Job(job) = try_next_job(&self.receivers) {
+            return Some(job);
+        }
+        self.exit(&mut workers);
+        None
+    }
+
+    fn unregister(&self) {
+        let mut workers = self.workers.lock().unwrap();
+        self.exit(&mut workers);
+    }
+
+    fn exit(&self, workers: &mut usize) {
+        self.metrics.worker_exited();
+        *workers -= 1;
+        if *workers == 0 {
+            self.exited.notify_all();
+        }
+    }
+
+    /// Blocks until every worker has exited, or until `timeout` elapses.
+    fn wait(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut workers = self.workers.lock().unwrap();
+        while *workers > 0 {
+            workers = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.exited.wait_timeout(workers, deadline - now).unwrap().0
                 }
-            });
+                None => self.exited.wait(workers).unwrap(),
+            };
         }
+        true
+    }
+}
+
+/// The number of jobs which can still be queued, shared by the queues of every priority.
+struct Slots {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Slots {
+    fn new(capacity: usize) -> Slots {
+        Slots {
+            free: Mutex::new(capacity),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Takes a slot, blocking until a worker frees one.
+    fn acquire(&self) {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.released.wait(free).unwrap();
+        }
+        *free -= 1;
+    }
+
+    /// Takes a slot if one is free.
+    fn try_acquire(&self) -> bool {
+        let mut free = self.free.lock().unwrap();
+        if *free == 0 {
+            return false;
+        }
+        *free -= 1;
+        true
+    }
+
+    fn release(&self) {
+        *self.free.lock().unwrap() += 1;
+        self.released.notify_one();
     }
 }
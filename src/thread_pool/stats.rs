@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// A point-in-time snapshot of the activity of a thread pool.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThreadPoolStats {
+    /// Jobs waiting in the queue for a worker.
+    pub queued: usize,
+    /// Workers currently running a job.
+    pub busy: usize,
+    /// Workers waiting for a job.
+    pub idle: usize,
+    /// Jobs run to completion or to a panic since the pool was created.
+    pub executed: u64,
+    /// Jobs that panicked since the pool was created.
+    pub panics: u64,
+}
+
+/// Counters updated by a thread pool as it runs jobs. It can be shared with other threads
+/// to report the activity of the pool without holding the pool itself.
+#[derive(Debug, Default)]
+pub struct ThreadPoolMetrics {
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    workers: AtomicUsize,
+    executed: AtomicU64,
+    panics: AtomicU64,
+}
+
+impl ThreadPoolMetrics {
+    /// Takes a snapshot of the counters.
+    pub fn snapshot(&self) -> ThreadPoolStats {
+        let busy = self.busy.load(Ordering::SeqCst);
+        ThreadPoolStats {
+            queued: self.queued.load(Ordering::SeqCst),
+            busy,
+            idle: self.workers.load(Ordering::SeqCst).saturating_sub(busy),
+            executed: self.executed.load(Ordering::SeqCst),
+            panics: self.panics.load(Ordering::SeqCst),
+        }
+    }
+
+    pub(crate) fn job_queued(&self) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Undoes `job_queued` for a job the queue refused.
+    pub(crate) fn job_rejected(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn job_started(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        self.busy.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn job_finished(&self, panicked: bool) {
+        self.busy.fetch_sub(1, Ordering::SeqCst);
+        self.executed.fetch_add(1, Ordering::SeqCst);
+        if panicked {
+            self.panics.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn worker_started(&self) {
+        self.workers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn worker_exited(&self) {
+        self.workers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -1,12 +1,64 @@
 use assert_cmd::prelude::*;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::process::{Child, Command};
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
+/// A `kvs-server` run by a test, killed and waited for once dropped.
+struct Server {
+    child: Child,
+}
+
+impl Server {
+    /// Spawns the server `cmd` runs, and waits until it accepts connections on `addr`.
+    fn spawn(addr: &str, cmd: &mut Command) -> Server {
+        let mut server = Server {
+            child: cmd.spawn().unwrap(),
+        };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !Server::ping(addr) {
+            if let Some(status) = server.child.try_wait().unwrap() {
+                panic!("server exited with {} before listening on {}", status, addr);
+            }
+            assert!(
+                Instant::now() < deadline,
+                "server not listening on {}",
+                addr
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+        server
+    }
+
+    /// Sends PING to `addr` if it accepts connections, so that the server is not left with an
+    /// empty request to complain about.
+    fn ping(addr: &str) -> bool {
+        let mut stream = match TcpStream::connect(addr) {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        let _ = stream.write_all(b"PING\r\n");
+        let _ = stream.shutdown(std::net::Shutdown::Write);
+        let _ = stream.read_to_string(&mut String::new());
+        true
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        // The server may have exited already.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
@@ -129,6 +181,18 @@ fn client_cli_invalid_subcommand() {
         .failure();
 }
 
+// `kvs-client` should exit with a dedicated code when the server is unreachable.
+#[test]
+fn client_cli_connection_failure() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4);
+}
+
 // `kvs-client -V` should print the version
 #[test]
 fn client_cli_version() {
@@ -172,6 +236,7 @@ fn cli_log_configuration() {
 }
 
 #[test]
+#[cfg(feature = "sled")]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
@@ -212,12 +277,63 @@ fn cli_wrong_engine() {
     }
 }
 
+// Without db.type, the server finds the engine from the files of the data directory.
+#[test]
+#[cfg(feature = "sled")]
+fn cli_sniff_engine() {
+    let addr = "127.0.0.1:4030";
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "sled", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+    drop(server);
+    let db_type = temp_dir.path().join("db.type");
+    fs::remove_file(&db_type).unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert!(!db_type.exists());
+
+    let mut server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir),
+    );
+    assert!(server.child.try_wait().unwrap().is_none());
+    drop(server);
+    assert_eq!(fs::read_to_string(&db_type).unwrap(), "sled");
+    assert!(!temp_dir.path().join("log").exists());
+}
+
+#[test]
+fn cli_wrong_log_format() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--log-format", "xml", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unknown log format"));
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        // The server is killed right after the last write, which has to be on disk already.
+        .args(&["--sled-flush", "writes:1"])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -264,7 +380,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .success()
+        .code(2)
         .stdout(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
@@ -272,7 +388,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
+        .code(2)
         .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
@@ -283,6 +399,14 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "key2", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout("value2\nvalue3\nKey not found\n");
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["rm", "key1", "--addr", addr])
@@ -300,6 +424,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        // The server is killed right after the last write, which has to be on disk already.
+        .args(&["--sled-flush", "writes:1"])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -321,7 +447,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .success()
+        .code(2)
         .stdout(contains("Key not found"));
     sender.send(()).unwrap();
     handle.join().unwrap();
@@ -333,6 +459,1562 @@ fn cli_access_server_kvs_engine() {
 }
 
 #[test]
+#[cfg(feature = "sled")]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Without the sled feature, the sled engine is refused before the data directory is marked
+// with it.
+#[test]
+#[cfg(not(feature = "sled"))]
+fn cli_sled_not_compiled() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "sled", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not compiled in"));
+    assert!(!temp_dir.path().join("db.type").exists());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not compiled in"));
+}
+
+// `kvs scan` prints the stored keys one per line, optionally filtered by prefix.
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    for key in &["user:2", "user:1", "order:1"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["set", key, "value"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("order:1\nuser:1\nuser:2\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "--prefix", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1\nuser:2\n");
+}
+
+// `kvs` can work on any data directory and follows the engine recorded in it.
+#[test]
+#[cfg(feature = "sled")]
+fn cli_data_dir_and_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let data_dir = data_dir.to_str().unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&[
+            "set",
+            "key1",
+            "value1",
+            "--data-dir",
+            data_dir,
+            "--engine",
+            "sled",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("data").join("db.type")).unwrap(),
+        "sled"
+    );
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1", "--data-dir", data_dir])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1", "--data-dir", data_dir, "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs compact` shrinks the log and keeps the latest values.
+#[test]
+fn cli_compact() {
+    let temp_dir = TempDir::new().unwrap();
+    for value in &["value1", "value2", "value3"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["set", "key1", value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    let size_before = fs::metadata(temp_dir.path().join("log")).unwrap().len();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("bytes reclaimed"));
+    assert!(fs::metadata(temp_dir.path().join("log")).unwrap().len() < size_before);
+    assert!(temp_dir.path().join("index").exists());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3\n");
+}
+
+// `kvs repair` drops corrupted records, truncates torn ones and rebuilds the index.
+#[test]
+fn cli_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    for (key, value) in &[("key1", "value1"), ("key2", "value2"), ("key3", "value3")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["set", key, value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    let log_path = temp_dir.path().join("log");
+    let mut log = fs::read_to_string(&log_path)
+        .unwrap()
+        .replace("value2", "valueX");
+    log.push_str("{\"crc\":1,\"cmd\":{\"Set\"");
+    fs::write(&log_path, log).unwrap();
+    fs::write(temp_dir.path().join("index"), "stale").unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Recovered 2 records").and(contains("Dropped 1 corrupted records")));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\nkey3\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3\n");
+}
+
+// A dump of one database can be restored into another one, even of a different engine.
+#[test]
+#[cfg(feature = "sled")]
+fn cli_dump_and_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let snapshot = temp_dir.path().join("snapshot.jsonl");
+    let snapshot = snapshot.to_str().unwrap();
+    for (key, value) in &[("key1", "value1"), ("key2", "value\n2")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["set", key, value, "--data-dir", "source"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["dump", "--output", snapshot, "--data-dir", "source"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Dumped 2 keys\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["restore", "--input", snapshot])
+        .args(&["--data-dir", "target", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Restored 2 keys\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key2", "--data-dir", "target"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value\n2\n");
+}
+
+// Request latencies are reported by INFO, along with the state of the engine, and by the
+// metrics endpoint.
+#[test]
+fn cli_latency_metrics() {
+    let addr = "127.0.0.1:4011";
+    let metrics_addr = "127.0.0.1:4012";
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", addr, "--metrics-addr", metrics_addr])
+            .current_dir(&temp_dir),
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["info", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("latency_set_count:1")
+                .and(contains("latency_set_exec_p99_us:"))
+                .and(contains("keys:1"))
+                .and(contains("compactions:0")),
+        );
+
+    // A request line without end is answered once the first 8 KiB of it are read.
+    let mut endless = TcpStream::connect(metrics_addr).unwrap();
+    endless.write_all(&[b'x'; 8192]).unwrap();
+    let mut response = String::new();
+    endless.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    let mut stream = TcpStream::connect(metrics_addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    drop(server);
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(
+        response.contains("kvs_request_duration_seconds_count{command=\"SET\",phase=\"queued\"} 1")
+    );
+}
+
+// SAVE makes the server write a checkpoint of its index.
+#[test]
+fn cli_save() {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(!temp_dir.path().join("index").exists());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["save", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    drop(server);
+
+    assert!(fs::read_to_string(temp_dir.path().join("index"))
+        .unwrap()
+        .contains("key1"));
+}
+
+// Values are framed by their length, so that they may contain line breaks.
+#[test]
+fn cli_value_with_line_breaks() {
+    let addr = "127.0.0.1:4014";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "line1\r\nline2\nline3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("line1\r\nline2\nline3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("\nline1\r\nline2\nline3\n");
+
+    // A value shorter than its declared length is rejected.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"SET\r\nkey3\r\n10\r\nvalue\r\n").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "Error\r\nBAD_REQUEST Malformed request.\r\n");
+}
+
+#[test]
+fn cli_key_policy() {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .args(&["--key-charset", "url-safe", "--reserved-prefix", "_sys"])
+            .current_dir(&temp_dir),
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "user-42", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "user 42", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3)
+        .stderr(contains("Invalid key"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "user-42", "_sys.config", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3)
+        .stderr(contains("reserved"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "user-42", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// Keys can be set with a time to live, or given one later, over the wire, and a verbose get
+// prints what is left of it.
+#[test]
+fn cli_ttl() {
+    let addr = "127.0.0.1:4018";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["ttl", "key1"]).assert().success().stdout("-1\n");
+    client(&["set", "key2", "value2", "--ttl", "100"])
+        .assert()
+        .success();
+    client(&["ttl", "key2"]).assert().success().stdout("100\n");
+    client(&["expire", "key1", "50"]).assert().success();
+    client(&["ttl", "key1"]).assert().success().stdout("50\n");
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["expire", "key3", "50"]).assert().code(2);
+    client(&["ttl", "key3"]).assert().code(2);
+    client(&["set", "key4", "value4"]).assert().success();
+    client(&["get", "--verbose", "key1", "key4"])
+        .assert()
+        .success()
+        .stdout("value1\nttl: 50s\nvalue4\nttl: none\n");
+    client(&["get", "-v", "key2", "key3"])
+        .assert()
+        .code(2)
+        .stdout("value2\nttl: 100s\nKey not found\n");
+
+    client(&["expire", "key1", "0"]).assert().success();
+    client(&["get", "key1"]).assert().code(2);
+}
+
+// The keys matching a pattern are listed one per line, and none at all prints nothing. The
+// length of a value is printed without the value.
+#[test]
+fn cli_keys_and_strlen() {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "user:1", "alice"]).assert().success();
+    client(&["set", "order:1", "tea"]).assert().success();
+    client(&["keys", "user:*"])
+        .assert()
+        .success()
+        .stdout("user:1\n");
+    client(&["keys", "*:?"])
+        .assert()
+        .success()
+        .stdout(contains("user:1").and(contains("order:1")));
+    client(&["keys", "item:*"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["strlen", "user:1"])
+        .assert()
+        .success()
+        .stdout("5\n");
+    client(&["strlen", "user:2"])
+        .assert()
+        .code(2)
+        .stdout("Key not found\n");
+}
+
+// The connections in progress are listed, whether waiting for a worker or being served.
+#[test]
+fn cli_client_list() {
+    let addr = "127.0.0.1:4020";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+
+    let mut lister = TcpStream::connect(addr).unwrap();
+    lister.write_all(b"CLIENT\r\n").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    // Stuck halfway through its request.
+    let mut stuck = TcpStream::connect(addr).unwrap();
+    stuck.write_all(b"GET\r\n").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    lister.write_all(b"LIST\r\n").unwrap();
+    lister.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = String::new();
+    lister.read_to_string(&mut response).unwrap();
+
+    let lines: Vec<&str> = response.lines().collect();
+    assert_eq!(lines[..2], ["Success", "2"]);
+    let listed = format!("addr={} ", lister.local_addr().unwrap());
+    assert!(lines[2].contains(&listed));
+    assert!(lines[2].ends_with(" cmd=client cmds=1"));
+    let stuck_addr = format!("addr={} ", stuck.local_addr().unwrap());
+    assert!(lines[3].contains(&stuck_addr));
+    drop(stuck);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["client", "list", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("cmd=client cmds=1"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["info", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("connected_clients:1"));
+}
+
+// The string keys of a Redis instance, here a fake one, are copied to the server.
+#[test]
+fn cli_import_redis() {
+    let addr = "127.0.0.1:4016";
+    let redis_addr = "127.0.0.1:4017";
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind(redis_addr).unwrap();
+    let redis = thread::spawn(move || {
+        let replies: &[(&str, &str)] = &[
+            (
+                "SCAN 0 MATCH user:* COUNT 100",
+                "*2\r\n$2\r\n17\r\n*2\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n",
+            ),
+            ("MGET user:1 user:2", "*2\r\n$6\r\nvalue1\r\n$-1\r\n"),
+            (
+                "SCAN 17 MATCH user:* COUNT 100",
+                "*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:3\r\n",
+            ),
+            ("MGET user:3", "*1\r\n$8\r\nvalue\r\n3\r\n"),
+        ];
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut read_line = || {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line.trim_end().to_owned()
+        };
+        for (request, reply) in replies {
+            let args: usize = read_line()[1..].parse().unwrap();
+            let args: Vec<String> = (0..args)
+                .map(|_| {
+                    read_line();
+                    read_line()
+                })
+                .collect();
+            assert_eq!(&args.join(" "), request);
+            (&stream).write_all(reply.as_bytes()).unwrap();
+        }
+    });
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["import-redis", "--from", &format!("redis://{}", redis_addr)])
+        .args(&["--pattern", "user:*", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Imported 2 keys\nSkipped 1 keys not holding UTF-8 strings\n");
+    redis.join().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "user:1", "user:3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\nvalue\r\n3\n");
+}
+
+// The keys with a prefix are copied from a server to another, across several batches, and the
+// other keys are left behind.
+#[test]
+fn cli_copy() {
+    let (from, to) = ("127.0.0.1:4021", "127.0.0.1:4022");
+    let (from_dir, to_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let _servers: Vec<_> = [(from, &from_dir), (to, &to_dir)]
+        .iter()
+        .map(|(addr, dir)| {
+            Server::spawn(
+                addr,
+                Command::cargo_bin("kvs-server")
+                    .unwrap()
+                    .args(&["--engine", "kvs", "--addr", addr])
+                    .current_dir(dir),
+            )
+        })
+        .collect();
+
+    for i in 0..250 {
+        let value = format!("value\r\n{}", i);
+        let mut stream = TcpStream::connect(from).unwrap();
+        write!(
+            stream,
+            "SET\r\nuser:{}\r\n{}\r\n{}\r\n",
+            i,
+            value.len(),
+            value
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "Success\r\n");
+    }
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).current_dir(&from_dir);
+        cmd
+    };
+    client(&["set", "order:1", "tea", "--addr", from])
+        .assert()
+        .success();
+
+    client(&["copy", "--from", from, "--to", to, "--prefix", "user:"])
+        .assert()
+        .success()
+        .stdout("Copied 250 keys\n");
+    client(&["get", "user:0", "user:249", "--addr", to])
+        .assert()
+        .success()
+        .stdout("value\r\n0\nvalue\r\n249\n");
+    client(&["keys", "order:*", "--addr", to])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["copy", "--from", from, "--to", to, "--prefix", "item:"])
+        .assert()
+        .success()
+        .stdout("Copied 0 keys\n");
+}
+
+// A SET the destination of a copy is too busy to take is sent again.
+#[test]
+fn cli_copy_busy() {
+    let (from, to) = ("127.0.0.1:4045", "127.0.0.1:4046");
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        from,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", from])
+            .current_dir(&temp_dir),
+    );
+    let listener = TcpListener::bind(to).unwrap();
+    let busy_server = thread::spawn(move || {
+        let replies = [
+            "Error\r\nBUSY The job queue of the thread pool is full.\r\n",
+            "Success\r\n",
+        ];
+        for reply in &replies {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut received = String::new();
+            while received.len() < "SET\r\nkey1\r\n6\r\nvalue1\r\n".len() {
+                reader.read_line(&mut received).unwrap();
+            }
+            assert_eq!(received, "SET\r\nkey1\r\n6\r\nvalue1\r\n");
+            stream.write_all(reply.as_bytes()).unwrap();
+        }
+    });
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1", "--addr", from])
+        .assert()
+        .success();
+    client(&["copy", "--from", from, "--to", to, "--prefix", "key"])
+        .assert()
+        .success()
+        .stdout("Copied 1 keys\n");
+    busy_server.join().unwrap();
+}
+
+// The keys matched by a regular expression are printed one per line, filtered by the server,
+// or by the client when the server does not know the command.
+#[test]
+fn cli_scan_regex() {
+    let addr = "127.0.0.1:4023";
+    let old_addr = "127.0.0.1:4024";
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+    for key in &["user:1", "user:22", "order:1"] {
+        client(&["set", key, "value", "--addr", addr])
+            .assert()
+            .success();
+    }
+    client(&["scan", "--regex", r"^user:\d{2}$", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("user:22\n");
+    client(&["scan", "--regex", ":1$", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("user:1\n").and(contains("order:1\n")));
+    client(&["scan", "--regex", "^item:", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["scan", "--regex", "(", "--addr", addr])
+        .assert()
+        .code(3)
+        .stderr(contains("Invalid pattern"));
+    drop(server);
+
+    let listener = TcpListener::bind(old_addr).unwrap();
+    let old_server = thread::spawn(move || {
+        let replies = [
+            (
+                "RSCAN\r\n^user:\r\n",
+                "Error\r\nUNSUPPORTED Command not support.\r\n",
+            ),
+            ("SCAN\r\n", "Success\r\nuser:1\r\norder:1\r\nuser:22\r\n"),
+        ];
+        for (request, reply) in &replies {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut received = String::new();
+            while received.len() < request.len() {
+                reader.read_line(&mut received).unwrap();
+            }
+            assert_eq!(received, *request);
+            stream.write_all(reply.as_bytes()).unwrap();
+        }
+    });
+    client(&["scan", "--regex", "^user:", "--addr", old_addr])
+        .assert()
+        .success()
+        .stdout("user:1\nuser:22\n");
+    old_server.join().unwrap();
+}
+
+// Every ping answered prints its round trip time, and a server which never answers makes the
+// client exit with the connection failure code.
+#[test]
+fn cli_ping() {
+    let addr = "127.0.0.1:4025";
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["ping", "--count", "2", "--interval", "10"])
+        .assert()
+        .success()
+        .stdout(
+            contains(format!("Reply from {}: time=", addr))
+                .and(contains("2 sent, 2 received, min/avg/max = ")),
+        );
+    drop(server);
+
+    client(&["ping"])
+        .assert()
+        .code(4)
+        .stdout("1 sent, 0 received\n")
+        .stderr(contains(format!("No reply from {}", addr)));
+}
+
+// The addresses of the server and client can be hostnames, resolved to every address they
+// designate, and the server can listen on every interface.
+#[test]
+fn cli_hostname_addr() {
+    let temp_dir = TempDir::new().unwrap();
+    let _servers: Vec<_> = [
+        ("localhost:4026", "localhost:4026"),
+        ("0.0.0.0:4027", "127.0.0.1:4027"),
+    ]
+    .iter()
+    .map(|(addr, connect_addr)| {
+        let dir = temp_dir.path().join(&addr[addr.len() - 4..]);
+        fs::create_dir(&dir).unwrap();
+        Server::spawn(
+            connect_addr,
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(&["--engine", "kvs", "--addr", addr])
+                .current_dir(dir),
+        )
+    })
+    .collect();
+
+    for addr in &["localhost:4026", "127.0.0.1:4027", "localhost:4027"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", addr, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(format!("{}\n", addr));
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "localhost:http"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid port"));
+}
+
+// The logs go to the log file rather than to stderr, which is rotated once it would exceed its
+// size, and only the most recent rotated files are kept.
+#[test]
+fn cli_log_file() {
+    let addr = "127.0.0.1:4028";
+    let temp_dir = TempDir::new().unwrap();
+    let log_file = temp_dir.path().join("server.log");
+    let stderr_path = temp_dir.path().join("stderr");
+    let server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr, "--log-file"])
+            .arg(&log_file)
+            .args(&["--log-rotate", "size:400", "--log-keep", "2"])
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap()),
+    );
+
+    for _ in 0..5 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["rm", "missing", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .code(2);
+    }
+    drop(server);
+    assert!(fs::read_to_string(&stderr_path).unwrap().is_empty());
+
+    let rotated = |n: usize| temp_dir.path().join(format!("server.log.{}", n));
+    assert!(rotated(1).exists() && rotated(2).exists());
+    assert!(!rotated(3).exists());
+    for path in &[log_file.clone(), rotated(1), rotated(2)] {
+        let logs = fs::read_to_string(path).unwrap();
+        assert!(logs.len() <= 400 || logs.lines().count() == 1);
+    }
+    let logs = fs::read_to_string(&log_file).unwrap();
+    assert!(logs.contains("Failed to serve a request."));
+}
+
+// `kvs-server --log-level warn --log-format text` logs the warnings but not the info lines,
+// one line each.
+#[test]
+fn cli_log_level() {
+    let addr = "127.0.0.1:4029";
+    let temp_dir = TempDir::new().unwrap();
+    let log_file = temp_dir.path().join("server.log");
+    let server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr, "--log-file"])
+            .arg(&log_file)
+            .args(&["--log-level", "warn", "--log-format", "text"])
+            .current_dir(&temp_dir),
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "missing", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2);
+    drop(server);
+
+    let logs = fs::read_to_string(&log_file).unwrap();
+    assert!(!logs.contains("Listening."));
+    let lines: Vec<&str> = logs.lines().collect();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].contains("WARN") && lines[0].contains("Failed to serve a request."));
+    assert!(!lines[0].starts_with('{') && !lines[0].contains('\x1b'));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--log-level", "verbose", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("log-level"));
+}
+
+// `kvs-server --addr A --addr B` serves the same store on both addresses, and fails if one of
+// them cannot be bound.
+#[test]
+fn cli_multiple_addrs() {
+    let (first, second) = ("127.0.0.1:4031", "127.0.0.1:4032");
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        second,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", first, "--addr", second])
+            .current_dir(&temp_dir),
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", first])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", second])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    let other_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--engine",
+            "kvs",
+            "--addr",
+            "127.0.0.1:4033",
+            "--addr",
+            second,
+        ])
+        .current_dir(&other_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-client set --if-version` only sets a key still at the version printed by
+// `kvs-client get --with-version`, and exits with 5 otherwise.
+#[test]
+fn cli_set_if_version() {
+    let addr = "127.0.0.1:4034";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--versioned", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    let created = client(&["set", "key1", "value1", "--if-version", "none"])
+        .output()
+        .unwrap();
+    assert!(created.status.success());
+    let version = String::from_utf8(created.stdout).unwrap();
+    client(&["get", "key1", "--with-version"])
+        .assert()
+        .success()
+        .stdout(format!("value1\nversion: {}", version));
+    client(&["set", "key1", "value2", "--if-version", "none"])
+        .assert()
+        .code(5)
+        .stderr(contains("Version mismatch"));
+    client(&["set", "key1", "value2", "--if-version", version.trim()])
+        .assert()
+        .success();
+    client(&["set", "key1", "value3", "--if-version", version.trim()])
+        .assert()
+        .code(5);
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+    client(&["get", "missing", "--with-version"])
+        .assert()
+        .code(2);
+}
+
+// `kvs-client meta` prints the size, the version and the times of a key, and exits with 2 for a
+// missing key.
+#[test]
+fn cli_meta() {
+    let addr = "127.0.0.1:4035";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--versioned", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "key1", "value10"]).assert().success();
+    let output = client(&["meta", "key1"]).output().unwrap();
+    assert!(output.status.success());
+    let output = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines[..2], ["size: 7", "version: 2"]);
+    assert!(lines[2].starts_with("created: ") && lines[3].starts_with("modified: "));
+    assert!(lines[2]["created: ".len()..].parse::<u64>().unwrap() > 0);
+    client(&["meta", "missing"])
+        .assert()
+        .code(2)
+        .stdout(contains("Key not found"));
+}
+
+// `kvs-server --audit-log` appends a JSON line for every key written or removed, with the address
+// of the client, but none for the reads or the failed writes.
+#[test]
+fn cli_audit_log() {
+    let addr = "127.0.0.1:4036";
+    let temp_dir = TempDir::new().unwrap();
+    let audit_log = temp_dir.path().join("audit.log");
+    let server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr, "--audit-log"])
+            .arg(&audit_log)
+            .current_dir(&temp_dir),
+    );
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["get", "key1"]).assert().success();
+    client(&["rm", "missing"]).assert().code(2);
+    client(&["rm", "key1"]).assert().success();
+    drop(server);
+
+    let records: Vec<serde_json::Value> = fs::read_to_string(&audit_log)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["command"], "SET");
+    assert_eq!(records[0]["key"], "key1");
+    assert_eq!(records[0]["value_size"], 6);
+    assert_eq!(records[1]["command"], "RM");
+    assert!(records[1]["value_size"].is_null());
+    for record in &records {
+        assert!(record["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+        assert!(record["time"].as_u64().unwrap() > 0);
+    }
+}
+
+// `kvs-server --statsd-addr` pushes its metrics over UDP, the requests counted since the previous
+// push.
+#[test]
+fn cli_statsd() {
+    let addr = "127.0.0.1:4037";
+    let temp_dir = TempDir::new().unwrap();
+    let statsd = UdpSocket::bind("127.0.0.1:0").unwrap();
+    statsd
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr, "--statsd-addr"])
+            .arg(statsd.local_addr().unwrap().to_string())
+            .args(&["--statsd-prefix", "test", "--statsd-interval", "1"])
+            .current_dir(&temp_dir),
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let mut buf = [0; 2048];
+    let mut pushed = String::new();
+    for _ in 0..10 {
+        let len = statsd.recv(&mut buf).expect("no metrics pushed");
+        pushed = String::from_utf8_lossy(&buf[..len]).into_owned();
+        if pushed.contains("test.requests.set:1|c") {
+            break;
+        }
+    }
+    assert!(pushed.contains("test.requests.set:1|c"));
+    assert!(pushed.contains("test.connected_clients:"));
+    assert!(pushed.contains("test.latency.set.exec.p99:"));
+}
+
+// `kvs-server --proxy-protocol` takes the address of the client from the PROXY header of either
+// version, and rejects the connections without one.
+#[test]
+fn cli_proxy_protocol() {
+    let addr = "127.0.0.1:4038";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr, "--proxy-protocol"])
+            .current_dir(&temp_dir),
+    );
+    let request = |header: &[u8]| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(header).unwrap();
+        stream.write_all(b"CLIENT\r\nLIST\r\n").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = request(b"PROXY TCP4 203.0.113.7 127.0.0.1 50123 4038\r\n");
+    assert!(response.contains("addr=203.0.113.7:50123 "));
+
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+    let mut src = [0u8; 16];
+    src[..2].copy_from_slice(&[0x20, 0x01]);
+    src[15] = 7;
+    header.extend_from_slice(&src);
+    header.extend_from_slice(&[0; 16]);
+    header.extend_from_slice(&[0xc3, 0x50, 0x0f, 0xc6]);
+    let response = request(&header);
+    assert!(response.contains("addr=[2001::7]:50000 "));
+
+    // The connections made by the load balancer itself keep their address.
+    let response = request(b"PROXY UNKNOWN\r\n");
+    assert!(response.contains("addr=127.0.0.1:"));
+
+    let response = request(b"");
+    assert!(response.starts_with("Error\r\nBAD_REQUEST "));
+}
+
+// `kvs-server --max-request-size` rejects the requests larger than the limit, whether a line or
+// a value, with a TOO_LARGE error.
+#[test]
+fn cli_max_request_size() {
+    let addr = "127.0.0.1:4039";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .args(&["--max-request-size", "64"])
+            .current_dir(&temp_dir),
+    );
+    let request = |request: &[u8]| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = request(b"SET\r\nkey1\r\n6\r\nvalue1\r\n");
+    assert_eq!(response, "Success\r\n");
+    let response = request(b"SET\r\nkey1\r\n1000000\r\n");
+    assert!(response.starts_with("Error\r\nTOO_LARGE "));
+    let mut line = b"GET\r\n".to_vec();
+    line.extend_from_slice(&[b'k'; 60]);
+    let response = request(&line);
+    assert!(response.starts_with("Error\r\nTOO_LARGE "));
+}
+
+// The keys read with TGET are tracked for the connection opened by TRACKING, which is pushed
+// their invalidation once they are written, and only then.
+#[test]
+fn cli_tracking() {
+    let addr = "127.0.0.1:4040";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+    let request = |request: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    request("SET\r\nkey1\r\n6\r\nvalue1\r\n");
+    request("SETEX\r\nkey2\r\n60\r\n6\r\nvalue2\r\n");
+
+    let mut tracking = TcpStream::connect(addr).unwrap();
+    tracking.write_all(b"TRACKING\r\n").unwrap();
+    tracking
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut pushed = BufReader::new(tracking);
+    let mut line = String::new();
+    pushed.read_line(&mut line).unwrap();
+    assert_eq!(line, "Success\r\n");
+    line.clear();
+    pushed.read_line(&mut line).unwrap();
+    let id: u64 = line.trim_end().parse().unwrap();
+
+    let tget = |key: &str| request(&format!("TGET\r\n{}\r\n{}\r\n", id, key));
+    assert_eq!(tget("key1"), "Success\r\n1\r\n6\r\nvalue1\r\n");
+    // The keys bound to expire are not to be cached.
+    assert_eq!(tget("key2"), "Success\r\n0\r\n6\r\nvalue2\r\n");
+    assert_eq!(tget("key3"), "Success\r\n1\r\n-1\r\n");
+    assert!(request("TGET\r\n999\r\nkey1\r\n").starts_with("Error\r\nNO_TRACKING "));
+
+    // The keys not read are not pushed, nor are the keys already invalidated, and a missing key
+    // is pushed once set.
+    request("SET\r\nkey4\r\n6\r\nvalue4\r\n");
+    request("SET\r\nkey1\r\n6\r\nvalue5\r\n");
+    request("SET\r\nkey1\r\n6\r\nvalue6\r\n");
+    request("MRM\r\n2\r\nkey3\r\nkey1\r\n");
+    request("SET\r\nkey3\r\n6\r\nvalue3\r\n");
+    let mut pushes = String::new();
+    for _ in 0..4 {
+        pushed.read_line(&mut pushes).unwrap();
+    }
+    assert_eq!(pushes, "INVALIDATE\r\nkey1\r\nINVALIDATE\r\nkey3\r\n");
+
+    // A connection its client closes is forgotten, though nothing was pushed to it since.
+    assert!(tget("key1").starts_with("Success\r\n"));
+    drop(pushed);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !tget("key1").starts_with("Error\r\nNO_TRACKING ") {
+        assert!(
+            Instant::now() < deadline,
+            "the tracking connection was not closed"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// The messages published to a channel are pushed to its subscribers, and to them only, and the
+// publisher is told how many received them.
+#[test]
+fn cli_pubsub() {
+    let addr = "127.0.0.1:4041";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+    let subscribe = |count: &str, channels: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["subscribe", "--count", count, "--addr", addr])
+            .args(channels)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap()
+    };
+    let both = subscribe("3", &["news", "sports"]);
+    let news = subscribe("2", &["news"]);
+    thread::sleep(Duration::from_secs(1));
+
+    let publish = |channel: &str, message: &str, receivers: &str| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["publish", channel, message, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(format!("{}\n", receivers));
+    };
+    publish("news", "hello", "2");
+    publish("weather", "sunny", "0");
+    publish("sports", "goal", "1");
+    publish("news", "bye", "2");
+
+    let output = both.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "news: hello\nsports: goal\nnews: bye\n"
+    );
+    let output = news.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "news: hello\nnews: bye\n"
+    );
+    // The messages are not keys.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "news", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout("Key not found\n");
+
+    // A subscriber its client closes is forgotten, and its connection shut down, though nothing
+    // was published to its channels since.
+    let mut quiet = TcpStream::connect(addr).unwrap();
+    quiet.write_all(b"SUBSCRIBE\r\n1\r\nquiet\r\n").unwrap();
+    quiet
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut pushed = BufReader::new(quiet);
+    let mut line = String::new();
+    pushed.read_line(&mut line).unwrap();
+    assert_eq!(line, "Success\r\n");
+    pushed
+        .get_ref()
+        .shutdown(std::net::Shutdown::Write)
+        .unwrap();
+    line.clear();
+    assert_eq!(pushed.read_line(&mut line).unwrap(), 0);
+    publish("quiet", "hello", "0");
+}
+
+// A lock acquired by a client is held until it is released under its fencing token, which the
+// next hold gets a greater one than.
+#[test]
+fn cli_lock() {
+    let addr = "127.0.0.1:4042";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command
+            .args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir);
+        command
+    };
+
+    let output = client(&["lock", "leader", "--ttl", "60"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let token: u64 = String::from_utf8(output).unwrap().trim().parse().unwrap();
+    client(&["lock", "leader", "--ttl", "60"])
+        .assert()
+        .code(6)
+        .stderr(contains("Lock held"));
+    client(&["unlock", "leader", &(token + 1).to_string()])
+        .assert()
+        .code(6)
+        .stderr(contains("Lock not held"));
+    client(&["unlock", "leader", &token.to_string()])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["lock", "leader", "--ttl", "60"])
+        .assert()
+        .success()
+        .stdout(format!("{}\n", token + 1));
+
+    client(&["set", "key", "value"]).assert().success();
+    client(&["lock", "key", "--ttl", "60"])
+        .assert()
+        .code(3)
+        .stderr(contains("does not hold a lock"));
+}
+
+// Lists, sets and hashes are filled and read through their own commands, which fail on a key of
+// another type.
+#[test]
+fn cli_collections() {
+    let addr = "127.0.0.1:4043";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command
+            .args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir);
+        command
+    };
+
+    client(&["lpush", "list", "a", "b"])
+        .assert()
+        .success()
+        .stdout("2\n");
+    client(&["lpush", "list", "c"])
+        .assert()
+        .success()
+        .stdout("3\n");
+    client(&["lrange", "list", "0", "-1"])
+        .assert()
+        .success()
+        .stdout("c\nb\na\n");
+    client(&["lrange", "list", "-2", "-2"])
+        .assert()
+        .success()
+        .stdout("b\n");
+    client(&["lrange", "missing", "0", "-1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    client(&["sadd", "set", "b", "a", "b"])
+        .assert()
+        .success()
+        .stdout("2\n");
+    client(&["smembers", "set"])
+        .assert()
+        .success()
+        .stdout("a\nb\n");
+
+    client(&["hset", "hash", "name", "alice"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["hget", "hash", "name"])
+        .assert()
+        .success()
+        .stdout("alice\n");
+    client(&["hget", "hash", "age"])
+        .assert()
+        .code(2)
+        .stdout("Key not found\n");
+
+    client(&["sadd", "list", "a"])
+        .assert()
+        .code(3)
+        .stderr(contains("does not hold a set"));
+    client(&["set", "string", "value"]).assert().success();
+    client(&["lrange", "string", "0", "-1"])
+        .assert()
+        .code(3)
+        .stderr(contains("does not hold a list"));
+}
+
+// The server exits on Ctrl-C even while an idle client keeps a connection open.
+#[cfg(unix)]
+#[test]
+fn cli_interrupt_idle_client() {
+    let addr = "127.0.0.1:4044";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Server::spawn(
+        addr,
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir),
+    );
+    let idle = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    Command::new("kill")
+        .args(&["-INT", &server.child.id().to_string()])
+        .assert()
+        .success();
+    let deadline = Instant::now() + Duration::from_secs(15);
+    let status = loop {
+        if let Some(status) = server.child.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            panic!("server did not exit on Ctrl-C while a client was idle");
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    drop(idle);
+    assert!(status.success());
+    assert!(temp_dir.path().join("index").exists());
+}
@@ -0,0 +1,271 @@
+use assert_cmd::prelude::*;
+use kvs::{Call, Interceptor, KvsClient, KvsClientBuilder, KvsError, Next, Reply, ServerAddr};
+use std::net::TcpStream;
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// A kvs-server of its own store, killed once dropped.
+struct Server {
+    addr: ServerAddr,
+    child: Child,
+    _dir: TempDir,
+}
+
+impl Server {
+    /// Starts a server on `addr` and waits for it to accept connections.
+    fn start(addr: &str) -> Server {
+        let dir = TempDir::new().unwrap();
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", addr])
+            .current_dir(&dir)
+            .spawn()
+            .unwrap();
+        let started = Instant::now();
+        while TcpStream::connect(addr).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "server not started"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+        Server {
+            addr: addr.parse().unwrap(),
+            child,
+            _dir: dir,
+        }
+    }
+
+    /// A client sending every request to this server.
+    fn client(&self) -> KvsClient {
+        KvsClient::new(self.addr.clone())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn get(client: &KvsClient, key: &str) -> Option<String> {
+    client.get(key.to_owned()).unwrap()
+}
+
+// A client sends the requests of kvs-client, and returns the errors of the server as those of a
+// store.
+#[test]
+fn writes_and_errors() {
+    let server = Server::start("127.0.0.1:4044");
+    let client = server.client();
+    client
+        .set("key1".to_owned(), "value\r\n1".to_owned())
+        .unwrap();
+    assert_eq!(get(&client, "key1"), Some("value\r\n1".to_owned()));
+    assert_eq!(client.keys().unwrap(), vec!["key1".to_owned()]);
+    client.save().unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(get(&client, "key1"), None);
+
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    match client.set("a".repeat(300), "value".to_owned()) {
+        Err(KvsError::Server { code, .. }) => assert_eq!(code, "INVALID_ARGUMENT"),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+// The reads of a client go to its replicas in turn, and its writes to the primary.
+#[test]
+fn reads_rotate_over_replicas() {
+    let primary = Server::start("127.0.0.1:4045");
+    let replicas = [
+        Server::start("127.0.0.1:4046"),
+        Server::start("127.0.0.1:4047"),
+    ];
+    for (i, server) in replicas.iter().enumerate() {
+        let value = format!("replica{}", i);
+        server.client().set("key".to_owned(), value).unwrap();
+    }
+    let client = KvsClientBuilder::new()
+        .replicas(replicas.iter().map(|r| r.addr.clone()).collect())
+        .build(primary.addr.clone());
+
+    let reads: Vec<_> = (0..4).map(|_| get(&client, "key").unwrap()).collect();
+    assert_ne!(reads[0], reads[1]);
+    assert_eq!(reads[0], reads[2]);
+    assert_eq!(reads[1], reads[3]);
+
+    client
+        .set("written".to_owned(), "value".to_owned())
+        .unwrap();
+    assert_eq!(get(&primary.client(), "written"), Some("value".to_owned()));
+    for replica in &replicas {
+        assert_eq!(get(&replica.client(), "written"), None);
+    }
+}
+
+// The reads skip a replica which cannot be reached, and go to the primary once none can.
+#[test]
+fn reads_fail_over() {
+    let primary = Server::start("127.0.0.1:4048");
+    let replica = Server::start("127.0.0.1:4049");
+    primary
+        .client()
+        .set("key".to_owned(), "primary".to_owned())
+        .unwrap();
+    replica
+        .client()
+        .set("key".to_owned(), "replica".to_owned())
+        .unwrap();
+    // Nothing listens on the first replica.
+    let down: ServerAddr = "127.0.0.1:4050".parse().unwrap();
+    let client = KvsClientBuilder::new()
+        .replicas(vec![down, replica.addr.clone()])
+        .build(primary.addr.clone());
+
+    for _ in 0..4 {
+        assert_eq!(get(&client, "key"), Some("replica".to_owned()));
+    }
+    drop(replica);
+    for _ in 0..4 {
+        assert_eq!(get(&client, "key"), Some("primary".to_owned()));
+    }
+}
+
+/// Calls `f` until it returns true, failing after a few seconds.
+fn eventually<F: FnMut() -> bool>(mut f: F) {
+    let started = Instant::now();
+    while !f() {
+        assert!(started.elapsed() < Duration::from_secs(5), "never true");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// A value cached is forgotten once its key is written, by the client or by another one.
+#[test]
+fn cache_invalidation() {
+    let server = Server::start("127.0.0.1:4051");
+    let writer = server.client();
+    let client = KvsClientBuilder::new().cache(10).build(server.addr.clone());
+    writer.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(get(&client, "key1"), Some("value1".to_owned()));
+    assert_eq!(get(&client, "key2"), None);
+
+    writer.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    writer.set("key2".to_owned(), "value3".to_owned()).unwrap();
+    eventually(|| {
+        get(&client, "key1") == Some("value2".to_owned())
+            && get(&client, "key2") == Some("value3".to_owned())
+    });
+
+    client.set("key1".to_owned(), "value4".to_owned()).unwrap();
+    assert_eq!(get(&client, "key1"), Some("value4".to_owned()));
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(get(&client, "key1"), None);
+}
+
+// The whole cache is dropped once its tracking connection closes, since the writes made
+// afterwards are not pushed to the client.
+#[test]
+fn cache_dropped_on_disconnect() {
+    let addr = "127.0.0.1:4052";
+    let server = Server::start(addr);
+    server
+        .client()
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap();
+    let client = KvsClientBuilder::new()
+        .cache(10)
+        .build(addr.parse().unwrap());
+    assert_eq!(get(&client, "key1"), Some("value1".to_owned()));
+
+    drop(server);
+    let server = Server::start(addr);
+    server
+        .client()
+        .set("key1".to_owned(), "value2".to_owned())
+        .unwrap();
+    eventually(|| client.get("key1".to_owned()).ok() == Some(Some("value2".to_owned())));
+}
+
+/// Records the calls it sees into `log`, before and after making them.
+struct Recorder {
+    name: &'static str,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl Interceptor for Recorder {
+    fn intercept(&self, call: Call, next: &Next) -> kvs::Result<Reply> {
+        let record = |event: &str| {
+            let entry = format!("{} {} {}", self.name, event, call.name());
+            self.log.lock().unwrap().push(entry);
+        };
+        record("before");
+        let reply = next.run(call.clone());
+        record("after");
+        reply
+    }
+}
+
+// The interceptors added first are the outermost ones, each making the call through the next.
+#[test]
+fn interceptors_order() {
+    let server = Server::start("127.0.0.1:4053");
+    let client = server.client();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    for name in &["first", "second"] {
+        let log = Arc::clone(&log);
+        client.add_interceptor(Recorder { name, log });
+    }
+
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![
+            "first before set",
+            "second before set",
+            "second after set",
+            "first after set",
+        ]
+    );
+}
+
+// An interceptor can change the arguments of the calls, and reply without making them.
+#[test]
+fn interceptors_rewrite_calls() {
+    let server = Server::start("127.0.0.1:4054");
+    let client = server.client();
+    client.add_interceptor(|call: Call, next: &Next| {
+        let call = match call {
+            Call::Set { key, value } => Call::Set {
+                key: format!("app:{}", key),
+                value,
+            },
+            Call::Get { key } => Call::Get {
+                key: format!("app:{}", key),
+            },
+            Call::Remove { key } if key == "protected" => {
+                return Err(KvsError::Interceptor("protected key".into()));
+            }
+            call => call,
+        };
+        next.run(call)
+    });
+
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(get(&client, "key1"), Some("value1".to_owned()));
+    assert_eq!(get(&server.client(), "app:key1"), Some("value1".to_owned()));
+    assert_eq!(get(&server.client(), "key1"), None);
+    assert_eq!(client.keys().unwrap(), vec!["app:key1".to_owned()]);
+    match client.remove("protected".to_owned()) {
+        Err(KvsError::Interceptor(e)) => assert_eq!(e.to_string(), "protected key"),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
@@ -0,0 +1,282 @@
+#![cfg(feature = "fault-injection")]
+
+use kvs::{FaultPlan, KvStore, KvStoreBuilder, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open_faulty(temp_dir: &TempDir, faults: &FaultPlan) -> Result<KvStore> {
+    KvStoreBuilder::new()
+        .log_storage(faults.opener())
+        .open(temp_dir.path())
+}
+
+// A crash loses the writes made since the last checkpoint, which synced the ones before it.
+#[test]
+fn crash_after_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultPlan::new();
+    let store = open_faulty(&temp_dir, &faults)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    faults.crash()?;
+    assert!(store.get("key2".to_owned()).is_err());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A torn append fails the write, which a crash then loses with the bytes it wrote.
+#[test]
+fn torn_append_then_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultPlan::new();
+    let store = open_faulty(&temp_dir, &faults)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let end = store.checkpoint()?;
+    faults.tear_append_at("log", end + 10);
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    faults.crash()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// The writes of a batch which fails to commit are rolled back, so that the reads keep finding
+// what they overwrote or removed.
+#[test]
+fn failed_commit_rolled_back() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultPlan::new();
+    let store = open_faulty(&temp_dir, &faults)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let end = store.checkpoint()?;
+
+    faults.fail_append_at("log", end);
+    assert!(store.set("key1".to_owned(), "new".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    faults.fail_append_at("log", end);
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    faults.fail_append_at("log", end);
+    assert!(store.set("key3".to_owned(), "value3".to_owned()).is_err());
+    assert_eq!(store.get("key3".to_owned())?, None);
+    let mut keys = store.scan();
+    keys.sort();
+    assert_eq!(keys, vec!["key1".to_owned(), "key2".to_owned()]);
+
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// The flush following a torn append resumes where it stopped, so that the record it tore is
+// completed rather than written again after its torn bytes.
+#[test]
+fn torn_append_then_retry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultPlan::new();
+    let store = open_faulty(&temp_dir, &faults)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let end = store.checkpoint()?;
+    faults.tear_append_at("log", end + 10);
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.checkpoint()?;
+    drop(store);
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.truncated_bytes, 0);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A torn append left in the log without a crash is cut off by a repair, after which the writes
+// made are kept.
+#[test]
+fn torn_append_then_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultPlan::new();
+    let store = open_faulty(&temp_dir, &faults)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let end = store.checkpoint()?;
+    faults.tear_append_at("log", end + 10);
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    drop(store);
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.truncated_bytes, 10);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A compaction failing to write the compacted log, then a crash, leave the store as it was, and
+// compaction works again once it is reopened.
+#[test]
+fn crash_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultPlan::new();
+    let store = open_faulty(&temp_dir, &faults)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    store.checkpoint()?;
+    faults.tear_append_at("log.tmp", 100);
+    assert!(store.compact().is_err());
+    faults.crash()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 90..100 {
+        assert_eq!(
+            store.get(format!("key{}", i % 10))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert!(store.compact()? > 0);
+    Ok(())
+}
+
+// Writes 100 values to 10 keys through `faults`, archiving the compacted logs, then fails a
+// compaction once the compacted log is synced, before it is renamed over the log, and crashes.
+fn crash_before_log_switch(dir: &Path) -> Result<()> {
+    let faults = FaultPlan::new();
+    let store = KvStoreBuilder::new()
+        .log_storage(faults.opener())
+        .archive(2)
+        .open(dir)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    store.checkpoint()?;
+    // The archive cannot be created where a file has its name.
+    fs::write(dir.join("archive"), "")?;
+    assert!(store.compact().is_err());
+    assert!(dir.join("log.tmp").exists());
+    faults.crash()?;
+    drop(store);
+    fs::remove_file(dir.join("archive"))?;
+    Ok(())
+}
+
+fn check_last_values(store: &KvStore) -> Result<()> {
+    for i in 90..100 {
+        assert_eq!(
+            store.get(format!("key{}", i % 10))?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+// A crash between the sync of the compacted log and its rename over the log leaves the log in
+// place, and the next compaction starts over.
+#[test]
+fn crash_between_compaction_steps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    crash_before_log_switch(temp_dir.path())?;
+
+    let store = KvStoreBuilder::new().archive(2).open(temp_dir.path())?;
+    check_last_values(&store)?;
+    assert!(store.compact()? > 0);
+    assert_eq!(store.archived_logs()?.len(), 1);
+    drop(store);
+    check_last_values(&KvStore::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+// The compacted log left alone by a crash after the log was removed, as earlier versions did
+// before renaming it, is the log the store is reopened with.
+#[test]
+fn crash_after_log_removal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    crash_before_log_switch(temp_dir.path())?;
+    fs::remove_file(temp_dir.path().join("log"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    check_last_values(&store)?;
+    assert!(!temp_dir.path().join("log.tmp").exists());
+    store.set("key0".to_owned(), "value100".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value100".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// A failed sync fails the checkpoint, which writes no index, so that a crash loses the writes
+// it did not make durable without losing the store.
+#[test]
+fn failed_sync_at_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultPlan::new();
+    let store = open_faulty(&temp_dir, &faults)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    faults.fail_next_sync("log");
+    assert!(store.checkpoint().is_err());
+    faults.crash()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A spill of the index which fails keeps the entries it was writing in memory, so that the
+// keys written before it are still found, and spilled by the next spill which succeeds.
+#[test]
+fn failed_index_spill() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .index_shards(1)
+        .index_budget(4096)
+        .open(temp_dir.path())?;
+    // The spill is written to a file next to this one, which cannot be created over a directory.
+    let blocker = temp_dir.path().join("index.0.spill.tmp");
+    fs::create_dir(&blocker)?;
+    let mut written = 0;
+    while store
+        .set(format!("key{}", written), format!("value{}", written))
+        .is_ok()
+    {
+        written += 1;
+    }
+    assert!(written > 0);
+    assert!(!temp_dir.path().join("index.0.spill").exists());
+    for i in 0..written {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    fs::remove_dir(&blocker)?;
+    store.set("last".to_owned(), "value".to_owned())?;
+    assert!(temp_dir.path().join("index.0.spill").exists());
+    for i in 0..written {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
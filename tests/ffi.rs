@@ -0,0 +1,54 @@
+#![cfg(feature = "ffi")]
+
+use kvs::ffi::*;
+use std::ffi::{CStr, CString};
+use std::ptr;
+use tempfile::TempDir;
+
+fn c(s: &str) -> CString {
+    CString::new(s).unwrap()
+}
+
+// The store can be opened, written and read through the C bindings.
+#[test]
+fn set_get_remove() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = c(temp_dir.path().to_str().unwrap());
+    unsafe {
+        let store = kvs_open(path.as_ptr());
+        assert!(!store.is_null());
+
+        assert_eq!(
+            kvs_set(store, c("key1").as_ptr(), c("value1").as_ptr()),
+            KVS_OK
+        );
+        let mut value = ptr::null_mut();
+        assert_eq!(kvs_get(store, c("key1").as_ptr(), &mut value), KVS_OK);
+        assert_eq!(CStr::from_ptr(value).to_str().unwrap(), "value1");
+        kvs_free(value);
+
+        assert_eq!(kvs_remove(store, c("key1").as_ptr()), KVS_OK);
+        assert_eq!(
+            kvs_get(store, c("key1").as_ptr(), &mut value),
+            KVS_NOT_FOUND
+        );
+        assert!(value.is_null());
+        assert_eq!(kvs_remove(store, c("key1").as_ptr()), KVS_NOT_FOUND);
+        kvs_close(store);
+    }
+}
+
+// Invalid arguments are reported with a message instead of crashing.
+#[test]
+fn invalid_arguments() {
+    unsafe {
+        assert_eq!(
+            kvs_set(ptr::null(), c("key").as_ptr(), c("value").as_ptr()),
+            KVS_INVALID_ARGUMENT
+        );
+        assert!(!kvs_last_error().is_null());
+        assert!(kvs_open(ptr::null()).is_null());
+        kvs_close(ptr::null_mut());
+        kvs_free(ptr::null_mut());
+    }
+}
@@ -1,6 +1,18 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::{
+    BackupTarget, Command, CompactionTrigger, CursorToken, FileStorage, KvStore, KvStoreBuilder,
+    KvsEngine, KvsError, LogStorage, MemKvsEngine, Result, TombstonePolicy, Version,
+};
+#[cfg(feature = "sled")]
+use kvs::{SledFlushPolicy, SledKvsEngine};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -81,6 +93,105 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// A cursor resumes a scan from any key, and does not see the writes made after it was created.
+#[test]
+fn cursor_seek() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in (0..10).rev() {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key5".to_owned())?;
+
+    let mut cursor = store.cursor();
+    store.set("key45".to_owned(), "value45".to_owned())?;
+    cursor.seek("key4");
+    assert_eq!(
+        cursor.by_ref().take(2).collect::<Vec<_>>(),
+        vec!["key4", "key6"]
+    );
+    cursor.seek("key99");
+    assert_eq!(cursor.next(), None);
+    cursor.seek_to_last();
+    assert_eq!(cursor.collect::<Vec<_>>(), vec!["key9"]);
+    Ok(())
+}
+
+// A saved cursor resumes after its last key over the keys it was created with, even once the
+// store is reopened, until the log is compacted.
+#[test]
+fn cursor_resume() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let saved = {
+        let store = KvStore::open(temp_dir.path())?;
+        for i in 0..5 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        let mut cursor = store.cursor();
+        store.set("key15".to_owned(), "value15".to_owned())?;
+        store.remove("key3".to_owned())?;
+        assert_eq!(
+            cursor.by_ref().take(2).collect::<Vec<_>>(),
+            vec!["key0", "key1"]
+        );
+        serde_json::to_string(&cursor.token())?
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    let token: CursorToken = serde_json::from_str(&saved)?;
+    assert_eq!(
+        store.resume_cursor(&token)?.collect::<Vec<_>>(),
+        vec!["key2", "key3", "key4"]
+    );
+
+    store.compact()?;
+    assert!(matches!(
+        store.resume_cursor(&token),
+        Err(KvsError::StaleOffset(_))
+    ));
+    Ok(())
+}
+
+// The metadata of a key keeps its creation time across overwrites, reopening, checkpoints and
+// compaction, until the key is removed.
+#[test]
+fn key_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_metadata("key1".to_owned())?, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let created = store.get_metadata("key1".to_owned())?.unwrap();
+    assert!(created.created_at.is_some());
+    assert_eq!(created.created_at, created.modified_at);
+    thread::sleep(Duration::from_millis(5));
+    // Large enough to be split in chunks.
+    store.set("key1".to_owned(), "v".repeat(10_000))?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    let modified = store.get_metadata("key1".to_owned())?.unwrap();
+    assert_eq!(modified.created_at, created.created_at);
+    assert!(modified.modified_at > created.modified_at);
+    assert_eq!(modified.size, 10_000);
+    // Overwritten again once the index is loaded from a checkpoint.
+    store.checkpoint()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let metadata = store.get_metadata("key1".to_owned())?.unwrap();
+    assert_eq!(metadata.created_at, created.created_at);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_metadata("key1".to_owned())?, None);
+    thread::sleep(Duration::from_millis(5));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let recreated = store.get_metadata("key1".to_owned())?.unwrap();
+    assert!(recreated.created_at > modified.modified_at);
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -127,6 +238,30 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// The records written after a compaction land at the end of the new log, whatever was read
+// from it in between.
+#[test]
+fn writes_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = |i: usize| format!("{}{}", i, "v".repeat(1000));
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), value(i))?;
+    }
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value(1)));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    for i in 3..50 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +344,2134 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Reads running along with writes and compactions see every value written before them.
+#[test]
+fn concurrent_get_and_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}-0", i))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for round in 1..50 {
+                for i in (thread_id..100).step_by(8) {
+                    let value = format!("value{}-{}{}", i, round, "v".repeat(100));
+                    store.set(format!("key{}", i), value.clone())?;
+                    assert_eq!(store.get(format!("key{}", i))?, Some(value));
+                    let other = store.get(format!("key{}", (i + 1) % 100))?;
+                    assert!(
+                        other.is_some_and(|v| v.starts_with(&format!("value{}-", (i + 1) % 100)))
+                    );
+                }
+                if round % 10 == 0 {
+                    store.compact()?;
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Errors from deep inside the engine should say which file they came from.
+#[test]
+fn open_error_has_context() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("index"), "not an index")?;
+
+    let err = KvStore::open(temp_dir.path())
+        .err()
+        .expect("open should fail");
+    assert!(err.to_string().contains("index"));
+    assert!(err.source().is_some());
+    match err.root() {
+        KvsError::DeserError(_) => (),
+        other => panic!("unexpected root error: {:?}", other),
+    }
+
+    Ok(())
+}
+
+// A record whose content no longer matches its checksum is reported as corruption.
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("log");
+    let log = fs::read_to_string(&log_path)?;
+    fs::write(&log_path, log.replace("value2", "valueX"))?;
+
+    let err = KvStore::open(temp_dir.path())
+        .err()
+        .expect("open should fail");
+    match err.root() {
+        KvsError::Corruption { path, offset, .. } => {
+            assert_eq!(path, &log_path);
+            assert!(*offset > 0);
+        }
+        other => panic!("unexpected root error: {:?}", other),
+    }
+
+    Ok(())
+}
+
+// A versioned store returns the past versions of a key, even after being reopened, until the
+// log is compacted.
+#[test]
+fn versioned_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .versioned(true)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.save_index_log()?;
+    drop(store);
+
+    let store = KvStoreBuilder::new()
+        .versioned(true)
+        .open(temp_dir.path())?;
+    let expected = vec![
+        Version {
+            seq: Some(5),
+            value: Some("value3".to_owned()),
+        },
+        Version {
+            seq: Some(4),
+            value: None,
+        },
+        Version {
+            seq: Some(3),
+            value: Some("value2".to_owned()),
+        },
+    ];
+    assert_eq!(store.get_history("key1".to_owned(), 3)?, expected);
+    assert_eq!(store.get_history("key1".to_owned(), 10)?.len(), 4);
+
+    store.set("key2".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_history("key2".to_owned(), 10)?[0].seq, Some(6));
+
+    store.compact()?;
+    assert_eq!(store.get_history("key1".to_owned(), 10)?.len(), 1);
+
+    Ok(())
+}
+
+// Compaction keeps the records of removed keys as long as the tombstone policy asks to.
+#[test]
+fn tombstone_policy() -> Result<()> {
+    let policies = vec![
+        (TombstonePolicy::Drop, false),
+        (TombstonePolicy::Retain(Duration::from_secs(3600)), true),
+        (TombstonePolicy::Keep, true),
+    ];
+    for (policy, retained) in policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log_path = temp_dir.path().join("log");
+        let builder = KvStoreBuilder::new().tombstone_policy(policy);
+
+        let store = builder.clone().open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        store.compact()?;
+        assert_eq!(fs::read_to_string(&log_path)?.contains("Rm"), retained);
+        drop(store);
+
+        // The tombstone is found again when reopening, and dropped once the key is set again.
+        let store = builder.open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        store.compact()?;
+        assert_eq!(fs::read_to_string(&log_path)?.contains("Rm"), retained);
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        store.compact()?;
+        assert!(!fs::read_to_string(&log_path)?.contains("Rm"));
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Compacted logs are archived, up to the retention limit, and can be restored.
+#[test]
+fn archive_compacted_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new().archive(2).open(temp_dir.path())?;
+    for i in 0..3 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+        store.compact()?;
+    }
+
+    let archived = store.archived_logs()?;
+    assert_eq!(archived.len(), 2);
+    assert!(archived
+        .iter()
+        .all(|log| log.parent().unwrap().ends_with("archive")));
+
+    // The oldest archived log is the one replaced by the second compaction.
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::copy(&archived[0], restore_dir.path().join("log"))?;
+    let restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A checkpoint covers the log written so far, and the writes made after it are replayed when
+// the store is reopened without a clean shutdown.
+#[test]
+fn checkpoint_and_replay_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let offset = store.checkpoint()?;
+    assert_eq!(offset, fs::metadata(temp_dir.path().join("log"))?.len());
+    assert!(temp_dir.path().join("index").exists());
+    assert!(!temp_dir.path().join("index.tmp").exists());
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    // Flush the log without writing a new checkpoint, as a crash right after would.
+    store.get("key3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Incremental backups only append the new records, and fail once the log was compacted.
+#[test]
+fn backup_incremental() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_log = backup_dir.path().join("log");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let offset = store.backup_incremental(backup_dir.path(), 0)?;
+    assert_eq!(fs::metadata(&backup_log)?.len(), offset);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let next_offset = store.backup_incremental(backup_dir.path(), offset)?;
+    assert!(next_offset > offset);
+    assert_eq!(
+        fs::read(&backup_log)?,
+        fs::read(temp_dir.path().join("log"))?
+    );
+
+    // The offset must match the backup it continues.
+    assert!(matches!(
+        store.backup_incremental(backup_dir.path(), offset),
+        Err(KvsError::StaleOffset(_))
+    ));
+
+    let restored = KvStore::open(backup_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, None);
+    assert_eq!(restored.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(restored);
+
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    assert!(matches!(
+        store.backup_incremental(backup_dir.path(), next_offset),
+        Err(KvsError::StaleOffset(_))
+    ));
+
+    Ok(())
+}
+
+/// A backup target keeping the files in memory.
+#[derive(Default)]
+struct MemTarget {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl BackupTarget for MemTarget {
+    fn size(&mut self, name: &str) -> Result<u64> {
+        Ok(self.files.get(name).map_or(0, |file| file.len() as u64))
+    }
+
+    fn read(&mut self, name: &str, pos: u64, len: u64) -> Result<Vec<u8>> {
+        let file = &self.files[name];
+        Ok(file[pos as usize..(pos + len) as usize].to_vec())
+    }
+
+    fn append(&mut self, name: &str, pos: u64, data: &mut dyn Read, _len: u64) -> Result<()> {
+        let file = self.files.entry(name.to_owned()).or_default();
+        assert_eq!(file.len() as u64, pos);
+        data.read_to_end(file)?;
+        Ok(())
+    }
+}
+
+// Backups can be streamed to any target, which only ever sees appends.
+#[test]
+fn backup_to_target() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut target = MemTarget::default();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let offset = store.backup_to(&mut target, 0)?;
+    assert_eq!(target.size("log")?, offset);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let next_offset = store.backup_to(&mut target, offset)?;
+    assert_eq!(target.files["log"], fs::read(temp_dir.path().join("log"))?);
+
+    assert!(matches!(
+        store.backup_to(&mut target, offset),
+        Err(KvsError::StaleOffset(_))
+    ));
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    assert!(matches!(
+        store.backup_to(&mut target, next_offset),
+        Err(KvsError::StaleOffset(_))
+    ));
+
+    Ok(())
+}
+
+// A tail returns the records already in the log, then waits for the new ones until the log is
+// compacted.
+#[test]
+fn tail_mutations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let mut tail = store.tail(0)?;
+    let (offset, cmd) = tail.next().unwrap()?;
+    assert_eq!(offset, 0);
+    assert!(
+        matches!(cmd, Command::Set { ref key, ref value, .. } if key == "key1" && value == "value1")
+    );
+    let (_, cmd) = tail.next().unwrap()?;
+    assert!(matches!(cmd, Command::Rm { ref key, .. } if key == "key1"));
+    assert!(tail.try_next().is_none());
+
+    // Resuming from the offset of a tail skips the records it returned.
+    let resume_offset = tail.offset();
+    let writer = store.clone();
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        writer.set("key2".to_owned(), "value2".to_owned())
+    });
+    let (offset, cmd) = tail.next().unwrap()?;
+    handle.join().unwrap()?;
+    assert_eq!(offset, resume_offset);
+    assert!(matches!(cmd, Command::Set { ref key, .. } if key == "key2"));
+
+    let mut resumed = store.tail(resume_offset)?;
+    assert_eq!(resumed.next().unwrap()?, (offset, cmd));
+
+    store.compact()?;
+    assert!(matches!(tail.next(), Some(Err(KvsError::StaleOffset(_)))));
+    assert!(tail.next().is_none());
+    assert!(matches!(
+        store.tail(resume_offset + 1000),
+        Err(KvsError::StaleOffset(_))
+    ));
+
+    Ok(())
+}
+
+// Durable writes from many threads are committed in groups and all acknowledged.
+#[test]
+fn concurrent_sync_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .sync_writes(true)
+        .commit_delay(Duration::from_millis(1))
+        .open(temp_dir.path())?;
+    let handles: Vec<_> = (0..100)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                store.set(format!("key{}", i), format!("value{}", i))?;
+                if i % 2 == 0 {
+                    store.remove(format!("key{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    // The log is durable without a clean shutdown.
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        let expected = if i % 2 == 0 {
+            None
+        } else {
+            Some(format!("value{}", i))
+        };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+
+    Ok(())
+}
+
+// Large values are kept out of the log, and compaction rewrites them once they are mostly
+// garbage.
+#[test]
+fn separate_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .separate_values(100)
+        .open(temp_dir.path())?;
+    let value = |i: usize, round: usize| format!("{}{}", round, "v".repeat(1000 + i));
+    for round in 0..3 {
+        for i in 0..20 {
+            store.set(format!("key{}", i), value(i, round))?;
+        }
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+
+    let log_size = fs::metadata(temp_dir.path().join("log"))?.len();
+    assert!(log_size < 20 * 1000);
+    assert!(fs::metadata(temp_dir.path().join("vlog.0"))?.len() > 3 * 20 * 1000);
+
+    store.compact()?;
+    assert!(!temp_dir.path().join("vlog.0").exists());
+    assert!(fs::metadata(temp_dir.path().join("vlog.1"))?.len() < 2 * 20 * 1000);
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i, 2)));
+    }
+
+    // Backups carry the values, and the values are found without the option.
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.backup_incremental(backup_dir.path(), 0)?;
+    drop(store);
+    for dir in &[temp_dir.path(), backup_dir.path()] {
+        let store = KvStore::open(dir)?;
+        assert_eq!(store.get("key3".to_owned())?, Some(value(3, 2)));
+        assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+        let (_, cmd) = store.tail(0)?.next().unwrap()?;
+        assert!(matches!(cmd, Command::Set { ref value, .. } if !value.is_empty()));
+    }
+
+    Ok(())
+}
+
+// Readers read separated values at once, while a compaction rewrites the value log.
+#[test]
+fn concurrent_get_separated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .separate_values(100)
+        .open(temp_dir.path())?;
+    let value = |i: usize, round: usize| format!("{}{}", round, "v".repeat(100 + i));
+    for round in 0..3 {
+        for i in 0..200 {
+            store.set(format!("key{}", i), value(i, round))?;
+        }
+    }
+
+    let compactor = store.clone();
+    let handle = thread::spawn(move || compactor.compact());
+    let mut readers = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        readers.push(thread::spawn(move || -> Result<()> {
+            for _ in 0..5 {
+                for i in (thread_id..200).step_by(8) {
+                    assert_eq!(store.get(format!("key{}", i))?, Some(value(i, 2)));
+                }
+            }
+            Ok(())
+        }));
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    handle.join().unwrap()?;
+    assert!(temp_dir.path().join("vlog.1").exists());
+
+    Ok(())
+}
+
+// Writes made while a compaction is running are kept, and the compacted log is consistent.
+#[test]
+fn compaction_concurrent_with_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for round in 0..5 {
+        for i in 0..1000 {
+            store.set(format!("key{}", i), format!("{}{}", round, "v".repeat(100)))?;
+        }
+    }
+
+    let compactor = store.clone();
+    let handle = thread::spawn(move || compactor.compact());
+    for i in 0..1000 {
+        if i % 3 == 0 {
+            store.remove(format!("key{}", i))?;
+        } else {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+    }
+    handle.join().unwrap()?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..1000 {
+            let expected = if i % 3 == 0 {
+                None
+            } else {
+                Some(format!("value{}", i))
+            };
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// A log replayed in parallel rebuilds the same index, history and tombstones as a sequential
+// replay.
+#[test]
+fn parallel_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStoreBuilder::new()
+        .versioned(true)
+        .tombstone_policy(TombstonePolicy::Keep);
+    let store = builder.clone().open(temp_dir.path())?;
+    let value = |i: usize| format!("{}{}", i, "v".repeat(1000));
+    for i in 0..10000 {
+        store.set(format!("key{}", i), value(i))?;
+        // Supersede keys written in earlier parts of the log.
+        if i % 100 == 99 {
+            store.set(format!("key{}", i - 90), "new".to_owned())?;
+            store.remove(format!("key{}", i - 50))?;
+        }
+        // Values split in chunks are replayed as a whole.
+        if i % 1000 == 500 {
+            store.set(format!("large{}", i), value(i).repeat(50))?;
+        }
+    }
+    drop(store);
+
+    let sequential = builder.clone().replay_threads(1).open(temp_dir.path())?;
+    let parallel = builder.replay_threads(4).open(temp_dir.path())?;
+    for i in 0..10000 {
+        let key = format!("key{}", i);
+        assert_eq!(sequential.get(key.clone())?, parallel.get(key.clone())?);
+        assert_eq!(
+            sequential.get_history(key.clone(), 10)?,
+            parallel.get_history(key, 10)?
+        );
+    }
+    for i in (500..10000).step_by(1000) {
+        assert_eq!(
+            parallel.get(format!("large{}", i))?,
+            Some(value(i).repeat(50))
+        );
+    }
+    assert_eq!(parallel.get("key9".to_owned())?, Some("new".to_owned()));
+    assert_eq!(parallel.get("key49".to_owned())?, None);
+    parallel.set("last".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        parallel.get_history("last".to_owned(), 1)?[0].seq,
+        Some(10000 + 2 * 100 + 10 + 1)
+    );
+
+    Ok(())
+}
+
+// The progress of the replay reaches the size of the log.
+#[test]
+fn open_with_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let log_len = fs::metadata(temp_dir.path().join("log"))?.len();
+
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let store = KvStore::open_with_progress(temp_dir.path(), {
+        let reports = Arc::clone(&reports);
+        move |replayed, total| reports.lock().unwrap().push((replayed, total))
+    })?;
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    let reports = reports.lock().unwrap();
+    assert_eq!(reports.first(), Some(&(0, log_len)));
+    assert_eq!(reports.last(), Some(&(log_len, log_len)));
+
+    Ok(())
+}
+
+// The index is checkpointed every given number of writes.
+#[test]
+fn checkpoint_every() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let index_path = temp_dir.path().join("index");
+    let store = KvStoreBuilder::new()
+        .checkpoint_every(10)
+        .open(temp_dir.path())?;
+    for i in 0..9 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(!index_path.exists());
+    store.remove("key0".to_owned())?;
+    assert!(index_path.exists());
+
+    let checkpoint = fs::read(&index_path)?;
+    for i in 0..9 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(fs::read(&index_path)?, checkpoint);
+    store.set("key9".to_owned(), "value9".to_owned())?;
+    assert_ne!(fs::read(&index_path)?, checkpoint);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+
+    Ok(())
+}
+
+// An index over its memory budget is spilled to disk and still finds every key.
+#[test]
+fn index_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStoreBuilder::new().index_budget(4096);
+    let store = builder.clone().open(temp_dir.path())?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(temp_dir.path().join("index.0.spill").exists());
+    for i in (0..2000).step_by(3) {
+        store.remove(format!("key{}", i))?;
+    }
+    for i in (0..2000).step_by(5) {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..2000 {
+            let expected = if i % 5 == 0 {
+                Some(format!("new{}", i))
+            } else if i % 3 == 0 {
+                None
+            } else {
+                Some(format!("value{}", i))
+            };
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        let expected = (0..2000).filter(|i| i % 5 == 0 || i % 3 != 0).count();
+        assert_eq!(store.scan().len(), expected);
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    store.checkpoint()?;
+    drop(store);
+
+    // The index is spilled while the checkpoint and the log are loaded into it, not once they
+    // are, the spill files left by the previous process being removed first.
+    let open_spilled = || -> Result<KvStore> {
+        for entry in fs::read_dir(temp_dir.path())? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "spill") {
+                fs::remove_file(path)?;
+            }
+        }
+        let spilled = Arc::new(AtomicBool::new(false));
+        let path = temp_dir.path().to_path_buf();
+        let store = builder.clone().open_with_progress(temp_dir.path(), {
+            let spilled = Arc::clone(&spilled);
+            move |replayed, total| {
+                if replayed == total {
+                    let exists = path.join("index.0.spill").exists();
+                    spilled.fetch_or(exists, Ordering::SeqCst);
+                }
+            }
+        })?;
+        assert!(spilled.load(Ordering::SeqCst));
+        Ok(store)
+    };
+    check(&open_spilled()?)?;
+    fs::remove_file(temp_dir.path().join("index"))?;
+    check(&open_spilled()?)?;
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// Readers look keys up in a spilled index at once, while a writer keeps spilling it.
+#[test]
+fn concurrent_get_spilled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .index_budget(4096)
+        .open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let writer = store.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for i in 1000..2000 {
+            writer.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        Ok(())
+    });
+    let mut readers = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        readers.push(thread::spawn(move || -> Result<()> {
+            for i in (thread_id..1000).step_by(8) {
+                assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+            }
+            Ok(())
+        }));
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    handle.join().unwrap()?;
+    assert_eq!(store.scan().len(), 2000);
+
+    Ok(())
+}
+
+// The store works the same whatever the number of shards of its index, which is checkpointed
+// and compacted as a whole.
+#[test]
+fn index_shards() -> Result<()> {
+    for &shards in &[1, 3, 64] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let builder = KvStoreBuilder::new().index_shards(shards);
+        let store = builder.clone().open(temp_dir.path())?;
+        for i in 0..500 {
+            store.set(format!("key{}", i % 100), format!("value{}", i))?;
+        }
+        for i in (0..100).step_by(4) {
+            store.remove(format!("key{}", i))?;
+        }
+        assert!(store.compact()? > 0);
+        assert_eq!(store.scan().len(), 75);
+        store.checkpoint()?;
+        drop(store);
+
+        // Reopened with another number of shards.
+        let store = KvStoreBuilder::new()
+            .index_shards(shards + 1)
+            .open(temp_dir.path())?;
+        for i in 0..100 {
+            let expected = if i % 4 == 0 {
+                None
+            } else {
+                Some(format!("value{}", 400 + i))
+            };
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+    }
+
+    Ok(())
+}
+
+// Values larger than a record are split in chunks and reassembled.
+#[test]
+fn large_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .versioned(true)
+        .open(temp_dir.path())?;
+    // Multi-byte characters straddle the boundaries of the chunks.
+    let large = |i: usize| format!("{}{}", i, "é€".repeat(20_000));
+    store.set("key1".to_owned(), large(1))?;
+    store.set("key2".to_owned(), "small".to_owned())?;
+    store.set("key1".to_owned(), large(2))?;
+    assert_eq!(store.get("key1".to_owned())?, Some(large(2)));
+    let history = store.get_history("key1".to_owned(), 2)?;
+    assert_eq!(history[1].value, Some(large(1)));
+    let sets: Vec<_> = store
+        .tail(0)?
+        .take(3)
+        .map(|record| record.map(|(_, cmd)| cmd))
+        .collect::<Result<_>>()?;
+    assert!(matches!(&sets[2], Command::Set { value, .. } if *value == large(2)));
+
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some(large(2)));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(large(2)));
+    assert_eq!(store.get("key2".to_owned())?, Some("small".to_owned()));
+
+    let too_large = "v".repeat((16 << 20) + 1);
+    assert!(matches!(
+        store.set("key3".to_owned(), too_large),
+        Err(KvsError::InvalidValueSize)
+    ));
+    drop(store);
+
+    // A value torn by a crash before the record setting its key is cut by a repair.
+    let log_path = temp_dir.path().join("log");
+    let intact_len = fs::metadata(&log_path)?.len();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), large(3))?;
+    drop(store);
+    let torn_len = fs::metadata(&log_path)?.len() - 100;
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(torn_len)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    drop(store);
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.truncated_bytes, torn_len - intact_len);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(large(2)));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Keys set with a time to live expire, and the sweeper appends their removals to the log.
+#[test]
+fn expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(300);
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    // Setting a key again drops its time to live.
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), ttl)?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.ttl("key2".to_owned())?.unwrap() > Duration::from_secs(50));
+    assert_eq!(store.ttl("key3".to_owned())?, None);
+    store.checkpoint()?;
+    drop(store);
+
+    // The expiries survive both the index file and a replay of the log.
+    let store = KvStoreBuilder::new()
+        .versioned(true)
+        .open(temp_dir.path())?;
+    assert!(store.ttl("key2".to_owned())?.is_some());
+    drop(store);
+    let store = KvStoreBuilder::new()
+        .sweep_interval(Duration::from_millis(50))
+        .open(temp_dir.path())?;
+    assert!(store.ttl("key1".to_owned())?.is_some());
+
+    thread::sleep(ttl);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(
+        store.ttl("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    let mut keys = store.scan();
+    keys.sort();
+    assert_eq!(keys, vec!["key2", "key3"]);
+
+    let mut tail = store.tail(0)?.map(|record| record.map(|(_, cmd)| cmd));
+    let first = tail.next().unwrap()?;
+    assert!(matches!(
+        first,
+        Command::Set {
+            expires_at: Some(_),
+            ..
+        }
+    ));
+    let swept = tail.nth(3).unwrap()?;
+    assert!(matches!(swept, Command::Rm { ref key, .. } if key == "key1"));
+    assert_eq!(store.sweep_expired()?, 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// The histograms of the sizes count every write, and are rebuilt from the log.
+#[test]
+fn size_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStoreBuilder::new().separate_values(1 << 10);
+    let store = builder.clone().open(temp_dir.path())?;
+    store.set("k".to_owned(), "".to_owned())?;
+    store.set("key".to_owned(), "v".repeat(100))?;
+    store.set("key".to_owned(), "v".repeat(2000))?;
+    store.set("long-key".to_owned(), "v".repeat(10_000))?;
+    store.remove("k".to_owned())?;
+
+    let check = |store: &KvStore| {
+        let stats = store.stats();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.key_sizes.count(), 4);
+        assert_eq!(stats.key_sizes.buckets(), vec![(1, 1), (3, 2), (15, 1)]);
+        assert_eq!(stats.value_sizes.percentile(0.25), 0);
+        assert_eq!(stats.value_sizes.percentile(0.5), 127);
+        assert_eq!(stats.value_sizes.percentile(1.0), 16383);
+    };
+    check(&store);
+    drop(store);
+    check(&builder.open(temp_dir.path())?);
+
+    Ok(())
+}
+
+// Every compaction is recorded with what triggered it and the bytes it moved and reclaimed.
+#[test]
+fn compaction_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().compactions, 0);
+    assert!(store.info_lines().contains(&"compactions:0".to_owned()));
+
+    let value = "v".repeat(1000);
+    store.set("key1".to_owned(), value.clone())?;
+    // About 1MB of superseded records starts a compaction.
+    for _ in 0..1100 {
+        store.set("key2".to_owned(), value.clone())?;
+    }
+    store.set("key3".to_owned(), value)?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let reclaimed = store.compact()?;
+
+    let stats = store.stats();
+    assert_eq!(stats.compactions, 2);
+    let (automatic, manual) = (&stats.recent_compactions[0], &stats.recent_compactions[1]);
+    assert_eq!(automatic.trigger, CompactionTrigger::Automatic);
+    assert!(automatic.bytes_reclaimed >= 1 << 20);
+    assert!(automatic.bytes_read >= 2000);
+    assert_eq!(automatic.bytes_read, automatic.bytes_written);
+    assert_eq!(manual.trigger, CompactionTrigger::Manual);
+    assert_eq!(manual.bytes_reclaimed, reclaimed);
+    assert!(manual.started_at >= automatic.finished_at);
+    assert!(manual.finished_at >= manual.started_at);
+    assert!(store
+        .info_lines()
+        .contains(&"last_compaction_trigger:manual".to_owned()));
+
+    Ok(())
+}
+
+// With a compaction ratio, the log is compacted once its redundant bytes make up that fraction
+// of it, long before the threshold of 1MB.
+#[test]
+fn compaction_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .compaction_ratio(0.5)
+        .open(temp_dir.path())?;
+    let value = "v".repeat(1000);
+    for i in 0..200 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    // Less than 64KB of redundant bytes is left alone, whatever their share of the log.
+    for _ in 0..50 {
+        store.set("key0".to_owned(), value.clone())?;
+    }
+    assert_eq!(store.stats().compactions, 0);
+    for _ in 0..200 {
+        store.set("key0".to_owned(), value.clone())?;
+    }
+    let stats = store.stats();
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(
+        stats.recent_compactions[0].trigger,
+        CompactionTrigger::Automatic
+    );
+    assert!(stats.recent_compactions[0].bytes_reclaimed < 1 << 20);
+    assert_eq!(store.get("key0".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Preallocating the log leaves its size and content as they are.
+#[test]
+fn preallocate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStoreBuilder::new().preallocate(1 << 20);
+    let store = builder.clone().open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "value".to_owned())?;
+    let end = store.checkpoint()?;
+    assert_eq!(fs::metadata(temp_dir.path().join("log"))?.len(), end);
+    drop(store);
+
+    let store = builder.open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+/// A log storage counting the bytes appended to the files underneath.
+struct CountingStorage {
+    file: FileStorage,
+    appended: Arc<Mutex<u64>>,
+}
+
+impl LogStorage for CountingStorage {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        *self.appended.lock().unwrap() += buf.len() as u64;
+        self.file.append(buf)
+    }
+
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read_at(pos, buf)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.file.len()
+    }
+}
+
+// A custom log storage sees every write, including the rewrite of a compaction, and the store
+// reads back through it.
+#[test]
+fn log_storage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let appended = Arc::new(Mutex::new(0));
+    let opened = Arc::new(Mutex::new(Vec::<PathBuf>::new()));
+    let builder = {
+        let (appended, opened) = (Arc::clone(&appended), Arc::clone(&opened));
+        KvStoreBuilder::new().log_storage(move |path| {
+            opened.lock().unwrap().push(path.to_owned());
+            let file = FileStorage::open(path)?;
+            let appended = Arc::clone(&appended);
+            Ok(Box::new(CountingStorage { file, appended }) as Box<dyn LogStorage>)
+        })
+    };
+
+    let store = builder.clone().open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    let end = store.checkpoint()?;
+    assert_eq!(*appended.lock().unwrap(), end);
+
+    store.compact()?;
+    assert_eq!(opened.lock().unwrap().len(), 2);
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    let mut tail = store.tail(0)?;
+    let (_, cmd) = tail.next().unwrap()?;
+    assert!(matches!(cmd, Command::Set { ref value, .. } if value == "value99"));
+    drop(tail);
+    drop(store);
+
+    let store = builder.open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Every flush policy of the sled engine keeps the writes once the engine is dropped.
+#[test]
+#[cfg(feature = "sled")]
+fn sled_flush_policies() -> Result<()> {
+    assert_eq!("background".parse(), Ok(SledFlushPolicy::Background));
+    assert_eq!("never".parse(), Ok(SledFlushPolicy::Never));
+    assert_eq!("writes:10".parse(), Ok(SledFlushPolicy::EveryWrites(10)));
+    assert_eq!(
+        "interval:200".parse(),
+        Ok(SledFlushPolicy::Interval(Duration::from_millis(200)))
+    );
+    assert!("writes:0".parse::<SledFlushPolicy>().is_err());
+    assert!("sometimes".parse::<SledFlushPolicy>().is_err());
+
+    for policy in &["background", "never", "writes:3", "interval:10"] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let policy = policy.parse().unwrap();
+        let engine = SledKvsEngine::open_with_flush_policy(temp_dir.path(), policy)?;
+        for i in 0..10 {
+            engine.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        engine.remove("key0".to_owned())?;
+        drop(engine);
+
+        let engine = SledKvsEngine::open_with_flush_policy(temp_dir.path(), policy)?;
+        assert_eq!(engine.get("key0".to_owned())?, None);
+        assert_eq!(engine.get("key9".to_owned())?, Some("value9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// The store works the same whatever the capacities of its buffers.
+#[test]
+fn buffer_capacities() -> Result<()> {
+    for &(read, write, sequential) in &[(0, 0, 0), (64, 16, 256 << 10)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let builder = KvStoreBuilder::new()
+            .read_buffer(read)
+            .write_buffer(write)
+            .sequential_buffer(sequential);
+        let store = builder.clone().open(temp_dir.path())?;
+        for i in 0..200 {
+            store.set(format!("key{}", i % 50), format!("value{}", i))?;
+        }
+        store.compact()?;
+        store.set("key0".to_owned(), "value".to_owned())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("key49".to_owned())?, Some("value199".to_owned()));
+        drop(store);
+
+        let store = builder.open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("key49".to_owned())?, Some("value199".to_owned()));
+    }
+
+    Ok(())
+}
+
+// The value cache is warmed up with the recently written keys, and never serves a stale value.
+#[test]
+fn value_cache_warm_up() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let builder = KvStoreBuilder::new()
+        .value_cache(1 << 20)
+        .warm_up_recent(10)
+        .warm_up_keys(vec!["key0".to_owned(), "missing".to_owned()]);
+    let store = builder.open(temp_dir.path())?;
+    // The warmed up values are served even once the log is gone.
+    let log = temp_dir.path().join("log");
+    let content = fs::read(&log)?;
+    fs::write(&log, vec![b' '; content.len()])?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key90".to_owned())?, Some("value90".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert!(store.get("key50".to_owned()).is_err());
+    fs::write(&log, content)?;
+
+    store.set("key99".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("new".to_owned()));
+    store.remove("key90".to_owned())?;
+    assert_eq!(store.get("key90".to_owned())?, None);
+
+    Ok(())
+}
+
+// An RDB export holds every key as a string of database 0, followed by the checksum.
+#[test]
+fn export_rdb() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "x".repeat(100))?;
+    store.set("a".to_owned(), "1".to_owned())?;
+
+    let mut rdb = Vec::new();
+    assert_eq!(store.export_rdb(&mut rdb)?, 2);
+    let mut expected = b"REDIS0009\xfe\x00".to_vec();
+    expected.extend_from_slice(b"\x00\x01a\x011");
+    expected.extend_from_slice(b"\x00\x01b\x40\x64");
+    expected.extend_from_slice("x".repeat(100).as_bytes());
+    expected.push(0xff);
+    let (content, crc) = rdb.split_at(rdb.len() - 8);
+    assert_eq!(content, &expected[..]);
+
+    // CRC-64/Jones, as computed by Redis.
+    let mut expected_crc = 0u64;
+    for &byte in content {
+        expected_crc ^= u64::from(byte);
+        for _ in 0..8 {
+            let poly = if expected_crc & 1 == 1 {
+                0x95AC_9329_AC4B_C9B5
+            } else {
+                0
+            };
+            expected_crc = (expected_crc >> 1) ^ poly;
+        }
+    }
+    assert_eq!(crc, &expected_crc.to_le_bytes());
+
+    Ok(())
+}
+
+// A Bitcask data file exported by a store can be imported into another one, and its tombstones
+// remove their keys.
+#[test]
+fn bitcask_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::open(temp_dir.path())?;
+    source.set("key1".to_owned(), "value1".to_owned())?;
+    source.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut data = Vec::new();
+    assert_eq!(source.export_bitcask(&mut data)?, 2);
+    let (header, record) = data.split_at(14);
+    assert_eq!(&header[8..], &[0, 4, 0, 0, 0, 6]);
+    assert_eq!(&record[..10], b"key1value1");
+    let crc = crc32fast::hash(&data[4..24]);
+    assert_eq!(&data[..4], &crc.to_be_bytes());
+
+    // The tombstone of key2, as written by Bitcask.
+    let tombstone = b"key2bitcask_tombstone";
+    let mut record = vec![0; 4];
+    record.extend_from_slice(&[0, 0, 0, 1, 0, 4, 0, 0, 0, 17]);
+    record.extend_from_slice(tombstone);
+    let crc = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_be_bytes());
+    data.extend_from_slice(&record);
+    // A record torn by a crash ends the file.
+    data.extend_from_slice(&record[..10]);
+
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target = KvStore::open(target_dir.path())?;
+    let data_file = temp_dir.path().join("1.bitcask.data");
+    fs::write(&data_file, &data)?;
+    assert_eq!(target.import_bitcask(&data_file)?, 2);
+    assert_eq!(target.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(target.get("key2".to_owned())?, None);
+
+    data[20] ^= 1;
+    fs::write(&data_file, &data)?;
+    match target.import_bitcask(&data_file) {
+        Err(KvsError::Corruption { path, offset, .. }) => {
+            assert_eq!(path, data_file);
+            assert_eq!(offset, 0);
+        }
+        _ => panic!("expected a corrupted record"),
+    }
+
+    Ok(())
+}
+
+// An SST export ends with the footer of a block-based table, whose blocks are checksummed
+// with CRC32C.
+#[test]
+fn export_sst() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut sst = Vec::new();
+    assert!(store.export_sst(&mut sst).is_err());
+
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    let mut sst = Vec::new();
+    assert_eq!(store.export_sst(&mut sst)?, 1000);
+
+    let (content, magic) = sst.split_at(sst.len() - 8);
+    assert_eq!(magic, &0xdb47_7524_8b80_fb57u64.to_le_bytes());
+    let mut footer = &content[content.len() - 40..];
+    let mut varint = || {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = footer[0];
+            footer = &footer[1..];
+            value |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte < 0x80 {
+                return value as usize;
+            }
+        }
+    };
+    let (_, _) = (varint(), varint());
+    let (index_offset, index_size) = (varint(), varint());
+
+    let crc32c = |bytes: &[u8]| {
+        let mut crc = !0u32;
+        for &byte in bytes {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                let poly = if crc & 1 == 1 { 0x82F6_3B78 } else { 0 };
+                crc = (crc >> 1) ^ poly;
+            }
+        }
+        !crc
+    };
+    assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    let index = &sst[index_offset..index_offset + index_size + 1];
+    let crc = crc32c(index).rotate_right(15).wrapping_add(0xa282_ead8);
+    let trailer = &sst[index_offset + index_size + 1..index_offset + index_size + 5];
+    assert_eq!(trailer, &crc.to_le_bytes());
+
+    // Several data blocks, indexed by their last key.
+    let blocks = u32::from_le_bytes([
+        index[index_size - 4],
+        index[index_size - 3],
+        index[index_size - 2],
+        index[index_size - 1],
+    ]);
+    assert!(blocks > 1);
+    assert!(sst.windows(11).any(|window| window == b"key0999\x01\0\0\0"));
+
+    Ok(())
+}
+
+// The in-memory engine shares its dataset between its clones, and round-trips through the
+// exports like the engines on disk.
+#[test]
+fn mem_engine() -> Result<()> {
+    let engine = MemKvsEngine::new();
+    let clone = engine.clone();
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    clone.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.scan(), vec!["key1".to_owned(), "key2".to_owned()]);
+
+    let mut data = Vec::new();
+    assert_eq!(engine.export(&mut data)?, 2);
+    let imported = MemKvsEngine::new();
+    assert_eq!(imported.import(&data[..])?, 2);
+    assert_eq!(imported.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    clone.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    Ok(())
+}
+
+// The keys matching a glob-style pattern are found in the index, across its shards and its
+// spill file, leaving the expired ones out.
+#[test]
+fn keys_matching_pattern() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .index_shards(4)
+        .index_budget(512)
+        .open(temp_dir.path())?;
+    for key in &[
+        "user:1",
+        "user:2",
+        "user:10",
+        "log:2024-01",
+        "log:2024-12",
+        "log:2025-01",
+        "a*b",
+        "[x]",
+        "ünïcode",
+    ] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.set_with_ttl(
+        "user:3".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    thread::sleep(Duration::from_millis(150));
+
+    let keys = |pattern: &str| {
+        let mut keys = store.keys(pattern);
+        keys.sort();
+        keys
+    };
+    assert_eq!(keys("user:*"), vec!["user:1", "user:10", "user:2"]);
+    assert_eq!(keys("user:?"), vec!["user:1", "user:2"]);
+    assert_eq!(keys("*:2024-??"), vec!["log:2024-01", "log:2024-12"]);
+    assert_eq!(keys("log:*-[01]1"), vec!["log:2024-01", "log:2025-01"]);
+    assert_eq!(keys("log:202[3-4]-1*"), vec!["log:2024-12"]);
+    assert_eq!(keys("user:[^1]"), vec!["user:2"]);
+    assert_eq!(keys("user:[!1]*"), vec!["user:2"]);
+    assert_eq!(keys("a\\*b"), vec!["a*b"]);
+    assert_eq!(keys("[[]x]"), vec!["[x]"]);
+    assert_eq!(keys("?n?cod*"), vec!["ünïcode"]);
+    assert_eq!(keys("*").len(), 9);
+    assert!(keys("user").is_empty());
+    assert!(keys("").is_empty());
+
+    // The engines without an index of their own match their scans.
+    let engine = MemKvsEngine::new();
+    engine.set("user:1".to_owned(), "value".to_owned())?;
+    engine.set("order:1".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.keys("user:*"), vec!["user:1"]);
+
+    Ok(())
+}
+
+/// Lists the 50 keys set in `engine` in pages of 16, and checks that every key is listed once,
+/// in order.
+fn paginated_listing<E: KvsEngine>(engine: E) -> Result<()> {
+    for i in 0..50 {
+        engine.set(format!("key{:02}", i), "value".to_owned())?;
+    }
+    let mut listed = Vec::new();
+    let mut after = None;
+    loop {
+        let page = engine.list_keys(after.as_deref(), 16)?;
+        if page.is_empty() {
+            break;
+        }
+        assert!(page.len() <= 16);
+        after = page.last().cloned();
+        listed.extend(page);
+    }
+    let expected: Vec<String> = (0..50).map(|i| format!("key{:02}", i)).collect();
+    assert_eq!(listed, expected);
+
+    assert_eq!(engine.list_keys(Some("key10x"), 2)?, vec!["key11", "key12"]);
+    assert_eq!(engine.list_keys(Some("a"), 1)?, vec!["key00"]);
+    assert!(engine.list_keys(None, 0)?.is_empty());
+    Ok(())
+}
+
+// The keys are listed a page at a time, in order, by every engine, the store leaving the expired
+// and removed keys out whether they are in memory or spilled.
+#[test]
+fn list_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .index_shards(4)
+        .index_budget(512)
+        .open(temp_dir.path())?;
+    paginated_listing(store.clone())?;
+    store.set_with_ttl(
+        "key25x".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    assert_eq!(store.list_keys(Some("key25"), 1)?, vec!["key25x"]);
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(store.list_keys(Some("key25"), 1)?, vec!["key26"]);
+    store.remove("key26".to_owned())?;
+    assert_eq!(store.list_keys(Some("key25"), 2)?, vec!["key27", "key28"]);
+
+    paginated_listing(MemKvsEngine::new())?;
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        paginated_listing(SledKvsEngine::open(temp_dir.path())?)?;
+    }
+    Ok(())
+}
+
+// The lengths of the values are returned in bytes, whether the values are in the log, split in
+// chunks or in the value log, and the missing or expired keys have none.
+#[test]
+fn value_lengths() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .separate_values(100)
+        .open(temp_dir.path())?;
+    store.set("small".to_owned(), "\"quoted\" é".to_owned())?;
+    store.set("large".to_owned(), "x".repeat(1000))?;
+    store.set("empty".to_owned(), String::new())?;
+    store.set("chunked".to_owned(), "y".repeat(10_000))?;
+    store.set_with_ttl(
+        "expired".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.strlen("small".to_owned())?, Some(11));
+        assert_eq!(store.strlen("large".to_owned())?, Some(1000));
+        assert_eq!(store.strlen("empty".to_owned())?, Some(0));
+        assert_eq!(store.strlen("chunked".to_owned())?, Some(10_000));
+        assert_eq!(store.strlen("expired".to_owned())?, None);
+        assert_eq!(store.strlen("missing".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    // The lengths are kept in the checkpoints, by the compactions and in a spilled index.
+    store.checkpoint()?;
+    drop(store);
+    let store = KvStoreBuilder::new()
+        .index_budget(0)
+        .open(temp_dir.path())?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    store.remove("small".to_owned())?;
+    assert_eq!(store.strlen("small".to_owned())?, None);
+
+    let engine = MemKvsEngine::new();
+    engine.set("key".to_owned(), "é".to_owned())?;
+    assert_eq!(engine.strlen("key".to_owned())?, Some(2));
+
+    Ok(())
+}
+
+// Once compaction falls behind, the writes are delayed, then stalled with a BUSY error until it
+// catches up.
+#[test]
+fn write_throttle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let started = Arc::new(Barrier::new(2));
+    let gate = Arc::new(Mutex::new(()));
+    let builder = {
+        let (started, gate) = (Arc::clone(&started), Arc::clone(&gate));
+        let first = AtomicBool::new(true);
+        KvStoreBuilder::new()
+            .throttle_writes(1 << 20, 2 << 20)
+            .log_storage(move |path| {
+                // Holds the first compaction back until the gate opens.
+                let compacted = path.extension().is_some_and(|ext| ext == "tmp");
+                if compacted && first.swap(false, Ordering::SeqCst) {
+                    started.wait();
+                    drop(gate.lock().unwrap());
+                }
+                Ok(Box::new(FileStorage::open(path)?) as Box<dyn LogStorage>)
+            })
+    };
+    let store = builder.open(temp_dir.path())?;
+    let value = "x".repeat(256 << 10);
+
+    let closed = gate.lock().unwrap();
+    // The fifth write makes the redundant bytes reach 1MB and starts a compaction.
+    let compacting = {
+        let (store, value) = (store.clone(), value.clone());
+        thread::spawn(move || -> Result<()> {
+            for _ in 0..5 {
+                store.set("key1".to_owned(), value.clone())?;
+            }
+            Ok(())
+        })
+    };
+    started.wait();
+
+    let mut stalled = None;
+    for _ in 0..10 {
+        if let Err(e) = store.set("key2".to_owned(), value.clone()) {
+            stalled = Some(e);
+            break;
+        }
+    }
+    let stalled = stalled.expect("the writes were never stalled");
+    assert!(matches!(stalled, KvsError::WriteStall(bytes) if bytes >= 2 << 20));
+    assert_eq!(stalled.code(), "BUSY");
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+
+    drop(closed);
+    compacting.join().unwrap()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// The values of several keys are got in the order of the keys, from the cache, the log or the
+// value log, whatever the order of their records.
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .separate_values(100)
+        .value_cache(1 << 10)
+        .index_budget(256)
+        .open(temp_dir.path())?;
+    for i in (0..50).rev() {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("large".to_owned(), "x".repeat(1000))?;
+    store.set_with_ttl(
+        "expired".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(10),
+    )?;
+    thread::sleep(Duration::from_millis(50));
+    // Cached.
+    assert_eq!(store.get("key7".to_owned())?, Some("value7".to_owned()));
+
+    let keys = [
+        "key3", "missing", "key42", "large", "key7", "expired", "key3",
+    ];
+    let values = store.get_many(keys.iter().map(|key| key.to_string()).collect())?;
+    assert_eq!(
+        values,
+        vec![
+            Some("value3".to_owned()),
+            None,
+            Some("value42".to_owned()),
+            Some("x".repeat(1000)),
+            Some("value7".to_owned()),
+            None,
+            Some("value3".to_owned()),
+        ]
+    );
+    assert!(store.get_many(Vec::new())?.is_empty());
+
+    Ok(())
+}
+
+/// A log whose next read waits for the gate to open once `hold` is set.
+struct GatedStorage {
+    file: FileStorage,
+    hold: Arc<AtomicBool>,
+    reading: Arc<Barrier>,
+    gate: Arc<Mutex<()>>,
+}
+
+impl LogStorage for GatedStorage {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        self.file.append(buf)
+    }
+
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if self.hold.swap(false, Ordering::SeqCst) {
+            self.reading.wait();
+            drop(self.gate.lock().unwrap());
+        }
+        self.file.read_at(pos, buf)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.file.len()
+    }
+}
+
+// A read in progress while a compaction switches logs finishes on the log and value log it
+// started on, which the compaction retires only after it.
+#[test]
+fn reads_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hold = Arc::new(AtomicBool::new(false));
+    let reading = Arc::new(Barrier::new(2));
+    let gate = Arc::new(Mutex::new(()));
+    let builder = {
+        let (hold, reading, gate) = (Arc::clone(&hold), Arc::clone(&reading), Arc::clone(&gate));
+        KvStoreBuilder::new()
+            .separate_values(10)
+            .log_storage(move |path| {
+                Ok(Box::new(GatedStorage {
+                    file: FileStorage::open(path)?,
+                    hold: Arc::clone(&hold),
+                    reading: Arc::clone(&reading),
+                    gate: Arc::clone(&gate),
+                }) as Box<dyn LogStorage>)
+            })
+    };
+    let store = builder.open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set("key1".to_owned(), format!("value{:010}", i))?;
+    }
+    let old_values = temp_dir.path().join("vlog.0");
+    assert!(old_values.exists());
+
+    let closed = gate.lock().unwrap();
+    hold.store(true, Ordering::SeqCst);
+    let read = {
+        let store = store.clone();
+        thread::spawn(move || store.get("key1".to_owned()))
+    };
+    reading.wait();
+
+    store.set("key1".to_owned(), format!("value{:010}", 10))?;
+    assert!(store.compact()? > 0);
+    assert!(temp_dir.path().join("vlog.1").exists());
+    assert!(old_values.exists());
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some(format!("value{:010}", 10))
+    );
+
+    drop(closed);
+    assert_eq!(read.join().unwrap()?, Some(format!("value{:010}", 9)));
+    assert!(!old_values.exists());
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some(format!("value{:010}", 10))
+    );
+
+    Ok(())
+}
+
+/// A log whose next append waits for the gate to open once `hold` is set, counting the appends.
+struct HeldStorage {
+    file: FileStorage,
+    hold: Arc<AtomicBool>,
+    appending: Arc<Barrier>,
+    gate: Arc<Mutex<()>>,
+    appends: Arc<AtomicUsize>,
+}
+
+impl LogStorage for HeldStorage {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        self.appends.fetch_add(1, Ordering::SeqCst);
+        if self.hold.swap(false, Ordering::SeqCst) {
+            self.appending.wait();
+            drop(self.gate.lock().unwrap());
+        }
+        self.file.append(buf)
+    }
+
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read_at(pos, buf)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.file.len()
+    }
+}
+
+// A record written but not flushed to the log yet is read from the buffer it waits in.
+#[test]
+fn reads_before_flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hold = Arc::new(AtomicBool::new(false));
+    let appending = Arc::new(Barrier::new(2));
+    let gate = Arc::new(Mutex::new(()));
+    let builder = {
+        let (hold, appending, gate) =
+            (Arc::clone(&hold), Arc::clone(&appending), Arc::clone(&gate));
+        KvStoreBuilder::new().log_storage(move |path| {
+            Ok(Box::new(HeldStorage {
+                file: FileStorage::open(path)?,
+                hold: Arc::clone(&hold),
+                appending: Arc::clone(&appending),
+                gate: Arc::clone(&gate),
+                appends: Arc::default(),
+            }) as Box<dyn LogStorage>)
+        })
+    };
+    let store = builder.open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log_size = fs::metadata(temp_dir.path().join("log"))?.len();
+
+    let closed = gate.lock().unwrap();
+    hold.store(true, Ordering::SeqCst);
+    let write = {
+        let store = store.clone();
+        thread::spawn(move || store.set("key1".to_owned(), "value2".to_owned()))
+    };
+    appending.wait();
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(fs::metadata(temp_dir.path().join("log"))?.len(), log_size);
+
+    drop(closed);
+    write.join().unwrap()?;
+    assert!(fs::metadata(temp_dir.path().join("log"))?.len() > log_size);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// The writes queued while the log is being flushed are flushed together once it is done.
+#[test]
+fn writes_share_flushes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hold = Arc::new(AtomicBool::new(false));
+    let appending = Arc::new(Barrier::new(2));
+    let gate = Arc::new(Mutex::new(()));
+    let appends = Arc::new(AtomicUsize::new(0));
+    let builder = {
+        let (hold, appending) = (Arc::clone(&hold), Arc::clone(&appending));
+        let (gate, appends) = (Arc::clone(&gate), Arc::clone(&appends));
+        KvStoreBuilder::new().log_storage(move |path| {
+            Ok(Box::new(HeldStorage {
+                file: FileStorage::open(path)?,
+                hold: Arc::clone(&hold),
+                appending: Arc::clone(&appending),
+                gate: Arc::clone(&gate),
+                appends: Arc::clone(&appends),
+            }) as Box<dyn LogStorage>)
+        })
+    };
+    let store = builder.open(temp_dir.path())?;
+
+    let closed = gate.lock().unwrap();
+    hold.store(true, Ordering::SeqCst);
+    let first = {
+        let store = store.clone();
+        thread::spawn(move || store.set("key0".to_owned(), "value0".to_owned()))
+    };
+    appending.wait();
+    let queued: Vec<_> = (1..=10)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || store.set(format!("key{}", i), format!("value{}", i)))
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(100));
+    drop(closed);
+    first.join().unwrap()?;
+    for write in queued {
+        write.join().unwrap()?;
+    }
+
+    assert!(appends.load(Ordering::SeqCst) < 11);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..=10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+/// Increments the counter at `key` from 8 threads at once, and checks that no increment is lost.
+fn concurrent_updates<E: KvsEngine>(engine: E) -> Result<()> {
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    engine.update("counter".to_owned(), |old| {
+                        let count: u64 = old.map_or(0, |old| old.parse().unwrap());
+                        Some((count + 1).to_string())
+                    })?;
+                }
+                Ok::<(), KvsError>(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("400".to_owned()));
+
+    assert_eq!(engine.update("counter".to_owned(), |_| None)?, None);
+    assert_eq!(engine.get("counter".to_owned())?, None);
+    assert_eq!(engine.update("missing".to_owned(), |old| old)?, None);
+    Ok(())
+}
+
+// No write comes in between the read and the write of an update.
+#[test]
+fn update_is_atomic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    concurrent_updates(store.clone())?;
+
+    // A key keeps its time to live, and too large values are rejected.
+    store.set_with_ttl("key".to_owned(), "1".to_owned(), Duration::from_secs(60))?;
+    store.update("key".to_owned(), |_| Some("2".to_owned()))?;
+    assert!(store.ttl("key".to_owned())?.is_some());
+    let too_large = "x".repeat(17 << 20);
+    assert!(store
+        .update("key".to_owned(), move |_| Some(too_large))
+        .is_err());
+    assert_eq!(store.get("key".to_owned())?, Some("2".to_owned()));
+
+    concurrent_updates(MemKvsEngine::new())?;
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        concurrent_updates(SledKvsEngine::open(temp_dir.path())?)?;
+    }
+    Ok(())
+}
+
+// A versioned store tells the version of the keys, which survives reopening, and only sets a key
+// still at the version expected.
+#[test]
+fn set_if_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let unversioned = KvStore::open(temp_dir.path())?;
+    unversioned.set("key1".to_owned(), "value1".to_owned())?;
+    match unversioned.get_versioned("key1".to_owned()) {
+        Err(KvsError::CmdNotSupport) => {}
+        _ => panic!("expected the store not to support versions"),
+    }
+    drop(unversioned);
+
+    let store = KvStoreBuilder::new()
+        .versioned(true)
+        .open(temp_dir.path())?;
+    // Written before the store was versioned.
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value1".to_owned(), 0))
+    );
+    let version = store.set_if_version("key1".to_owned(), "value2".to_owned(), Some(0))?;
+    assert!(version > 0);
+    match store.set_if_version("key1".to_owned(), "value3".to_owned(), Some(0)) {
+        Err(KvsError::VersionMismatch { expected, actual }) => {
+            assert_eq!((expected, actual), (Some(0), Some(version)));
+        }
+        _ => panic!("expected a version mismatch"),
+    }
+    assert!(store
+        .set_if_version("key1".to_owned(), "value3".to_owned(), None)
+        .is_err());
+    let created = store.set_if_version("key2".to_owned(), "value".to_owned(), None)?;
+    assert!(created > version);
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get_versioned("key2".to_owned())?, None);
+    drop(store);
+
+    let store = KvStoreBuilder::new()
+        .versioned(true)
+        .open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value2".to_owned(), version))
+    );
+    assert!(store.set("key3".to_owned(), "value".to_owned()).is_ok());
+    assert!(store.get_versioned("key3".to_owned())?.unwrap().1 > created);
+    Ok(())
+}
+
+/// Races 8 threads for a lock of `engine`, and checks that only one acquires it, that it is only
+/// released under its token, and that every hold gets a greater token than the one before.
+fn lock_holds<E: KvsEngine>(engine: E) -> Result<()> {
+    let ttl = Duration::from_secs(60);
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || engine.acquire_lock("leader".to_owned(), ttl))
+        })
+        .collect();
+    let mut tokens = Vec::new();
+    for handle in handles {
+        tokens.extend(handle.join().unwrap()?);
+    }
+    assert_eq!(tokens.len(), 1);
+    let token = tokens[0];
+
+    assert!(!engine.release_lock("leader".to_owned(), token + 1)?);
+    assert_eq!(engine.acquire_lock("leader".to_owned(), ttl)?, None);
+    assert!(engine.release_lock("leader".to_owned(), token)?);
+    assert!(!engine.release_lock("leader".to_owned(), token)?);
+    let next = engine.acquire_lock("leader".to_owned(), Duration::from_millis(50))?;
+    assert!(next.unwrap() > token);
+
+    // An expired hold is no longer released, and the lock can be acquired again.
+    thread::sleep(Duration::from_millis(100));
+    assert!(!engine.release_lock("leader".to_owned(), next.unwrap())?);
+    let last = engine.acquire_lock("leader".to_owned(), ttl)?;
+    assert!(last > next);
+    assert!(!engine.release_lock("missing".to_owned(), 1)?);
+
+    // A held lock is neither read nor overwritten as a string.
+    match engine.get("leader".to_owned()) {
+        Err(KvsError::WrongType { key, expected }) => {
+            assert_eq!(key, "leader");
+            assert_eq!(expected, "string");
+        }
+        _ => panic!("expected a lock not to be read as a string"),
+    }
+    match engine.set("leader".to_owned(), "1".to_owned()) {
+        Err(KvsError::WrongType { key, .. }) => assert_eq!(key, "leader"),
+        _ => panic!("expected a lock not to be overwritten by a string"),
+    }
+
+    // Removing a lock releases it, and the next hold still gets a greater token.
+    engine.remove("leader".to_owned())?;
+    assert!(!engine.release_lock("leader".to_owned(), last.unwrap())?);
+    let again = engine.acquire_lock("leader".to_owned(), ttl)?;
+    assert!(again > last);
+
+    engine.set("key".to_owned(), "value".to_owned())?;
+    match engine.acquire_lock("key".to_owned(), ttl) {
+        Err(KvsError::WrongType { key, .. }) => assert_eq!(key, "key"),
+        _ => panic!("expected the key not to hold a lock"),
+    }
+    assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+
+    // A string which reads like a fencing token is not a lock either.
+    engine.set("counter".to_owned(), "42".to_owned())?;
+    match engine.acquire_lock("counter".to_owned(), ttl) {
+        Err(KvsError::WrongType { key, .. }) => assert_eq!(key, "counter"),
+        _ => panic!("expected the key not to hold a lock"),
+    }
+    match engine.release_lock("counter".to_owned(), 42) {
+        Err(KvsError::WrongType { key, .. }) => assert_eq!(key, "counter"),
+        _ => panic!("expected the key not to hold a lock"),
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("42".to_owned()));
+    Ok(())
+}
+
+// A lock is held by one client at a time, under fencing tokens which keep increasing, even once
+// the store is reopened.
+#[test]
+fn locks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    lock_holds(store.clone())?;
+    let token = store
+        .acquire_lock("lock".to_owned(), Duration::from_secs(60))?
+        .unwrap();
+    assert!(store.release_lock("lock".to_owned(), token)?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let next = store.acquire_lock("lock".to_owned(), Duration::from_secs(60))?;
+    assert_eq!(next, Some(token + 1));
+
+    lock_holds(MemKvsEngine::new())?;
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        lock_holds(SledKvsEngine::open(temp_dir.path())?)?;
+    }
+    Ok(())
+}
+
+/// Fills a list, a set and a hash of `engine`, and checks their content and that no command
+/// works on a key of another type.
+fn collections<E: KvsEngine>(engine: &E) -> Result<()> {
+    let strings = |strings: &[&str]| strings.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        engine.list_push("list".to_owned(), strings(&["a", "b"]))?,
+        2
+    );
+    assert_eq!(engine.list_push("list".to_owned(), strings(&["c"]))?, 3);
+    assert_eq!(
+        engine.list_range("list".to_owned(), 0, -1)?,
+        strings(&["c", "b", "a"])
+    );
+    assert_eq!(engine.list_range("list".to_owned(), 1, 1)?, strings(&["b"]));
+    assert_eq!(
+        engine.list_range("list".to_owned(), -5, 0)?,
+        strings(&["c"])
+    );
+    assert!(engine.list_range("list".to_owned(), 2, 1)?.is_empty());
+    assert!(engine.list_range("list".to_owned(), 3, 10)?.is_empty());
+    assert!(engine.list_range("missing".to_owned(), 0, -1)?.is_empty());
+
+    assert_eq!(
+        engine.set_add("set".to_owned(), strings(&["b", "a", "b"]))?,
+        2
+    );
+    assert_eq!(engine.set_add("set".to_owned(), strings(&["a"]))?, 0);
+    assert_eq!(engine.set_members("set".to_owned())?, strings(&["a", "b"]));
+
+    let field = |field: &str| engine.hash_get("hash".to_owned(), field.to_owned());
+    assert!(engine.hash_set("hash".to_owned(), "f1".to_owned(), "v1".to_owned())?);
+    assert!(!engine.hash_set("hash".to_owned(), "f1".to_owned(), "v2".to_owned())?);
+    assert!(engine.hash_set("hash".to_owned(), "f2".to_owned(), "line\nbreak".to_owned())?);
+    assert_eq!(field("f1")?, Some("v2".to_owned()));
+    assert_eq!(field("f2")?, Some("line\nbreak".to_owned()));
+    assert_eq!(field("f3")?, None);
+
+    engine.set("string".to_owned(), "value".to_owned())?;
+    let wrong_types = vec![
+        engine.list_push("set".to_owned(), strings(&["a"])).err(),
+        engine.list_range("string".to_owned(), 0, -1).err(),
+        engine.set_add("hash".to_owned(), strings(&["a"])).err(),
+        engine.set_members("list".to_owned()).err(),
+        engine
+            .hash_set("string".to_owned(), "f".to_owned(), "v".to_owned())
+            .err(),
+        engine.hash_get("list".to_owned(), "f".to_owned()).err(),
+        engine.get("list".to_owned()).err(),
+        engine.get("set".to_owned()).err(),
+        engine.get("hash".to_owned()).err(),
+        engine.strlen("list".to_owned()).err(),
+        engine.get_many(strings(&["string", "hash"])).err(),
+    ];
+    for error in wrong_types {
+        match error {
+            Some(KvsError::WrongType { .. }) => {}
+            _ => panic!("expected the key to hold another type"),
+        }
+    }
+    assert_eq!(engine.get("string".to_owned())?, Some("value".to_owned()));
+    assert_eq!(engine.set_members("set".to_owned())?, strings(&["a", "b"]));
+
+    // A string is a string, whatever it looks like, and a string set over a collection
+    // replaces it.
+    let forged = r#"["x"]"#.to_owned();
+    engine.set("forged".to_owned(), forged.clone())?;
+    assert!(matches!(
+        engine.list_range("forged".to_owned(), 0, -1),
+        Err(KvsError::WrongType { .. })
+    ));
+    assert_eq!(engine.get("forged".to_owned())?, Some(forged));
+    engine.set_add("replaced".to_owned(), strings(&["a"]))?;
+    engine.set("replaced".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get("replaced".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Lists, sets and hashes are kept in the values of their keys by every engine, and survive the
+// store being reopened.
+#[test]
+fn collection_types() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    collections(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.list_range("list".to_owned(), 0, 0)?, vec!["c"]);
+    assert_eq!(store.set_members("set".to_owned())?, vec!["a", "b"]);
+    assert_eq!(
+        store.hash_get("hash".to_owned(), "f1".to_owned())?,
+        Some("v2".to_owned())
+    );
+    assert!(matches!(
+        store.get("hash".to_owned()),
+        Err(KvsError::WrongType { .. })
+    ));
+
+    // An export keeps the types, and the other formats leave the collections out.
+    let mut exported = Vec::new();
+    store.export(&mut exported)?;
+    let imported = MemKvsEngine::new();
+    imported.import(&exported[..])?;
+    assert_eq!(
+        imported.list_range("list".to_owned(), 0, -1)?,
+        vec!["c", "b", "a"]
+    );
+    assert_eq!(imported.get("string".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.export_bitcask(io::sink())?, 3);
+
+    store.remove("list".to_owned())?;
+    assert!(store.list_range("list".to_owned(), 0, -1)?.is_empty());
+
+    collections(&MemKvsEngine::new())?;
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        collections(&SledKvsEngine::open(temp_dir.path())?)?;
+    }
+    Ok(())
+}
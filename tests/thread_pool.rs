@@ -1,8 +1,10 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -68,3 +70,450 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn shared_queue_thread_pool_shutdown_drains_queue() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let pool = SharedQueueThreadPool::new(2)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    pool.shutdown();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_join_timeout() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    pool.spawn(|| thread::sleep(Duration::from_millis(500)));
+
+    assert!(!pool.join(Duration::from_millis(10)));
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_bounded_queue_full() -> Result<()> {
+    let pool: SharedQueueThreadPool = ThreadPoolBuilder::new(1).queue_capacity(1).build()?;
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+    });
+    started_rx.recv().unwrap();
+
+    pool.try_spawn(|| ())?;
+    match pool.try_spawn(|| ()) {
+        Err(KvsError::QueueFull) => (),
+        _ => panic!("expected the queue to be full"),
+    }
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_bound_shared_by_priorities() -> Result<()> {
+    let pool: SharedQueueThreadPool = ThreadPoolBuilder::new(1).queue_capacity(2).build()?;
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+    });
+    started_rx.recv().unwrap();
+
+    pool.try_spawn_with_priority(|| (), Priority::Low)?;
+    pool.try_spawn_with_priority(|| (), Priority::Normal)?;
+    for priority in [Priority::High, Priority::Normal, Priority::Low].iter() {
+        match pool.try_spawn_with_priority(|| (), *priority) {
+            Err(KvsError::QueueFull) => (),
+            _ => panic!("expected the queues to be full"),
+        }
+    }
+    let metrics = pool.metrics();
+    pool.shutdown();
+    assert_eq!(metrics.snapshot().executed, 3);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_try_spawn_saturated() -> Result<()> {
+    let pool = NaiveThreadPool::new(1)?;
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    pool.try_spawn(move || release_rx.recv().unwrap())?;
+    match pool.try_spawn(|| ()) {
+        Err(KvsError::QueueFull) => (),
+        _ => panic!("expected the pool to be saturated"),
+    }
+
+    release_tx.send(()).unwrap();
+    let metrics = pool.metrics();
+    pool.shutdown();
+    assert_eq!(metrics.snapshot().queued, 0);
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_try_spawn() -> Result<()> {
+    let pool = RayonThreadPool::new(1)?;
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for i in 0..10 {
+        let sender = sender.clone();
+        pool.try_spawn_with_priority(move || sender.send(i).unwrap(), Priority::Low)?;
+    }
+    assert_eq!(receiver.iter().take(10).sum::<i32>(), 45);
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_build_error() {
+    let result = ThreadPoolBuilder::new(1)
+        .stack_size(1 << 60)
+        .build::<RayonThreadPool>();
+    assert!(matches!(result, Err(KvsError::Internal(_))));
+}
+
+fn panic_handler<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 10;
+
+    let handled = Arc::new(AtomicUsize::new(0));
+    let pool: P = {
+        let handled = Arc::clone(&handled);
+        ThreadPoolBuilder::new(2)
+            .panic_handler(move |payload| {
+                assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+                handled.fetch_add(1, Ordering::SeqCst);
+            })
+            .build()?
+    };
+    for _ in 0..TASK_NUM {
+        pool.spawn(|| panic!("boom"));
+    }
+
+    while pool.stats().panics < TASK_NUM as u64 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    pool.shutdown();
+    assert_eq!(handled.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_panic_handler() -> Result<()> {
+    panic_handler::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_panic_handler() -> Result<()> {
+    panic_handler::<NaiveThreadPool>()
+}
+
+// A panicking panic handler neither takes the worker down nor keeps `shutdown` waiting.
+#[test]
+fn shared_queue_thread_pool_panicking_panic_handler() -> Result<()> {
+    let pool = ThreadPoolBuilder::new(1)
+        .panic_handler(|_| panic!("handler"))
+        .build::<SharedQueueThreadPool>()?;
+    pool.spawn(|| panic!("boom"));
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    pool.spawn(move || sender.send(()).unwrap());
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    pool.shutdown();
+    Ok(())
+}
+
+// A worker whose start callback panics is no longer counted, so `shutdown` returns.
+#[test]
+fn shared_queue_thread_pool_panicking_start() -> Result<()> {
+    let pool = ThreadPoolBuilder::new(2)
+        .on_thread_start(|_| panic!("start"))
+        .build::<SharedQueueThreadPool>()?;
+    pool.spawn(|| ());
+    pool.shutdown();
+    Ok(())
+}
+
+// A job whose thread cannot be spawned is rejected by `try_spawn` and dropped by `spawn`,
+// giving its thread back to the pool.
+#[test]
+fn naive_thread_pool_spawn_error() -> Result<()> {
+    let pool = ThreadPoolBuilder::new(1)
+        .stack_size(1 << 60)
+        .build::<NaiveThreadPool>()?;
+    assert!(pool.try_spawn(|| ()).is_err());
+    pool.spawn(|| ());
+    pool.spawn(|| ());
+    let metrics = pool.metrics();
+    pool.shutdown();
+    assert_eq!(metrics.snapshot().queued, 0);
+    assert_eq!(metrics.snapshot().executed, 0);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_error() -> Result<()> {
+    let pool = ThreadPoolBuilder::new(1)
+        .idle_timeout(Duration::from_millis(100))
+        .stack_size(1 << 60)
+        .build::<SharedQueueThreadPool>()?;
+    assert!(pool.try_spawn(|| ()).is_err());
+    pool.spawn(|| ());
+    pool.spawn(|| ());
+    let metrics = pool.metrics();
+    pool.shutdown();
+    assert_eq!(metrics.snapshot().queued, 0);
+    assert_eq!(metrics.snapshot().executed, 0);
+    Ok(())
+}
+
+// A scoped job dropped because its thread cannot be spawned counts as finished, so that the
+// scope returns.
+#[test]
+fn naive_thread_pool_scope_spawn_error() -> Result<()> {
+    let pool = ThreadPoolBuilder::new(1)
+        .stack_size(1 << 60)
+        .build::<NaiveThreadPool>()?;
+    let mut ran = false;
+    pool.scope(|s| s.spawn(|| ran = true));
+    assert!(!ran);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_scope_spawn_error() -> Result<()> {
+    let pool = ThreadPoolBuilder::new(1)
+        .idle_timeout(Duration::from_millis(100))
+        .stack_size(1 << 60)
+        .build::<SharedQueueThreadPool>()?;
+    let mut ran = false;
+    pool.scope(|s| s.spawn(|| ran = true));
+    assert!(!ran);
+    Ok(())
+}
+
+fn named_threads<P: ThreadPool>() -> Result<()> {
+    let started = Arc::new(AtomicUsize::new(0));
+    let pool: P = {
+        let started = Arc::clone(&started);
+        ThreadPoolBuilder::new(2)
+            .thread_name("worker")
+            .on_thread_start(move |_| {
+                started.fetch_add(1, Ordering::SeqCst);
+            })
+            .build()?
+    };
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    pool.spawn(move || {
+        let name = thread::current().name().map(str::to_owned);
+        sender.send(name).unwrap();
+    });
+    let name = receiver.recv().unwrap().unwrap();
+    assert!(name.starts_with("worker-"));
+    assert!(started.load(Ordering::SeqCst) >= 1);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_named_threads() -> Result<()> {
+    named_threads::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_named_threads() -> Result<()> {
+    named_threads::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_named_threads() -> Result<()> {
+    named_threads::<RayonThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_priority_order() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    pool.spawn(move || release_rx.recv().unwrap());
+
+    let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+    for &priority in &[Priority::Low, Priority::Normal, Priority::High] {
+        let order = Arc::clone(&order);
+        pool.spawn_with_priority(move || order.lock().unwrap().push(priority), priority);
+    }
+    release_tx.send(()).unwrap();
+    pool.shutdown();
+
+    assert_eq!(
+        *order.lock().unwrap(),
+        vec![Priority::High, Priority::Normal, Priority::Low]
+    );
+    Ok(())
+}
+
+fn scoped_jobs<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let mut results = vec![0; 8];
+    pool.scope(|s| {
+        for (i, result) in results.iter_mut().enumerate() {
+            s.spawn(move || *result = i * i);
+        }
+    });
+    assert_eq!(results, (0..8).map(|i| i * i).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_scope() -> Result<()> {
+    scoped_jobs::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_scope() -> Result<()> {
+    scoped_jobs::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_scope() -> Result<()> {
+    scoped_jobs::<RayonThreadPool>()
+}
+
+#[test]
+#[should_panic]
+fn shared_queue_thread_pool_scope_propagates_panic() {
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    pool.scope(|s| s.spawn(|| panic!()));
+}
+
+#[test]
+fn shared_queue_thread_pool_stats() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let (release_tx, release_rx) = crossbeam_channel::unbounded::<()>();
+    for _ in 0..3 {
+        let release_rx = release_rx.clone();
+        pool.spawn(move || release_rx.recv().unwrap());
+    }
+    while pool.stats().busy < 2 {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let stats = pool.stats();
+    assert_eq!((stats.queued, stats.busy, stats.idle), (1, 2, 0));
+
+    for _ in 0..3 {
+        release_tx.send(()).unwrap();
+    }
+    while pool.stats().executed < 3 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let stats = pool.stats();
+    assert_eq!((stats.queued, stats.busy, stats.idle), (0, 0, 2));
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_bounds_concurrency() -> Result<()> {
+    const THREADS: usize = 2;
+
+    let pool = NaiveThreadPool::new(THREADS)?;
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+    for _ in 0..10 {
+        let running = Arc::clone(&running);
+        let max_running = Arc::clone(&max_running);
+        pool.spawn(move || {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    pool.shutdown();
+
+    assert_eq!(running.load(Ordering::SeqCst), 0);
+    assert!(max_running.load(Ordering::SeqCst) <= THREADS);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_idle_timeout() -> Result<()> {
+    let pool: SharedQueueThreadPool = ThreadPoolBuilder::new(4)
+        .idle_timeout(Duration::from_millis(50))
+        .build()?;
+    assert_eq!(pool.stats().idle, 0);
+
+    let (release_tx, release_rx) = crossbeam_channel::unbounded::<()>();
+    for _ in 0..4 {
+        let release_rx = release_rx.clone();
+        pool.spawn(move || release_rx.recv().unwrap());
+    }
+    while pool.stats().busy < 4 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    for _ in 0..4 {
+        release_tx.send(()).unwrap();
+    }
+
+    // Every worker exits once idle for longer than the timeout.
+    thread::sleep(Duration::from_millis(300));
+    let stats = pool.stats();
+    assert_eq!((stats.busy, stats.idle), (0, 0));
+
+    spawn_counter(pool)
+}
+
+/// The cores the calling thread may run on, as listed by Linux.
+#[cfg(target_os = "linux")]
+fn allowed_cores_list() -> String {
+    let status = std::fs::read_to_string("/proc/thread-self/status").unwrap();
+    let line = status
+        .lines()
+        .find(|line| line.starts_with("Cpus_allowed_list:"))
+        .unwrap();
+    line["Cpus_allowed_list:".len()..].trim().to_owned()
+}
+
+#[cfg(target_os = "linux")]
+fn pinned_threads<P: ThreadPool>() -> Result<()> {
+    let pool: P = ThreadPoolBuilder::new(2)
+        .core_affinity(CoreAffinity::Spread)
+        .build()?;
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for _ in 0..4 {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(allowed_cores_list()).unwrap());
+    }
+    for cores in receiver.iter().take(4) {
+        // A single core, not a list or a range.
+        assert!(cores.parse::<usize>().is_ok(), "not pinned: {}", cores);
+    }
+
+    let unavailable = ThreadPoolBuilder::new(2)
+        .core_affinity(CoreAffinity::Cores(vec![usize::MAX]))
+        .build::<P>();
+    assert!(unavailable.is_err());
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn naive_thread_pool_pinned_threads() -> Result<()> {
+    pinned_threads::<NaiveThreadPool>()
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn shared_queue_thread_pool_pinned_threads() -> Result<()> {
+    pinned_threads::<SharedQueueThreadPool>()
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn rayon_thread_pool_pinned_threads() -> Result<()> {
+    pinned_threads::<RayonThreadPool>()
+}